@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use simm_rs::{Crif, EngineConfig, SIMM, V2_5, filter_rows, k_delta, sum_sensitivities};
+
+const TENORS: [&str; 12] = ["2w", "1m", "3m", "6m", "1y", "2y", "3y", "5y", "10y", "15y", "20y", "30y"];
+const CURRENCIES: [&str; 6] = ["USD", "EUR", "GBP", "JPY", "CHF", "AUD"];
+
+/// Deterministic CRIF with `n` rows spread over every risk class
+fn generate_crif(n: usize) -> Crif {
+    let header = ["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2", "Amount", "AmountCurrency", "AmountUSD"];
+    let mut crif: Crif = vec![header.iter().map(|s| s.to_string()).collect()];
+
+    for i in 0..n {
+        let ccy = CURRENCIES[i % CURRENCIES.len()];
+        let tenor = TENORS[i % TENORS.len()];
+        let bucket = (i % 12 + 1).to_string();
+        // Alternate signs so netting is exercised
+        let amount = if i % 3 == 0 { -1.0 } else { 1.0 } * (10_000.0 + (i * 7919 % 1_000_000) as f64);
+
+        let row: [String; 6] = match i % 8 {
+            0 => ["RatesFX".into(), "Risk_IRCurve".into(), ccy.into(), "1".into(), tenor.into(), "OIS".into()],
+            1 => ["RatesFX".into(), "Risk_IRVol".into(), ccy.into(), "".into(), tenor.into(), "".into()],
+            2 => ["RatesFX".into(), "Risk_FX".into(), ccy.into(), "".into(), "".into(), "".into()],
+            3 => ["Credit".into(), "Risk_CreditQ".into(), format!("ISSUER{}", i % 40), bucket, TENORS[4 + i % 8].into(), "USD".into()],
+            4 => ["Credit".into(), "Risk_CreditNonQ".into(), format!("RMBS{}", i % 20), (i % 2 + 1).to_string(), TENORS[4 + i % 8].into(), "USD".into()],
+            5 => ["Equity".into(), "Risk_Equity".into(), format!("EQ{}", i % 60), bucket, "".into(), "".into()],
+            6 => ["Equity".into(), "Risk_EquityVol".into(), format!("EQ{}", i % 60), bucket, tenor.into(), "".into()],
+            _ => ["Commodity".into(), "Risk_Commodity".into(), format!("CMD{}", i % 30), (i % 16 + 1).to_string(), "".into(), "".into()],
+        };
+
+        let mut row: Vec<String> = row.to_vec();
+        row.push(amount.to_string());
+        row.push("USD".to_string());
+        row.push(amount.to_string());
+        crif.push(row);
+    }
+
+    crif
+}
+
+fn bench_from_crif(c: &mut Criterion) {
+    let cfg = EngineConfig::default();
+    let wnc = V2_5;
+
+    let mut group = c.benchmark_group("from_crif");
+    group.sample_size(10);
+    for size in [50, 500, 5000] {
+        let crif = generate_crif(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &crif, |b, crif| {
+            b.iter(|| SIMM::from_crif(black_box(crif.clone()), &cfg, &wnc).unwrap().simm)
+        });
+    }
+    group.finish();
+}
+
+fn bench_helpers(c: &mut Criterion) {
+    let crif = generate_crif(2000);
+
+    let mut conditions = HashMap::new();
+    conditions.insert("RiskType".to_string(), vec!["Risk_IRCurve".to_string()]);
+    conditions.insert("Qualifier".to_string(), vec!["USD".to_string()]);
+
+    c.bench_function("filter_rows/2000", |b| b.iter(|| filter_rows(black_box(&crif), &conditions)));
+    c.bench_function("sum_sensitivities/2000", |b| b.iter(|| sum_sensitivities(black_box(&crif))));
+
+    // Single-currency rates book: every tenor pair goes through the IR tenor correlation
+    let ws: Vec<f64> = (0..TENORS.len()).map(|i| 1_000.0 * (i as f64 + 1.0)).collect();
+    let index = ["OIS"; 12];
+    let wnc = V2_5;
+    c.bench_function("k_delta/rates_12_tenors", |b| {
+        b.iter(|| k_delta(&wnc, "Rates", black_box(&ws), None, None, Some(&TENORS), Some(&index), "USD"))
+    });
+}
+
+criterion_group!(benches, bench_from_crif, bench_helpers);
+criterion_main!(benches);
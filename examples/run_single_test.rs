@@ -1,6 +1,6 @@
 
-use simm_rs::file_utils::{read_json_to_list, calculate_simm_by_measure};
-use simm_rs::SIMM;
+use simm_rs::file_utils::{read_json_to_list, calculate_simm_by_measures};
+use simm_rs::{BreakdownColumn, RiskType, SIMM};
 use simm_rs::EngineConfig;
 use simm_rs::V2_5;
 use std::path::PathBuf;
@@ -11,6 +11,7 @@ fn main() {
         weights_and_corr_version: "2_5".to_string(),
         calculation_currency: "USD".to_string(),
         exchange_rate: 1.0,
+        ..Default::default()
     };
 
     // Read C298 CRIF from JSON file (relative to project root)
@@ -34,16 +35,17 @@ fn main() {
     let breakdown = &simm.simm_break_down;
 
     // Calculate totals for each measure using the proper aggregation method
-    let delta_total = calculate_simm_by_measure(breakdown, &crif_for_measure, "Delta", &wnc);
-    let vega_total = calculate_simm_by_measure(breakdown, &crif_for_measure, "Vega", &wnc);
-    let curvature_total = calculate_simm_by_measure(breakdown, &crif_for_measure, "Curvature", &wnc);
-    let basecorr_total = calculate_simm_by_measure(breakdown, &crif_for_measure, "BaseCorr", &wnc);
+    let totals = calculate_simm_by_measures(breakdown, &crif_for_measure, &wnc);
+    let delta_total = totals[&RiskType::Delta];
+    let vega_total = totals[&RiskType::Vega];
+    let curvature_total = totals[&RiskType::Curvature];
+    let basecorr_total = totals[&RiskType::BaseCorr];
 
     // Get AddOn if it exists
     let mut addon_total = 0.0;
     if breakdown.len() > 1 {
         let header = &breakdown[0];
-        if let Some(addon_idx) = header.iter().position(|s| s == "Add-On") {
+        if let Some(addon_idx) = BreakdownColumn::AddOn.index(header) {
             for row in breakdown.iter().skip(1) {
                 if addon_idx < row.len() && !row[addon_idx].is_empty() {
                     if let Ok(val) = row[addon_idx].parse::<f64>() {
@@ -1,50 +1,302 @@
-use anyhow::{Context, Result};
-use std::collections::HashMap;
+use anyhow::{Context, Result, bail};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::Path;
 
-use crate::constants::margin_by_risk_class;
-use crate::engine_config::EngineConfig;
-use crate::margin_risk_class::{MarginByRiskClass, filter_rows};
-use crate::simm_utils::{Crif, get_column_index, product_list};
-use crate::wnc::WeightsAndCorr;
+use crate::constants::{
+    LIST_COMMODITY, LIST_CREDIT_NON_Q, LIST_CREDIT_Q, LIST_EQUITY, LIST_FX, LIST_PRODUCT_CLASSES, LIST_RATES, RiskClass,
+    RiskType, margin_by_risk_class,
+};
+use crate::engine_config::{AddOnFactorScale, ConcentrationMode, EngineConfig, PsiMatrix};
+use crate::file_utils::read_csv_to_list;
+use crate::margin_risk_class::{MarginByRiskClass, MarginDict, WsRow, drop_rows, filter_rows};
+use crate::rates::RateProvider;
+use crate::simm_result::{AddOnBreakdown, BreakdownColumn, DefaultedLookup, SimmResult, SimmResultRow};
+use crate::simm_utils::{
+    Bucket, Crif, CrifRecord, ROW_INDEX_COLUMN, RiskKey, add_row_index, apply_column_aliases, apply_sign_overrides,
+    crif_from_records, derive_amount_usd, exclude_qualifiers, filter_by_regulation, get_column_index,
+    map_dates_to_tenors, merge_crifs, normalize_rows, parse_amount, product_list, use_amount_as_sensitivity,
+};
+use crate::validation::{validate_amount_magnitudes, validate_crif};
+use crate::wnc::{RecordingWnc, WeightsAndCorr};
+
+/// Risk classes in the order of the psi aggregation and `PsiMatrix`
+///
+/// Differs from the calibrations' `PSI_TABLE_ORDER`; psi is always looked
+/// up by name, never by position.
+pub(crate) const PSI_RISK_CLASSES: [&str; 6] = ["Rates", "FX", "CreditQ", "CreditNonQ", "Equity", "Commodity"];
+
+/// Correlation between the i-th and j-th of `PSI_RISK_CLASSES`, taken from
+/// the override when given and otherwise from the calibration
+pub(crate) fn cross_class_psi(wnc: &dyn WeightsAndCorr, psi_override: Option<&PsiMatrix>, i: usize, j: usize) -> f64 {
+    match psi_override {
+        Some(psi) => psi.0[i][j],
+        None if i == j => 1.0,
+        None => wnc.psi(PSI_RISK_CLASSES[i], PSI_RISK_CLASSES[j]).unwrap_or(0.0),
+    }
+}
+
+/// Combine the SIMM of each product class into the portfolio total
+///
+/// The ISDA methodology recognises no diversification between product
+/// classes, so the total is their plain sum. All product-class aggregation
+/// goes through here, giving research variants a single place to plug in.
+pub(crate) fn combine_product_classes(totals: &[f64]) -> f64 {
+    totals.iter().sum()
+}
+
+/// Margin by risk class then risk measure, ordered so the breakdown rows
+/// come out in the same order on every run
+type RiskClassMargins = BTreeMap<String, BTreeMap<RiskType, f64>>;
+
+/// Risk factors of one risk class present in a CRIF
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RiskClassStructure {
+    /// Numbered buckets in order, then the residual bucket
+    pub buckets: BTreeSet<Bucket>,
+    pub qualifiers: BTreeSet<String>,
+    /// `Label1` values, e.g. IR and credit tenors or vol expiries
+    pub tenors: BTreeSet<String>,
+    /// `Label2` values, e.g. IR sub-curves such as `OIS`
+    pub sub_curves: BTreeSet<String>,
+}
+
+/// Risk factors present in a CRIF by risk class name; risk classes without
+/// rows are absent
+pub type PortfolioStructure = BTreeMap<String, RiskClassStructure>;
 
 /// Main SIMM calculator
 pub struct SIMM<'a> {
     crif_list: Crif,
     pub simm: f64,
     pub simm_break_down: Crif,
+    /// Delta margin aggregated across risk classes and summed over product classes
+    ///
+    /// This is the standalone IM of the portfolio's delta risk: every other
+    /// measure is zeroed before the psi aggregation, which then runs as for
+    /// the total, and add-ons are excluded. It matches the `Delta` line of
+    /// `calculate_simm_by_measure` without going through the breakdown, and
+    /// because of the cross risk class correlations it is not the sum of the
+    /// delta breakdown rows.
+    pub delta_total: f64,
+    /// Vega margin aggregated across risk classes and summed over product
+    /// classes; the standalone vega IM, as for `delta_total`
+    pub vega_total: f64,
+    /// Curvature margin aggregated across risk classes and summed over product
+    /// classes; the standalone curvature IM, as for `delta_total`
+    pub curvature_total: f64,
+    /// Base correlation margin aggregated across risk classes and summed over product classes
+    pub base_corr_total: f64,
+    result: SimmResult,
+    /// Input row positions consumed by a margin calculation
+    used_rows: BTreeSet<usize>,
+    /// Delta weighted sensitivities of every product class
+    ws_rows: Vec<WsRow>,
+    ir_currency_k: BTreeMap<String, f64>,
+    raw_margins: BTreeMap<String, RiskClassMargins>,
+    strict: bool,
+    diagnostics: bool,
     calc_currency: String,
     exchange_rate: f64,
+    /// USD -> sensitivity currency rate applied to concentration thresholds
+    threshold_rate: f64,
+    im_threshold: f64,
+    psi_override: Option<PsiMatrix>,
+    bucket_filter: HashMap<RiskClass, HashSet<usize>>,
+    concentration_mode: ConcentrationMode,
+    include_curvature: bool,
+    addon_factor_scale: AddOnFactorScale,
+    risk_class_multipliers: HashMap<RiskClass, f64>,
+    /// Validation warnings of the input CRIF
+    warnings: Vec<String>,
     wnc: &'a dyn WeightsAndCorr,
 }
 
 impl<'a> SIMM<'a> {
     /// Create SIMM calculator from CRIF list
     ///
+    /// A CRIF with a header but no data rows is a valid, empty portfolio: it
+    /// gives a SIMM of zero and a single all-zero breakdown row. Only a
+    /// CRIF without even a header is an error.
+    ///
     /// # Arguments
     /// * `crif` - List of lists where first row is header, subsequent rows are data
     /// * `cfg` - Engine configuration (calculation currency, exchange rate, etc.)
     /// * `wnc` - Weights and correlations implementation
     pub fn from_crif(crif: Crif, cfg: &EngineConfig, wnc: &'a dyn WeightsAndCorr) -> Result<Self> {
+        Self::from_crif_with_rates(crif, cfg, wnc, None)
+    }
+
+    /// Create SIMM calculator from CRIF list, taking the USD to calculation
+    /// currency rate from a rate provider
+    ///
+    /// # Arguments
+    /// * `crif` - List of lists where first row is header, subsequent rows are data
+    /// * `cfg` - Engine configuration
+    /// * `wnc` - Weights and correlations implementation
+    /// * `rates` - Rate provider; when absent or missing the rate, `cfg.exchange_rate` is used.
+    ///   A rate that is not a finite positive number is an error.
+    pub fn from_crif_with_rates(
+        mut crif: Crif,
+        cfg: &EngineConfig,
+        wnc: &'a dyn WeightsAndCorr,
+        rates: Option<&dyn RateProvider>,
+    ) -> Result<Self> {
         if crif.is_empty() {
             return Err(anyhow::anyhow!("crif list must have at least a header row"));
         }
 
+        apply_column_aliases(&mut crif, &cfg.column_aliases);
+        let row_warnings = normalize_rows(&mut crif);
+        if let Some(valuation_date) = &cfg.valuation_date {
+            map_dates_to_tenors(&mut crif, valuation_date)?;
+        }
+
+        // Local-currency rows are converted when fx_rates or a rate provider
+        // are given for a CRIF carrying Amount and AmountCurrency
+        let has_local_amounts =
+            get_column_index(&crif, "Amount").is_some() && get_column_index(&crif, "AmountCurrency").is_some();
+        let conversion_warnings = if (!cfg.fx_rates.is_empty() || rates.is_some()) && has_local_amounts {
+            derive_amount_usd(&mut crif, &cfg.fx_rates, rates)?
+        } else {
+            Vec::new()
+        };
+
+        // Swapped in before validation, so the amounts actually used are checked
+        let amount_warnings = if cfg.sensitivities_already_in_calc_ccy {
+            use_amount_as_sensitivity(&mut crif)?
+        } else {
+            Vec::new()
+        };
+
+        let mut report = validate_crif(&crif);
+        report.warnings.extend(row_warnings);
+        report.warnings.extend(conversion_warnings);
+        report.warnings.extend(amount_warnings);
+        report.merge(validate_amount_magnitudes(&crif, cfg.max_abs_amount, cfg.max_amount_to_median));
+        if !report.is_valid() {
+            return Err(anyhow::anyhow!("invalid CRIF: {}", report.errors.join("; ")));
+        }
+        if cfg.strict && !report.warnings.is_empty() {
+            return Err(anyhow::anyhow!("invalid CRIF (strict): {}", report.warnings.join("; ")));
+        }
+
+        let usd_rate = match rates.and_then(|r| r.rate("USD", &cfg.calculation_currency)) {
+            Some(rate) if !rate.is_finite() || rate <= 0.0 => {
+                bail!("rate provider returned {} for USD to {}; expected a positive number", rate, cfg.calculation_currency)
+            }
+            Some(rate) => rate,
+            None => cfg.exchange_rate,
+        };
+        // Sensitivities already in the calculation currency need no
+        // conversion, but the USD concentration thresholds then do
+        let (exchange_rate, threshold_rate) = if cfg.sensitivities_already_in_calc_ccy {
+            (1.0, usd_rate)
+        } else {
+            (usd_rate, 1.0)
+        };
+        apply_sign_overrides(&mut crif, &cfg.sign_overrides);
+
+        add_row_index(&mut crif);
+        // After indexing, so row positions still refer to the input CRIF
+        if let Some(regulation) = &cfg.regulation {
+            crif = filter_by_regulation(&crif, regulation);
+        }
+        if !cfg.excluded_qualifiers.is_empty() {
+            crif = exclude_qualifiers(&crif, &cfg.excluded_qualifiers);
+        }
+
         let mut simm = SIMM {
             crif_list: crif,
             simm: 0.0,
             simm_break_down: Vec::new(),
+            delta_total: 0.0,
+            vega_total: 0.0,
+            curvature_total: 0.0,
+            base_corr_total: 0.0,
+            result: SimmResult::default(),
+            used_rows: BTreeSet::new(),
+            ws_rows: Vec::new(),
+            ir_currency_k: BTreeMap::new(),
+            raw_margins: BTreeMap::new(),
             calc_currency: cfg.calculation_currency.clone(),
-            exchange_rate: cfg.exchange_rate,
+            exchange_rate,
+            threshold_rate,
+            im_threshold: cfg.im_threshold,
+            psi_override: cfg.psi_override,
+            bucket_filter: cfg.bucket_filter.clone(),
+            concentration_mode: cfg.concentration_mode,
+            include_curvature: cfg.include_curvature,
+            addon_factor_scale: cfg.addon_factor_scale,
+            risk_class_multipliers: cfg.risk_class_multipliers.clone(),
+            warnings: report.warnings,
+            strict: cfg.strict,
+            diagnostics: cfg.diagnostics,
             wnc,
         };
 
         simm.calculate_simm()?;
+        simm.check_magnitudes(cfg.max_component_value)?;
         Ok(simm)
     }
 
+    /// Create SIMM calculator from sensitivities already summed per risk key
+    ///
+    /// Each group becomes one CRIF row carrying the amount in both `Amount`
+    /// and `AmountUSD`, in key order, so the result does not depend on the
+    /// map's iteration order.
+    ///
+    /// # Arguments
+    /// * `groups` - Sensitivity per risk key
+    /// * `cfg` - Engine configuration
+    /// * `wnc` - Weights and correlations implementation
+    pub fn from_grouped(groups: HashMap<RiskKey, f64>, cfg: &EngineConfig, wnc: &'a dyn WeightsAndCorr) -> Result<Self> {
+        let mut groups: Vec<(RiskKey, f64)> = groups.into_iter().collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let records: Vec<CrifRecord> = groups
+            .into_iter()
+            .map(|(key, amount)| CrifRecord {
+                product_class: key.product_class,
+                risk_type: key.risk_type,
+                qualifier: key.qualifier,
+                bucket: key.bucket,
+                label1: key.label1,
+                label2: key.label2,
+                amount,
+                amount_currency: String::new(),
+                amount_usd: amount,
+            })
+            .collect();
+
+        Self::from_crif(crif_from_records(&records), cfg, wnc)
+    }
+
+    /// SIMM of the records selected by a predicate
+    ///
+    /// The general form of the subset what-ifs such as `im_for_risk_type`:
+    /// any slice of the portfolio expressible over `CrifRecord` fields, e.g.
+    /// all USD delta in buckets 1 to 3.
+    ///
+    /// # Arguments
+    /// * `records` - Portfolio sensitivities
+    /// * `cfg` - Engine configuration
+    /// * `wnc` - Weights and correlations implementation
+    /// * `predicate` - Keeps the records it returns true for
+    pub fn im_where<F: Fn(&CrifRecord) -> bool>(
+        records: &[CrifRecord],
+        cfg: &EngineConfig,
+        wnc: &'a dyn WeightsAndCorr,
+        predicate: F,
+    ) -> Result<f64> {
+        let subset: Vec<CrifRecord> = records.iter().filter(|r| predicate(r)).cloned().collect();
+        Ok(Self::from_crif(crif_from_records(&subset), cfg, wnc)?.simm)
+    }
+
     /// Create SIMM calculator from CSV file
     ///
+    /// Read with `read_csv_to_list`, so ragged rows are accepted; fields
+    /// beyond the header are dropped with a warning.
+    ///
     /// # Arguments
     /// * `csv_path` - Path to CSV file
     /// * `cfg` - Engine configuration
@@ -54,23 +306,34 @@ impl<'a> SIMM<'a> {
         cfg: &EngineConfig,
         wnc: &'a dyn WeightsAndCorr,
     ) -> Result<Self> {
-        let mut reader = csv::Reader::from_path(csv_path)
-            .context("Failed to open CSV file")?;
-
-        let mut crif_list: Vec<Vec<String>> = Vec::new();
-
-        // Get headers
-        let headers = reader.headers()
-            .context("Failed to read CSV headers")?;
-        crif_list.push(headers.iter().map(|s| s.to_string()).collect());
+        let crif_list = read_csv_to_list(csv_path)?;
+        Self::from_crif(crif_list, cfg, wnc)
+    }
 
-        // Read all records
-        for result in reader.records() {
-            let record = result.context("Failed to read CSV record")?;
-            crif_list.push(record.iter().map(|s| s.to_string()).collect());
-        }
+    /// Create SIMM calculator from several CRIF CSV files, such as one per
+    /// trade
+    ///
+    /// The files are merged into one CRIF before the calculation, so
+    /// offsetting sensitivities of different trades net. Every file must
+    /// have the same columns, in any order.
+    ///
+    /// # Arguments
+    /// * `paths` - Paths to CSV files
+    /// * `cfg` - Engine configuration
+    /// * `wnc` - Weights and correlations implementation
+    pub fn from_crif_files<P: AsRef<Path>>(
+        paths: &[P],
+        cfg: &EngineConfig,
+        wnc: &'a dyn WeightsAndCorr,
+    ) -> Result<Self> {
+        let crifs = paths
+            .iter()
+            .map(|path| {
+                read_csv_to_list(path).with_context(|| format!("Failed to read CRIF {}", path.as_ref().display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        Self::from_crif(crif_list, cfg, wnc)
+        Self::from_crif(merge_crifs(&crifs)?, cfg, wnc)
     }
 
     /// Helper method to filter CRIF by a single column value
@@ -87,69 +350,97 @@ impl<'a> SIMM<'a> {
         filter_rows(&self.crif_list, &conditions)
     }
 
+    /// Add defaulted lookups to the results, skipping ones already there
+    fn add_diagnostics(&mut self, lookups: Vec<DefaultedLookup>) {
+        for lookup in lookups {
+            if !self.result.diagnostics.contains(&lookup) {
+                self.result.diagnostics.push(lookup);
+            }
+        }
+    }
+
     /// Calculate margin by risk class
     ///
     /// # Arguments
+    /// * `product_class` - Product class of the CRIF data
     /// * `crif` - CRIF data
     ///
     /// # Returns
     /// Dict of margins by risk class and measure
-    fn simm_risk_class(&self, crif: &Crif) -> HashMap<String, HashMap<String, f64>> {
-        let margin = MarginByRiskClass::new(crif.clone(), self.calc_currency.clone(), self.wnc);
+    fn simm_risk_class(&mut self, product_class: &str, crif: &Crif) -> RiskClassMargins {
+        let recorder = RecordingWnc::new(self.wnc);
+        let wnc: &dyn WeightsAndCorr = if self.diagnostics { &recorder } else { self.wnc };
+        let margin = MarginByRiskClass::new(crif.clone(), self.calc_currency.clone(), wnc)
+            .with_bucket_filter(self.bucket_filter.clone())
+            .with_concentration_mode(self.concentration_mode)
+            .with_threshold_rate(self.threshold_rate);
 
         // Get results from each margin calculation
         let ir_delta = margin.ir_delta_margin();
         let delta = margin.delta_margin();
         let ir_vega = margin.ir_vega_margin();
         let vega = margin.vega_margin();
-        let ir_curvature = margin.ir_curvature_margin();
-        let curvature = margin.curvature_margin();
+        let (ir_curvature, curvature) = if self.include_curvature {
+            (margin.ir_curvature_margin(), margin.curvature_margin())
+        } else {
+            (MarginDict::new(), MarginDict::new())
+        };
         let base_corr = margin.base_corr_margin();
+        self.used_rows.extend(margin.used_rows());
+        self.ws_rows.extend(margin.weighted_sensitivities().into_iter().map(|row| WsRow {
+            product_class: product_class.to_string(),
+            ..row
+        }));
+        for (currency, k) in margin.ir_delta_k() {
+            *self.ir_currency_k.entry(currency).or_insert(0.0) += k;
+        }
+        self.warnings.extend(margin.warnings());
+        self.add_diagnostics(recorder.defaulted());
 
         // Sum all the margin components
         let dict_margin = margin_by_risk_class();
-        let mut df_margin_aggregated: HashMap<String, HashMap<String, f64>> = HashMap::new();
+        let mut df_margin_aggregated = RiskClassMargins::new();
 
         for (risk_class, measures) in &dict_margin {
-            let mut measure_map = HashMap::new();
+            let mut measure_map = BTreeMap::new();
             for measure in measures {
                 let total = ir_delta
                     .get(*risk_class)
-                    .and_then(|m| m.get(*measure))
+                    .and_then(|m| m.get(measure))
                     .copied()
                     .unwrap_or(0.0)
                     + delta
                         .get(*risk_class)
-                        .and_then(|m| m.get(*measure))
+                        .and_then(|m| m.get(measure))
                         .copied()
                         .unwrap_or(0.0)
                     + ir_vega
                         .get(*risk_class)
-                        .and_then(|m| m.get(*measure))
+                        .and_then(|m| m.get(measure))
                         .copied()
                         .unwrap_or(0.0)
                     + vega
                         .get(*risk_class)
-                        .and_then(|m| m.get(*measure))
+                        .and_then(|m| m.get(measure))
                         .copied()
                         .unwrap_or(0.0)
                     + ir_curvature
                         .get(*risk_class)
-                        .and_then(|m| m.get(*measure))
+                        .and_then(|m| m.get(measure))
                         .copied()
                         .unwrap_or(0.0)
                     + curvature
                         .get(*risk_class)
-                        .and_then(|m| m.get(*measure))
+                        .and_then(|m| m.get(measure))
                         .copied()
                         .unwrap_or(0.0)
                     + base_corr
                         .get(*risk_class)
-                        .and_then(|m| m.get(*measure))
+                        .and_then(|m| m.get(measure))
                         .copied()
                         .unwrap_or(0.0);
 
-                measure_map.insert(measure.to_string(), total);
+                measure_map.insert(*measure, total);
             }
             df_margin_aggregated.insert(risk_class.to_string(), measure_map);
         }
@@ -157,14 +448,20 @@ impl<'a> SIMM<'a> {
         // BaseCorr only presents in the CreditQ
         for (risk_class, measures) in df_margin_aggregated.iter_mut() {
             if risk_class != "CreditQ" {
-                measures.remove("BaseCorr");
+                measures.remove(&RiskType::BaseCorr);
             }
         }
 
-        // Apply exchange rate
-        for measures in df_margin_aggregated.values_mut() {
+        // Apply exchange rate, and any risk class multiplier
+        for (risk_class, measures) in df_margin_aggregated.iter_mut() {
+            let multiplier = risk_class
+                .parse::<RiskClass>()
+                .ok()
+                .and_then(|rc| self.risk_class_multipliers.get(&rc))
+                .copied()
+                .unwrap_or(1.0);
             for value in measures.values_mut() {
-                *value *= self.exchange_rate;
+                *value *= self.exchange_rate * multiplier;
             }
         }
 
@@ -174,65 +471,66 @@ impl<'a> SIMM<'a> {
     /// Calculate SIMM for a product class
     ///
     /// # Arguments
-    /// * `product_class` - Product class name
+    /// * `simm_by_risk_class` - Margins by risk class and measure for the product class
     ///
     /// # Returns
     /// SIMM value for the product class
-    fn simm_product(&self, product_class: &str) -> Result<f64> {
-        let crif = self.filter_crif_by_column("ProductClass", product_class);
-        let simm_by_risk_class = self.simm_risk_class(&crif);
+    fn simm_product(&self, simm_by_risk_class: &RiskClassMargins) -> f64 {
+        self.aggregate_risk_classes(|risk_class| {
+            simm_by_risk_class
+                .get(risk_class)
+                .map(|m| m.values().sum())
+                .unwrap_or(0.0)
+        })
+    }
 
-        let risk_class_list = ["Rates", "FX", "CreditQ", "CreditNonQ", "Equity", "Commodity"];
-        let mut dict_simm_risk_class = HashMap::new();
+    /// Total of one risk measure for a product class, aggregated across risk classes
+    ///
+    /// # Arguments
+    /// * `simm_by_risk_class` - Margins by risk class and measure for the product class
+    /// * `measure` - Risk measure
+    fn measure_product(&self, simm_by_risk_class: &RiskClassMargins, measure: RiskType) -> f64 {
+        self.aggregate_risk_classes(|risk_class| {
+            simm_by_risk_class
+                .get(risk_class)
+                .and_then(|m| m.get(&measure))
+                .copied()
+                .unwrap_or(0.0)
+        })
+    }
 
-        for risk_class in &risk_class_list {
-            let simm_rc: f64 = simm_by_risk_class
-                .get(*risk_class)
-                .map(|m| m.values().sum())
-                .unwrap_or(0.0);
-            dict_simm_risk_class.insert(*risk_class, simm_rc);
-        }
+    /// Aggregate per-risk-class values with the psi cross-risk-class correlations
+    fn aggregate_risk_classes(&self, value: impl Fn(&str) -> f64) -> f64 {
+        let values: Vec<f64> = PSI_RISK_CLASSES.iter().map(|rc| value(rc)).collect();
 
         let mut simm_product = 0.0;
         for i in 0..6 {
             for j in 0..6 {
-                let psi = if i == j {
-                    1.0
-                } else {
-                    self.wnc
-                        .psi(risk_class_list[i], risk_class_list[j])
-                        .unwrap_or(0.0)
-                };
-
-                simm_product += psi
-                    * dict_simm_risk_class
-                        .get(risk_class_list[i])
-                        .copied()
-                        .unwrap_or(0.0)
-                    * dict_simm_risk_class
-                        .get(risk_class_list[j])
-                        .copied()
-                        .unwrap_or(0.0);
+                let psi = cross_class_psi(self.wnc, self.psi_override.as_ref(), i, j);
+                simm_product += psi * values[i] * values[j];
             }
         }
 
-        Ok(simm_product.sqrt())
+        simm_product.sqrt()
     }
 
     /// Calculate detailed results for a product class
     ///
     /// # Arguments
     /// * `product_class` - Product class name
+    /// * `dict_results` - Margins by risk class and measure for the product class
     ///
     /// # Returns
     /// List of result rows
-    fn results_product_class(&self, product_class: &str) -> Result<Vec<HashMap<String, String>>> {
-        let crif = self.filter_crif_by_column("ProductClass", product_class);
-        let dict_results = self.simm_risk_class(&crif);
+    fn results_product_class(
+        &self,
+        product_class: &str,
+        dict_results: &RiskClassMargins,
+    ) -> Result<Vec<HashMap<String, String>>> {
 
         let mut result_rows = Vec::new();
 
-        for (risk_class, dic_sensi_type) in &dict_results {
+        for (risk_class, dic_sensi_type) in dict_results {
             let values: Vec<f64> = dic_sensi_type.values().copied().collect();
             let all_zeros = values.iter().all(|&v| v == 0.0);
 
@@ -241,7 +539,7 @@ impl<'a> SIMM<'a> {
                     let mut row = HashMap::new();
                     row.insert("Product Class".to_string(), product_class.to_string());
                     row.insert("Risk Class".to_string(), risk_class.clone());
-                    row.insert("Risk Measure".to_string(), risk_measure.clone());
+                    row.insert("Risk Measure".to_string(), risk_measure.as_str().to_string());
                     row.insert("SIMM_RiskMeasure".to_string(), format!("{:.2}", value));
                     result_rows.push(row);
                 }
@@ -261,18 +559,87 @@ impl<'a> SIMM<'a> {
 
     /// Calculate add-on margin
     ///
+    /// Add-on rows are USD amounts like the sensitivities, so they are
+    /// converted to the calculation currency with the same exchange rate.
+    ///
     /// # Returns
-    /// Add-on margin value
+    /// Add-on margin value in the calculation currency
     fn addon_margin(&self) -> Result<f64> {
-        let amount_idx = get_column_index(&self.crif_list, "AmountUSD")
+        let (fixed, notional_based) = Self::addon_components(&self.crif_list, self.addon_factor_scale)?;
+        Ok((fixed + notional_based.iter().map(|(_, a)| a).sum::<f64>()) * self.exchange_rate)
+    }
+
+    /// Add-on margin split into its fixed, notional-based and product class
+    /// multiplier components
+    ///
+    /// All components are in the calculation currency; `result().addon` is
+    /// the rounded total of these components.
+    pub fn addon_breakdown(&self) -> Result<AddOnBreakdown> {
+        let (fixed, notional_based) = Self::addon_components(&self.crif_list, self.addon_factor_scale)?;
+        let fixed = fixed * self.exchange_rate;
+        let notional_based = notional_based
+            .into_iter()
+            .map(|(qualifier, amount)| (qualifier, amount * self.exchange_rate))
+            .collect();
+        let product_multiplier = self
+            .result
+            .product_classes
+            .iter()
+            .map(|(product_class, simm_prod)| {
+                let ms = self.product_class_multiplier(product_class);
+                if ms != 0.0 { simm_prod * (ms - 1.0) } else { 0.0 }
+            })
+            .sum();
+
+        Ok(AddOnBreakdown {
+            fixed,
+            notional_based,
+            product_multiplier,
+        })
+    }
+
+    /// Calculate only the add-on margin of a CRIF, without the sensitivity-based IM
+    ///
+    /// Sums `Param_AddOnFixedAmount` rows plus, per qualifier, the
+    /// `Param_AddOnNotionalFactor` (in `cfg.addon_factor_scale` units) times
+    /// the `Notional`, reading the columns through `cfg.column_aliases`. The
+    /// product class multiplier is not included since it scales the
+    /// sensitivity-based SIMM.
+    ///
+    /// # Arguments
+    /// * `crif` - CRIF data
+    /// * `cfg` - Engine configuration
+    ///
+    /// # Returns
+    /// Add-on margin in the calculation currency, as `SimmResult::addon` of a
+    /// full calculation without a rate provider would report it
+    pub fn addon_only(crif: &Crif, cfg: &EngineConfig) -> Result<f64> {
+        let mut crif = crif.clone();
+        apply_column_aliases(&mut crif, &cfg.column_aliases);
+        normalize_rows(&mut crif);
+        let rate = if cfg.sensitivities_already_in_calc_ccy {
+            use_amount_as_sensitivity(&mut crif)?;
+            1.0
+        } else {
+            cfg.exchange_rate
+        };
+
+        let (fixed, notional_based) = Self::addon_components(&crif, cfg.addon_factor_scale)?;
+        Ok((fixed + notional_based.iter().map(|(_, a)| a).sum::<f64>()) * rate)
+    }
+
+    /// Fixed add-on and the factor times notional add-on per qualifier, in
+    /// qualifier order, reading the factors in `factor_scale` units
+    fn addon_components(crif: &Crif, factor_scale: AddOnFactorScale) -> Result<(f64, Vec<(String, f64)>)> {
+        let amount_idx = get_column_index(crif, "AmountUSD")
             .ok_or_else(|| anyhow::anyhow!("AmountUSD column not found"))?;
-        let risk_type_idx = get_column_index(&self.crif_list, "RiskType")
+        let risk_type_idx = get_column_index(crif, "RiskType")
             .ok_or_else(|| anyhow::anyhow!("RiskType column not found"))?;
-        let qualifier_idx = get_column_index(&self.crif_list, "Qualifier")
+        let qualifier_idx = get_column_index(crif, "Qualifier")
             .ok_or_else(|| anyhow::anyhow!("Qualifier column not found"))?;
 
         // Fixed addon
-        let fixed: f64 = self.crif_list
+        let fixed: f64 = crif
             .iter()
             .skip(1) // Skip header
             .filter(|row| {
@@ -281,7 +648,7 @@ impl<'a> SIMM<'a> {
             })
             .filter_map(|row| {
                 if amount_idx < row.len() {
-                    row[amount_idx].parse::<f64>().ok()
+                    parse_amount(&row[amount_idx]).ok()
                 } else {
                     None
                 }
@@ -289,9 +656,9 @@ impl<'a> SIMM<'a> {
             .sum();
 
         // Factor * Notional per qualifier
-        let mut qualifier_map: HashMap<String, (f64, f64)> = HashMap::new();
+        let mut qualifier_map: BTreeMap<String, (f64, f64)> = BTreeMap::new();
 
-        for row in self.crif_list.iter().skip(1) {
+        for row in crif.iter().skip(1) {
             if risk_type_idx >= row.len() || qualifier_idx >= row.len() || amount_idx >= row.len() {
                 continue;
             }
@@ -302,23 +669,432 @@ impl<'a> SIMM<'a> {
             }
 
             let qualifier = &row[qualifier_idx];
-            let amount = row[amount_idx].parse::<f64>().unwrap_or(0.0);
+            let amount = parse_amount(&row[amount_idx]).unwrap_or(0.0);
 
             let entry = qualifier_map.entry(qualifier.clone()).or_insert((0.0, 0.0));
 
             if risk_type == "Param_AddOnNotionalFactor" {
-                entry.0 += amount / 100.0; // factor
+                entry.0 += amount / factor_scale.divisor(); // factor
             } else if risk_type == "Notional" {
                 entry.1 += amount; // notional
             }
         }
 
-        let mut addon = fixed;
-        for (factor, notional) in qualifier_map.values() {
-            addon += factor * notional;
+        let notional_based = qualifier_map
+            .into_iter()
+            .map(|(qualifier, (factor, notional))| (qualifier, factor * notional))
+            .collect();
+
+        Ok((fixed, notional_based))
+    }
+
+    /// Summed `Param_ProductClassMultiplier` of a product class (0 when absent)
+    fn product_class_multiplier(&self, product_class: &str) -> f64 {
+        let amount_idx = get_column_index(&self.crif_list, "AmountUSD").unwrap();
+        let risk_type_idx = get_column_index(&self.crif_list, "RiskType").unwrap();
+        let qualifier_idx = get_column_index(&self.crif_list, "Qualifier").unwrap();
+
+        self.crif_list
+            .iter()
+            .skip(1)
+            .filter(|row| {
+                risk_type_idx < row.len() &&
+                qualifier_idx < row.len() &&
+                row[risk_type_idx] == "Param_ProductClassMultiplier" &&
+                row[qualifier_idx] == product_class
+            })
+            .filter_map(|row| {
+                if amount_idx < row.len() {
+                    parse_amount(&row[amount_idx]).ok()
+                } else {
+                    None
+                }
+            })
+            .sum()
+    }
+
+    /// Error if the total or any breakdown amount is non-finite or exceeds `bound`
+    fn check_magnitudes(&self, bound: f64) -> Result<()> {
+        let check = |name: &str, value: f64| -> Result<()> {
+            if !value.is_finite() || value.abs() > bound {
+                anyhow::bail!("{} of {} exceeds the maximum component value {}", name, value, bound);
+            }
+            Ok(())
+        };
+
+        check("SIMM Total", self.simm)?;
+
+        let Some(header) = self.simm_break_down.first() else {
+            return Ok(());
+        };
+        for row in self.simm_break_down.iter().skip(1) {
+            for (name, cell) in header.iter().zip(row) {
+                if !(name.starts_with("SIMM") || name == "Add-On") {
+                    continue;
+                }
+                if let Ok(value) = cell.parse::<f64>() {
+                    check(name, value)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// CRIF the calculation ran on, after column aliases, date mapping,
+    /// amount conversion and the configured row filters
+    ///
+    /// Write it out with `crif_to_csv` to reproduce the IM of a derived
+    /// portfolio.
+    pub fn crif(&self) -> Crif {
+        let Some(index_idx) = get_column_index(&self.crif_list, ROW_INDEX_COLUMN) else {
+            return self.crif_list.clone();
+        };
+        self.crif_list
+            .iter()
+            .map(|row| row.iter().enumerate().filter(|(i, _)| *i != index_idx).map(|(_, v)| v.clone()).collect())
+            .collect()
+    }
+
+    /// Structured results of the calculation
+    pub fn result(&self) -> &SimmResult {
+        &self.result
+    }
+
+    /// Initial margin net of the configured `im_threshold`, floored at zero
+    ///
+    /// For internal reporting against a threshold or minimum transfer amount;
+    /// the regulatory SIMM in `simm` is not affected.
+    pub fn im_after_threshold(&self) -> f64 {
+        (self.simm - self.im_threshold).max(0.0)
+    }
+
+    /// Check that the breakdown reproduces the reported SIMM
+    ///
+    /// Recomputes each product class SIMM from its per risk class, per measure
+    /// results and the total from those plus the add-on, then checks the
+    /// measure totals against `calculate_simm_by_measures` on the breakdown.
+    /// The breakdown holds amounts rounded to 2 decimals, so that comparison
+    /// allows for the rounding.
+    pub fn reconcile(&self) -> Result<()> {
+        let check = |name: &str, recomputed: f64, reported: f64, tolerance: f64| -> Result<()> {
+            if (recomputed - reported).abs() > tolerance + 1e-9 * reported.abs() {
+                bail!("{} of {} does not reconcile with the recomputed {}", name, reported, recomputed);
+            }
+            Ok(())
+        };
+
+        let result = &self.result;
+        let mut product_totals = Vec::new();
+        for (product_class, reported) in &result.product_classes {
+            let recomputed = self.aggregate_risk_classes(|risk_class| {
+                result
+                    .rows
+                    .iter()
+                    .filter(|r| &r.product_class == product_class && r.risk_class == risk_class)
+                    .map(|r| r.amount)
+                    .sum()
+            });
+            check(&format!("SIMM_ProductClass {}", product_class), recomputed, *reported, 0.0)?;
+            product_totals.push(recomputed);
+        }
+        check("SIMM Total", combine_product_classes(&product_totals) + result.addon, self.simm, 0.0)?;
+
+        let rounding = 0.005 * self.simm_break_down.len() as f64;
+        let from_breakdown = crate::file_utils::calculate_simm_by_measures_with_psi(
+            &self.simm_break_down,
+            &self.crif_list,
+            self.wnc,
+            self.psi_override.as_ref(),
+        );
+        for (measure, total) in [
+            (RiskType::Delta, self.delta_total),
+            (RiskType::Vega, self.vega_total),
+            (RiskType::Curvature, self.curvature_total),
+            (RiskType::BaseCorr, self.base_corr_total),
+        ] {
+            check(&format!("{} total", measure.as_str()), from_breakdown[&measure], total, rounding)?;
+        }
+
+        Ok(())
+    }
+
+    /// Validation warnings of the input CRIF, such as unparseable amounts,
+    /// amounts beyond `max_abs_amount` / `max_amount_to_median` or buckets
+    /// without a concentration threshold
+    pub fn validation_warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Delta weighted sensitivities per vertex, for reconciliation
+    ///
+    /// One row per (risk type, bucket, qualifier, tenor, label2) vertex that
+    /// entered a delta K aggregation, with the sensitivity, risk weight and
+    /// concentration factor behind it. Amounts are before the exchange rate.
+    pub fn weighted_sensitivities(&self) -> &[WsRow] {
+        &self.ws_rows
+    }
+
+    /// Herfindahl index of the delta weighted sensitivities of each risk class
+    ///
+    /// Weighted sensitivities are netted per qualifier (issuer, currency,
+    /// ...) across tenors and product classes; the index is the sum of the
+    /// squared shares of each qualifier in the class's absolute total. It is
+    /// 1 for a single name and tends to 0 for many equal ones, which helps
+    /// explain where the concentration thresholds bite. Risk classes without
+    /// delta exposure are omitted.
+    pub fn concentration_index(&self) -> BTreeMap<RiskClass, f64> {
+        let mut by_qualifier: BTreeMap<RiskClass, BTreeMap<&str, f64>> = BTreeMap::new();
+        for row in &self.ws_rows {
+            if let Some(risk_class) = RiskClass::of_risk_type(&row.risk_type) {
+                *by_qualifier.entry(risk_class).or_default().entry(&row.qualifier).or_insert(0.0) += row.ws;
+            }
+        }
+
+        by_qualifier
+            .into_iter()
+            .filter_map(|(risk_class, ws)| {
+                let total: f64 = ws.values().map(|w| w.abs()).sum();
+                (total > 0.0).then(|| (risk_class, ws.values().map(|w| (w.abs() / total).powi(2)).sum()))
+            })
+            .collect()
+    }
+
+    /// Margins of one product class by risk class and measure, before the
+    /// psi aggregation
+    ///
+    /// Every risk class is present with Delta, Vega and Curvature (and
+    /// BaseCorr for CreditQ), zero where there is no exposure. A product
+    /// class not in the CRIF gives an empty map.
+    pub fn raw_margins(&self, product_class: &str) -> MarginDict {
+        self.raw_margins
+            .get(product_class)
+            .map(|margins| {
+                margins
+                    .iter()
+                    .map(|(risk_class, measures)| (risk_class.clone(), measures.iter().map(|(m, v)| (*m, *v)).collect()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Buckets, qualifiers, tenors and sub-curves of each risk class in the
+    /// CRIF, for populating UIs and checking coverage
+    ///
+    /// Empty cells are left out; add-on and notional rows belong to no risk
+    /// class and are skipped.
+    pub fn portfolio_structure(&self) -> PortfolioStructure {
+        let mut structure = PortfolioStructure::new();
+        let column = |name| get_column_index(&self.crif_list, name);
+        let Some(risk_type_idx) = column("RiskType") else {
+            return structure;
+        };
+        let (qualifier_idx, bucket_idx, label1_idx, label2_idx) =
+            (column("Qualifier"), column("Bucket"), column("Label1"), column("Label2"));
+
+        for row in self.crif_list.iter().skip(1) {
+            let Some(risk_class) = RiskClass::of_risk_type(&row[risk_type_idx]) else {
+                continue;
+            };
+            let entry = structure.entry(risk_class.as_str().to_string()).or_default();
+            let cell = |idx: Option<usize>| idx.map(|i| row[i].as_str()).filter(|v| !v.is_empty());
+
+            if let Some(bucket) = cell(bucket_idx).and_then(|b| b.parse().ok()) {
+                entry.buckets.insert(bucket);
+            }
+            for (set, idx) in [
+                (&mut entry.qualifiers, qualifier_idx),
+                (&mut entry.tenors, label1_idx),
+                (&mut entry.sub_curves, label2_idx),
+            ] {
+                if let Some(value) = cell(idx) {
+                    set.insert(value.to_string());
+                }
+            }
+        }
+
+        structure
+    }
+
+    /// IR delta margin of each currency before the cross-currency
+    /// aggregation, in the calculation currency
+    ///
+    /// The K of each currency from the IR delta calculation, summed over
+    /// product classes. Only the IR delta margin is split this way; the
+    /// currencies' K do not add up to it, which is diversified across them.
+    pub fn ir_margin_by_currency(&self) -> BTreeMap<String, f64> {
+        self.ir_currency_k
+            .iter()
+            .map(|(currency, k)| (currency.clone(), k * self.exchange_rate))
+            .collect()
+    }
+
+    /// CRIF rows that contributed nothing to the margin, with the reason
+    ///
+    /// Rows are numbered from 1 among the data rows of the input CRIF (the
+    /// header is not counted), as in the "Row N" validation and conversion
+    /// messages. Add-on and multiplier parameter rows are never reported
+    /// since they feed the add-on rather than the aggregation.
+    pub fn unused_rows(&self) -> Vec<(usize, String)> {
+        let column = |name| get_column_index(&self.crif_list, name);
+        let (Some(index_idx), Some(product_idx), Some(risk_type_idx), Some(amount_idx)) = (
+            column(ROW_INDEX_COLUMN),
+            column("ProductClass"),
+            column("RiskType"),
+            column("AmountUSD"),
+        ) else {
+            return Vec::new();
+        };
+
+        let known_risk_types = [LIST_RATES, LIST_FX, LIST_CREDIT_Q, LIST_CREDIT_NON_Q, LIST_EQUITY, LIST_COMMODITY];
+
+        let mut unused = Vec::new();
+        for row in self.crif_list.iter().skip(1) {
+            let Ok(index) = row[index_idx].parse::<usize>() else {
+                continue;
+            };
+            let risk_type = row[risk_type_idx].as_str();
+            if risk_type.starts_with("Param_") || risk_type == "Notional" {
+                continue;
+            }
+
+            let amount = &row[amount_idx];
+            let reason = if !amount.is_empty() && parse_amount(amount).is_err() {
+                "unparseable amount"
+            } else if parse_amount(amount).unwrap_or(0.0) == 0.0 {
+                "zero amount"
+            } else if self.used_rows.contains(&index) {
+                continue;
+            } else if row[product_idx].is_empty() {
+                "missing product class"
+            } else if !known_risk_types.iter().any(|list| list.contains(&risk_type)) {
+                "unrecognised risk type"
+            } else {
+                "not used by any margin calculation (check the bucket and labels)"
+            };
+            unused.push((index + 1, reason.to_string()));
+        }
+
+        unused
+    }
+
+    /// SIMM of the portfolio restricted to a single risk type
+    ///
+    /// Runs the full aggregation on only the rows of `risk_type` (e.g.
+    /// `Risk_CreditQ` without `Risk_CreditVol`), with the same configuration.
+    /// The SIMMs of the individual risk types do not add up to the total:
+    /// the total benefits from diversification across them.
+    pub fn im_for_risk_type(&self, risk_type: &str) -> Result<f64> {
+        let crif = self.filter_crif_by_column("RiskType", risk_type);
+        self.recalculate(crif, self.psi_override)
+    }
+
+    /// SIMM of the portfolio with every row of one qualifier of a risk type
+    /// removed
+    ///
+    /// For "what if these positions were closed out" questions, e.g.
+    /// `im_without_qualifier("Risk_IRCurve", "EUR")`; the IM saved is
+    /// `simm` minus the result.
+    pub fn im_without_qualifier(&self, risk_type: &str, qualifier: &str) -> Result<f64> {
+        let mut conditions = HashMap::new();
+        conditions.insert("RiskType".to_string(), risk_type.to_string());
+        conditions.insert("Qualifier".to_string(), qualifier.to_string());
+        let crif = drop_rows(&self.crif_list, &conditions);
+        self.recalculate(crif, self.psi_override)
+    }
+
+    /// Undiversified SIMM, with every pair of risk classes fully correlated
+    ///
+    /// Reruns the calculation with all psi correlations set to 1, so each
+    /// product class margin is the sum of its risk class margins. A
+    /// conservative bound on, and never below, the diversified `simm`.
+    pub fn gross_simm(&self) -> Result<f64> {
+        self.recalculate(self.crif_list.clone(), Some(PsiMatrix::ones()))
+    }
+
+    /// SIMM with every delta and vega concentration risk factor set to 1
+    ///
+    /// Reruns the calculation in `ConcentrationMode::Disabled`, whatever the
+    /// configured mode. Equal to `simm` when no exposure exceeds its
+    /// threshold; the difference is the IM due to concentration, which helps
+    /// tell whether a break with a counterparty comes from the thresholds.
+    pub fn im_without_concentration(&self) -> Result<f64> {
+        let mut simm = self.uncalculated(self.crif_list.clone());
+        simm.concentration_mode = ConcentrationMode::Disabled;
+        simm.calculate_simm()?;
+        Ok(simm.simm)
+    }
+
+    /// SIMM of `crif` with the same settings as this calculation, bar the
+    /// psi override
+    fn recalculate(&self, crif: Crif, psi_override: Option<PsiMatrix>) -> Result<f64> {
+        let mut simm = self.uncalculated(crif);
+        simm.psi_override = psi_override;
+        simm.calculate_simm()?;
+        Ok(simm.simm)
+    }
+
+    /// Calculator for `crif` with the settings of this one, not yet run
+    fn uncalculated(&self, crif: Crif) -> SIMM<'a> {
+        SIMM {
+            crif_list: crif,
+            simm: 0.0,
+            simm_break_down: Vec::new(),
+            delta_total: 0.0,
+            vega_total: 0.0,
+            curvature_total: 0.0,
+            base_corr_total: 0.0,
+            result: SimmResult::default(),
+            used_rows: BTreeSet::new(),
+            ws_rows: Vec::new(),
+            ir_currency_k: BTreeMap::new(),
+            raw_margins: BTreeMap::new(),
+            calc_currency: self.calc_currency.clone(),
+            exchange_rate: self.exchange_rate,
+            threshold_rate: self.threshold_rate,
+            im_threshold: self.im_threshold,
+            psi_override: self.psi_override,
+            bucket_filter: self.bucket_filter.clone(),
+            concentration_mode: self.concentration_mode,
+            include_curvature: self.include_curvature,
+            addon_factor_scale: self.addon_factor_scale,
+            risk_class_multipliers: self.risk_class_multipliers.clone(),
+            warnings: Vec::new(),
+            strict: self.strict,
+            diagnostics: self.diagnostics,
+            wnc: self.wnc,
         }
+    }
+
+    /// Add a product class to the structured results, skipping risk classes
+    /// with no margin as the breakdown does
+    fn record_result(
+        &mut self,
+        product_class: &str,
+        simm_prod: f64,
+        simm_by_risk_class: &RiskClassMargins,
+    ) {
+        self.result.product_classes.push((product_class.to_string(), simm_prod));
+
+        for risk_class in PSI_RISK_CLASSES {
+            let Some(measures) = simm_by_risk_class.get(risk_class) else {
+                continue;
+            };
+            if measures.values().all(|&v| v == 0.0) {
+                continue;
+            }
 
-        Ok(addon)
+            for risk_measure in RiskType::ALL {
+                if let Some(&amount) = measures.get(&risk_measure) {
+                    self.result.rows.push(SimmResultRow {
+                        product_class: product_class.to_string(),
+                        risk_class: risk_class.to_string(),
+                        risk_measure: risk_measure.as_str().to_string(),
+                        amount,
+                    });
+                }
+            }
+        }
     }
 
     /// Main SIMM calculation
@@ -329,10 +1105,27 @@ impl<'a> SIMM<'a> {
         let product_classes = product_list(&self.crif_list);
 
         let mut all_results = Vec::new();
+        let mut product_totals = Vec::new();
+        let mut measure_totals: [Vec<f64>; 4] = Default::default();
 
         for product_class in &product_classes {
-            let result_rows = self.results_product_class(product_class)?;
-            let simm_prod = self.simm_product(product_class)?;
+            let crif = self.filter_crif_by_column("ProductClass", product_class);
+            let warnings_before = self.warnings.len();
+            let simm_by_risk_class = self.simm_risk_class(product_class, &crif);
+            if self.strict && self.warnings.len() > warnings_before {
+                bail!("{} (strict)", self.warnings[warnings_before..].join("; "));
+            }
+
+            let result_rows = self.results_product_class(product_class, &simm_by_risk_class)?;
+            let simm_prod = self.simm_product(&simm_by_risk_class);
+
+            // Measure totals combine across product classes like the SIMM itself
+            for (totals, measure) in measure_totals.iter_mut().zip(RiskType::ALL) {
+                totals.push(self.measure_product(&simm_by_risk_class, measure));
+            }
+
+            self.record_result(product_class, simm_prod, &simm_by_risk_class);
+            self.raw_margins.insert(product_class.clone(), simm_by_risk_class);
 
             // Add SIMM_ProductClass to each row
             for mut row in result_rows {
@@ -343,30 +1136,10 @@ impl<'a> SIMM<'a> {
                 all_results.push(row);
             }
 
-            self.simm += simm_prod;
+            product_totals.push(simm_prod);
 
             // Check for product class multiplier
-            let amount_idx = get_column_index(&self.crif_list, "AmountUSD").unwrap();
-            let risk_type_idx = get_column_index(&self.crif_list, "RiskType").unwrap();
-            let qualifier_idx = get_column_index(&self.crif_list, "Qualifier").unwrap();
-
-            let ms_result: f64 = self.crif_list
-                .iter()
-                .skip(1)
-                .filter(|row| {
-                    risk_type_idx < row.len() &&
-                    qualifier_idx < row.len() &&
-                    row[risk_type_idx] == "Param_ProductClassMultiplier" &&
-                    &row[qualifier_idx] == product_class
-                })
-                .filter_map(|row| {
-                    if amount_idx < row.len() {
-                        row[amount_idx].parse::<f64>().ok()
-                    } else {
-                        None
-                    }
-                })
-                .sum();
+            let ms_result = self.product_class_multiplier(product_class);
 
             if ms_result != 0.0 {
                 let ms = ms_result - 1.0;
@@ -374,25 +1147,43 @@ impl<'a> SIMM<'a> {
             }
         }
 
-        let addon_margin = (addon_ms + self.addon_margin()?).round() * 100.0 / 100.0; // round to 2 decimals
-        self.simm += addon_margin;
+        if self.diagnostics && self.psi_override.is_none() {
+            let recorder = RecordingWnc::new(self.wnc);
+            for i in 0..6 {
+                for j in 0..6 {
+                    cross_class_psi(&recorder, None, i, j);
+                }
+            }
+            self.add_diagnostics(recorder.defaulted());
+        }
+
+        self.simm = combine_product_classes(&product_totals);
+        let [delta, vega, curvature, base_corr] = measure_totals.map(|t| combine_product_classes(&t));
+        self.delta_total = delta;
+        self.vega_total = vega;
+        self.curvature_total = curvature;
+        self.base_corr_total = base_corr;
+
+        let addon_margin = (addon_ms + self.addon_margin()?).round() * 100.0 / 100.0; // round to 2 decimals
+        self.simm += addon_margin;
+
+        // Canonical product class order, whatever order the CRIF lists them in
+        let product_rank = |p: &str| LIST_PRODUCT_CLASSES.iter().position(|c| *c == p);
+        self.result.product_classes.sort_by_key(|(p, _)| product_rank(p));
+        self.result.rows.sort_by_key(|r| product_rank(&r.product_class));
+
+        self.result.total = self.simm;
+        self.result.addon = addon_margin;
+        self.result.calculation_currency = self.calc_currency.clone();
 
-        // Handle case where there are no product classes (only AddOn data)
+        // Handle case where there are no product classes (only AddOn data, or
+        // a header-only CRIF)
         let has_real_products = !product_classes.is_empty();
 
         if !has_real_products {
             // Create minimal structure for AddOn-only case
             if addon_margin.abs() > 0.0 {
-                let header = vec![
-                    "SIMM Total".to_string(),
-                    "Add-On".to_string(),
-                    "Product Class".to_string(),
-                    "SIMM_ProductClass".to_string(),
-                    "Risk Class".to_string(),
-                    "SIMM_RiskClass".to_string(),
-                    "Risk Measure".to_string(),
-                    "SIMM_RiskMeasure".to_string(),
-                ];
+                let header = BreakdownColumn::header(true);
                 let row = vec![
                     format!("{:.2}", self.simm),
                     format!("{:.2}", addon_margin),
@@ -405,15 +1196,7 @@ impl<'a> SIMM<'a> {
                 ];
                 self.simm_break_down = vec![header, row];
             } else {
-                let header = vec![
-                    "SIMM Total".to_string(),
-                    "Product Class".to_string(),
-                    "SIMM_ProductClass".to_string(),
-                    "Risk Class".to_string(),
-                    "SIMM_RiskClass".to_string(),
-                    "Risk Measure".to_string(),
-                    "SIMM_RiskMeasure".to_string(),
-                ];
+                let header = BreakdownColumn::header(false);
                 let row = vec![
                     format!("{:.2}", self.simm),
                     "".to_string(),
@@ -428,16 +1211,7 @@ impl<'a> SIMM<'a> {
         } else {
             // Build breakdown as list of lists
             if addon_margin.abs() > 0.0 {
-                let header = vec![
-                    "SIMM Total".to_string(),
-                    "Add-On".to_string(),
-                    "Product Class".to_string(),
-                    "SIMM_ProductClass".to_string(),
-                    "Risk Class".to_string(),
-                    "SIMM_RiskClass".to_string(),
-                    "Risk Measure".to_string(),
-                    "SIMM_RiskMeasure".to_string(),
-                ];
+                let header = BreakdownColumn::header(true);
                 let mut rows = Vec::new();
                 for result in &all_results {
                     let row = vec![
@@ -464,15 +1238,7 @@ impl<'a> SIMM<'a> {
                 self.simm_break_down = vec![header];
                 self.simm_break_down.extend(rows);
             } else {
-                let header = vec![
-                    "SIMM Total".to_string(),
-                    "Product Class".to_string(),
-                    "SIMM_ProductClass".to_string(),
-                    "Risk Class".to_string(),
-                    "SIMM_RiskClass".to_string(),
-                    "Risk Measure".to_string(),
-                    "SIMM_RiskMeasure".to_string(),
-                ];
+                let header = BreakdownColumn::header(false);
                 let mut rows = Vec::new();
                 for result in &all_results {
                     let row = vec![
@@ -507,6 +1273,8 @@ impl<'a> SIMM<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rates::StaticRates;
+    use crate::simm_utils::crif;
     use crate::v2_5::V2_5;
 
     #[test]
@@ -534,6 +1302,7 @@ mod tests {
             weights_and_corr_version: "2_5".to_string(),
             calculation_currency: "USD".to_string(),
             exchange_rate: 1.0,
+            ..Default::default()
         };
 
         let wnc = V2_5;
@@ -582,7 +1351,7 @@ mod tests {
             ],
             // FX Delta
             vec![
-                "FX".to_string(),
+                "RatesFX".to_string(),
                 "Risk_FX".to_string(),
                 "EURUSD".to_string(),
                 "".to_string(),
@@ -597,6 +1366,7 @@ mod tests {
             weights_and_corr_version: "2_5".to_string(),
             calculation_currency: "USD".to_string(),
             exchange_rate: 1.0,
+            ..Default::default()
         };
 
         let wnc = V2_5;
@@ -615,4 +1385,1194 @@ mod tests {
         assert!(header.contains(&"Risk Class".to_string()));
         assert!(header.contains(&"Risk Measure".to_string()));
     }
+
+    #[test]
+    fn test_column_aliases() {
+        let crif = crate::file_utils::read_csv_to_list("tests_2_5/C1_crif.csv").unwrap();
+        let mut aliased = crif.clone();
+        let idx = get_column_index(&aliased, "AmountUSD").unwrap();
+        aliased[0][idx] = "AmountUsd".to_string();
+
+        let mut cfg = EngineConfig::default();
+        let wnc = V2_5;
+        let expected = SIMM::from_crif(crif, &cfg, &wnc).unwrap().simm;
+
+        cfg.column_aliases.insert("AmountUSD".to_string(), "AmountUsd".to_string());
+        let simm = SIMM::from_crif(aliased, &cfg, &wnc).unwrap();
+        assert_eq!(simm.simm, expected);
+    }
+
+    #[test]
+    fn test_addon_only() {
+        let crif = crif(&[
+            ["", "Param_AddOnFixedAmount", "", "", "", "", "1000"],
+            ["", "Param_AddOnNotionalFactor", "Trade1", "", "", "", "2"],
+            ["", "Notional", "Trade1", "", "", "", "1000000"],
+            ["Rates", "Risk_IRCurve", "USD", "1", "5y", "", "50000"],
+        ]);
+
+        // 1000 fixed + 2% of 1,000,000 notional; the IR sensitivity is ignored
+        assert_eq!(SIMM::addon_only(&crif, &EngineConfig::default()).unwrap(), 21000.0);
+    }
+
+    #[test]
+    fn test_addon_only_matches_full_calculation() {
+        let crif = crif(&[
+            ["", "Param_AddOnFixedAmount", "", "", "", "", "1000"],
+            ["", "Param_AddOnNotionalFactor", "Trade1", "", "", "", "200"],
+            ["", "Notional", "Trade1", "", "", "", "1000000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "50000"],
+        ]);
+        let cfg = EngineConfig {
+            calculation_currency: "EUR".to_string(),
+            exchange_rate: 0.5,
+            addon_factor_scale: AddOnFactorScale::BasisPoints,
+            ..Default::default()
+        };
+
+        let full = SIMM::from_crif(crif.clone(), &cfg, &V2_5).unwrap();
+        // (1000 fixed + 200bp of 1,000,000 notional) at 0.5 EUR per USD
+        assert_eq!(SIMM::addon_only(&crif, &cfg).unwrap(), 10500.0);
+        assert_eq!(SIMM::addon_only(&crif, &cfg).unwrap(), full.result().addon);
+    }
+
+    #[test]
+    fn test_sensitivities_already_in_calc_ccy() {
+        let header = ["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2", "Amount", "AmountCurrency", "AmountUSD"];
+        let rows = [
+            ["RatesFX", "Risk_IRCurve", "EUR", "1", "5y", "OIS", "40000", "EUR", "50000"],
+            ["RatesFX", "Risk_IRCurve", "EUR", "1", "10y", "OIS", "-16000", "EUR", "-20000"],
+        ];
+        let mut crif: Crif = vec![header.iter().map(|s| s.to_string()).collect()];
+        crif.extend(rows.iter().map(|r| r.iter().map(|s| s.to_string()).collect()));
+
+        let wnc = V2_5;
+        let cfg = EngineConfig {
+            calculation_currency: "EUR".to_string(),
+            exchange_rate: 0.8,
+            ..Default::default()
+        };
+        let converted = SIMM::from_crif(crif.clone(), &cfg, &wnc).unwrap();
+
+        let native_cfg = EngineConfig { sensitivities_already_in_calc_ccy: true, ..cfg };
+        let native = SIMM::from_crif(crif, &native_cfg, &wnc).unwrap();
+
+        // Amount is exactly 0.8 * AmountUSD, well below the thresholds, so both paths agree
+        assert!(converted.simm > 0.0);
+        assert!((converted.simm - native.simm).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_measure_totals_match_breakdown_aggregation() {
+        let crif = crate::file_utils::read_csv_to_list("tests_2_5/C298_crif.csv").unwrap();
+        let wnc = V2_5;
+        let simm = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+
+        let by_measure = |measure: &str| {
+            crate::file_utils::calculate_simm_by_measure(&simm.simm_break_down, &crif, measure, &wnc)
+        };
+
+        // The breakdown holds values rounded to 2 decimals, so allow for that
+        for (total, measure) in [
+            (simm.delta_total, "Delta"),
+            (simm.vega_total, "Vega"),
+            (simm.curvature_total, "Curvature"),
+            (simm.base_corr_total, "BaseCorr"),
+        ] {
+            assert!((total - by_measure(measure)).abs() < 0.1, "{} differs", measure);
+        }
+        assert!(simm.delta_total > 0.0);
+        assert!(simm.base_corr_total > 0.0);
+    }
+
+    #[test]
+    fn test_isda_csv_matches_breakdown() {
+        let crif = crate::file_utils::read_csv_to_list("tests_2_5/C298_crif.csv").unwrap();
+        let wnc = V2_5;
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &wnc).unwrap();
+
+        // Reference layout rebuilt independently from the bespoke breakdown
+        let breakdown = crate::file_utils::list_to_dict_list(&simm.simm_break_down);
+        let mut reference = crate::simm_result::ISDA_RESULTS_HEADER.join(",") + "\n";
+        let mut expected_rows = 0;
+        for product_class in crate::constants::LIST_PRODUCT_CLASSES {
+            for risk_class in ["Rates", "FX", "CreditQ", "CreditNonQ", "Equity", "Commodity"] {
+                for risk_measure in ["Delta", "Vega", "Curvature", "BaseCorr"] {
+                    if let Some(row) = breakdown.iter().find(|r| {
+                        r["Product Class"] == *product_class && r["Risk Class"] == risk_class && r["Risk Measure"] == risk_measure
+                    }) {
+                        reference += &format!(
+                            "SIMM,{},{},{},{},USD\n",
+                            product_class, risk_class, risk_measure, row["SIMM_RiskMeasure"]
+                        );
+                        expected_rows += 1;
+                    }
+                }
+            }
+        }
+
+        let result = simm.result();
+        assert_eq!(result.rows.len(), expected_rows);
+        assert_eq!(result.total, simm.simm);
+
+        let dir = std::env::temp_dir();
+        let calc_path = dir.join("simm_rs_isda_results_calc.csv");
+        let reference_path = dir.join("simm_rs_isda_results_reference.csv");
+        std::fs::write(&calc_path, result.to_isda_csv()).unwrap();
+        std::fs::write(&reference_path, reference).unwrap();
+
+        let (matches, diffs) = crate::file_utils::compare_csv_files(&calc_path, &reference_path).unwrap();
+        assert!(matches, "{:?}", diffs);
+
+        let _ = std::fs::remove_file(&calc_path);
+        let _ = std::fs::remove_file(&reference_path);
+    }
+
+    #[test]
+    fn test_rate_provider_overrides_exchange_rate() {
+        let crif = crif(&[["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000000"]]);
+
+        let wnc = V2_5;
+        let cfg = EngineConfig { calculation_currency: "EUR".to_string(), exchange_rate: 0.5, ..Default::default() };
+        let usd = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap().simm;
+
+        let rates = StaticRates::new().with_rate("EUR", "USD", 1.25);
+        let live = SIMM::from_crif_with_rates(crif.clone(), &cfg, &wnc, Some(&rates)).unwrap();
+        assert!((live.simm - usd * 0.8).abs() < 1e-6);
+
+        // A provider without the pair falls back to the configured rate
+        let empty = StaticRates::new();
+        let fallback = SIMM::from_crif_with_rates(crif.clone(), &cfg, &wnc, Some(&empty)).unwrap();
+        assert!((fallback.simm - usd * 0.5).abs() < 1e-6);
+
+        // A zero, negative or NaN rate is an error rather than a scaled IM
+        for bad in [0.0, -0.8, f64::NAN] {
+            let rates = StaticRates::new().with_rate("USD", "EUR", bad);
+            let err = SIMM::from_crif_with_rates(crif.clone(), &cfg, &wnc, Some(&rates)).err().unwrap();
+            assert!(err.to_string().contains("rate provider"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_max_component_value_guard() {
+        let crif = crif(&[["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000000"]]);
+
+        let wnc = V2_5;
+        assert!(SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).is_ok());
+
+        let cfg = EngineConfig { max_component_value: 1000.0, ..Default::default() };
+        let err = SIMM::from_crif(crif, &cfg, &wnc).err().unwrap();
+        assert!(err.to_string().contains("maximum component value"));
+    }
+
+    #[test]
+    fn test_unknown_product_class_rejected() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "50000"],
+            ["Ratess", "Risk_IRCurve", "USD", "1", "5y", "OIS", "50000"],
+        ]);
+
+        let wnc = V2_5;
+        let err = SIMM::from_crif(crif, &EngineConfig::default(), &wnc).err().unwrap();
+        assert!(err.to_string().contains("Ratess"));
+    }
+
+    #[test]
+    fn test_unused_rows() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "50000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "7y", "OIS", "50000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "10y", "OIS", "0"],
+            ["RatesFX", "Risk_Unknown", "USD", "1", "5y", "OIS", "50000"],
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "20000"],
+            ["", "Param_AddOnFixedAmount", "", "", "", "", "1000"],
+        ]);
+
+        let wnc = V2_5;
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &wnc).unwrap();
+        let unused = simm.unused_rows();
+
+        let indices: Vec<usize> = unused.iter().map(|(i, _)| *i).collect();
+        assert_eq!(indices, vec![2, 3, 4]);
+        assert!(unused[0].1.contains("not used"));
+        assert_eq!(unused[1].1, "zero amount");
+        assert_eq!(unused[2].1, "unrecognised risk type");
+    }
+
+    #[test]
+    fn test_im_after_threshold() {
+        let crif = crif(&[["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "50000"]]);
+
+        let wnc = V2_5;
+        let im = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap().simm;
+
+        let above = EngineConfig { im_threshold: im + 1.0, ..Default::default() };
+        let simm = SIMM::from_crif(crif.clone(), &above, &wnc).unwrap();
+        assert_eq!(simm.im_after_threshold(), 0.0);
+        assert_eq!(simm.simm, im);
+
+        let below = EngineConfig { im_threshold: 1000.0, ..Default::default() };
+        let simm = SIMM::from_crif(crif, &below, &wnc).unwrap();
+        assert_eq!(simm.im_after_threshold(), im - 1000.0);
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_unparseable_amount() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1,000,000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "10y", "OIS", "1.5e6"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "2y", "OIS", "abc"],
+        ]);
+
+        let wnc = V2_5;
+        let lenient = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+        assert!(lenient.simm > 0.0);
+        // Numbered like the validation warning for the same row
+        assert_eq!(lenient.unused_rows(), vec![(3, "unparseable amount".to_string())]);
+        assert!(lenient.validation_warnings()[0].starts_with("Row 3:"), "{:?}", lenient.validation_warnings());
+
+        let strict = EngineConfig { strict: true, ..Default::default() };
+        let err = SIMM::from_crif(crif, &strict, &wnc).err().unwrap();
+        assert!(err.to_string().contains("abc"));
+    }
+
+    #[test]
+    fn test_psi_override() {
+        let crif = crate::file_utils::read_csv_to_list("tests_2_5/C298_crif.csv").unwrap();
+        let wnc = V2_5;
+        let simm_with = |psi: Option<PsiMatrix>| {
+            let cfg = EngineConfig { psi_override: psi, ..Default::default() };
+            SIMM::from_crif(crif.clone(), &cfg, &wnc).unwrap()
+        };
+
+        // Per product class: (aggregated SIMM, margin of each risk class)
+        let by_class = |simm: &SIMM| -> Vec<(f64, Vec<f64>)> {
+            let result = simm.result();
+            result
+                .product_classes
+                .iter()
+                .map(|(pc, total)| {
+                    let margins = PSI_RISK_CLASSES
+                        .iter()
+                        .map(|rc| {
+                            result.rows.iter()
+                                .filter(|r| &r.product_class == pc && r.risk_class == *rc)
+                                .map(|r| r.amount)
+                                .sum()
+                        })
+                        .collect();
+                    (*total, margins)
+                })
+                .collect()
+        };
+
+        let ones = simm_with(Some(PsiMatrix::ones()));
+        for (total, margins) in by_class(&ones) {
+            let sum: f64 = margins.iter().sum();
+            assert!((total - sum).abs() < 1e-6 * sum);
+        }
+
+        let identity = simm_with(Some(PsiMatrix::identity()));
+        for (total, margins) in by_class(&identity) {
+            let rss = margins.iter().map(|m| m * m).sum::<f64>().sqrt();
+            assert!((total - rss).abs() < 1e-6 * rss);
+        }
+
+        let calibrated = simm_with(None);
+        assert!(identity.simm < calibrated.simm && calibrated.simm < ones.simm);
+    }
+
+    #[test]
+    fn test_combine_product_classes_is_undiversified_sum() {
+        assert_eq!(combine_product_classes(&[]), 0.0);
+        assert_eq!(combine_product_classes(&[100.0, 250.0, 50.0]), 400.0);
+
+        let crif = crate::file_utils::read_csv_to_list("tests_2_5/C298_crif.csv").unwrap();
+        let wnc = V2_5;
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &wnc).unwrap();
+        let result = simm.result();
+        assert!(result.product_classes.len() > 1);
+
+        let totals: Vec<f64> = result.product_classes.iter().map(|(_, v)| *v).collect();
+        let expected = combine_product_classes(&totals) + result.addon;
+        assert!((simm.simm - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_rates_fx_product_class_aggregates_ir_and_fx() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "50000000"],
+        ]);
+
+        let wnc = V2_5;
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &wnc).unwrap();
+        let result = simm.result();
+
+        // One product class, with Rates and FX combined through psi
+        assert_eq!(result.product_classes.len(), 1);
+        let rates = result.amount("RatesFX", "Rates", "Delta").unwrap();
+        let fx = result.amount("RatesFX", "FX", "Delta").unwrap();
+        let psi = wnc.psi("Rates", "FX").unwrap();
+        let expected = (rates * rates + fx * fx + 2.0 * psi * rates * fx).sqrt();
+        assert!((result.product_classes[0].1 - expected).abs() < 1e-6 * expected);
+        assert!(result.product_classes[0].1 < rates + fx);
+    }
+
+    #[test]
+    fn test_weighted_sensitivities() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "10000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "5000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "1000000"],
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "20000"],
+        ]);
+
+        let wnc = V2_5;
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &wnc).unwrap();
+        let ws = simm.weighted_sensitivities();
+        assert_eq!(ws.len(), 3);
+
+        let ir = ws.iter().find(|r| r.risk_type == "Risk_IRCurve").unwrap();
+        assert_eq!(ir.product_class, "RatesFX");
+        assert_eq!((ir.qualifier.as_str(), ir.tenor.as_str(), ir.label2.as_str()), ("USD", "5y", "OIS"));
+        assert_eq!(ir.sensitivity, 15000.0);
+
+        let equity = ws.iter().find(|r| r.risk_type == "Risk_Equity").unwrap();
+        assert_eq!(equity.product_class, "Equity");
+        assert_eq!(equity.risk_weight, wnc.rw("Risk_Equity", "1").unwrap());
+
+        for row in ws {
+            assert_eq!(row.ws, row.sensitivity * row.risk_weight * row.concentration);
+        }
+    }
+
+    #[test]
+    fn test_breakdown_order_is_deterministic() {
+        let crif = crate::file_utils::read_csv_to_list("tests_2_5/C298_crif.csv").unwrap();
+        let wnc = V2_5;
+        let first = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+        let second = SIMM::from_crif(crif, &EngineConfig::default(), &wnc).unwrap();
+        assert_eq!(first.simm_break_down, second.simm_break_down);
+
+        // Within each product class, risk classes come out in key order
+        let header = &first.simm_break_down[0];
+        let column = |name: &str| header.iter().position(|h| h == name).unwrap();
+        let (pc, rc) = (column("Product Class"), column("Risk Class"));
+        for pair in first.simm_break_down[1..].windows(2) {
+            if pair[0][pc] == pair[1][pc] {
+                assert!(pair[0][rc] <= pair[1][rc]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bucket_filter() {
+        let rows = [
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "20000"],
+            ["Equity", "Risk_Equity", "DEF", "5", "", "", "30000"],
+            ["Equity", "Risk_Equity", "GHI", "5", "", "", "-10000"],
+            ["Equity", "Risk_Equity", "JKL", "Residual", "", "", "15000"],
+            ["Equity", "Risk_EquityVol", "DEF", "5", "1y", "", "40000"],
+            ["Equity", "Risk_EquityVol", "JKL", "Residual", "1y", "", "25000"],
+        ];
+        let to_crif = |keep: &dyn Fn(&[&str; 7]) -> bool| -> Crif {
+            crif(&rows.iter().filter(|r| keep(r)).copied().collect::<Vec<_>>())
+        };
+
+        let wnc = V2_5;
+        for (bucket, label) in [(5, "5"), (0, "Residual")] {
+            let cfg = EngineConfig {
+                bucket_filter: HashMap::from([(RiskClass::Equity, HashSet::from([bucket]))]),
+                ..Default::default()
+            };
+            let filtered = SIMM::from_crif(to_crif(&|_| true), &cfg, &wnc).unwrap();
+            let subset = SIMM::from_crif(to_crif(&|r| r[3] == label), &EngineConfig::default(), &wnc).unwrap();
+
+            assert!(filtered.simm > 0.0);
+            assert!((filtered.simm - subset.simm).abs() < 1e-9, "bucket {}", label);
+            assert_eq!(filtered.result().rows, subset.result().rows);
+        }
+    }
+
+    /// Every reference CRIF with the calibration of its directory
+    fn reference_portfolios() -> Vec<(std::path::PathBuf, Crif, &'static dyn WeightsAndCorr)> {
+        let wncs: [(&str, &'static dyn WeightsAndCorr); 3] = [
+            ("tests_2_5", &V2_5),
+            ("tests_2_6", &crate::v2_6::V2_6),
+            ("tests_2_7", &crate::v2_7::V2_7),
+        ];
+
+        let mut portfolios = Vec::new();
+        for (dir, wnc) in wncs {
+            let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(dir);
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if !path.to_string_lossy().ends_with("_crif.csv") {
+                    continue;
+                }
+
+                let crif = crate::file_utils::read_csv_to_list(&path).unwrap();
+                portfolios.push((path, crif, wnc));
+            }
+        }
+        portfolios
+    }
+
+    #[test]
+    fn test_reconcile_reference_portfolios() {
+        for (path, crif, wnc) in reference_portfolios() {
+            let simm = SIMM::from_crif(crif, &EngineConfig::default(), wnc).unwrap();
+            if let Err(e) = simm.reconcile() {
+                panic!("{}: {}", path.display(), e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gross_simm_bounds_diversified() {
+        for (path, crif, wnc) in reference_portfolios() {
+            let simm = SIMM::from_crif(crif, &EngineConfig::default(), wnc).unwrap();
+            let gross = simm.gross_simm().unwrap();
+            assert!(gross >= simm.simm - 1e-6, "{}: {} < {}", path.display(), gross, simm.simm);
+        }
+
+        // A single risk class has nothing to diversify against
+        let crif = crif(&[["Equity", "Risk_Equity", "ABC", "1", "", "", "5000000"]]);
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &V2_5).unwrap();
+        assert!((simm.gross_simm().unwrap() - simm.simm).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_concentration_disabled() {
+        let crif = crif(&[["Equity", "Risk_Equity", "ABC", "1", "", "", "5e9"]]);
+
+        let wnc = V2_5;
+        let standard = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+        let cfg = EngineConfig {
+            concentration_mode: ConcentrationMode::Disabled,
+            ..Default::default()
+        };
+        let disabled = SIMM::from_crif(crif, &cfg, &wnc).unwrap();
+
+        // A single position's margin is its weighted sensitivity, with CR = 1
+        let rw = wnc.rw("Risk_Equity", "1").unwrap();
+        assert!((disabled.simm - rw * 5e9).abs() < 1e-6);
+        assert!(standard.simm > disabled.simm);
+    }
+
+    #[test]
+    fn test_addon_breakdown() {
+        let crif = crif(&[
+            ["", "Param_AddOnFixedAmount", "", "", "", "", "1000"],
+            ["", "Param_AddOnNotionalFactor", "Trade2", "", "", "", "1"],
+            ["", "Notional", "Trade2", "", "", "", "500000"],
+            ["", "Param_AddOnNotionalFactor", "Trade1", "", "", "", "2"],
+            ["", "Notional", "Trade1", "", "", "", "1000000"],
+            ["", "Param_ProductClassMultiplier", "RatesFX", "", "", "", "1.5"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "50000"],
+        ]);
+
+        let wnc = V2_5;
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &wnc).unwrap();
+        let breakdown = simm.addon_breakdown().unwrap();
+
+        assert_eq!(breakdown.fixed, 1000.0);
+        assert_eq!(
+            breakdown.notional_based,
+            vec![("Trade1".to_string(), 20000.0), ("Trade2".to_string(), 5000.0)]
+        );
+        let rates_fx = simm.result().product_classes[0].1;
+        assert!((breakdown.product_multiplier - 0.5 * rates_fx).abs() < 1e-9);
+        assert_eq!(breakdown.total().round(), simm.result().addon);
+    }
+
+    #[test]
+    fn test_im_for_risk_type() {
+        let rows = [
+            ["Credit", "Risk_CreditQ", "ISSUER1", "2", "5y", "USD", "500000"],
+            ["Credit", "Risk_CreditQ", "ISSUER2", "3", "5y", "USD", "-200000"],
+            ["Credit", "Risk_CreditVol", "ISSUER1", "2", "5y", "USD", "3000000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "2000000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "90000000"],
+        ];
+        let to_crif = |risk_type: Option<&str>| -> Crif {
+            crif(&rows.iter().filter(|r| risk_type.is_none_or(|rt| r[1] == rt)).copied().collect::<Vec<_>>())
+        };
+
+        let wnc = V2_5;
+        let cfg = EngineConfig::default();
+        let simm = SIMM::from_crif(to_crif(None), &cfg, &wnc).unwrap();
+
+        let mut sum = 0.0;
+        for risk_type in ["Risk_CreditQ", "Risk_CreditVol", "Risk_IRCurve", "Risk_FX"] {
+            let im = simm.im_for_risk_type(risk_type).unwrap();
+            let alone = SIMM::from_crif(to_crif(Some(risk_type)), &cfg, &wnc).unwrap().simm;
+            assert!(im > 0.0, "{}", risk_type);
+            assert!((im - alone).abs() < 1e-9, "{}", risk_type);
+            sum += im;
+        }
+        assert_eq!(simm.im_for_risk_type("Risk_Commodity").unwrap(), 0.0);
+
+        // Rates and FX diversify against each other within RatesFX
+        assert!(sum > simm.simm);
+    }
+
+    #[test]
+    fn test_rates_fx_without_bucket_column() {
+        let rows = [
+            ["RatesFX", "Risk_IRCurve", "USD", "", "5y", "OIS", "2000000"],
+            ["RatesFX", "Risk_IRCurve", "EUR", "", "10y", "Libor3m", "1000000"],
+            ["RatesFX", "Risk_IRVol", "USD", "", "1y", "", "3000000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "90000000"],
+            ["RatesFX", "Risk_FXVol", "EURUSD", "", "1y", "", "3000000"],
+        ];
+        let to_crif = |keep_bucket: bool| -> Crif {
+            let mut crif = crif(&rows);
+            if !keep_bucket {
+                crif.iter_mut().for_each(|row| drop(row.remove(3)));
+            }
+            crif
+        };
+
+        let wnc = V2_5;
+        let with_bucket = SIMM::from_crif(to_crif(true), &EngineConfig::default(), &wnc).unwrap();
+        let without = SIMM::from_crif(to_crif(false), &EngineConfig::default(), &wnc).unwrap();
+
+        assert!(without.simm > 0.0);
+        assert_eq!(without.result(), with_bucket.result());
+        assert!(without.unused_rows().is_empty());
+    }
+
+    #[test]
+    fn test_from_grouped_matches_from_crif() {
+        let crif = crate::file_utils::read_csv_to_list("tests_2_5/C298_crif.csv").unwrap();
+        let records = crate::simm_utils::records_from_crif(&crif).unwrap();
+        let groups = crate::simm_utils::group_records(&records);
+        assert!(groups.len() <= records.len());
+
+        let wnc = V2_5;
+        let cfg = EngineConfig::default();
+        let from_crif = SIMM::from_crif(crif, &cfg, &wnc).unwrap();
+        let from_grouped = SIMM::from_grouped(groups, &cfg, &wnc).unwrap();
+
+        assert!((from_grouped.simm - from_crif.simm).abs() < 1e-6 * from_crif.simm);
+    }
+
+    #[test]
+    fn test_amount_magnitude_warnings() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "2y", "OIS", "1000000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "2000000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "10y", "OIS", "5e9"],
+        ]);
+
+        let wnc = V2_5;
+        let plain = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+        assert!(plain.validation_warnings().is_empty());
+
+        let cfg = EngineConfig {
+            max_amount_to_median: Some(1000.0),
+            ..Default::default()
+        };
+        let checked = SIMM::from_crif(crif.clone(), &cfg, &wnc).unwrap();
+        assert_eq!(checked.validation_warnings().len(), 1);
+        assert!(checked.validation_warnings()[0].starts_with("Row 3:"));
+        assert_eq!(checked.simm, plain.simm);
+
+        let strict = EngineConfig { strict: true, ..cfg };
+        assert!(SIMM::from_crif(crif, &strict, &wnc).is_err());
+    }
+
+    #[test]
+    fn test_valuation_date_maps_label1_dates() {
+        let crif_with = |label1: &str| crif(&[["RatesFX", "Risk_IRCurve", "USD", "1", label1, "OIS", "1000000"]]);
+
+        let wnc = V2_5;
+        let cfg = EngineConfig {
+            valuation_date: Some("2026-01-15".to_string()),
+            ..Default::default()
+        };
+        let dated = SIMM::from_crif(crif_with("2031-01-15"), &cfg, &wnc).unwrap();
+        let tenor = SIMM::from_crif(crif_with("5y"), &EngineConfig::default(), &wnc).unwrap();
+        assert_eq!(dated.simm, tenor.simm);
+
+        // Off by default, leaving a label that matches no tenor
+        let undated = SIMM::from_crif(crif_with("2031-01-15"), &EngineConfig::default(), &wnc).unwrap();
+        assert_eq!(undated.simm, 0.0);
+        assert_eq!(undated.unused_rows().len(), 1);
+    }
+
+    #[test]
+    fn test_header_only_crif_is_zero() {
+        let simm = SIMM::from_crif(crif(&[]), &EngineConfig::default(), &V2_5).unwrap();
+
+        assert_eq!(simm.simm, 0.0);
+        assert_eq!(simm.simm_break_down.len(), 2);
+        assert_eq!(simm.simm_break_down[1][0], "0.00");
+        assert!(simm.result().rows.is_empty());
+        assert!(simm.reconcile().is_ok());
+
+        assert!(SIMM::from_crif(Vec::new(), &EngineConfig::default(), &V2_5).is_err());
+    }
+
+    #[test]
+    fn test_fx_rates_convert_each_row() {
+        let header = ["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2", "Amount", "AmountCurrency"];
+        let to_crif = |rows: &[[&str; 8]]| -> Crif {
+            std::iter::once(header.iter().map(|s| s.to_string()).collect())
+                .chain(rows.iter().map(|r| r.iter().map(|s| s.to_string()).collect()))
+                .collect()
+        };
+        let local = [
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "40000", "EUR"],
+            ["Equity", "Risk_Equity", "XYZ", "5", "", "", "-8000", "GBP"],
+        ];
+        let usd = [
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "60000", "USD"],
+            ["Equity", "Risk_Equity", "XYZ", "5", "", "", "-10000", "USD"],
+        ];
+        let cfg = EngineConfig {
+            fx_rates: HashMap::from([("EUR".to_string(), 1.5), ("GBP".to_string(), 1.25)]),
+            ..Default::default()
+        };
+
+        let converted = SIMM::from_crif(to_crif(&local), &cfg, &V2_5).unwrap();
+        let expected = SIMM::from_crif(to_crif(&usd), &cfg, &V2_5).unwrap();
+        assert!(converted.simm > 0.0);
+        assert_eq!(converted.simm, expected.simm);
+
+        // A currency without a rate is skipped, or rejected in strict mode
+        let mut missing = local;
+        missing[1][7] = "JPY";
+        let lenient = SIMM::from_crif(to_crif(&missing), &cfg, &V2_5).unwrap();
+        assert_eq!(lenient.validation_warnings().len(), 1);
+        assert_eq!(lenient.unused_rows().len(), 1);
+        let strict = EngineConfig { strict: true, ..cfg };
+        assert!(SIMM::from_crif(to_crif(&missing), &strict, &V2_5).is_err());
+    }
+
+    #[test]
+    fn test_fx_rates_leave_amount_usd_only_crif_alone() {
+        let crif = crif(&[["Equity", "Risk_Equity", "ABC", "1", "", "", "60000"]]);
+        let cfg = EngineConfig { fx_rates: HashMap::from([("EUR".to_string(), 1.5)]), ..Default::default() };
+
+        let with_rates = SIMM::from_crif(crif.clone(), &cfg, &V2_5).unwrap();
+        let without = SIMM::from_crif(crif, &EngineConfig::default(), &V2_5).unwrap();
+        assert!(with_rates.simm > 0.0);
+        assert_eq!(with_rates.simm, without.simm);
+    }
+
+    #[test]
+    fn test_ir_margin_by_currency() {
+        let rows = [
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "20000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "10y", "Libor3m", "-5000"],
+            ["RatesFX", "Risk_IRCurve", "EUR", "1", "2y", "OIS", "12000"],
+        ];
+        let to_crif = |currency: Option<&str>| -> Crif {
+            crif(&rows.iter().filter(|r| currency.is_none_or(|c| r[2] == c)).copied().collect::<Vec<_>>())
+        };
+
+        let cfg = EngineConfig::default();
+        let simm = SIMM::from_crif(to_crif(None), &cfg, &V2_5).unwrap();
+        let by_currency = simm.ir_margin_by_currency();
+        assert_eq!(by_currency.keys().collect::<Vec<_>>(), ["EUR", "USD"]);
+
+        // Each currency's K is the margin of that currency alone
+        for (currency, k) in &by_currency {
+            let alone = SIMM::from_crif(to_crif(Some(currency)), &cfg, &V2_5).unwrap().simm;
+            assert!((k - alone).abs() < 1e-6, "{}: {} vs {}", currency, k, alone);
+        }
+        let sum: f64 = by_currency.values().sum();
+        assert!(sum > simm.simm);
+
+        let eur = EngineConfig { calculation_currency: "EUR".to_string(), exchange_rate: 0.5, ..Default::default() };
+        let in_eur = SIMM::from_crif(to_crif(None), &eur, &V2_5).unwrap();
+        assert!((in_eur.ir_margin_by_currency()["USD"] - 0.5 * by_currency["USD"]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_missing_delta_threshold_strict() {
+        let crif = crif(&[["Equity", "Risk_Equity", "ABC", "13", "", "", "5000000"]]);
+
+        let lenient = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &V2_5).unwrap();
+        assert_eq!(lenient.validation_warnings().len(), 1);
+        assert_eq!(lenient.weighted_sensitivities()[0].concentration, 1.0);
+
+        let strict = EngineConfig { strict: true, ..Default::default() };
+        let err = SIMM::from_crif(crif, &strict, &V2_5).err().unwrap();
+        assert!(err.to_string().contains("No delta concentration threshold"), "{}", err);
+    }
+
+    #[test]
+    fn test_diagnostics_records_defaulted_lookups() {
+        let cfg = EngineConfig { diagnostics: true, ..Default::default() };
+        for (path, crif, wnc) in reference_portfolios() {
+            let simm = SIMM::from_crif(crif, &cfg, wnc).unwrap();
+            assert!(simm.result().diagnostics.is_empty(), "{}: {:?}", path.display(), simm.result().diagnostics);
+        }
+
+        let crif = crif(&[["Equity", "Risk_Equity", "ABC", "13", "", "", "5000000"]]);
+        let simm = SIMM::from_crif(crif.clone(), &cfg, &V2_5).unwrap();
+        let functions: Vec<&str> = simm.result().diagnostics.iter().map(|d| d.function).collect();
+        assert_eq!(functions, ["rw", "t"]);
+        assert_eq!(simm.result().diagnostics[0].arguments, "Risk_Equity, 13");
+
+        // Off by default
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &V2_5).unwrap();
+        assert!(simm.result().diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_im_without_qualifier() {
+        let rows = [
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "2000000"],
+            ["RatesFX", "Risk_IRCurve", "EUR", "1", "10y", "OIS", "-1500000"],
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "5000000"],
+        ];
+        let to_crif = |keep: &dyn Fn(&[&str; 7]) -> bool| -> Crif {
+            crif(&rows.iter().filter(|r| keep(r)).copied().collect::<Vec<_>>())
+        };
+        let cfg = EngineConfig::default();
+        let simm = SIMM::from_crif(to_crif(&|_| true), &cfg, &V2_5).unwrap();
+
+        // ABC is the only equity qualifier, so the equity margin goes entirely
+        let without_abc = simm.im_without_qualifier("Risk_Equity", "ABC").unwrap();
+        let rates_only = SIMM::from_crif(to_crif(&|r| r[1] == "Risk_IRCurve"), &cfg, &V2_5).unwrap();
+        assert_eq!(without_abc, rates_only.simm);
+
+        let without_eur = simm.im_without_qualifier("Risk_IRCurve", "EUR").unwrap();
+        let expected = SIMM::from_crif(to_crif(&|r| r[2] != "EUR"), &cfg, &V2_5).unwrap();
+        assert_eq!(without_eur, expected.simm);
+        assert!(without_eur < simm.simm);
+
+        // Nothing to remove
+        assert_eq!(simm.im_without_qualifier("Risk_IRCurve", "JPY").unwrap(), simm.simm);
+    }
+
+    #[test]
+    fn test_raw_margins() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "2000000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "90000000"],
+        ]);
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &V2_5).unwrap();
+
+        let raw = simm.raw_margins("RatesFX");
+        assert_eq!(raw.len(), 6);
+        for (risk_class, measures) in &raw {
+            let expected = if risk_class == "CreditQ" { 4 } else { 3 };
+            assert_eq!(measures.len(), expected, "{}", risk_class);
+        }
+        assert_eq!(raw["Rates"][&RiskType::Delta], simm.result().amount("RatesFX", "Rates", "Delta").unwrap());
+        assert!(raw["FX"][&RiskType::Delta] > 0.0);
+        assert_eq!(raw["Equity"][&RiskType::Delta], 0.0);
+
+        assert!(simm.raw_margins("Credit").is_empty());
+    }
+
+    #[test]
+    fn test_addon_uses_exchange_rate() {
+        let crif = crif(&[
+            ["", "Param_AddOnFixedAmount", "", "", "", "", "1000"],
+            ["", "Param_AddOnNotionalFactor", "Trade1", "", "", "", "2"],
+            ["", "Notional", "Trade1", "", "", "", "1000000"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "50000"],
+        ]);
+
+        let usd = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &V2_5).unwrap();
+        let eur_cfg = EngineConfig { calculation_currency: "EUR".to_string(), exchange_rate: 0.5, ..Default::default() };
+        let eur = SIMM::from_crif(crif, &eur_cfg, &V2_5).unwrap();
+
+        assert_eq!(usd.result().addon, 21000.0);
+        assert_eq!(eur.result().addon, 10500.0);
+        assert_eq!(eur.addon_breakdown().unwrap().fixed, 500.0);
+        // The whole SIMM, sensitivities and add-on alike, scales with the rate
+        assert!((eur.simm - 0.5 * usd.simm).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_from_crif_files_nets_trades() {
+        let dir = std::env::temp_dir();
+        let trade1 = dir.join("simm_rs_trade1_crif.csv");
+        let trade2 = dir.join("simm_rs_trade2_crif.csv");
+        let trade3 = dir.join("simm_rs_trade3_crif.csv");
+        std::fs::write(
+            &trade1,
+            "ProductClass,RiskType,Qualifier,Bucket,Label1,Label2,AmountUSD\n\
+             RatesFX,Risk_IRCurve,USD,1,5y,OIS,1000000\n\
+             Equity,Risk_Equity,ABC,1,,,50000\n",
+        )
+        .unwrap();
+        // Columns in a different order, with the IR sensitivity offsetting trade1
+        std::fs::write(
+            &trade2,
+            "RiskType,ProductClass,Qualifier,Bucket,Label1,Label2,AmountUSD\n\
+             Risk_IRCurve,RatesFX,USD,1,5y,OIS,-1000000\n",
+        )
+        .unwrap();
+        std::fs::write(&trade3, "ProductClass,RiskType,Qualifier,AmountUSD\n").unwrap();
+
+        let wnc = V2_5;
+        let cfg = EngineConfig::default();
+        let merged = SIMM::from_crif_files(&[&trade1, &trade2], &cfg, &wnc).unwrap();
+        let equity_only = SIMM::from_crif_files(&[&trade1], &cfg, &wnc)
+            .unwrap()
+            .im_without_qualifier("Risk_IRCurve", "USD")
+            .unwrap();
+        assert!((merged.simm - equity_only).abs() < 1e-6);
+
+        let err = SIMM::from_crif_files(&[&trade1, &trade3], &cfg, &wnc).err().unwrap();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+
+        for path in [trade1, trade2, trade3] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn test_calc_ccy_sensitivities_convert_thresholds() {
+        let header = ["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2", "Amount", "AmountCurrency", "AmountUSD"];
+        let rows = [["Equity", "Risk_Equity", "ABC", "1", "", "", "40000000", "EUR", "50000000"]];
+        let mut crif: Crif = vec![header.iter().map(|s| s.to_string()).collect()];
+        crif.extend(rows.iter().map(|r| r.iter().map(|s| s.to_string()).collect()));
+
+        let wnc = V2_5;
+        let cfg = EngineConfig { calculation_currency: "EUR".to_string(), exchange_rate: 0.8, ..Default::default() };
+        let converted = SIMM::from_crif(crif.clone(), &cfg, &wnc).unwrap();
+        let native_cfg = EngineConfig { sensitivities_already_in_calc_ccy: true, ..cfg };
+        let native = SIMM::from_crif(crif, &native_cfg, &wnc).unwrap();
+
+        // Above the threshold the concentration factor only agrees when the
+        // threshold is converted to EUR along with the sensitivity
+        let t = wnc.t("Risk_Equity", "Delta", None, Some("1")).unwrap();
+        assert!(5e7 > t);
+        assert!(converted.weighted_sensitivities()[0].concentration > 1.0);
+        assert!((converted.simm - native.simm).abs() < 1e-6 * converted.simm);
+    }
+
+    #[test]
+    fn test_regulation_filters_rows() {
+        let header = ["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2", "AmountUSD", "CollectRegulations", "PostRegulations"];
+        let rows = [
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "100000", "CFTC,SEC", ""],
+            ["Equity", "Risk_Equity", "XYZ", "1", "", "", "200000", "EMIR", "EMIR"],
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "0", "", ""],
+        ];
+        let mut crif: Crif = vec![header.iter().map(|s| s.to_string()).collect()];
+        crif.extend(rows.iter().map(|r| r.iter().map(|s| s.to_string()).collect()));
+
+        let wnc = V2_5;
+        let cftc_cfg = EngineConfig { regulation: Some("CFTC".to_string()), ..Default::default() };
+        let cftc = SIMM::from_crif(crif.clone(), &cftc_cfg, &wnc).unwrap();
+        let rw = wnc.rw("Risk_Equity", "1").unwrap();
+        assert!((cftc.simm - rw * 100000.0).abs() < 1e-6);
+        // Row positions still refer to the input CRIF
+        assert_eq!(cftc.unused_rows(), vec![(3, "zero amount".to_string())]);
+
+        let all = SIMM::from_crif(crif, &EngineConfig::default(), &wnc).unwrap();
+        assert!(all.simm > cftc.simm);
+    }
+
+    #[test]
+    fn test_im_where_matches_filtered_crif() {
+        let record = |qualifier: &str, bucket: &str, label1: &str, amount: f64| CrifRecord {
+            product_class: "RatesFX".to_string(),
+            risk_type: "Risk_IRCurve".to_string(),
+            qualifier: qualifier.to_string(),
+            bucket: bucket.to_string(),
+            label1: label1.to_string(),
+            label2: "OIS".to_string(),
+            amount,
+            amount_currency: "USD".to_string(),
+            amount_usd: amount,
+        };
+        let records = vec![
+            record("USD", "1", "5y", 2000000.0),
+            record("EUR", "1", "5y", -1500000.0),
+            record("EUR", "1", "10y", 800000.0),
+            record("JPY", "2", "2y", 500000.0),
+        ];
+
+        let wnc = V2_5;
+        let cfg = EngineConfig::default();
+        let eur = SIMM::im_where(&records, &cfg, &wnc, |r| r.qualifier == "EUR").unwrap();
+
+        let eur_records: Vec<CrifRecord> = records[1..3].to_vec();
+        let expected = SIMM::from_crif(crif_from_records(&eur_records), &cfg, &wnc).unwrap().simm;
+        assert!(eur > 0.0);
+        assert_eq!(eur, expected);
+
+        let all = SIMM::from_crif(crif_from_records(&records), &cfg, &wnc).unwrap().simm;
+        assert_eq!(SIMM::im_where(&records, &cfg, &wnc, |_| true).unwrap(), all);
+    }
+
+    #[test]
+    fn test_portfolio_structure() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000"],
+            ["RatesFX", "Risk_IRCurve", "EUR", "1", "10y", "Libor3m", "1000"],
+            ["RatesFX", "Risk_IRVol", "USD", "", "1y", "", "1000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "1000"],
+            ["Equity", "Risk_Equity", "XYZ", "Residual", "", "", "1000"],
+            ["Equity", "Risk_Equity", "ABC", "11", "", "", "1000"],
+            ["Equity", "Risk_EquityVol", "ABC", "2", "1y", "", "1000"],
+            ["", "Param_AddOnFixedAmount", "", "", "", "", "1000"],
+        ]);
+
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &V2_5).unwrap();
+        let structure = simm.portfolio_structure();
+        let set = |values: &[&str]| values.iter().map(|s| s.to_string()).collect::<BTreeSet<_>>();
+
+        assert_eq!(structure.keys().collect::<Vec<_>>(), ["Equity", "FX", "Rates"]);
+        let rates = &structure["Rates"];
+        assert_eq!(rates.qualifiers, set(&["EUR", "USD"]));
+        assert_eq!(rates.tenors, set(&["10y", "1y", "5y"]));
+        assert_eq!(rates.sub_curves, set(&["Libor3m", "OIS"]));
+        assert_eq!(structure["FX"].qualifiers, set(&["EUR"]));
+        assert!(structure["FX"].buckets.is_empty());
+
+        let equity = &structure["Equity"];
+        assert_eq!(
+            equity.buckets.iter().copied().collect::<Vec<_>>(),
+            [Bucket::Numbered(2), Bucket::Numbered(11), Bucket::Residual]
+        );
+        assert_eq!(equity.qualifiers, set(&["ABC", "XYZ"]));
+    }
+
+    #[test]
+    fn test_exclude_curvature() {
+        let crif = crif(&[
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "1000000"],
+            ["Equity", "Risk_EquityVol", "ABC", "1", "1y", "", "5000000"],
+            ["RatesFX", "Risk_IRVol", "USD", "", "5y", "", "2000000"],
+        ]);
+
+        let wnc = V2_5;
+        let full = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+        let cfg = EngineConfig { include_curvature: false, ..Default::default() };
+        let linear = SIMM::from_crif(crif, &cfg, &wnc).unwrap();
+
+        assert!(full.raw_margins("Equity")["Equity"][&RiskType::Curvature] > 0.0);
+        for (product_class, risk_class) in [("Equity", "Equity"), ("RatesFX", "Rates")] {
+            assert_eq!(linear.raw_margins(product_class)[risk_class][&RiskType::Curvature], 0.0);
+            assert_eq!(
+                linear.raw_margins(product_class)[risk_class][&RiskType::Vega],
+                full.raw_margins(product_class)[risk_class][&RiskType::Vega]
+            );
+        }
+        assert_eq!(linear.curvature_total, 0.0);
+        assert!(linear.simm < full.simm);
+        assert!(linear.unused_rows().is_empty());
+    }
+
+    #[test]
+    fn test_standalone_measure_ims() {
+        let crif = crif(&[
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "1000000"],
+            ["Equity", "Risk_EquityVol", "ABC", "1", "1y", "", "5000000"],
+            ["Equity", "Risk_IRCurve", "USD", "1", "5y", "OIS", "20000"],
+            ["Equity", "Risk_IRVol", "USD", "", "5y", "", "2000000"],
+        ]);
+
+        let wnc = V2_5;
+        let simm = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+        let standalone = [simm.delta_total, simm.vega_total, simm.curvature_total];
+        for im in standalone {
+            assert!(im > 0.0 && im <= simm.simm, "{} vs {}", im, simm.simm);
+        }
+
+        // Dropping curvature leaves the delta and vega IMs unchanged
+        let cfg = EngineConfig { include_curvature: false, ..Default::default() };
+        let linear = SIMM::from_crif(crif, &cfg, &wnc).unwrap();
+        assert_eq!(linear.delta_total, standalone[0]);
+        assert_eq!(linear.vega_total, standalone[1]);
+        assert_eq!(linear.curvature_total, 0.0);
+    }
+
+    #[test]
+    fn test_sign_overrides() {
+        let to_crif = |fx_amount: &str| {
+            crif(&[
+                ["RatesFX", "Risk_FX", "EUR", "", "", "", fx_amount],
+                ["RatesFX", "Risk_FX", "GBP", "", "", "", "-400000"],
+                ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "20000"],
+            ])
+        };
+
+        let wnc = V2_5;
+        let ours = SIMM::from_crif(to_crif("1000000"), &EngineConfig::default(), &wnc).unwrap();
+
+        // The counterparty reports FX deltas with the opposite sign
+        let mut theirs_crif = to_crif("-1000000");
+        theirs_crif[2][6] = "400000".to_string();
+        let cfg = EngineConfig {
+            sign_overrides: HashMap::from([("Risk_FX".to_string(), -1.0)]),
+            ..Default::default()
+        };
+        let theirs = SIMM::from_crif(theirs_crif, &cfg, &wnc).unwrap();
+
+        let eur = theirs.weighted_sensitivities().iter().find(|r| r.qualifier == "EUR").unwrap();
+        assert_eq!(eur.sensitivity, 1000000.0);
+        assert_eq!(theirs.result(), ours.result());
+    }
+
+    #[test]
+    fn test_im_without_concentration() {
+        let to_crif = |amount: &str| crif(&[["Equity", "Risk_Equity", "ABC", "1", "", "", amount]]);
+
+        let wnc = V2_5;
+        let small = SIMM::from_crif(to_crif("1000000"), &EngineConfig::default(), &wnc).unwrap();
+        assert_eq!(small.im_without_concentration().unwrap(), small.simm);
+
+        let concentrated = SIMM::from_crif(to_crif("5e9"), &EngineConfig::default(), &wnc).unwrap();
+        let rw = wnc.rw("Risk_Equity", "1").unwrap();
+        assert!((concentrated.im_without_concentration().unwrap() - rw * 5e9).abs() < 1e-6);
+        assert!(concentrated.im_without_concentration().unwrap() < concentrated.simm);
+    }
+
+    #[test]
+    fn test_concentration_index() {
+        let wnc = V2_5;
+        let single = crif(&[
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "1000000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "1000000"],
+        ]);
+        let index = SIMM::from_crif(single, &EngineConfig::default(), &wnc).unwrap().concentration_index();
+        assert_eq!(index.keys().copied().collect::<Vec<_>>(), vec![RiskClass::FX, RiskClass::Equity]);
+        assert!((index[&RiskClass::Equity] - 1.0).abs() < 1e-12);
+
+        // Four equal names in the same bucket: 4 * (1/4)^2
+        let diversified = crif(&[
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "1000000"],
+            ["Equity", "Risk_Equity", "DEF", "1", "", "", "-1000000"],
+            ["Equity", "Risk_Equity", "GHI", "1", "", "", "1000000"],
+            ["Equity", "Risk_Equity", "JKL", "1", "", "", "1000000"],
+        ]);
+        let index = SIMM::from_crif(diversified, &EngineConfig::default(), &wnc).unwrap().concentration_index();
+        assert!((index[&RiskClass::Equity] - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_addon_factor_scale() {
+        let crif = crif(&[
+            ["", "Param_AddOnNotionalFactor", "Trade1", "", "", "", "2"],
+            ["", "Notional", "Trade1", "", "", "", "1000000"],
+        ]);
+
+        let wnc = V2_5;
+        for (scale, addon) in [
+            (AddOnFactorScale::Percent, 20000.0),
+            (AddOnFactorScale::BasisPoints, 200.0),
+            (AddOnFactorScale::Fraction, 2000000.0),
+        ] {
+            let cfg = EngineConfig { addon_factor_scale: scale, ..Default::default() };
+            let simm = SIMM::from_crif(crif.clone(), &cfg, &wnc).unwrap();
+            assert_eq!(simm.result().addon, addon, "{:?}", scale);
+            assert_eq!(simm.addon_breakdown().unwrap().notional_based, vec![("Trade1".to_string(), addon)]);
+        }
+    }
+
+    #[test]
+    fn test_excluded_qualifiers() {
+        let crif = crif(&[
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "1000000"],
+            ["Equity", "Risk_Equity", "DEF", "1", "", "", "500000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "1000000"],
+        ]);
+
+        let wnc = V2_5;
+        let all = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+        let cfg: EngineConfig = toml::from_str(
+            "weights_and_corr_version = \"2_5\"\n\
+             calculation_currency = \"USD\"\n\
+             exchange_rate = 1.0\n\
+             excluded_qualifiers = [\"ABC\"]\n",
+        )
+        .unwrap();
+        let without_abc = SIMM::from_crif(crif, &cfg, &wnc).unwrap();
+
+        let equity = |simm: &SIMM| simm.raw_margins("Equity")["Equity"][&RiskType::Delta];
+        let rw = wnc.rw("Risk_Equity", "1").unwrap();
+        assert!((equity(&without_abc) - rw * 500000.0).abs() < 1e-6);
+        assert!(equity(&without_abc) < equity(&all));
+        assert_eq!(without_abc.raw_margins("RatesFX"), all.raw_margins("RatesFX"));
+        assert!(without_abc.weighted_sensitivities().iter().all(|r| r.qualifier != "ABC"));
+    }
+
+    #[test]
+    fn test_dumped_crif_reproduces_im() {
+        let crif = crif(&[
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "1,000,000"],
+            ["Equity", "Risk_Equity", "DEF", "1", "", "", "500000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "1000000"],
+        ]);
+        let cfg = EngineConfig { excluded_qualifiers: HashSet::from(["DEF".to_string()]), ..Default::default() };
+        let simm = SIMM::from_crif(crif.clone(), &cfg, &V2_5).unwrap();
+
+        let dumped = simm.crif();
+        assert_eq!(dumped[0], crif[0]);
+        assert_eq!(dumped.len(), 3);
+
+        let csv = crate::simm_utils::crif_to_csv(&dumped);
+        let reloaded = crate::file_utils::parse_csv_from_string(&csv).unwrap();
+        let again = SIMM::from_crif(reloaded, &EngineConfig::default(), &V2_5).unwrap();
+        assert_eq!(again.simm, simm.simm);
+    }
+
+    #[test]
+    fn test_risk_class_multiplier() {
+        let crif = crif(&[
+            ["Credit", "Risk_CreditQ", "ISSUER1", "2", "5y", "USD", "50000"],
+            ["Credit", "Risk_Equity", "ABC", "1", "", "", "1000000"],
+        ]);
+
+        let wnc = V2_5;
+        let base = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+        let cfg = EngineConfig {
+            risk_class_multipliers: HashMap::from([(RiskClass::CreditQ, 1.2)]),
+            ..Default::default()
+        };
+        let scaled = SIMM::from_crif(crif, &cfg, &wnc).unwrap();
+
+        let margin = |simm: &SIMM, rc: &str| simm.raw_margins("Credit")[rc].values().sum::<f64>();
+        let (credit, equity) = (margin(&base, "CreditQ"), margin(&base, "Equity"));
+        assert!((margin(&scaled, "CreditQ") - 1.2 * credit).abs() < 1e-6);
+        assert_eq!(margin(&scaled, "Equity"), equity);
+
+        // Only the scaled class's terms of the psi aggregation change
+        let psi = wnc.psi("CreditQ", "Equity").unwrap();
+        let c = 1.2 * credit;
+        let expected = (c * c + equity * equity + 2.0 * psi * c * equity).sqrt();
+        assert!((scaled.simm - expected).abs() < 1e-6, "{} vs {}", scaled.simm, expected);
+        assert!(scaled.simm > base.simm);
+        assert!(scaled.reconcile().is_ok());
+    }
+
+    #[test]
+    fn test_calc_ccy_amounts_are_validated() {
+        let header = ["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2", "Amount", "AmountCurrency", "AmountUSD"];
+        let rows = [
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "4O000", "EUR", "50000"],
+            ["Equity", "Risk_Equity", "XYZ", "1", "", "", "", "EUR", "20000"],
+        ];
+        let mut crif: Crif = vec![header.iter().map(|s| s.to_string()).collect()];
+        crif.extend(rows.iter().map(|r| r.iter().map(|s| s.to_string()).collect()));
+
+        let wnc = V2_5;
+        let cfg = EngineConfig {
+            calculation_currency: "EUR".to_string(),
+            sensitivities_already_in_calc_ccy: true,
+            ..Default::default()
+        };
+        // Neither row falls back to its USD figure
+        let simm = SIMM::from_crif(crif.clone(), &cfg, &wnc).unwrap();
+        assert_eq!(simm.simm, 0.0);
+        assert_eq!(simm.warnings.len(), 2, "{:?}", simm.warnings);
+
+        let err = SIMM::from_crif(crif, &EngineConfig { strict: true, ..cfg }, &wnc).err().unwrap();
+        assert!(err.to_string().contains("Row 1"), "{}", err);
+        assert!(err.to_string().contains("Row 2: empty Amount"), "{}", err);
+    }
 }
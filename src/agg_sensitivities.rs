@@ -1,7 +1,16 @@
+use anyhow::{Result, bail};
+
 use crate::constants::*;
 use crate::wnc::WeightsAndCorr;
 use crate::v2_5::*;
 
+/// Panic with the slice name if an optional slice is shorter than required
+fn assert_len(name: &str, len: Option<usize>, required: usize) {
+    if let Some(len) = len {
+        assert!(len >= required, "{} has {} entries but {} are required", name, len, required);
+    }
+}
+
 /// Compute delta capital charge K for a given risk class and bucket
 ///
 /// # Arguments
@@ -13,6 +22,10 @@ use crate::v2_5::*;
 /// * `tenor` - Tenor labels (optional, for rates)
 /// * `index` - Index labels (optional, for credit/rates)
 /// * `calculation_currency` - Calculation currency (default "USD")
+///
+/// # Panics
+/// If a provided slice is shorter than `list_ws` (for non-FX risk classes
+/// only the first bucket is used).
 pub fn k_delta(
     wnc: &dyn WeightsAndCorr,
     risk_class: &str,
@@ -25,6 +38,15 @@ pub fn k_delta(
 ) -> f64 {
     let n = list_ws.len();
 
+    assert_len("list_cr", list_cr.map(|s| s.len()), n);
+    assert_len("tenor", tenor.map(|s| s.len()), n);
+    assert_len("index", index.map(|s| s.len()), n);
+    if LIST_FX.contains(&risk_class) {
+        assert_len("bucket", bucket.map(|s| s.len()), n);
+    } else {
+        assert_len("bucket", bucket.map(|s| s.len()), n.min(1));
+    }
+
     // K = sum of WS^2
     let mut k: f64 = list_ws.iter().map(|ws| ws.powi(2)).sum();
 
@@ -46,11 +68,11 @@ pub fn k_delta(
                     if idx[i] == idx[j] {
                         phi = 1.0;
                     } else if idx[i] == "XCcy" || idx[j] == "XCcy" {
-                        phi = CCY_BASIS_SPREAD_CORR;
+                        phi = wnc.scalar_params().ccy_basis_spread_corr;
                     } else if idx[i] == "Inf" || idx[j] == "Inf" {
-                        phi = INFLATION_CORR;
+                        phi = wnc.scalar_params().inflation_corr;
                     } else {
-                        phi = SUB_CURVES_CORR;
+                        phi = wnc.scalar_params().sub_curves_corr;
                     }
 
                     // Calculate rho for rates
@@ -84,7 +106,7 @@ pub fn k_delta(
                 // Equity, Commodity, FX
                 if LIST_EQUITY.contains(&risk_class) || LIST_COMMODITY.contains(&risk_class) {
                     if let Some(bkt) = bucket {
-                        rho = wnc.rho(risk_class, "", "", Some(bkt[0])).unwrap_or(1.0);
+                        rho = wnc.intra_bucket_rho(risk_class, bkt[0]).unwrap_or(1.0);
                     }
                 } else if LIST_FX.contains(&risk_class) {
                     // FX correlation logic
@@ -144,6 +166,9 @@ pub fn k_delta(
 /// * `vcr` - List of vega concentration risk factors (optional)
 /// * `bucket` - Bucket identifier (optional)
 /// * `index` - Index labels (optional)
+///
+/// # Panics
+/// If `vcr` or `index` is shorter than `vr`.
 pub fn k_vega(
     wnc: &dyn WeightsAndCorr,
     risk_class: &str,
@@ -154,6 +179,9 @@ pub fn k_vega(
 ) -> f64 {
     let n = vr.len();
 
+    assert_len("vcr", vcr.map(|s| s.len()), n);
+    assert_len("index", index.map(|s| s.len()), n);
+
     // K = sum of VR^2
     let mut k: f64 = vr.iter().map(|v| v.powi(2)).sum();
 
@@ -173,17 +201,21 @@ pub fn k_vega(
 
             // Calculate rho based on risk class
             if risk_class == "Rates" {
+                // The methodology sums inflation vega across tenors into a
+                // single risk factor, which is the same as correlating the
+                // inflation tenors at 1; that factor then correlates with
+                // each IR vol tenor at the inflation correlation
                 if idx[i] == "Inf" && idx[j] == "Inf" {
                     rho = 1.0;
                 } else if idx[i] == "Inf" || idx[j] == "Inf" {
-                    rho = INFLATION_CORR;
+                    rho = wnc.scalar_params().inflation_corr;
                 } else {
                     rho = wnc.rho("Risk_IRVol", idx[i], idx[j], None).unwrap_or(1.0);
                 }
 
                 f = 1.0; // For rates, f is always 1
             } else if LIST_EQUITY.contains(&risk_class) || LIST_COMMODITY.contains(&risk_class) {
-                rho = wnc.rho(risk_class, "", "", bucket).unwrap_or(1.0);
+                rho = bucket.and_then(|b| wnc.intra_bucket_rho(risk_class, b)).unwrap_or(1.0);
 
                 // Calculate f
                 if let Some(vcr_vals) = vcr {
@@ -194,7 +226,7 @@ pub fn k_vega(
                     }
                 }
             } else if LIST_FX.contains(&risk_class) {
-                rho = FX_VEGA_CORR;
+                rho = wnc.scalar_params().fx_vega_corr;
 
                 // Calculate f
                 if let Some(vcr_vals) = vcr {
@@ -232,6 +264,9 @@ pub fn k_vega(
 /// * `cvr_list` - List of curvature risk values
 /// * `bucket` - Bucket identifier (optional)
 /// * `index` - Index labels (optional)
+///
+/// # Panics
+/// If `index` is shorter than `cvr_list`.
 pub fn k_curvature(
     wnc: &dyn WeightsAndCorr,
     risk_class: &str,
@@ -241,6 +276,8 @@ pub fn k_curvature(
 ) -> f64 {
     let n = cvr_list.len();
 
+    assert_len("index", index.map(|s| s.len()), n);
+
     // K = sum of CVR^2
     let mut k: f64 = cvr_list.iter().map(|cvr| cvr.powi(2)).sum();
 
@@ -259,17 +296,18 @@ pub fn k_curvature(
 
             // Calculate rho based on risk class
             if risk_class == "Rates" {
+                // Inflation tenors form a single factor, as in `k_vega`
                 if idx[i] == "Inf" && idx[j] == "Inf" {
                     rho = 1.0;
                 } else if idx[i] == "Inf" || idx[j] == "Inf" {
-                    rho = INFLATION_CORR;
+                    rho = wnc.scalar_params().inflation_corr;
                 } else {
                     rho = wnc.rho("Risk_IRVol", idx[i], idx[j], None).unwrap_or(1.0);
                 }
             } else if LIST_EQUITY.contains(&risk_class) || LIST_COMMODITY.contains(&risk_class) {
-                rho = wnc.rho(risk_class, "", "", bucket).unwrap_or(1.0);
+                rho = bucket.and_then(|b| wnc.intra_bucket_rho(risk_class, b)).unwrap_or(1.0);
             } else if LIST_FX.contains(&risk_class) {
-                rho = FX_VEGA_CORR;
+                rho = wnc.scalar_params().fx_vega_corr;
             } else if risk_class == "Risk_CreditVol" || risk_class == "Risk_CreditVolNonQ" {
                 rho = wnc.rho(risk_class, idx[i], idx[j], None).unwrap_or(1.0);
             }
@@ -281,6 +319,147 @@ pub fn k_curvature(
     k.sqrt()
 }
 
+/// Named-argument entry point to the K-factor functions
+///
+/// Checks that the optional slices line up with the values before calling
+/// `k_delta`, `k_vega` or `k_curvature`.
+///
+/// ```
+/// use simm_rs::{KFactorRequest, V2_5};
+///
+/// let ws = [100.0, 200.0];
+/// let tenors = ["2y", "5y"];
+/// let index = ["OIS", "OIS"];
+/// let k = KFactorRequest::new(&V2_5, "Rates", &ws)
+///     .tenors(&tenors)
+///     .index(&index)
+///     .delta()
+///     .unwrap();
+/// assert!(k > 0.0);
+/// ```
+pub struct KFactorRequest<'a> {
+    wnc: &'a dyn WeightsAndCorr,
+    risk_class: &'a str,
+    values: &'a [f64],
+    concentration: Option<&'a [f64]>,
+    buckets: Option<&'a [&'a str]>,
+    tenors: Option<&'a [&'a str]>,
+    index: Option<&'a [&'a str]>,
+    calculation_currency: &'a str,
+}
+
+impl<'a> KFactorRequest<'a> {
+    /// # Arguments
+    /// * `wnc` - Weights and correlations provider
+    /// * `risk_class` - Risk class (e.g., "Rates", "Risk_CreditQ", etc.)
+    /// * `values` - Weighted sensitivities (delta), vega risks or curvature risks
+    pub fn new(wnc: &'a dyn WeightsAndCorr, risk_class: &'a str, values: &'a [f64]) -> Self {
+        Self {
+            wnc,
+            risk_class,
+            values,
+            concentration: None,
+            buckets: None,
+            tenors: None,
+            index: None,
+            calculation_currency: "USD",
+        }
+    }
+
+    /// Concentration risk factors, one per value
+    pub fn concentration(mut self, concentration: &'a [f64]) -> Self {
+        self.concentration = Some(concentration);
+        self
+    }
+
+    /// Bucket: a single bucket, or for FX delta one currency per value
+    pub fn buckets(mut self, buckets: &'a [&'a str]) -> Self {
+        self.buckets = Some(buckets);
+        self
+    }
+
+    /// Tenor labels, one per value (rates delta)
+    pub fn tenors(mut self, tenors: &'a [&'a str]) -> Self {
+        self.tenors = Some(tenors);
+        self
+    }
+
+    /// Index labels, one per value (rates sub-curves, credit qualifiers, vol tenors)
+    pub fn index(mut self, index: &'a [&'a str]) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    /// Calculation currency (default "USD")
+    pub fn calculation_currency(mut self, currency: &'a str) -> Self {
+        self.calculation_currency = currency;
+        self
+    }
+
+    /// Delta capital charge
+    pub fn delta(&self) -> Result<f64> {
+        self.check_lengths(true)?;
+        Ok(k_delta(
+            self.wnc,
+            self.risk_class,
+            self.values,
+            self.concentration,
+            self.buckets,
+            self.tenors,
+            self.index,
+            self.calculation_currency,
+        ))
+    }
+
+    /// Vega capital charge
+    pub fn vega(&self) -> Result<f64> {
+        self.check_lengths(false)?;
+        Ok(k_vega(self.wnc, self.risk_class, self.values, self.concentration, self.single_bucket(), self.index))
+    }
+
+    /// Curvature capital charge
+    pub fn curvature(&self) -> Result<f64> {
+        self.check_lengths(false)?;
+        Ok(k_curvature(self.wnc, self.risk_class, self.values, self.single_bucket(), self.index))
+    }
+
+    fn single_bucket(&self) -> Option<&'a str> {
+        self.buckets.and_then(|b| b.first().copied())
+    }
+
+    fn check_lengths(&self, per_value_buckets: bool) -> Result<()> {
+        let n = self.values.len();
+
+        if let Some(cr) = self.concentration
+            && cr.len() != n
+        {
+            bail!("concentration has {} entries but there are {} values", cr.len(), n);
+        }
+        if let Some(tenors) = self.tenors
+            && tenors.len() != n
+        {
+            bail!("tenors has {} entries but there are {} values", tenors.len(), n);
+        }
+        if let Some(index) = self.index
+            && index.len() != n
+        {
+            bail!("index has {} entries but there are {} values", index.len(), n);
+        }
+
+        if let Some(buckets) = self.buckets {
+            let fx_delta = per_value_buckets && LIST_FX.contains(&self.risk_class);
+            if fx_delta && buckets.len() != n {
+                bail!("FX delta needs one currency per value, got {} for {} values", buckets.len(), n);
+            }
+            if !fx_delta && buckets.len() != 1 {
+                bail!("expected a single bucket, got {}", buckets.len());
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -302,6 +481,72 @@ mod tests {
         assert!(result > 0.0);
     }
 
+    #[test]
+    fn test_commodity_intra_bucket_corr_shared_across_measures() {
+        // Two crude oil (bucket 2) qualifiers of equal size
+        let wnc = V2_5;
+        let rho = wnc.intra_bucket_rho("Risk_Commodity", "2").unwrap();
+        assert_eq!(rho, 0.98);
+        assert_eq!(wnc.intra_bucket_rho("Risk_CommodityVol", "2"), Some(rho));
+
+        let values = [100.0, 100.0];
+        let correlated = (2.0 * 100.0_f64.powi(2) * (1.0 + rho)).sqrt();
+
+        let delta = k_delta(&wnc, "Risk_Commodity", &values, None, Some(&["2"]), None, None, "USD");
+        let vega = k_vega(&wnc, "Risk_CommodityVol", &values, None, Some("2"), None);
+        assert!((delta - correlated).abs() < 1e-9);
+        assert!((vega - correlated).abs() < 1e-9);
+
+        let curvature = k_curvature(&wnc, "Risk_CommodityVol", &values, Some("2"), None);
+        let expected = (2.0 * 100.0_f64.powi(2) * (1.0 + rho * rho)).sqrt();
+        assert!((curvature - expected).abs() < 1e-9);
+
+        // Nearly perfect correlation: close to the fully additive K
+        assert!(vega > 0.99 * 200.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "index has 1 entries but 2 are required")]
+    fn test_k_delta_short_index_panics_with_name() {
+        let wnc = V2_5;
+        k_delta(&wnc, "Risk_CreditQ", &[100.0, 200.0], None, None, None, Some(&["A"]), "USD");
+    }
+
+    #[test]
+    #[should_panic(expected = "vcr has")]
+    fn test_k_vega_short_vcr_panics_with_name() {
+        let wnc = V2_5;
+        k_vega(&wnc, "Risk_EquityVol", &[100.0, 200.0], Some(&[1.0]), Some("1"), None);
+    }
+
+    #[test]
+    fn test_k_factor_request_matches_functions() {
+        let wnc = V2_5;
+        let ws = [100.0, -50.0, 75.0];
+        let cr = [1.0, 1.2, 1.0];
+        let bucket = ["3"];
+
+        let request = KFactorRequest::new(&wnc, "Risk_Equity", &ws).concentration(&cr).buckets(&bucket);
+        assert_eq!(
+            request.delta().unwrap(),
+            k_delta(&wnc, "Risk_Equity", &ws, Some(&cr), Some(&bucket), None, None, "USD")
+        );
+        assert_eq!(request.vega().unwrap(), k_vega(&wnc, "Risk_Equity", &ws, Some(&cr), Some("3"), None));
+        assert_eq!(request.curvature().unwrap(), k_curvature(&wnc, "Risk_Equity", &ws, Some("3"), None));
+    }
+
+    #[test]
+    fn test_k_factor_request_rejects_mismatched_lengths() {
+        let wnc = V2_5;
+        let ws = [100.0, 200.0];
+
+        let err = KFactorRequest::new(&wnc, "Rates", &ws).tenors(&["2y"]).delta().unwrap_err();
+        assert!(err.to_string().contains("tenors"));
+        assert!(KFactorRequest::new(&wnc, "Risk_CreditQ", &ws).concentration(&[1.0]).delta().is_err());
+        assert!(KFactorRequest::new(&wnc, "Risk_FX", &ws).buckets(&["EUR"]).delta().is_err());
+        assert!(KFactorRequest::new(&wnc, "Risk_FX", &ws).buckets(&["EUR", "JPY"]).delta().is_ok());
+    }
+
     #[test]
     fn test_k_curvature_simple() {
         let wnc = V2_5;
@@ -310,4 +555,18 @@ mod tests {
         let result = k_curvature(&wnc, "Rates", &cvr, None, Some(&index));
         assert!(result > 0.0);
     }
+
+    #[test]
+    fn test_k_vega_inflation_tenors_act_as_one_factor() {
+        let wnc = V2_5;
+
+        // Two inflation vol tenors and one IR vol tenor, against the
+        // inflation vega summed into a single vertex
+        let split = k_vega(&wnc, "Rates", &[300.0, -100.0, 250.0], None, None, Some(&["Inf", "Inf", "1y"]));
+        let summed = k_vega(&wnc, "Rates", &[200.0, 250.0], None, None, Some(&["Inf", "1y"]));
+        assert!((split - summed).abs() < 1e-9);
+
+        let expected = (200.0_f64.powi(2) + 250.0_f64.powi(2) + 2.0 * INFLATION_CORR * 200.0 * 250.0).sqrt();
+        assert!((summed - expected).abs() < 1e-9);
+    }
 }
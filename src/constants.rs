@@ -1,4 +1,5 @@
 
+use serde::Deserialize;
 use std::str::FromStr;
 
 /// Constants and configuration values for ISDA SIMM calculations
@@ -23,6 +24,38 @@ pub const SIMM_TENOR_LIST: &[&str] = &[
     "2w","1m","3m","6m","1y","2y","3y","5y","10y","15y","20y","30y"
 ];
 
+/// Position of a tenor in `SIMM_TENOR_LIST`, without scanning the list on
+/// each IR correlation lookup
+pub(crate) fn tenor_index(tenor: &str) -> Option<usize> {
+    match tenor {
+        "2w" => Some(0),
+        "1m" => Some(1),
+        "3m" => Some(2),
+        "6m" => Some(3),
+        "1y" => Some(4),
+        "2y" => Some(5),
+        "3y" => Some(6),
+        "5y" => Some(7),
+        "10y" => Some(8),
+        "15y" => Some(9),
+        "20y" => Some(10),
+        "30y" => Some(11),
+        _ => None,
+    }
+}
+
+/// Vertices of the credit delta and vega sensitivities
+pub const CREDIT_TENOR_LIST: &[&str] = &["1y", "2y", "3y", "5y", "10y"];
+
+/// ProductClass values recognised in a CRIF
+pub const LIST_PRODUCT_CLASSES: &[&str] = &[
+    "RatesFX",
+    "Rates",
+    "Credit",
+    "Equity",
+    "Commodity",
+];
+
 pub const LIST_RATES: &[&str] = &[
     "Risk_IRCurve",
     "Risk_Inflation",
@@ -57,8 +90,14 @@ pub const LIST_COMMODITY: &[&str] = &[
     "Risk_CommodityVol",
 ];
 
-#[allow(dead_code)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+/// Row and column order of the cross risk class correlation (`CORR_PARAMS`)
+/// tables of the calibrations, as laid out in the ISDA methodology
+///
+/// The engine aggregates in its own order (`PsiMatrix`) and looks psi up by
+/// risk class name, so the two orders need not agree.
+pub const PSI_TABLE_ORDER: [&str; 6] = ["Rates", "CreditQ", "CreditNonQ", "Equity", "Commodity", "FX"];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord, Deserialize)]
 pub enum RiskClass {
     Rates,
     FX,
@@ -68,8 +107,8 @@ pub enum RiskClass {
     Commodity,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+/// Risk measure of a margin component, in breakdown order
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub enum RiskType {
     Delta,
     Vega,
@@ -122,7 +161,6 @@ impl RiskClass {
 }
 
 impl RiskType {
-    #[allow(dead_code)]
     pub const fn as_str(self) -> &'static str {
         match self {
             RiskType::Delta => "Delta",
@@ -134,6 +172,21 @@ impl RiskType {
 }
 
 impl RiskClass {
+    /// Risk class of a CRIF `RiskType` such as `Risk_EquityVol`
+    pub fn of_risk_type(risk_type: &str) -> Option<RiskClass> {
+        [
+            (LIST_RATES, RiskClass::Rates),
+            (LIST_FX, RiskClass::FX),
+            (LIST_CREDIT_Q, RiskClass::CreditQ),
+            (LIST_CREDIT_NON_Q, RiskClass::CreditNonQ),
+            (LIST_EQUITY, RiskClass::Equity),
+            (LIST_COMMODITY, RiskClass::Commodity),
+        ]
+        .into_iter()
+        .find(|(list, _)| list.contains(&risk_type))
+        .map(|(_, rc)| rc)
+    }
+
     /// Which risk types are valid for this risk class (SIMM spec)
     #[allow(dead_code)]
     pub const fn allowed_risk_types(self) -> &'static [RiskType] {
@@ -182,7 +235,6 @@ impl RiskClass {
 }
 
 impl RiskType {
-    #[allow(dead_code)]
     pub const ALL: [RiskType; 4] = [
         RiskType::Delta,
         RiskType::Vega,
@@ -191,15 +243,39 @@ impl RiskType {
     ];
 }
 
+/// Risk weight group of an FX currency (the calculation currency or the
+/// qualifier), used to pick the FX delta risk weight
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum RiskLevel {
+    Regular,
+    High,
+}
+
+/// Volatility group of an FX currency, used to pick the FX delta correlation
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum VolatilityLevel {
+    Regular,
+    High,
+}
+
+impl VolatilityLevel {
+    pub(crate) const fn as_index(self) -> usize {
+        match self {
+            VolatilityLevel::Regular => 0,
+            VolatilityLevel::High => 1,
+        }
+    }
+}
+
 /// Dict mapping each risk class to its allowed risk measures
-pub fn margin_by_risk_class() -> std::collections::HashMap<&'static str, Vec<&'static str>> {
+pub fn margin_by_risk_class() -> std::collections::HashMap<&'static str, Vec<RiskType>> {
     let mut map = std::collections::HashMap::new();
-    map.insert("Rates", vec!["Delta", "Vega", "Curvature", "BaseCorr"]);
-    map.insert("FX", vec!["Delta", "Vega", "Curvature", "BaseCorr"]);
-    map.insert("CreditQ", vec!["Delta", "Vega", "Curvature", "BaseCorr"]);
-    map.insert("CreditNonQ", vec!["Delta", "Vega", "Curvature", "BaseCorr"]);
-    map.insert("Equity", vec!["Delta", "Vega", "Curvature", "BaseCorr"]);
-    map.insert("Commodity", vec!["Delta", "Vega", "Curvature", "BaseCorr"]);
+    map.insert("Rates", RiskType::ALL.to_vec());
+    map.insert("FX", RiskType::ALL.to_vec());
+    map.insert("CreditQ", RiskType::ALL.to_vec());
+    map.insert("CreditNonQ", RiskType::ALL.to_vec());
+    map.insert("Equity", RiskType::ALL.to_vec());
+    map.insert("Commodity", RiskType::ALL.to_vec());
     map
 }
 
@@ -0,0 +1,217 @@
+//! Polars DataFrame integration for CRIF input and breakdown output
+//!
+//! Enabled with the `polars` feature.
+
+use anyhow::{Context, Result, bail};
+use polars::prelude::*;
+
+use crate::agg_margins::SIMM;
+use crate::engine_config::EngineConfig;
+use crate::simm_utils::Crif;
+use crate::wnc::WeightsAndCorr;
+
+/// Text columns the engine requires
+const REQUIRED_TEXT_COLUMNS: [&str; 5] = ["ProductClass", "RiskType", "Qualifier", "Label1", "Label2"];
+
+/// Numeric columns the engine requires
+const REQUIRED_NUMERIC_COLUMNS: [&str; 1] = ["AmountUSD"];
+
+/// Optional columns carried through when present; a CRIF without `Bucket`
+/// has its buckets derived by the engine
+const OPTIONAL_TEXT_COLUMNS: [&str; 2] = ["Bucket", "AmountCurrency"];
+const OPTIONAL_NUMERIC_COLUMNS: [&str; 1] = ["Amount"];
+
+/// Convert a text column to strings, nulls becoming empty strings
+///
+/// Integer columns are accepted too, since polars infers an integer type
+/// for an all-numeric `Bucket` column (one without `Residual` rows).
+fn text_values(df: &DataFrame, name: &str) -> Result<Vec<String>> {
+    let column = df.column(name)?;
+    let column = match column.dtype() {
+        DataType::String => column.clone(),
+        dtype if dtype.is_integer() => column.cast(&DataType::String)?,
+        // An all-null column (e.g. an empty Label2) has no inferred type
+        DataType::Null => return Ok(vec![String::new(); df.height()]),
+        other => bail!("column {} must be a string column, found {}", name, other),
+    };
+
+    Ok(column
+        .str()?
+        .into_iter()
+        .map(|v| v.unwrap_or_default().to_string())
+        .collect())
+}
+
+/// Convert a numeric column to strings, nulls becoming empty strings
+fn numeric_values(df: &DataFrame, name: &str) -> Result<Vec<String>> {
+    let column = df.column(name)?;
+    if !column.dtype().is_primitive_numeric() {
+        bail!("column {} must be numeric, found {}", name, column.dtype());
+    }
+
+    let values = column.cast(&DataType::Float64)?;
+    Ok(values
+        .f64()?
+        .into_iter()
+        .map(|v| v.map(|x| x.to_string()).unwrap_or_default())
+        .collect())
+}
+
+/// Convert a CRIF DataFrame into the engine's `Crif` representation
+pub fn crif_from_dataframe(df: &DataFrame) -> Result<Crif> {
+    let present: Vec<&str> = df.get_column_names().iter().map(|c| c.as_str()).collect();
+
+    let missing: Vec<&str> = REQUIRED_TEXT_COLUMNS
+        .iter()
+        .chain(REQUIRED_NUMERIC_COLUMNS.iter())
+        .filter(|c| !present.contains(c))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        bail!("DataFrame is missing required CRIF columns: {}", missing.join(", "));
+    }
+
+    let mut header = Vec::new();
+    let mut columns = Vec::new();
+
+    for name in REQUIRED_TEXT_COLUMNS.iter().chain(OPTIONAL_TEXT_COLUMNS.iter()) {
+        if present.contains(name) {
+            header.push(name.to_string());
+            columns.push(text_values(df, name)?);
+        }
+    }
+    for name in REQUIRED_NUMERIC_COLUMNS.iter().chain(OPTIONAL_NUMERIC_COLUMNS.iter()) {
+        if present.contains(name) {
+            header.push(name.to_string());
+            columns.push(numeric_values(df, name)?);
+        }
+    }
+
+    let mut crif = vec![header];
+    for i in 0..df.height() {
+        crif.push(columns.iter().map(|c| c[i].clone()).collect());
+    }
+
+    Ok(crif)
+}
+
+impl<'a> SIMM<'a> {
+    /// Create SIMM calculator from a Polars DataFrame holding a CRIF
+    ///
+    /// # Arguments
+    /// * `df` - DataFrame with the standard CRIF columns; `AmountUSD` must be numeric
+    /// * `cfg` - Engine configuration
+    /// * `wnc` - Weights and correlations implementation
+    pub fn from_dataframe(df: &DataFrame, cfg: &EngineConfig, wnc: &'a dyn WeightsAndCorr) -> Result<Self> {
+        let crif = crif_from_dataframe(df).context("Invalid CRIF DataFrame")?;
+        Self::from_crif(crif, cfg, wnc)
+    }
+
+    /// Breakdown as a DataFrame with one string column per breakdown column
+    pub fn breakdown_dataframe(&self) -> Result<DataFrame> {
+        let Some(header) = self.simm_break_down.first() else {
+            return Ok(DataFrame::empty());
+        };
+
+        let columns = header
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let values: Vec<String> = self
+                    .simm_break_down
+                    .iter()
+                    .skip(1)
+                    .map(|row| row.get(i).cloned().unwrap_or_default())
+                    .collect();
+                Column::new(name.as_str().into(), values)
+            })
+            .collect();
+
+        Ok(DataFrame::new(columns)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v2_5::V2_5;
+
+    fn sample_df() -> DataFrame {
+        df!(
+            "ProductClass" => ["Rates", "Rates"],
+            "RiskType" => ["Risk_IRCurve", "Risk_IRCurve"],
+            "Qualifier" => ["USD", "USD"],
+            "Bucket" => ["1", "1"],
+            "Label1" => ["2w", "1y"],
+            "Label2" => ["OIS", "OIS"],
+            "AmountUSD" => [10000.0, 20000.0]
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_dataframe_matches_from_crif() {
+        let df = sample_df();
+        let crif = crif_from_dataframe(&df).unwrap();
+
+        let cfg = EngineConfig::default();
+        let wnc = V2_5;
+        let from_df = SIMM::from_dataframe(&df, &cfg, &wnc).unwrap();
+        let from_crif = SIMM::from_crif(crif, &cfg, &wnc).unwrap();
+        assert_eq!(from_df.simm, from_crif.simm);
+
+        let breakdown = from_df.breakdown_dataframe().unwrap();
+        assert_eq!(breakdown.height(), from_df.simm_break_down.len() - 1);
+    }
+
+    #[test]
+    fn test_from_dataframe_rejects_bad_columns() {
+        let mut df = sample_df();
+        let _ = df.drop_in_place("Label1").unwrap();
+        let err = crif_from_dataframe(&df).unwrap_err();
+        assert!(err.to_string().contains("Label1"));
+
+        let df = df!(
+            "ProductClass" => ["Rates"],
+            "RiskType" => ["Risk_IRCurve"],
+            "Qualifier" => ["USD"],
+            "Bucket" => ["1"],
+            "Label1" => ["2w"],
+            "Label2" => ["OIS"],
+            "AmountUSD" => ["10000"]
+        )
+        .unwrap();
+        assert!(crif_from_dataframe(&df).unwrap_err().to_string().contains("numeric"));
+    }
+
+    #[test]
+    fn test_from_dataframe_integer_or_missing_bucket() {
+        let df = df!(
+            "ProductClass" => ["Equity", "Equity"],
+            "RiskType" => ["Risk_Equity", "Risk_Equity"],
+            "Qualifier" => ["ABC", "XYZ"],
+            "Bucket" => [1i64, 5],
+            "Label1" => ["", ""],
+            "Label2" => ["", ""],
+            "AmountUSD" => [30000.0, -10000.0]
+        )
+        .unwrap();
+        let crif = crif_from_dataframe(&df).unwrap();
+        let bucket_idx = crif[0].iter().position(|c| c == "Bucket").unwrap();
+        assert_eq!(crif[1][bucket_idx], "1");
+        assert_eq!(crif[2][bucket_idx], "5");
+
+        let wnc = V2_5;
+        let with_bucket = SIMM::from_dataframe(&df, &EngineConfig::default(), &wnc).unwrap();
+        assert!(with_bucket.simm > 0.0);
+
+        // Rates and FX rows need no Bucket column
+        let mut df = sample_df();
+        let bucketed = SIMM::from_dataframe(&df, &EngineConfig::default(), &wnc).unwrap();
+        let _ = df.drop_in_place("Bucket").unwrap();
+        let crif = crif_from_dataframe(&df).unwrap();
+        assert!(!crif[0].iter().any(|c| c == "Bucket"));
+        let bucketless = SIMM::from_dataframe(&df, &EngineConfig::default(), &wnc).unwrap();
+        assert_eq!(bucketless.simm, bucketed.simm);
+    }
+}
@@ -1,16 +1,331 @@
 use serde::Deserialize;
 use anyhow::{Result, bail};
+
+use crate::constants::RiskClass;
+use crate::rates::parse_rate;
+use crate::simm_utils::parse_date;
+use crate::wnc::WeightsAndCorr;
+use crate::{v2_5, v2_6, v2_7};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
+use std::str::FromStr;
+
+/// SIMM calibration versions supported by the engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimmVersion {
+    V2_5,
+    V2_6,
+    V2_7,
+}
+
+impl SimmVersion {
+    pub const ALL: [SimmVersion; 3] = [SimmVersion::V2_5, SimmVersion::V2_6, SimmVersion::V2_7];
+
+    /// Version string as used in `weights_and_corr_version`
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            SimmVersion::V2_5 => "2_5",
+            SimmVersion::V2_6 => "2_6",
+            SimmVersion::V2_7 => "2_7",
+        }
+    }
+
+    /// Weights and correlations of the version
+    pub fn wnc(self) -> &'static dyn WeightsAndCorr {
+        match self {
+            SimmVersion::V2_5 => &v2_5::V2_5,
+            SimmVersion::V2_6 => &v2_6::V2_6,
+            SimmVersion::V2_7 => &v2_7::V2_7,
+        }
+    }
+
+    /// Whether the calibration puts `currency` in an FX concentration
+    /// threshold category of its own rather than "Others"
+    fn has_fx_category(self, currency: &str) -> bool {
+        let (category1, category2): (&[&str], &[&str]) = match self {
+            SimmVersion::V2_5 => (&v2_5::FX_CATEGORY1, &v2_5::FX_CATEGORY2),
+            SimmVersion::V2_6 => (&v2_6::FX_CATEGORY1, &v2_6::FX_CATEGORY2),
+            SimmVersion::V2_7 => (&v2_7::FX_CATEGORY1, &v2_7::FX_CATEGORY2),
+        };
+        category1.contains(&currency) || category2.contains(&currency)
+    }
+}
+
+impl FromStr for SimmVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match SimmVersion::ALL.into_iter().find(|v| v.as_str() == s) {
+            Some(version) => Ok(version),
+            None => bail!("Unsupported SIMM version: {} (expected 2_5, 2_6 or 2_7)", s),
+        }
+    }
+}
+
+/// Cross risk class correlations (psi) replacing those of the calibration
+///
+/// Rows and columns follow the order Rates, FX, CreditQ, CreditNonQ, Equity,
+/// Commodity. The diagonal is used as given, so `ones()` gives the fully
+/// correlated sum and `identity()` the fully independent root-sum-square.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct PsiMatrix(pub [[f64; 6]; 6]);
+
+impl PsiMatrix {
+    /// Every pair of risk classes fully correlated
+    pub fn ones() -> Self {
+        Self([[1.0; 6]; 6])
+    }
+
+    /// Risk classes fully independent
+    pub fn identity() -> Self {
+        let mut m = [[0.0; 6]; 6];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        Self(m)
+    }
+}
+
+/// How concentration risk factors are applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum ConcentrationMode {
+    /// `max(1, sqrt(|sum| / threshold))` as in the methodology
+    #[default]
+    Standard,
+    /// Every CR and VCR is 1, giving the un-concentrated margin
+    Disabled,
+}
+
+/// Unit of the `Param_AddOnNotionalFactor` amounts in the CRIF
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum AddOnFactorScale {
+    /// `1` is 1% of the notional
+    #[default]
+    Percent,
+    /// `1` is 1bp of the notional
+    BasisPoints,
+    /// `0.01` is 1% of the notional
+    Fraction,
+}
+
+impl AddOnFactorScale {
+    /// Divisor turning a CRIF factor into a fraction of the notional
+    pub const fn divisor(self) -> f64 {
+        match self {
+            AddOnFactorScale::Percent => 100.0,
+            AddOnFactorScale::BasisPoints => 10_000.0,
+            AddOnFactorScale::Fraction => 1.0,
+        }
+    }
+}
+
+/// A rate in a config file, as a number or as text in either decimal
+/// convention (see `parse_rate`)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RateValue {
+    Number(f64),
+    Text(String),
+}
+
+impl RateValue {
+    fn parse<E: serde::de::Error>(self) -> std::result::Result<f64, E> {
+        match self {
+            RateValue::Number(rate) => Ok(rate),
+            RateValue::Text(text) => parse_rate(&text).map_err(E::custom),
+        }
+    }
+}
+
+fn deserialize_rate<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<f64, D::Error> {
+    RateValue::deserialize(deserializer)?.parse()
+}
+
+fn deserialize_rates<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<HashMap<String, f64>, D::Error> {
+    HashMap::<String, RateValue>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(ccy, rate)| Ok((ccy, rate.parse()?)))
+        .collect()
+}
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct EngineConfig {
     pub weights_and_corr_version: String,
     pub calculation_currency: String,
+    /// USD to calculation currency rate; a string such as `"1,2345"` is
+    /// read with `parse_rate`
+    #[serde(deserialize_with = "deserialize_rate")]
     pub exchange_rate: f64,
+    /// Canonical CRIF column name -> header name used in the input file
+    /// (e.g. `AmountUSD = "amount_usd"`)
+    #[serde(default)]
+    pub column_aliases: HashMap<String, String>,
+    /// Read sensitivities from `Amount` (already in the calculation currency)
+    /// instead of `AmountUSD`, and skip the final `exchange_rate` multiply.
+    /// The calibration's USD concentration thresholds are converted with
+    /// the exchange rate so they compare against calculation-currency amounts.
+    /// `Amount` is validated in place of `AmountUSD`; rows with an empty
+    /// `Amount` are skipped with a warning rather than read in USD.
+    #[serde(default)]
+    pub sensitivities_already_in_calc_ccy: bool,
+    /// Largest magnitude accepted for the total or any breakdown component;
+    /// beyond this f64 can no longer represent every integer exactly
+    #[serde(default = "default_max_component_value")]
+    pub max_component_value: f64,
+    /// Threshold subtracted from the SIMM by `SIMM::im_after_threshold`;
+    /// reporting only, the SIMM itself is unchanged
+    #[serde(default)]
+    pub im_threshold: f64,
+    /// Treat CRIF validation warnings (e.g. unparseable amounts, or buckets
+    /// without a concentration threshold) as errors instead of skipping the
+    /// affected values
+    #[serde(default)]
+    pub strict: bool,
+    /// Stress override for the cross risk class correlations
+    #[serde(default)]
+    pub psi_override: Option<PsiMatrix>,
+    /// Buckets to keep per risk class (0 is the residual bucket); risk
+    /// classes not listed keep all their buckets. Only the bucketed classes
+    /// (CreditQ, CreditNonQ, Equity, Commodity) can be filtered.
+    #[serde(default)]
+    pub bucket_filter: HashMap<RiskClass, HashSet<usize>>,
+    /// Concentration risk factors; `Disabled` isolates the margin before
+    /// concentration for scenario analysis
+    #[serde(default)]
+    pub concentration_mode: ConcentrationMode,
+    /// Warn about sensitivities whose absolute `AmountUSD` exceeds this
+    #[serde(default)]
+    pub max_abs_amount: Option<f64>,
+    /// Warn about sensitivities more than this many times the median
+    /// absolute `AmountUSD` of the CRIF
+    #[serde(default)]
+    pub max_amount_to_median: Option<f64>,
+    /// Valuation date (`YYYY-MM-DD`); when set, `Label1` maturity dates are
+    /// mapped to the nearest SIMM tenor before the calculation
+    #[serde(default)]
+    pub valuation_date: Option<String>,
+    /// USD value of one unit of each currency (e.g. `EUR = 1.08`); when
+    /// set, rows without an `AmountUSD` are converted from `Amount` at the
+    /// rate of their `AmountCurrency`; a CRIF without those two columns is
+    /// used as is. Rates may be strings, as for
+    /// `exchange_rate`. A `RateProvider` passed to the engine takes
+    /// precedence for the currencies it quotes.
+    #[serde(default, deserialize_with = "deserialize_rates")]
+    pub fx_rates: HashMap<String, f64>,
+    /// Record every calibration lookup that falls back to a default in
+    /// `SimmResult::diagnostics`
+    #[serde(default)]
+    pub diagnostics: bool,
+    /// Regulatory regime (e.g. `CFTC`) to margin for; when set, rows whose
+    /// `CollectRegulations`/`PostRegulations` name other regimes only are
+    /// dropped before the calculation
+    #[serde(default)]
+    pub regulation: Option<String>,
+    /// Include the curvature margin; `false` zeroes it before the psi
+    /// aggregation, leaving delta and vega risk only
+    #[serde(default = "default_include_curvature")]
+    pub include_curvature: bool,
+    /// Sign (1 or -1) applied to the `AmountUSD` of each listed risk type
+    /// (e.g. `Risk_FX = -1`), to align a CRIF written with another
+    /// producer's sign convention. For interop only; the methodology is
+    /// unchanged.
+    #[serde(default)]
+    pub sign_overrides: HashMap<String, f64>,
+    /// Unit of `Param_AddOnNotionalFactor`: `Percent` (the default),
+    /// `BasisPoints` or `Fraction`
+    #[serde(default)]
+    pub addon_factor_scale: AddOnFactorScale,
+    /// Qualifiers (issuers, currencies, tickers, ...) whose rows are dropped
+    /// before the calculation, for reproducible scenario runs
+    #[serde(default)]
+    pub excluded_qualifiers: HashSet<String>,
+    /// Multiplier on the margin of a risk class (e.g. `CreditQ = 1.2`)
+    /// before the psi aggregation, for regulator-imposed model add-ons.
+    /// Unlike `Param_ProductClassMultiplier`, it scales one risk class
+    /// inside every product class.
+    #[serde(default)]
+    pub risk_class_multipliers: HashMap<RiskClass, f64>,
+}
+
+fn default_max_component_value() -> f64 {
+    1e15
+}
+
+fn default_include_curvature() -> bool {
+    true
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            weights_and_corr_version: "2_5".to_string(),
+            calculation_currency: "USD".to_string(),
+            exchange_rate: 1.0,
+            column_aliases: HashMap::new(),
+            sensitivities_already_in_calc_ccy: false,
+            max_component_value: default_max_component_value(),
+            im_threshold: 0.0,
+            strict: false,
+            psi_override: None,
+            bucket_filter: HashMap::new(),
+            concentration_mode: ConcentrationMode::Standard,
+            max_abs_amount: None,
+            max_amount_to_median: None,
+            valuation_date: None,
+            fx_rates: HashMap::new(),
+            diagnostics: false,
+            regulation: None,
+            include_curvature: true,
+            sign_overrides: HashMap::new(),
+            addon_factor_scale: AddOnFactorScale::Percent,
+            excluded_qualifiers: HashSet::new(),
+            risk_class_multipliers: HashMap::new(),
+        }
+    }
 }
 
 impl EngineConfig {
+    /// Configuration for a version and calculation currency, checked
+    /// against the calibration
+    ///
+    /// Fails on an unknown version or an invalid currency or rate; see
+    /// `warnings` for the softer checks.
+    pub fn new(version: &str, calculation_currency: &str, exchange_rate: f64) -> Result<Self> {
+        let cfg = Self {
+            weights_and_corr_version: version.to_string(),
+            calculation_currency: calculation_currency.to_string(),
+            exchange_rate,
+            ..Default::default()
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Calibration version of `weights_and_corr_version`
+    pub fn version(&self) -> Result<SimmVersion> {
+        self.weights_and_corr_version.parse()
+    }
+
+    /// Settings that are valid but probably not intended, such as a
+    /// calculation currency the calibration has no FX category for
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Ok(version) = self.version()
+            && !version.has_fx_category(&self.calculation_currency)
+        {
+            warnings.push(format!(
+                "calculation_currency {} is not in an FX category of SIMM {}; the Others concentration threshold applies",
+                self.calculation_currency,
+                version.as_str()
+            ));
+        }
+        warnings
+    }
+
     /// Load from TOML file
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
         let text = fs::read_to_string(path)?;
@@ -24,15 +339,146 @@ impl EngineConfig {
         if self.weights_and_corr_version.is_empty() {
             bail!("weights_and_corr_version must not be empty");
         }
+        self.version()?;
 
         if self.calculation_currency.len() != 3 {
             bail!("calculation_currency must be ISO-4217 (e.g. USD, EUR)");
         }
 
-        if self.exchange_rate <= 0.0 {
-            bail!("exchange_rate must be > 0");
+        if !self.exchange_rate.is_finite() || self.exchange_rate <= 0.0 {
+            bail!("exchange_rate must be finite and > 0");
+        }
+
+        if self.max_component_value.is_nan() || self.max_component_value <= 0.0 {
+            bail!("max_component_value must be > 0");
+        }
+
+        if let Some(psi) = &self.psi_override
+            && psi.0.iter().flatten().any(|v| !(-1.0..=1.0).contains(v))
+        {
+            bail!("psi_override entries must be within [-1, 1]");
+        }
+
+        if let Some(rc) = self.bucket_filter.keys().find(|rc| matches!(rc, RiskClass::Rates | RiskClass::FX)) {
+            bail!("bucket_filter cannot filter {} buckets", rc.as_str());
+        }
+
+        if self.im_threshold.is_nan() || self.im_threshold < 0.0 {
+            bail!("im_threshold must be >= 0");
+        }
+
+        if self.max_abs_amount.is_some_and(|v| v.is_nan() || v <= 0.0) {
+            bail!("max_abs_amount must be > 0");
+        }
+
+        if self.max_amount_to_median.is_some_and(|v| v.is_nan() || v <= 0.0) {
+            bail!("max_amount_to_median must be > 0");
+        }
+
+        if let Some((ccy, _)) = self.fx_rates.iter().find(|(_, rate)| !rate.is_finite() || **rate <= 0.0) {
+            bail!("fx_rates entry for {} must be finite and > 0", ccy);
+        }
+
+        if let Some((risk_type, _)) = self.sign_overrides.iter().find(|(_, sign)| sign.abs() != 1.0) {
+            bail!("sign_overrides entry for {} must be 1 or -1", risk_type);
+        }
+
+        if let Some((rc, _)) = self.risk_class_multipliers.iter().find(|(_, m)| !m.is_finite() || **m <= 0.0) {
+            bail!("risk_class_multipliers entry for {} must be finite and > 0", rc.as_str());
+        }
+
+        if let Some(date) = &self.valuation_date
+            && parse_date(date).is_none()
+        {
+            bail!("valuation_date must be a YYYY-MM-DD date");
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_checks_version_and_currency() {
+        let cfg = EngineConfig::new("2_7", "EUR", 0.9).unwrap();
+        assert_eq!(cfg.version().unwrap(), SimmVersion::V2_7);
+        assert!(cfg.warnings().is_empty());
+
+        let cfg = EngineConfig::new("2_6", "XXX", 1.0).unwrap();
+        assert_eq!(cfg.warnings().len(), 1);
+        assert!(cfg.warnings()[0].contains("Others"));
+
+        let err = EngineConfig::new("2_9", "USD", 1.0).err().unwrap();
+        assert!(err.to_string().contains("Unsupported SIMM version: 2_9"), "{}", err);
+        assert!(toml::from_str::<EngineConfig>(
+            "weights_and_corr_version = \"2_9\"\ncalculation_currency = \"USD\"\nexchange_rate = 1.0"
+        )
+        .unwrap()
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_rates_accept_decimal_commas() {
+        let cfg: EngineConfig = toml::from_str(
+            "weights_and_corr_version = \"2_6\"\n\
+             calculation_currency = \"EUR\"\n\
+             exchange_rate = \"1,2345\"\n\
+             [fx_rates]\n\
+             GBP = \"1,27\"\n\
+             JPY = 6.7e-3\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.exchange_rate, 1.2345);
+        assert_eq!(cfg.fx_rates["GBP"], 1.27);
+        assert_eq!(cfg.fx_rates["JPY"], 0.0067);
+        assert!(cfg.validate().is_ok());
+
+        let err = toml::from_str::<EngineConfig>(
+            "weights_and_corr_version = \"2_6\"\ncalculation_currency = \"EUR\"\nexchange_rate = \"1,2,3\"",
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("Ambiguous rate '1,2,3'"), "{}", err);
+
+        let cfg = EngineConfig { exchange_rate: f64::INFINITY, ..Default::default() };
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_sign_overrides_must_be_unit() {
+        let cfg: EngineConfig = toml::from_str(
+            "weights_and_corr_version = \"2_6\"\n\
+             calculation_currency = \"USD\"\n\
+             exchange_rate = 1.0\n\
+             [sign_overrides]\n\
+             Risk_FX = -1\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.sign_overrides["Risk_FX"], -1.0);
+        assert!(cfg.validate().is_ok());
+
+        let cfg = EngineConfig { sign_overrides: HashMap::from([("Risk_FX".to_string(), -2.0)]), ..cfg };
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_risk_class_multipliers_must_be_positive() {
+        let cfg: EngineConfig = toml::from_str(
+            "weights_and_corr_version = \"2_6\"\n\
+             calculation_currency = \"USD\"\n\
+             exchange_rate = 1.0\n\
+             [risk_class_multipliers]\n\
+             CreditQ = 1.2\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.risk_class_multipliers[&RiskClass::CreditQ], 1.2);
+        assert!(cfg.validate().is_ok());
+
+        let cfg = EngineConfig { risk_class_multipliers: HashMap::from([(RiskClass::Equity, 0.0)]), ..cfg };
+        assert!(cfg.validate().is_err());
+    }
+}
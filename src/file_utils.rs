@@ -1,18 +1,25 @@
 use anyhow::{Context, Result};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
 use serde_json;
 
-use crate::agg_margins::SIMM;
-use crate::engine_config::EngineConfig;
-use crate::simm_utils::{Crif, product_list};
+use crate::agg_margins::{PSI_RISK_CLASSES, SIMM, combine_product_classes, cross_class_psi};
+use crate::constants::RiskType;
+use crate::engine_config::{EngineConfig, PsiMatrix};
+use crate::simm_result::BreakdownColumn;
+use crate::simm_utils::{Crif, apply_column_aliases, get_column_index, pad_rows, product_list};
 use crate::wnc::WeightsAndCorr;
 
 /// Read CSV file into list of lists with all values as strings
+///
+/// Ragged rows are accepted: short rows are padded to the header width and
+/// fields beyond it are kept, for the engine to drop with a warning.
 pub fn read_csv_to_list<P: AsRef<Path>>(filepath: P) -> Result<Crif> {
-    let mut reader = csv::Reader::from_path(filepath)
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_path(filepath)
         .context("Failed to open CSV file")?;
 
     let mut data = Vec::new();
@@ -28,9 +35,56 @@ pub fn read_csv_to_list<P: AsRef<Path>>(filepath: P) -> Result<Crif> {
         data.push(record.iter().map(|s| s.to_string()).collect());
     }
 
+    pad_rows(&mut data);
     Ok(data)
 }
 
+/// CRIF column layouts, from the columns the engine reads to a full
+/// trade-level export
+///
+/// Each schema holds the columns of the previous one plus its own, so a
+/// CRIF can be checked against the layout its producer is expected to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CrifSchema {
+    /// Sensitivity columns read by the calculation
+    Minimal,
+    /// Adds the amount in its original currency
+    Standard,
+    /// Adds the trade, model and regulation columns of a trade-level export
+    TradeLevel,
+}
+
+impl CrifSchema {
+    /// Every schema, from the smallest to the richest
+    pub const ALL: [CrifSchema; 3] = [CrifSchema::Minimal, CrifSchema::Standard, CrifSchema::TradeLevel];
+
+    /// Columns the schema requires, in CRIF order
+    pub fn columns(self) -> Vec<&'static str> {
+        let mut columns = vec!["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2"];
+        if self >= CrifSchema::Standard {
+            columns.extend(["Amount", "AmountCurrency"]);
+        }
+        columns.push("AmountUSD");
+        if self >= CrifSchema::TradeLevel {
+            columns.extend(["TradeID", "IMModel", "CollectRegulations", "PostRegulations"]);
+        }
+        columns
+    }
+}
+
+/// Richest schema whose columns are all in the CRIF header
+///
+/// A CRIF missing some of even the minimal columns is reported as
+/// `Minimal`; `validate_crif_schema` lists what is missing.
+pub fn detect_crif_schema(crif: &Crif) -> CrifSchema {
+    let header = crif.first().map(Vec::as_slice).unwrap_or_default();
+    CrifSchema::ALL
+        .into_iter()
+        .rev()
+        .find(|schema| schema.columns().iter().all(|c| header.iter().any(|h| h == c)))
+        .unwrap_or(CrifSchema::Minimal)
+}
+
 /// Read JSON file into list of lists (CRIF format)
 pub fn read_json_to_list<P: AsRef<Path>>(filepath: P) -> Result<Crif> {
     let file = File::open(filepath)
@@ -72,8 +126,13 @@ pub fn read_json_to_list<P: AsRef<Path>>(filepath: P) -> Result<Crif> {
 }
 
 /// Parse CSV content from a string into a Crif list
+///
+/// Ragged rows are accepted: short rows are padded to the header width and
+/// fields beyond it are kept, for the engine to drop with a warning.
 pub fn parse_csv_from_string(csv_content: &str) -> anyhow::Result<Vec<Vec<String>>> {
-    let mut reader = csv::Reader::from_reader(csv_content.as_bytes());
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(csv_content.as_bytes());
     let mut data = Vec::new();
 
     // Get headers
@@ -86,6 +145,7 @@ pub fn parse_csv_from_string(csv_content: &str) -> anyhow::Result<Vec<Vec<String
         data.push(record.iter().map(|s| s.to_string()).collect());
     }
 
+    pad_rows(&mut data);
     Ok(data)
 }
 
@@ -114,6 +174,83 @@ pub fn list_to_dict_list(data_list: &Crif) -> Vec<HashMap<String, String>> {
     result
 }
 
+/// Partition a CRIF by the values of a column (e.g. `NettingSet` or `Counterparty`)
+///
+/// Each sub-CRIF keeps the full header. Rows too short to hold the column are
+/// grouped under the empty string.
+pub fn split_crif_by(crif: &Crif, column: &str) -> Result<BTreeMap<String, Crif>> {
+    let header = crif.first().context("CRIF must have at least a header row")?;
+    let idx = get_column_index(crif, column)
+        .with_context(|| format!("{} column not found", column))?;
+
+    let mut parts: BTreeMap<String, Crif> = BTreeMap::new();
+    for row in crif.iter().skip(1) {
+        let key = row.get(idx).cloned().unwrap_or_default();
+        parts
+            .entry(key)
+            .or_insert_with(|| vec![header.clone()])
+            .push(row.clone());
+    }
+
+    Ok(parts)
+}
+
+/// Calculate SIMM independently for each value of a netting-set column
+///
+/// SIMM is not additive across netting sets, so each set is margined on its own.
+pub fn calculate_simm_by_netting_set(
+    crif: &Crif,
+    column: &str,
+    cfg: &EngineConfig,
+) -> Result<BTreeMap<String, f64>> {
+    let wnc = crate::load_wnc(cfg)?;
+
+    split_crif_by(crif, column)?
+        .into_iter()
+        .map(|(key, sub_crif)| {
+            let portfolio = SIMM::from_crif(sub_crif, cfg, wnc)
+                .with_context(|| format!("SIMM failed for {} '{}'", column, key))?;
+            Ok((key, portfolio.simm))
+        })
+        .collect()
+}
+
+/// Calculate SIMM for every `*_crif.csv` file in a directory, reporting progress
+///
+/// Files are processed in name order. Before each one, `on_progress` gets
+/// its 0-based index, the number of files and the file name, so a CLI or
+/// service can report on long batch runs.
+///
+/// # Returns
+/// SIMM of each file, keyed by file name in processing order
+pub fn process_crif_dir_with_progress<P: AsRef<Path>>(
+    dir: P,
+    cfg: &EngineConfig,
+    mut on_progress: impl FnMut(usize, usize, &str),
+) -> Result<Vec<(String, f64)>> {
+    let dir = dir.as_ref();
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with("_crif.csv"))
+        .collect();
+    names.sort();
+
+    let wnc = crate::load_wnc(cfg)?;
+    let total = names.len();
+    names
+        .into_iter()
+        .enumerate()
+        .map(|(index, name)| {
+            on_progress(index, total, &name);
+            let crif = read_csv_to_list(dir.join(&name))?;
+            let portfolio = SIMM::from_crif(crif, cfg, wnc).with_context(|| format!("SIMM failed for {}", name))?;
+            Ok((name, portfolio.simm))
+        })
+        .collect()
+}
+
 /// Calculate total SIMM for a specific risk measure using product class aggregation
 pub fn calculate_simm_by_measure(
     breakdown_list: &Crif,
@@ -121,91 +258,126 @@ pub fn calculate_simm_by_measure(
     measure_name: &str,
     wnc: &dyn WeightsAndCorr,
 ) -> f64 {
+    calculate_simm_by_measure_with_psi(breakdown_list, portfolio_crif, measure_name, wnc, None)
+}
+
+/// Calculate total SIMM for a specific risk measure, aggregating risk
+/// classes with `psi_override` in place of the calibration's psi when given
+pub fn calculate_simm_by_measure_with_psi(
+    breakdown_list: &Crif,
+    portfolio_crif: &Crif,
+    measure_name: &str,
+    wnc: &dyn WeightsAndCorr,
+    psi_override: Option<&PsiMatrix>,
+) -> f64 {
+    measure_name
+        .parse::<RiskType>()
+        .ok()
+        .and_then(|measure| measure_totals(breakdown_list, portfolio_crif, &[measure], wnc, psi_override).remove(&measure))
+        .unwrap_or(0.0)
+}
+
+/// Calculate the total SIMM of every risk measure in one pass over the
+/// breakdown
+///
+/// Gives the same values as `calculate_simm_by_measure` for each measure,
+/// grouping the breakdown by product class, risk class and measure once.
+pub fn calculate_simm_by_measures(
+    breakdown_list: &Crif,
+    portfolio_crif: &Crif,
+    wnc: &dyn WeightsAndCorr,
+) -> BTreeMap<RiskType, f64> {
+    calculate_simm_by_measures_with_psi(breakdown_list, portfolio_crif, wnc, None)
+}
+
+/// `calculate_simm_by_measures` with `psi_override` in place of the
+/// calibration's psi when given
+pub fn calculate_simm_by_measures_with_psi(
+    breakdown_list: &Crif,
+    portfolio_crif: &Crif,
+    wnc: &dyn WeightsAndCorr,
+    psi_override: Option<&PsiMatrix>,
+) -> BTreeMap<RiskType, f64> {
+    measure_totals(breakdown_list, portfolio_crif, &RiskType::ALL, wnc, psi_override)
+}
+
+/// Total SIMM of each of `measures`, reading only their breakdown rows
+fn measure_totals(
+    breakdown_list: &Crif,
+    portfolio_crif: &Crif,
+    measures: &[RiskType],
+    wnc: &dyn WeightsAndCorr,
+    psi_override: Option<&PsiMatrix>,
+) -> BTreeMap<RiskType, f64> {
     let product_classes = product_list(portfolio_crif);
 
     // If no product classes (AddOn-only case), return 0 for all risk measures
     if product_classes.is_empty() {
-        return 0.0;
+        return measures.iter().map(|&measure| (measure, 0.0)).collect();
     }
 
-    // Convert breakdown to list of dicts for easier access
-    let breakdown_dicts = list_to_dict_list(breakdown_list);
-
-    let mut product_simm_values = Vec::new();
-
-    for product_class in &product_classes {
-        // Filter to this product class
-        let df_product: Vec<_> = breakdown_dicts
-            .iter()
-            .filter(|row| row.get("Product Class").map(|s| s.as_str()) == Some(product_class))
-            .collect();
-
-        // Sum the measure value for each of the 6 risk classes
-        let risk_class_list = ["Rates", "FX", "CreditQ", "CreditNonQ", "Equity", "Commodity"];
-        let mut dict_risk_class_measure = HashMap::new();
+    // Sum SIMM_RiskMeasure per (product class, risk class, measure)
+    let header = breakdown_list.first().map(Vec::as_slice).unwrap_or_default();
+    let column = |c: BreakdownColumn| c.index(header);
+    let mut sums: HashMap<(&str, &str, RiskType), f64> = HashMap::new();
+    if let (Some(product_idx), Some(risk_class_idx), Some(measure_idx), Some(value_idx)) = (
+        column(BreakdownColumn::ProductClass),
+        column(BreakdownColumn::RiskClass),
+        column(BreakdownColumn::RiskMeasure),
+        column(BreakdownColumn::SimmRiskMeasure),
+    ) {
+        for row in breakdown_list.iter().skip(1) {
+            let cell = |i: usize| row.get(i).map(String::as_str).unwrap_or("");
+            let Ok(measure) = cell(measure_idx).parse::<RiskType>() else {
+                continue;
+            };
+            if !measures.contains(&measure) {
+                continue;
+            }
+            let Ok(value) = cell(value_idx).parse::<f64>() else {
+                continue;
+            };
+            *sums.entry((cell(product_idx), cell(risk_class_idx), measure)).or_insert(0.0) += value;
+        }
+    }
 
-        for &risk_class in &risk_class_list {
-            // Filter to this risk class and measure
-            let matching_rows: Vec<_> = df_product
+    measures
+        .iter()
+        .map(|&measure| {
+            let product_simm_values: Vec<f64> = product_classes
                 .iter()
-                .filter(|row| {
-                    row.get("Risk Class").map(|s| s.as_str()) == Some(risk_class)
-                        && row.get("Risk Measure").map(|s| s.as_str()) == Some(measure_name)
-                })
-                .collect();
-
-            let total = if !matching_rows.is_empty() {
-                let mut sum = 0.0;
-                for row in matching_rows {
-                    if let Some(val) = row.get("SIMM_RiskMeasure") {
-                        if !val.is_empty() && val != "-" {
-                            if let Ok(num) = val.parse::<f64>() {
-                                sum += num;
-                            }
+                .map(|product_class| {
+                    // Aggregate across risk classes using psi correlations (same as simm_product)
+                    let value = |i: usize| {
+                        sums.get(&(product_class.as_str(), PSI_RISK_CLASSES[i], measure)).copied().unwrap_or(0.0)
+                    };
+                    let mut simm_product = 0.0;
+                    for i in 0..6 {
+                        for j in 0..6 {
+                            simm_product += cross_class_psi(wnc, psi_override, i, j) * value(i) * value(j);
                         }
                     }
-                }
-                sum
-            } else {
-                0.0
-            };
-
-            dict_risk_class_measure.insert(risk_class, total);
-        }
+                    simm_product.sqrt()
+                })
+                .collect();
 
-        // Aggregate across risk classes using psi correlations (same as simm_product)
-        let mut simm_product = 0.0;
-        for i in 0..6 {
-            for j in 0..6 {
-                let psi = if i == j {
-                    1.0
-                } else {
-                    wnc.psi(risk_class_list[i], risk_class_list[j])
-                        .unwrap_or(0.0)
-                };
-
-                simm_product += psi
-                    * dict_risk_class_measure
-                        .get(risk_class_list[i])
-                        .copied()
-                        .unwrap_or(0.0)
-                    * dict_risk_class_measure
-                        .get(risk_class_list[j])
-                        .copied()
-                        .unwrap_or(0.0);
-            }
-        }
+            // No correlation between product classes
+            (measure, combine_product_classes(&product_simm_values))
+        })
+        .collect()
+}
 
-        product_simm_values.push(simm_product.sqrt());
-    }
+/// Values whose magnitude is below this round to zero in the summary output
+pub(crate) const DISPLAY_ZERO_TOLERANCE: f64 = 0.5;
 
-    // Sum across all product classes (no correlation between products)
-    product_simm_values.iter().sum()
+/// Whether a value rounds to zero when printed as an integer
+pub(crate) fn is_display_zero(val: f64) -> bool {
+    val.abs() < DISPLAY_ZERO_TOLERANCE
 }
 
-/// Format value - use hyphen for zero values
+/// Format value - use hyphen for values that round to zero
 fn _format_value(val: f64) -> String {
-    if val == 0.0 {
+    if is_display_zero(val) {
         "-".to_string()
     } else {
         // Round using ROUND_HALF_UP behavior
@@ -221,27 +393,28 @@ pub fn process_crif_file<P: AsRef<Path>>(
     cfg: &EngineConfig,
 ) -> Result<HashMap<String, String>> {
     // Read CRIF as list of lists
-    let crif = read_csv_to_list(&crif_path)?;
+    let mut crif = read_csv_to_list(&crif_path)?;
+    apply_column_aliases(&mut crif, &cfg.column_aliases);
 
     // Calculate SIMM using the correct version from config
-    let wnc = crate::load_wnc(cfg);
-    let portfolio = SIMM::from_crif(crif.clone(), cfg, wnc.as_ref())?;
+    let wnc = crate::load_wnc(cfg)?;
+    let portfolio = SIMM::from_crif(crif.clone(), cfg, wnc)?;
 
     // Get breakdown (list of lists)
     let breakdown_list = &portfolio.simm_break_down;
 
-    // Calculate totals for each measure
-    let delta_total = calculate_simm_by_measure(breakdown_list, &crif, "Delta", wnc.as_ref());
-    let vega_total = calculate_simm_by_measure(breakdown_list, &crif, "Vega", wnc.as_ref());
-    let curvature_total = calculate_simm_by_measure(breakdown_list, &crif, "Curvature", wnc.as_ref());
-    let basecorr_total = calculate_simm_by_measure(breakdown_list, &crif, "BaseCorr", wnc.as_ref());
+    // Totals for each measure
+    let delta_total = portfolio.delta_total;
+    let vega_total = portfolio.vega_total;
+    let curvature_total = portfolio.curvature_total;
+    let basecorr_total = portfolio.base_corr_total;
 
     // Get AddOn if it exists
     let mut addon_total = 0.0;
     if breakdown_list.len() > 1 {
         // Has data rows
         let header = &breakdown_list[0];
-        if let Some(addon_idx) = header.iter().position(|s| s == "Add-On") {
+        if let Some(addon_idx) = BreakdownColumn::AddOn.index(header) {
             for row in breakdown_list.iter().skip(1) {
                 if addon_idx < row.len() && !row[addon_idx].is_empty() {
                     if let Ok(val) = row[addon_idx].parse::<f64>() {
@@ -290,6 +463,20 @@ pub fn process_crif_file<P: AsRef<Path>>(
     Ok(summary_data)
 }
 
+/// Write a complete `SIMM::simm_break_down` table to a CSV file
+///
+/// Every column is written under the table's own header, so the add-on and
+/// no-add-on layouts both come out as they are; `read_csv_to_list` reads
+/// the file back into the same table.
+pub fn write_breakdown_csv<P: AsRef<Path>>(breakdown: &Crif, path: P) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path.as_ref()).context("Failed to create breakdown file")?;
+    for row in breakdown {
+        writer.write_record(row).context("Failed to write breakdown row")?;
+    }
+    writer.flush().context("Failed to write breakdown file")?;
+    Ok(())
+}
+
 /// Compare calculated output with expected output
 /// Allows for rounding tolerance of 1 for large numbers (> 1 billion)
 pub fn compare_csv_files<P: AsRef<Path>>(calc_path: P, expected_path: P) -> Result<(bool, Vec<String>)> {
@@ -399,6 +586,268 @@ mod tests {
         assert_eq!(dicts.len(), 1);
         assert_eq!(dicts[0].get("ProductClass").unwrap(), "Rates");
     }
+
+    #[test]
+    fn test_parse_ragged_rows() {
+        let crif = parse_csv_from_string(
+            "ProductClass,RiskType,Qualifier,AmountUSD\n\
+             RatesFX,Risk_IRCurve\n\
+             Equity,Risk_Equity,\"ISSUER, INC\",100,extra\n",
+        )
+        .unwrap();
+
+        assert_eq!(crif[1], vec!["RatesFX", "Risk_IRCurve", "", ""]);
+        assert_eq!(crif[2][2], "ISSUER, INC");
+        assert_eq!(crif[2][3], "100");
+        // Kept for the engine, which drops it with a warning
+        assert_eq!(crif[2][4], "extra");
+    }
+
+    #[test]
+    fn test_from_csv_accepts_ragged_rows() {
+        let path = std::env::temp_dir().join("simm_rs_ragged_crif.csv");
+        std::fs::write(
+            &path,
+            "ProductClass,RiskType,Qualifier,Bucket,Label1,Label2,AmountUSD\n\
+             Equity,Risk_Equity,ABC,1,,,1000\n\
+             Equity,Risk_Equity,XYZ,1,,,2000,stray\n\
+             Equity,Risk_Equity,DEF,1\n",
+        )
+        .unwrap();
+
+        let from_csv = SIMM::from_csv(&path, &EngineConfig::default(), &crate::v2_5::V2_5).unwrap();
+        let from_list =
+            SIMM::from_crif(read_csv_to_list(&path).unwrap(), &EngineConfig::default(), &crate::v2_5::V2_5).unwrap();
+        assert_eq!(from_csv.simm, from_list.simm);
+        assert!(from_csv.simm > 0.0);
+        assert_eq!(from_csv.validation_warnings(), ["Row 2: 1 field(s) beyond the header dropped"]);
+
+        let strict = EngineConfig { strict: true, ..Default::default() };
+        assert!(SIMM::from_csv(&path, &strict, &crate::v2_5::V2_5).is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_parse_csv_aligns_columns(
+            rows in proptest::collection::vec(
+                proptest::collection::vec("[a-zA-Z0-9 ,\"\n.-]{0,8}", 1..10),
+                0..20,
+            )
+        ) {
+            let header = ["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2", "AmountUSD"];
+
+            let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(Vec::new());
+            writer.write_record(header).unwrap();
+            for row in &rows {
+                writer.write_record(row).unwrap();
+            }
+            let content = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+            let crif = parse_csv_from_string(&content).unwrap();
+            proptest::prop_assert_eq!(crif[0].len(), header.len());
+            for (parsed, original) in crif.iter().skip(1).zip(&rows) {
+                proptest::prop_assert_eq!(parsed.len(), header.len().max(original.len()));
+            }
+
+            proptest::prop_assert_eq!(crif.len() - 1, rows.len());
+            for (parsed, original) in crif.iter().skip(1).zip(&rows) {
+                for (i, value) in parsed.iter().enumerate() {
+                    let want = original.get(i).map(String::as_str).unwrap_or("");
+                    proptest::prop_assert_eq!(value.as_str(), want);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_crif_by_counterparty() {
+        let crif = parse_csv_from_string(
+            "Counterparty,ProductClass,RiskType,Qualifier,Bucket,Label1,Label2,AmountUSD\n\
+             CP_A,RatesFX,Risk_IRCurve,USD,1,5y,OIS,1000000\n\
+             CP_B,RatesFX,Risk_IRCurve,USD,1,5y,OIS,-1000000\n\
+             CP_A,Equity,Risk_Equity,ISSUER,1,,,50000\n",
+        )
+        .unwrap();
+
+        let parts = split_crif_by(&crif, "Counterparty").unwrap();
+        assert_eq!(parts.keys().collect::<Vec<_>>(), vec!["CP_A", "CP_B"]);
+        assert_eq!(parts["CP_A"].len(), 3);
+        assert_eq!(parts["CP_B"].len(), 2);
+        assert_eq!(parts["CP_B"][0], crif[0]);
+
+        // The opposing IR sensitivities do not offset across counterparties
+        let by_set = calculate_simm_by_netting_set(&crif, "Counterparty", &EngineConfig::default()).unwrap();
+        assert!(by_set["CP_A"] > 0.0);
+        assert!(by_set["CP_B"] > 0.0);
+
+        assert!(split_crif_by(&crif, "NettingSet").is_err());
+
+        // An unknown calibration is an error, not a panic
+        let bad_version = EngineConfig { weights_and_corr_version: "9_9".to_string(), ..Default::default() };
+        assert!(calculate_simm_by_netting_set(&crif, "Counterparty", &bad_version).is_err());
+    }
+
+    #[test]
+    fn test_near_zero_vega_formats_as_hyphen() {
+        assert_eq!(_format_value(1e-10), "-");
+        assert_eq!(_format_value(-0.4), "-");
+        assert_eq!(_format_value(0.5), "1");
+
+        let dir = std::env::temp_dir();
+        let crif_path = dir.join("simm_rs_near_zero_vega_crif.csv");
+        let output_path = dir.join("simm_rs_near_zero_vega_output.csv");
+        std::fs::write(
+            &crif_path,
+            "ProductClass,RiskType,Qualifier,Bucket,Label1,Label2,AmountUSD\n\
+             Rates,Risk_IRCurve,USD,1,5y,OIS,1000000\n\
+             Rates,Risk_IRVol,USD,,5y,,1e-10\n",
+        )
+        .unwrap();
+
+        let summary = process_crif_file(&crif_path, &output_path, &EngineConfig::default()).unwrap();
+        assert_eq!(summary["SIMM Vega"], "-");
+        assert_ne!(summary["SIMM Delta"], "-");
+
+        let _ = std::fs::remove_file(&crif_path);
+        let _ = std::fs::remove_file(&output_path);
+    }
+
+    #[test]
+    fn test_process_crif_dir_reports_progress_in_order() {
+        let dir = std::env::temp_dir().join("simm_rs_crif_dir");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, amount) in [("C2_crif.csv", 2000000), ("C1_crif.csv", 1000000), ("C3_crif.csv", 3000000)] {
+            std::fs::write(
+                dir.join(name),
+                format!("ProductClass,RiskType,Qualifier,Bucket,Label1,Label2,AmountUSD\nRatesFX,Risk_FX,EUR,,,,{}\n", amount),
+            )
+            .unwrap();
+        }
+        std::fs::write(dir.join("C1_expected_output.csv"), "SIMM Benchmark\n1\n").unwrap();
+
+        let mut calls = Vec::new();
+        let results = process_crif_dir_with_progress(&dir, &EngineConfig::default(), |index, total, name| {
+            calls.push((index, total, name.to_string()))
+        })
+        .unwrap();
+
+        let names = ["C1_crif.csv", "C2_crif.csv", "C3_crif.csv"];
+        assert_eq!(calls, names.iter().enumerate().map(|(i, n)| (i, 3, n.to_string())).collect::<Vec<_>>());
+        assert_eq!(results.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>(), names);
+        assert!(results[0].1 < results[1].1 && results[1].1 < results[2].1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_excel_bom_and_crlf() {
+        let content = "\u{feff}ProductClass,RiskType,Qualifier,Bucket,Label1,Label2,AmountUSD\r\n\
+                       RatesFX,Risk_FX,EUR,,,,1000000\r\n\
+                       Equity,Risk_Equity,ABC,1,,,500000\r\n";
+        let path = std::env::temp_dir().join("simm_rs_excel_crif.csv");
+        std::fs::write(&path, content).unwrap();
+
+        let crif = read_csv_to_list(&path).unwrap();
+        assert_eq!(crif[0][0], "ProductClass");
+        assert_eq!(crif[1][6], "1000000");
+        assert_eq!(product_list(&crif), vec!["RatesFX", "Equity"]);
+        assert_eq!(parse_csv_from_string(content).unwrap(), crif);
+
+        // A CRIF split by hand keeps the BOM until the engine normalizes it
+        let mut split: Crif = content.lines().map(|line| line.split(',').map(String::from).collect()).collect();
+        crate::simm_utils::normalize_rows(&mut split);
+        assert_eq!(split, crif);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_detect_crif_schema() {
+        let header = |columns: &[&str]| -> Crif { vec![columns.iter().map(|c| c.to_string()).collect()] };
+
+        assert_eq!(detect_crif_schema(&header(&CrifSchema::Minimal.columns())), CrifSchema::Minimal);
+        assert_eq!(detect_crif_schema(&header(&["ProductClass", "RiskType"])), CrifSchema::Minimal);
+
+        // Column order does not matter, and extra columns are allowed
+        let mut columns = CrifSchema::TradeLevel.columns();
+        columns.reverse();
+        columns.push("EndDate");
+        assert_eq!(detect_crif_schema(&header(&columns)), CrifSchema::TradeLevel);
+
+        columns.retain(|c| *c != "IMModel");
+        assert_eq!(detect_crif_schema(&header(&columns)), CrifSchema::Standard);
+    }
+
+    #[test]
+    fn test_measure_totals_in_one_pass() {
+        let crif = parse_csv_from_string(
+            "ProductClass,RiskType,Qualifier,Bucket,Label1,Label2,AmountUSD\n\
+             RatesFX,Risk_IRCurve,USD,1,5y,OIS,1000000\n\
+             RatesFX,Risk_IRVol,USD,,5y,,2000000\n\
+             Credit,Risk_BaseCorr,CDX,,,,30000\n\
+             Equity,Risk_EquityVol,ABC,1,1y,,5000000\n\
+             ,Param_AddOnFixedAmount,,,,,1000\n",
+        )
+        .unwrap();
+        let wnc = crate::V2_5;
+        let simm = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+
+        let totals = calculate_simm_by_measures(&simm.simm_break_down, &crif, &wnc);
+        assert_eq!(totals.keys().copied().collect::<Vec<_>>(), RiskType::ALL);
+        assert!(totals[&RiskType::BaseCorr] > 0.0);
+        assert_eq!(calculate_simm_by_measure(&simm.simm_break_down, &crif, "Theta", &wnc), 0.0);
+
+        // Both paths match the engine's own unrounded measure totals on
+        // every reference CRIF, up to the 2-decimal rounding of the breakdown
+        let mut names: Vec<_> = std::fs::read_dir("tests_2_5")
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter(|name| name.ends_with("_crif.csv"))
+            .collect();
+        names.sort();
+        assert!(!names.is_empty());
+        for name in names {
+            let crif = read_csv_to_list(format!("tests_2_5/{}", name)).unwrap();
+            let simm = SIMM::from_crif(crif.clone(), &EngineConfig::default(), &wnc).unwrap();
+            let breakdown = &simm.simm_break_down;
+            let tolerance = 0.005 * breakdown.len() as f64 + 1e-9 * simm.simm;
+
+            let totals = calculate_simm_by_measures(breakdown, &crif, &wnc);
+            for (measure, expected) in [
+                (RiskType::Delta, simm.delta_total),
+                (RiskType::Vega, simm.vega_total),
+                (RiskType::Curvature, simm.curvature_total),
+                (RiskType::BaseCorr, simm.base_corr_total),
+            ] {
+                assert!((totals[&measure] - expected).abs() <= tolerance, "{} {:?}", name, measure);
+                let single = calculate_simm_by_measure(breakdown, &crif, measure.as_str(), &wnc);
+                assert!((single - expected).abs() <= tolerance, "{} {:?}", name, measure);
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_breakdown_csv_round_trips() {
+        let crif_csv = "ProductClass,RiskType,Qualifier,Bucket,Label1,Label2,AmountUSD\n\
+                        RatesFX,Risk_IRCurve,USD,1,5y,OIS,1000000\n\
+                        Equity,Risk_Equity,ABC,1,,,500000\n";
+        let addon_csv = format!("{},Param_AddOnFixedAmount,,,,,1000\n", crif_csv);
+        let path = std::env::temp_dir().join("simm_rs_breakdown.csv");
+
+        for (csv, has_addon) in [(crif_csv.to_string(), false), (addon_csv, true)] {
+            let crif = parse_csv_from_string(&csv).unwrap();
+            let simm = SIMM::from_crif(crif, &EngineConfig::default(), &crate::V2_5).unwrap();
+            assert_eq!(BreakdownColumn::AddOn.index(&simm.simm_break_down[0]).is_some(), has_addon);
+
+            write_breakdown_csv(&simm.simm_break_down, &path).unwrap();
+            assert_eq!(read_csv_to_list(&path).unwrap(), simm.simm_break_down);
+        }
+
+        let _ = std::fs::remove_file(&path);
+    }
 }
     #[test]
     fn test_product_list_c99()  {
@@ -420,6 +869,7 @@ mod tests {
             weights_and_corr_version: "2_5".to_string(),
             calculation_currency: "USD".to_string(),
             exchange_rate: 1.0,
+            ..Default::default()
         };
 
         let crif = read_csv_to_list("tests_2_5/C99_crif.csv").unwrap();
@@ -474,6 +924,7 @@ mod tests {
             weights_and_corr_version: "2_5".to_string(),
             calculation_currency: "USD".to_string(),
             exchange_rate: 1.0,
+            ..Default::default()
         };
 
         let crif_path = "tests_2_5/C298_crif.csv";
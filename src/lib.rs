@@ -90,6 +90,7 @@
 //!     weights_and_corr_version: "2_5".to_string(),
 //!     calculation_currency: "USD".to_string(),
 //!     exchange_rate: 1.0,
+//!     ..Default::default()
 //! };
 //!
 //! // Load CRIF data (Common Risk Interchange Format)
@@ -141,29 +142,73 @@
 mod agg_margins;
 mod agg_sensitivities;
 mod constants;
+#[cfg(feature = "polars")]
+mod dataframe;
 mod engine_config;
 mod margin_risk_class;
 pub mod file_utils;
+mod rates;
+mod simm_result;
 mod simm_utils;
+mod stress;
 mod v2_5;
 mod v2_6;
 mod v2_7;
+mod validation;
 mod wnc;
 
 use serde_json::json;
-pub use agg_margins::SIMM;
-pub use agg_sensitivities::{k_delta, k_vega, k_curvature};
-pub use engine_config::EngineConfig;
-pub use margin_risk_class::MarginByRiskClass;
-pub use simm_utils::Crif;
-pub use wnc::WeightsAndCorr;
+pub use agg_margins::{PortfolioStructure, RiskClassStructure, SIMM};
+pub use agg_sensitivities::{KFactorRequest, k_delta, k_vega, k_curvature};
+pub use constants::{RiskClass, RiskLevel, RiskType, VolatilityLevel};
+pub use engine_config::{AddOnFactorScale, ConcentrationMode, EngineConfig, PsiMatrix, SimmVersion};
+pub use margin_risk_class::{MarginByRiskClass, MarginDict, WsRow, curvature_lambda, filter_rows};
+pub use rates::{RateProvider, StaticRates, parse_rate};
+pub use simm_result::{
+    AddOnBreakdown, BreakdownColumn, DefaultedLookup, ISDA_RESULTS_HEADER, ReconCell, ReconReport, SimmResult,
+    SimmResultRow, reconcile_against,
+};
+pub use simm_utils::{
+    Bucket, Crif, CrifRecord, RiskKey, apply_sign_overrides, capped_bucket_sum, crif_from_records, crif_to_csv,
+    exclude_qualifiers, filter_by_regulation, group_records, map_dates_to_tenors, merge_crifs, parse_amount,
+    records_from_crif, sum_sensitivities,
+};
+pub use stress::{im_sensitivity_to_rw, stressed_im};
+pub use validation::{
+    ValidationReport, validate_crif, validate_crif_schema, validate_fx_amount_currency, validate_ir_delta_scale,
+};
+pub use wnc::{CalibrationDiff, ParamDiff, ScalarParams, ScaledWnc, WeightsAndCorr, diff_calibrations};
 pub use v2_5::V2_5;
 pub use v2_6::V2_6;
 pub use v2_7::V2_7;
 pub use file_utils::parse_csv_from_string;
+#[cfg(feature = "polars")]
+pub use dataframe::crif_from_dataframe;
 
 
 
+/// One line per product class / risk class / risk measure of a breakdown
+/// table, with the measure's margin
+fn breakdown_lines(breakdown: &Crif) -> Vec<String> {
+    let Some((header, rows)) = breakdown.split_first() else {
+        return Vec::new();
+    };
+    let cell = |row: &[String], column: BreakdownColumn| -> String {
+        column.index(header).and_then(|i| row.get(i).cloned()).unwrap_or_default()
+    };
+
+    rows.iter()
+        .filter(|row| !cell(row, BreakdownColumn::RiskMeasure).is_empty())
+        .map(|row| {
+            format!("{:15} | {:12} | {:10} | {:>15}",
+                    cell(row, BreakdownColumn::ProductClass),
+                    cell(row, BreakdownColumn::RiskClass),
+                    cell(row, BreakdownColumn::RiskMeasure),
+                    cell(row, BreakdownColumn::SimmRiskMeasure))
+        })
+        .collect()
+}
+
 pub fn calc_simm(version:&str, currency:&str, exchange_rate:f64, crif_csv:&str) -> anyhow::Result<String> {
     // see C298_crif.csv
     // Create configuration
@@ -171,6 +216,7 @@ pub fn calc_simm(version:&str, currency:&str, exchange_rate:f64, crif_csv:&str)
         weights_and_corr_version: version.to_string(),
         calculation_currency: currency.to_string(),
         exchange_rate,
+        ..Default::default()
     };
 
     // Parse the CRIF data from CSV string
@@ -180,8 +226,8 @@ pub fn calc_simm(version:&str, currency:&str, exchange_rate:f64, crif_csv:&str)
 
 
     // Calculate SIMM
-    let wnc = load_wnc(&cfg);
-    let simm = SIMM::from_crif(crif.clone(), &cfg, wnc.as_ref())
+    let wnc = load_wnc(&cfg)?;
+    let simm = SIMM::from_crif(crif.clone(), &cfg, wnc)
         .expect("Failed to create SIMM calculator");
 
     println!("\n=== SIMM Calculation Results ===\n");
@@ -189,18 +235,17 @@ pub fn calc_simm(version:&str, currency:&str, exchange_rate:f64, crif_csv:&str)
     // Extract risk class breakdown
     let breakdown: &Vec<Vec<String>> = &simm.simm_break_down;
 
-    // Calculate SIMM by measure using the same logic as file_utils::calculate_simm_by_measure
-    let wnc2 = load_wnc(&cfg);
-    let delta_sum = file_utils::calculate_simm_by_measure(&breakdown, &crif, "Delta", wnc2.as_ref());
-    let vega_sum = file_utils::calculate_simm_by_measure(&breakdown, &crif, "Vega", wnc2.as_ref());
-    let curvature_sum = file_utils::calculate_simm_by_measure(&breakdown, &crif, "Curvature", wnc2.as_ref());
-    let base_corr_sum = file_utils::calculate_simm_by_measure(&breakdown, &crif, "BaseCorr", wnc2.as_ref());
+    // Totals by risk measure
+    let delta_sum = simm.delta_total;
+    let vega_sum = simm.vega_total;
+    let curvature_sum = simm.curvature_total;
+    let base_corr_sum = simm.base_corr_total;
 
     // Get add-on value from breakdown
     let mut addon_value = 0.0;
     if breakdown.len() > 1 {
         let header = &breakdown[0];
-        if let Some(addon_idx) = header.iter().position(|h| h == "Add-On") {
+        if let Some(addon_idx) = BreakdownColumn::AddOn.index(header) {
             for row in breakdown.iter().skip(1) {
                 if addon_idx < row.len() && !row[addon_idx].is_empty() {
                     if let Ok(val) = row[addon_idx].parse::<f64>() {
@@ -212,11 +257,11 @@ pub fn calc_simm(version:&str, currency:&str, exchange_rate:f64, crif_csv:&str)
         }
     }
 
-    // Format output values (use "-" for zero values except AddOn)
-    let delta_str = if delta_sum == 0.0 { "-".to_string() } else { format!("{:.0}", delta_sum) };
-    let vega_str = if vega_sum == 0.0 { "-".to_string() } else { format!("{:.0}", vega_sum) };
-    let curvature_str = if curvature_sum == 0.0 { "-".to_string() } else { format!("{:.0}", curvature_sum) };
-    let base_corr_str = if base_corr_sum == 0.0 { "-".to_string() } else { format!("{:.0}", base_corr_sum) };
+    // Format output values (use "-" for values that round to zero, except AddOn)
+    let delta_str = if file_utils::is_display_zero(delta_sum) { "-".to_string() } else { format!("{:.0}", delta_sum) };
+    let vega_str = if file_utils::is_display_zero(vega_sum) { "-".to_string() } else { format!("{:.0}", vega_sum) };
+    let curvature_str = if file_utils::is_display_zero(curvature_sum) { "-".to_string() } else { format!("{:.0}", curvature_sum) };
+    let base_corr_str = if file_utils::is_display_zero(base_corr_sum) { "-".to_string() } else { format!("{:.0}", base_corr_sum) };
     let addon_str = format!("{:.0}", addon_value);
 
     // Create JSON output
@@ -235,26 +280,14 @@ pub fn calc_simm(version:&str, currency:&str, exchange_rate:f64, crif_csv:&str)
     println!("\n=== Detailed Breakdown by Risk Class ===\n");
 
     // Print detailed breakdown
-    for row in breakdown.iter() {
-        if row.len() > 1 && !row[0].is_empty() && row[0] != "ProductClass" {
-            let delta = if row.len() > 2 { &row[2] } else { "-" };
-            let vega = if row.len() > 3 { &row[3] } else { "-" };
-            let curvature = if row.len() > 4 { &row[4] } else { "-" };
-
-            println!("{:15} | {:20} | Delta: {:>15} | Vega: {:>15} | Curvature: {:>15}",
-                     &row[0],
-                     &row[1],
-                     delta,
-                     vega,
-                     curvature
-            );
-        }
+    for line in breakdown_lines(breakdown) {
+        println!("{}", line);
     }
 
     // Create detailed breakdown array
     let mut detailed_breakdown = Vec::new();
-    for row in breakdown.iter() {
-        if row.len() > 0 && !row[0].is_empty() {
+    for row in breakdown.iter().skip(1) {
+        if !row.is_empty() {
             let mut row_obj = serde_json::Map::new();
             for (i, col_name) in breakdown[0].iter().enumerate() {
                 if i < row.len() {
@@ -281,13 +314,8 @@ pub fn calc_simm(version:&str, currency:&str, exchange_rate:f64, crif_csv:&str)
     Ok(serde_json::to_string_pretty(&final_output)?)
 }
 
-fn load_wnc(cfg: &EngineConfig) -> Box<dyn WeightsAndCorr> {
-    match cfg.weights_and_corr_version.as_str() {
-        "2_5" => Box::new(V2_5),
-        "2_6" => Box::new(V2_6),
-        "2_7" => Box::new(V2_7),
-        other => panic!("Unsupported SIMM version: {}", other),
-    }
+fn load_wnc(cfg: &EngineConfig) -> anyhow::Result<&'static dyn WeightsAndCorr> {
+    Ok(cfg.version()?.wnc())
 }
 
 #[cfg(test)]
@@ -302,6 +330,7 @@ mod tests {
             weights_and_corr_version: "2_5".to_string(),
             calculation_currency: "USD".to_string(),
             exchange_rate: 1.0,
+            ..Default::default()
         };
 
         // Get tests directory relative to project root
@@ -424,6 +453,7 @@ mod tests {
             weights_and_corr_version: "2_6".to_string(),
             calculation_currency: "USD".to_string(),
             exchange_rate: 1.0,
+            ..Default::default()
         };
 
         // Get tests directory relative to project root
@@ -527,6 +557,7 @@ mod tests {
             weights_and_corr_version: "2_7".to_string(),
             calculation_currency: "USD".to_string(),
             exchange_rate: 1.0,
+            ..Default::default()
         };
 
         // Get tests directory relative to project root
@@ -623,5 +654,74 @@ mod tests {
         // No expected outputs to compare against for v2.7
         assert!(true, "v2.7 tests completed - no expected outputs to validate");
     }
-}
 
+    #[test]
+    fn test_versions_give_distinct_totals() {
+        // Delta, vega (and so curvature) in every risk class
+        let rows = [
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "2000000"],
+            ["RatesFX", "Risk_IRCurve", "EUR", "1", "10y", "Libor3m", "-1500000"],
+            ["RatesFX", "Risk_Inflation", "USD", "", "", "", "300000"],
+            ["RatesFX", "Risk_XCcyBasis", "EUR", "", "", "", "200000"],
+            ["RatesFX", "Risk_IRVol", "USD", "", "1y", "", "40000000"],
+            ["RatesFX", "Risk_FX", "EUR", "", "", "", "90000000"],
+            ["RatesFX", "Risk_FX", "JPY", "", "", "", "-40000000"],
+            ["RatesFX", "Risk_FXVol", "EURUSD", "", "6m", "", "25000000"],
+            ["Credit", "Risk_CreditQ", "ISSUER1", "2", "5y", "USD", "500000"],
+            ["Credit", "Risk_CreditQ", "ISSUER2", "7", "3y", "USD", "-300000"],
+            ["Credit", "Risk_CreditVol", "ISSUER1", "2", "5y", "", "3000000"],
+            ["Credit", "Risk_BaseCorr", "CDX.IG", "", "", "", "150000"],
+            ["Credit", "Risk_CreditNonQ", "RMBS1", "1", "5y", "CMBX", "200000"],
+            ["Credit", "Risk_CreditVolNonQ", "RMBS1", "1", "5y", "", "1000000"],
+            ["Equity", "Risk_Equity", "ABC", "1", "", "", "5000000"],
+            ["Equity", "Risk_Equity", "XYZ", "5", "", "", "-2000000"],
+            ["Equity", "Risk_EquityVol", "ABC", "1", "1y", "", "8000000"],
+            ["Commodity", "Risk_Commodity", "WTI", "2", "", "", "3000000"],
+            ["Commodity", "Risk_CommodityVol", "WTI", "2", "6m", "", "6000000"],
+        ];
+        let crif = simm_utils::crif(&rows);
+
+        let cfg = EngineConfig::default();
+        let totals: Vec<f64> = [&V2_5 as &dyn WeightsAndCorr, &V2_6, &V2_7]
+            .iter()
+            .map(|wnc| {
+                let simm = SIMM::from_crif(crif.clone(), &cfg, *wnc).unwrap();
+                assert!(simm.unused_rows().is_empty());
+                simm.simm
+            })
+            .collect();
+
+        // The calibrations move the total by a few percent either way
+        for i in 0..totals.len() {
+            for j in i + 1..totals.len() {
+                let rel = (totals[i] - totals[j]).abs() / totals[i];
+                assert!((0.01..0.25).contains(&rel), "{:?}", totals);
+            }
+        }
+    }
+
+    #[test]
+    fn test_breakdown_lines_match_measure_totals() {
+        // An add-on shifts the breakdown columns, which must not move the values read
+        let crif_csv = "ProductClass,RiskType,Qualifier,Bucket,Label1,Label2,AmountUSD\n\
+                        Equity,Risk_Equity,ABC,1,,,5000000\n\
+                        Equity,Risk_EquityVol,ABC,1,1y,,8000000\n\
+                        ,Param_AddOnFixedAmount,,,,,1000\n";
+        let crif = parse_csv_from_string(crif_csv).unwrap();
+        let simm = SIMM::from_crif(crif, &EngineConfig::default(), &V2_5).unwrap();
+
+        let lines = breakdown_lines(&simm.simm_break_down);
+        let value = |measure: &str| -> f64 {
+            let line = lines.iter().find(|l| l.split('|').nth(2).unwrap().trim() == measure).unwrap();
+            line.split('|').nth(3).unwrap().trim().parse().unwrap()
+        };
+        assert!((value("Delta") - simm.delta_total).abs() < 0.01);
+        assert!((value("Vega") - simm.vega_total).abs() < 0.01);
+        assert!(lines.iter().all(|l| l.starts_with("Equity")));
+
+        let json: serde_json::Value = serde_json::from_str(&calc_simm("2_5", "USD", 1.0, crif_csv).unwrap()).unwrap();
+        let detailed = json["detailed_breakdown"].as_array().unwrap();
+        assert_eq!(detailed.len(), simm.simm_break_down.len() - 1);
+        assert!(detailed.iter().all(|row| row["Add-On"] == "1000.00"));
+    }
+}
@@ -1,23 +1,28 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use statrs::distribution::{ContinuousCDF, Normal};
 
 use crate::agg_sensitivities::{k_delta, k_vega, k_curvature};
 use crate::constants::*;
-use crate::simm_utils::{self, Crif};
+use crate::engine_config::ConcentrationMode;
+use crate::simm_utils::{self, Bucket, Crif, CrifRecord};
 use crate::wnc::WeightsAndCorr;
 use crate::v2_5::*;
 
+/// Margins by risk class and risk measure
+pub type MarginDict = HashMap<String, HashMap<RiskType, f64>>;
+
 /// Initialize margin dictionary with zeros
-pub fn init_margin_dict() -> HashMap<String, HashMap<String, f64>> {
+pub fn init_margin_dict() -> MarginDict {
     let mut result = HashMap::new();
 
     for risk_class in &["Rates", "CreditQ", "CreditNonQ", "Equity", "Commodity", "FX"] {
         let mut measures = HashMap::new();
-        measures.insert("Delta".to_string(), 0.0);
-        measures.insert("Vega".to_string(), 0.0);
-        measures.insert("Curvature".to_string(), 0.0);
+        measures.insert(RiskType::Delta, 0.0);
+        measures.insert(RiskType::Vega, 0.0);
+        measures.insert(RiskType::Curvature, 0.0);
         if *risk_class == "CreditQ" {
-            measures.insert("BaseCorr".to_string(), 0.0);
+            measures.insert(RiskType::BaseCorr, 0.0);
         }
         result.insert(risk_class.to_string(), measures);
     }
@@ -100,6 +105,56 @@ pub fn sum_column(crif: &Crif, _column_name: &str) -> f64 {
     simm_utils::sum_sensitivities(crif)
 }
 
+/// Whether a bucket of a vega risk type contributes curvature margin
+///
+/// Equity bucket 12 (volatility indices) has no curvature.
+pub fn has_curvature(risk_class: &str, bucket: Bucket) -> bool {
+    !(risk_class == "Risk_EquityVol" && bucket == Bucket::Numbered(12))
+}
+
+/// Confidence level of the curvature margin's lambda
+const CURVATURE_CONFIDENCE: f64 = 0.995;
+
+/// Curvature theta and lambda for a set of curvature risk exposures
+///
+/// `theta = min(sum CVR / sum |CVR|, 0)` (0 when all CVR are zero) and
+/// `lambda = (Phi^-1(confidence)^2 - 1) * (1 + theta) - theta`.
+///
+/// # Returns
+/// `(theta, lambda)`
+pub fn curvature_lambda(cvr_sum: f64, cvr_abs_sum: f64, confidence: f64) -> (f64, f64) {
+    let theta = if cvr_abs_sum != 0.0 {
+        (cvr_sum / cvr_abs_sum).min(0.0)
+    } else {
+        0.0
+    };
+
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let lambda = (normal.inverse_cdf(confidence).powi(2) - 1.0) * (1.0 + theta) - theta;
+
+    (theta, lambda)
+}
+
+/// Lambdas of the non-residual and residual buckets of a curvature risk
+/// type, each from the theta of its own CVRs
+///
+/// `non_residual` and `residual` are `(sum CVR, sum |CVR|)` of each group. A
+/// group with no buckets in `buckets` gets a lambda of 0, so it adds nothing
+/// to the margin.
+///
+/// # Returns
+/// `(lambda, lambda_res)`
+fn split_curvature_lambdas(buckets: &[Bucket], non_residual: (f64, f64), residual: (f64, f64)) -> (f64, f64) {
+    let lambda_of = |present: bool, (cvr_sum, cvr_abs_sum): (f64, f64)| {
+        if present { curvature_lambda(cvr_sum, cvr_abs_sum, CURVATURE_CONFIDENCE).1 } else { 0.0 }
+    };
+
+    (
+        lambda_of(buckets.iter().any(|b| !b.is_residual()), non_residual),
+        lambda_of(buckets.iter().any(|b| b.is_residual()), residual),
+    )
+}
+
 /// Drop rows that match ALL conditions
 pub fn drop_rows(crif: &Crif, conditions: &HashMap<String, String>) -> Crif {
     if crif.len() <= 1 {
@@ -163,27 +218,202 @@ pub fn to_list(crif: &Crif, column_name: &str) -> Vec<String> {
         .collect()
 }
 
+/// A delta weighted sensitivity at one vertex, as fed to the K aggregation
+///
+/// Amounts are in the units of the CRIF sensitivities, before the exchange
+/// rate to the calculation currency is applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsRow {
+    /// Product class; empty when produced by `MarginByRiskClass` directly
+    pub product_class: String,
+    pub risk_type: String,
+    pub bucket: String,
+    pub qualifier: String,
+    pub tenor: String,
+    pub label2: String,
+    /// Net sensitivity at the vertex
+    pub sensitivity: f64,
+    pub risk_weight: f64,
+    pub concentration: f64,
+    /// `sensitivity * risk_weight * concentration`
+    pub ws: f64,
+}
+
 /// MarginByRiskClass calculator
 pub struct MarginByRiskClass<'a> {
     pub crif: Crif,
     pub calculation_currency: String,
     pub wnc: &'a dyn WeightsAndCorr,
     pub list_risk_types: Vec<String>,
+    used_rows: RefCell<BTreeSet<usize>>,
+    ws_rows: RefCell<Vec<WsRow>>,
+    ir_delta_k: RefCell<Vec<(String, f64)>>,
+    warnings: RefCell<Vec<String>>,
+    bucket_filter: HashMap<RiskClass, HashSet<usize>>,
+    concentration_mode: ConcentrationMode,
+    threshold_rate: f64,
+}
+
+/// IR delta risk weight for a currency and tenor
+///
+/// Currencies outside the regular and low volatility groups fall back to the
+/// high volatility weights: the ISDA methodology places "all other currencies"
+/// in the high volatility group, so this is the intended default.
+fn ir_delta_rw(currency: &str, tenor: &str) -> anyhow::Result<f64> {
+    if REG_VOL_CCY_BUCKET.contains(&currency) {
+        reg_vol_rw_lookup(tenor)
+    } else if LOW_VOL_CCY_BUCKET.contains(&currency) {
+        low_vol_rw_lookup(tenor)
+    } else {
+        high_vol_rw_lookup(tenor)
+    }
+}
+
+/// Whether any row of the given risk type carries a non-zero amount
+fn has_non_zero_amount(crif: &Crif, risk_type: &str) -> bool {
+    let mut cond = HashMap::new();
+    cond.insert("RiskType".to_string(), vec![risk_type.to_string()]);
+    let crif_risk_type = filter_rows(crif, &cond);
+
+    to_list(&crif_risk_type, "AmountUSD")
+        .iter()
+        .any(|v| simm_utils::parse_amount(v).map(|x| x != 0.0).unwrap_or(false))
 }
 
 impl<'a> MarginByRiskClass<'a> {
     pub fn new(crif: Crif, calculation_currency: String, wnc: &'a dyn WeightsAndCorr) -> Self {
-        let list_risk_types = unique_values(&crif, "RiskType");
+        // Risk types whose amounts are all zero contribute nothing, so leave them
+        // out to skip the per-currency/per-bucket loops entirely
+        let list_risk_types = unique_values(&crif, "RiskType")
+            .into_iter()
+            .filter(|rt| has_non_zero_amount(&crif, rt))
+            .collect();
         Self {
             crif,
             calculation_currency,
             wnc,
             list_risk_types,
+            used_rows: RefCell::new(BTreeSet::new()),
+            ws_rows: RefCell::new(Vec::new()),
+            ir_delta_k: RefCell::new(Vec::new()),
+            warnings: RefCell::new(Vec::new()),
+            bucket_filter: HashMap::new(),
+            concentration_mode: ConcentrationMode::Standard,
+            threshold_rate: 1.0,
+        }
+    }
+
+    /// Only margin the listed buckets (0 for residual) of the given risk
+    /// classes; see `EngineConfig::bucket_filter`
+    pub fn with_bucket_filter(mut self, bucket_filter: HashMap<RiskClass, HashSet<usize>>) -> Self {
+        self.bucket_filter = bucket_filter;
+        self
+    }
+
+    /// Apply or disable concentration risk factors; see
+    /// `EngineConfig::concentration_mode`
+    pub fn with_concentration_mode(mut self, concentration_mode: ConcentrationMode) -> Self {
+        self.concentration_mode = concentration_mode;
+        self
+    }
+
+    /// Convert concentration thresholds with `rate` (USD -> sensitivity
+    /// currency) when the CRIF amounts are not in USD
+    pub fn with_threshold_rate(mut self, rate: f64) -> Self {
+        self.threshold_rate = rate;
+        self
+    }
+
+    /// Concentration threshold in the currency of the sensitivities
+    ///
+    /// Calibration thresholds `base_t` are in USD; they are scaled by the
+    /// threshold rate, which is 1 for `AmountUSD` sensitivities.
+    pub fn effective_threshold(&self, base_t: f64) -> f64 {
+        base_t * self.threshold_rate
+    }
+
+    /// Concentration risk factor for summed sensitivities against threshold `t`
+    fn concentration(&self, sum_s: f64, t: f64) -> f64 {
+        match self.concentration_mode {
+            ConcentrationMode::Standard => simm_utils::concentration_threshold(sum_s, self.effective_threshold(t)),
+            ConcentrationMode::Disabled => 1.0,
         }
     }
 
+    /// Delta concentration threshold of a bucket
+    ///
+    /// A calibration without a threshold for the bucket (e.g. a bucket number
+    /// beyond its range) gets no concentration factor and a warning, rather
+    /// than a threshold of 1 that would scale the margin by orders of magnitude.
+    fn delta_threshold(&self, risk_class: &str, bucket_label: &str) -> f64 {
+        self.wnc.t(risk_class, "Delta", None, Some(bucket_label)).unwrap_or_else(|| {
+            self.warnings.borrow_mut().push(format!(
+                "No delta concentration threshold for {} bucket {}; concentration not applied",
+                risk_class, bucket_label
+            ));
+            f64::INFINITY
+        })
+    }
+
+    /// Buckets of `crif` to margin for `risk_type` after the bucket filter
+    fn selected_buckets(&self, risk_type: &str, crif: &Crif) -> Vec<Bucket> {
+        let mut buckets = simm_utils::bucket_list(crif);
+        if let Some(keep) = RiskClass::of_risk_type(risk_type).and_then(|rc| self.bucket_filter.get(&rc)) {
+            buckets.retain(|b| keep.contains(&b.index()));
+        }
+        buckets
+    }
+
+    /// Input row positions consumed by the margin calculations run so far
+    ///
+    /// Only populated when the CRIF carries the engine's row index column,
+    /// as it does when built through `SIMM`.
+    pub fn used_rows(&self) -> BTreeSet<usize> {
+        self.used_rows.borrow().clone()
+    }
+
+    /// Delta weighted sensitivities computed by the delta margins run so far
+    pub fn weighted_sensitivities(&self) -> Vec<WsRow> {
+        self.ws_rows.borrow().clone()
+    }
+
+    /// IR delta K of each currency computed by `ir_delta_margin`, before
+    /// the cross-currency aggregation
+    pub fn ir_delta_k(&self) -> Vec<(String, f64)> {
+        self.ir_delta_k.borrow().clone()
+    }
+
+    /// Problems met by the margin calculations run so far, such as missing
+    /// concentration thresholds
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.borrow().clone()
+    }
+
+    /// Keep a weighted sensitivity for reporting and return its WS
+    fn record_ws(&self, row: WsRow) -> f64 {
+        let ws = row.ws;
+        self.ws_rows.borrow_mut().push(row);
+        ws
+    }
+
+    /// Record the rows of `crif` as consumed and return their summed sensitivities
+    fn consume(&self, crif: &Crif) -> f64 {
+        self.mark_used(crif);
+        simm_utils::sum_sensitivities(crif)
+    }
+
+    /// Record the rows of `crif` as consumed
+    fn mark_used(&self, crif: &Crif) {
+        self.used_rows.borrow_mut().extend(simm_utils::row_indices(crif));
+    }
+
+    /// Build from typed CRIF records instead of a string `Crif`
+    pub fn from_records(records: &[CrifRecord], calculation_currency: String, wnc: &'a dyn WeightsAndCorr) -> Self {
+        Self::new(simm_utils::crif_from_records(records), calculation_currency, wnc)
+    }
+
     /// Calculate all margins
-    pub fn calculate_all(&self) -> HashMap<String, HashMap<String, f64>> {
+    pub fn calculate_all(&self) -> MarginDict {
         let mut results = init_margin_dict();
 
         // Delta margins
@@ -209,7 +439,7 @@ impl<'a> MarginByRiskClass<'a> {
         {
             if let Some(result_measures) = results.get_mut(risk_class) {
                 for (measure, value) in measures {
-                    *result_measures.entry(measure.clone()).or_insert(0.0) += value;
+                    *result_measures.entry(*measure).or_insert(0.0) += value;
                 }
             }
         }
@@ -218,7 +448,7 @@ impl<'a> MarginByRiskClass<'a> {
     }
 
     /// Delta Margin for Rates Risk Classes Only
-    pub fn ir_delta_margin(&self) -> HashMap<String, HashMap<String, f64>> {
+    pub fn ir_delta_margin(&self) -> MarginDict {
         let mut updates = init_margin_dict();
 
         // Check if rates risk types exist
@@ -251,14 +481,16 @@ impl<'a> MarginByRiskClass<'a> {
             curr_cond.insert("Qualifier".to_string(), vec![currency.clone()]);
             let crif_currency = filter_rows(&crif, &curr_cond);
 
-            // Drop XCcyBasis for CR calculation
+            // Cross currency basis is not part of the concentration: ISDA
+            // computes CR from the IR curve and inflation sensitivities only,
+            // and the basis WS is RW * s without a CR
             let mut drop_cond = HashMap::new();
             drop_cond.insert("RiskType".to_string(), "Risk_XCcyBasis".to_string());
             let crif_wo_xccybasis = drop_rows(&crif_currency, &drop_cond);
 
             // Concentration Threshold
             let t = self.wnc.t("Rates", "Delta", Some(currency), None).unwrap_or(1.0);
-            let cr = simm_utils::concentration_threshold(simm_utils::sum_sensitivities(&crif_wo_xccybasis), t);
+            let cr = self.concentration(simm_utils::sum_sensitivities(&crif_wo_xccybasis), t);
             dict_cr.insert(currency.clone(), cr);
 
             // Process each rates risk type
@@ -276,14 +508,42 @@ impl<'a> MarginByRiskClass<'a> {
                 let sensitivities = simm_utils::sum_sensitivities(&crif_risk_class);
 
                 if risk_class == "Risk_Inflation" {
-                    let rw = INFLATION_RW;
-                    let ws = rw * sensitivities * cr;
+                    // ISDA has one inflation risk factor per currency: the
+                    // sensitivities of every tenor and every index (Label2) are
+                    // summed into a single WS, unlike the IR sub-curves
+                    self.mark_used(&crif_risk_class);
+                    let rw = self.wnc.scalar_params().inflation_rw;
+                    let ws = self.record_ws(WsRow {
+                        product_class: String::new(),
+                        risk_type: risk_class.clone(),
+                        bucket: currency.clone(),
+                        qualifier: currency.clone(),
+                        tenor: String::new(),
+                        label2: String::new(),
+                        sensitivity: sensitivities,
+                        risk_weight: rw,
+                        concentration: cr,
+                        ws: rw * sensitivities * cr,
+                    });
                     list_ws.push(ws);
                     tenor_k.push("Inf".to_string());
                     index.push("Inf".to_string());
                 } else if risk_class == "Risk_XCcyBasis" {
-                    let rw = CCY_BASIS_SWAP_SPREAD_RW;
-                    let ws = rw * sensitivities;
+                    // No CR for basis, see the concentration above
+                    self.mark_used(&crif_risk_class);
+                    let rw = self.wnc.scalar_params().ccy_basis_swap_spread_rw;
+                    let ws = self.record_ws(WsRow {
+                        product_class: String::new(),
+                        risk_type: risk_class.clone(),
+                        bucket: currency.clone(),
+                        qualifier: currency.clone(),
+                        tenor: String::new(),
+                        label2: String::new(),
+                        sensitivity: sensitivities,
+                        risk_weight: rw,
+                        concentration: 1.0,
+                        ws: rw * sensitivities,
+                    });
                     list_ws.push(ws);
                     tenor_k.push("XCcy".to_string());
                     index.push("XCcy".to_string());
@@ -300,18 +560,32 @@ impl<'a> MarginByRiskClass<'a> {
                             t_cond.insert("Label1".to_string(), vec![tenor.clone()]);
                             let crif_tenor = filter_rows(&crif_subcurve, &t_cond);
 
-                            let s = simm_utils::sum_sensitivities(&crif_tenor);
-
-                            // Determine RW based on currency volatility
-                            let rw = if REG_VOL_CCY_BUCKET.contains(&currency.as_str()) {
-                                reg_vol_rw_lookup(&tenor)
-                            } else if LOW_VOL_CCY_BUCKET.contains(&currency.as_str()) {
-                                low_vol_rw_lookup(&tenor)
-                            } else {
-                                high_vol_rw_lookup(&tenor)
+                            // Determine RW based on currency volatility; the rows stay
+                            // unused when the tenor has no weight
+                            let rw = match ir_delta_rw(currency, &tenor) {
+                                Ok(rw) => rw,
+                                Err(e) => {
+                                    self.warnings.borrow_mut().push(format!(
+                                        "Risk_IRCurve {} {} {}: {}; sensitivity not margined",
+                                        currency, subcurve, tenor, e
+                                    ));
+                                    continue;
+                                }
                             };
-
-                            let ws = rw * s * cr;
+                            let s = self.consume(&crif_tenor);
+
+                            let ws = self.record_ws(WsRow {
+                                product_class: String::new(),
+                                risk_type: risk_class.clone(),
+                                bucket: currency.clone(),
+                                qualifier: currency.clone(),
+                                tenor: tenor.clone(),
+                                label2: subcurve.clone(),
+                                sensitivity: s,
+                                risk_weight: rw,
+                                concentration: cr,
+                                ws: rw * s * cr,
+                            });
                             list_ws.push(ws);
                             tenor_k.push(tenor.clone());
                             index.push(subcurve.clone());
@@ -334,8 +608,9 @@ impl<'a> MarginByRiskClass<'a> {
                 &self.calculation_currency,
             );
             list_k.push(k);
+            self.ir_delta_k.borrow_mut().push((currency.clone(), k));
 
-            let s_b = list_ws.iter().sum::<f64>().min(k).max(-k);
+            let s_b = simm_utils::capped_bucket_sum(list_ws.iter().sum(), k);
             list_s.push(s_b);
         }
 
@@ -355,7 +630,7 @@ impl<'a> MarginByRiskClass<'a> {
                 let g = cr_b.min(cr_c) / cr_b.max(cr_c);
 
                 let gamma = if currency_list.len() > 1 {
-                    IR_GAMMA_DIFF_CCY
+                    self.wnc.scalar_params().ir_gamma_diff_ccy
                 } else {
                     1.0
                 };
@@ -365,14 +640,14 @@ impl<'a> MarginByRiskClass<'a> {
         }
 
         if let Some(rates) = updates.get_mut("Rates") {
-            *rates.get_mut("Delta").unwrap() += k_squared_sum.sqrt();
+            *rates.get_mut(&RiskType::Delta).unwrap() += k_squared_sum.sqrt();
         }
 
         updates
     }
 
     /// Delta Margin for non-Rates risk classes
-    pub fn delta_margin(&self) -> HashMap<String, HashMap<String, f64>> {
+    pub fn delta_margin(&self) -> MarginDict {
         let mut updates = init_margin_dict();
 
         let allowed = ["Risk_FX", "Risk_CreditQ", "Risk_CreditNonQ", "Risk_Equity", "Risk_Commodity"];
@@ -400,7 +675,16 @@ impl<'a> MarginByRiskClass<'a> {
                 cond.insert("RiskType".to_string(), vec![risk_class.clone()]);
                 let crif_fx = filter_rows(&self.crif, &cond);
 
-                let currency_list = unique_values(&crif_fx, "Qualifier");
+                let (self_fx, currency_list): (Vec<String>, Vec<String>) = unique_values(&crif_fx, "Qualifier")
+                    .into_iter()
+                    .partition(|currency| simm_utils::is_self_fx(currency, &self.calculation_currency));
+
+                // Positions in the calculation currency carry no FX risk
+                for currency in &self_fx {
+                    let mut curr_cond = HashMap::new();
+                    curr_cond.insert("Qualifier".to_string(), vec![currency.clone()]);
+                    self.mark_used(&filter_rows(&crif_fx, &curr_cond));
+                }
 
                 for currency in &currency_list {
                     let mut curr_cond = HashMap::new();
@@ -408,23 +692,30 @@ impl<'a> MarginByRiskClass<'a> {
                     let crif_currency = filter_rows(&crif_fx, &curr_cond);
 
                     let t = self.wnc.t(risk_class, "Delta", Some(currency), None).unwrap_or(1.0);
-                    let sensitivities = simm_utils::sum_sensitivities(&crif_currency);
-                    let cr = simm_utils::concentration_threshold(sensitivities, t);
+                    let sensitivities = self.consume(&crif_currency);
+                    let cr = self.concentration(sensitivities, t);
                     list_cr.push(cr);
 
                     let is_given_high = HIGH_VOL_CURRENCY_GROUP.contains(&currency.as_str());
                     let is_calc_high = HIGH_VOL_CURRENCY_GROUP.contains(&self.calculation_currency.as_str());
 
-                    let rw = if currency == &self.calculation_currency {
-                        0.0
-                    } else {
-                        fx_rw(
-                            if is_calc_high { RiskLevel::High } else { RiskLevel::Regular },
-                            if is_given_high { RiskLevel::High } else { RiskLevel::Regular },
-                        )
-                    };
+                    let rw = fx_rw(
+                        if is_calc_high { RiskLevel::High } else { RiskLevel::Regular },
+                        if is_given_high { RiskLevel::High } else { RiskLevel::Regular },
+                    );
 
-                    list_ws.push(sensitivities * cr * rw);
+                    list_ws.push(self.record_ws(WsRow {
+                        product_class: String::new(),
+                        risk_type: risk_class.clone(),
+                        bucket: String::new(),
+                        qualifier: currency.clone(),
+                        tenor: String::new(),
+                        label2: String::new(),
+                        sensitivity: sensitivities,
+                        risk_weight: rw,
+                        concentration: cr,
+                        ws: sensitivities * cr * rw,
+                    }));
                 }
 
                 let currency_refs: Vec<&str> = currency_list.iter().map(|s| s.as_str()).collect();
@@ -440,27 +731,22 @@ impl<'a> MarginByRiskClass<'a> {
                 );
 
                 if let Some(fx) = updates.get_mut("FX") {
-                    *fx.get_mut("Delta").unwrap() += k;
+                    *fx.get_mut(&RiskType::Delta).unwrap() += k;
                 }
             } else if ["Risk_CreditQ", "Risk_CreditNonQ", "Risk_Equity", "Risk_Commodity"].contains(&risk_class.as_str()) {
                 let mut cond = HashMap::new();
                 cond.insert("RiskType".to_string(), vec![risk_class.clone()]);
                 let crif_others = filter_rows(&self.crif, &cond);
-                let bucket_list = simm_utils::bucket_list(&crif_others);
+                let bucket_list = self.selected_buckets(risk_class, &crif_others);
 
                 for bucket in &bucket_list {
-                    let crif_bucket = if *bucket == 0 {
-                        let mut b_cond = HashMap::new();
-                        b_cond.insert("Bucket".to_string(), vec!["Residual".to_string()]);
-                        filter_rows(&crif_others, &b_cond)
-                    } else {
-                        let mut b_cond = HashMap::new();
-                        b_cond.insert("Bucket".to_string(), vec![bucket.to_string()]);
-                        filter_rows(&crif_others, &b_cond)
-                    };
+                    let bucket_label = bucket.to_string();
+                    let mut b_cond = HashMap::new();
+                    b_cond.insert("Bucket".to_string(), vec![bucket_label.clone()]);
+                    let crif_bucket = filter_rows(&crif_others, &b_cond);
 
-                    let rw = self.wnc.rw(risk_class, &bucket.to_string()).unwrap_or(1.0);
-                    let t = self.wnc.t(risk_class, "Delta", None, Some(&bucket.to_string())).unwrap_or(1.0);
+                    let rw = self.wnc.rw(risk_class, &bucket_label).unwrap_or(1.0);
+                    let t = self.delta_threshold(risk_class, &bucket_label);
 
                     let mut list_ws = Vec::new();
                     let mut list_cr_local = Vec::new();
@@ -475,7 +761,7 @@ impl<'a> MarginByRiskClass<'a> {
 
                         if ["Risk_CreditQ", "Risk_CreditNonQ"].contains(&risk_class.as_str()) {
                             let sensitivities_cr = simm_utils::sum_sensitivities(&crif_qualifier);
-                            let cr = 1.0_f64.max((sensitivities_cr.abs() / t).sqrt());
+                            let cr = self.concentration(sensitivities_cr, t);
 
                             let label2_list = unique_values(&crif_qualifier, "Label2");
                             for label2 in &label2_list {
@@ -485,11 +771,25 @@ impl<'a> MarginByRiskClass<'a> {
                                     t_cond.insert("Label2".to_string(), vec![label2.clone()]);
                                     let crif_tenor = filter_rows(&crif_qualifier, &t_cond);
 
-                                    let sensitivities = simm_utils::sum_sensitivities(&crif_tenor);
-                                    list_ws.push(rw * sensitivities * cr);
+                                    let sensitivities = self.consume(&crif_tenor);
+                                    list_ws.push(self.record_ws(WsRow {
+                                        product_class: String::new(),
+                                        risk_type: risk_class.clone(),
+                                        bucket: bucket_label.clone(),
+                                        qualifier: qualifier.clone(),
+                                        tenor: tenor.clone(),
+                                        label2: label2.clone(),
+                                        sensitivity: sensitivities,
+                                        risk_weight: rw,
+                                        concentration: cr,
+                                        ws: rw * sensitivities * cr,
+                                    }));
                                     list_cr_local.push(cr);
 
-                                    if *bucket == 0 {
+                                    // CreditQ correlates by issuer (Qualifier): its
+                                    // Label2 sub-curves are separate vertices of the
+                                    // same issuer. CreditNonQ correlates by Label2.
+                                    if bucket.is_residual() {
                                         index.push("Res".to_string());
                                     } else if risk_class == "Risk_CreditQ" {
                                         index.push(qualifier.clone());
@@ -500,10 +800,21 @@ impl<'a> MarginByRiskClass<'a> {
                             }
                         } else {
                             // Equity, Commodity
-                            let sensitivities = simm_utils::sum_sensitivities(&crif_qualifier);
-                            let cr = 1.0_f64.max((sensitivities.abs() / t).sqrt());
+                            let sensitivities = self.consume(&crif_qualifier);
+                            let cr = self.concentration(sensitivities, t);
                             list_cr_local.push(cr);
-                            list_ws.push(rw * sensitivities * cr);
+                            list_ws.push(self.record_ws(WsRow {
+                                product_class: String::new(),
+                                risk_type: risk_class.clone(),
+                                bucket: bucket_label.clone(),
+                                qualifier: qualifier.clone(),
+                                tenor: String::new(),
+                                label2: String::new(),
+                                sensitivity: sensitivities,
+                                risk_weight: rw,
+                                concentration: cr,
+                                ws: rw * sensitivities * cr,
+                            }));
                         }
                     }
 
@@ -513,24 +824,24 @@ impl<'a> MarginByRiskClass<'a> {
                         risk_class,
                         &list_ws,
                         Some(&list_cr_local),
-                        Some(&[&bucket.to_string()]),
+                        Some(&[bucket_label.as_str()]),
                         None,
                         if index.is_empty() { None } else { Some(&index_refs) },
                         &self.calculation_currency,
                     );
 
-                    if *bucket == 0 {
+                    if bucket.is_residual() {
                         k_res += k;
                     } else {
                         list_k.push(k);
                     }
 
-                    let s_b = list_ws.iter().sum::<f64>().min(k).max(-k);
+                    let s_b = simm_utils::capped_bucket_sum(list_ws.iter().sum(), k);
                     list_s.push(s_b);
                 }
 
                 // Calculate aggregated K
-                let bucket_list_non_res: Vec<usize> = bucket_list.iter().filter(|&&b| b != 0).copied().collect();
+                let bucket_list_non_res: Vec<Bucket> = bucket_list.iter().filter(|b| !b.is_residual()).copied().collect();
 
                 if risk_class != "Risk_FX" {
                     let mut k_squared_sum: f64 = list_k.iter().map(|x| x.powi(2)).sum();
@@ -554,19 +865,19 @@ impl<'a> MarginByRiskClass<'a> {
 
                     if LIST_CREDIT_Q.contains(&risk_class.as_str()) {
                         if let Some(cq) = updates.get_mut("CreditQ") {
-                            *cq.get_mut("Delta").unwrap() += total;
+                            *cq.get_mut(&RiskType::Delta).unwrap() += total;
                         }
                     } else if LIST_CREDIT_NON_Q.contains(&risk_class.as_str()) {
                         if let Some(cnq) = updates.get_mut("CreditNonQ") {
-                            *cnq.get_mut("Delta").unwrap() += total;
+                            *cnq.get_mut(&RiskType::Delta).unwrap() += total;
                         }
                     } else if LIST_EQUITY.contains(&risk_class.as_str()) {
                         if let Some(eq) = updates.get_mut("Equity") {
-                            *eq.get_mut("Delta").unwrap() += total;
+                            *eq.get_mut(&RiskType::Delta).unwrap() += total;
                         }
                     } else if LIST_COMMODITY.contains(&risk_class.as_str()) {
                         if let Some(com) = updates.get_mut("Commodity") {
-                            *com.get_mut("Delta").unwrap() += k_squared_sum.sqrt();
+                            *com.get_mut(&RiskType::Delta).unwrap() += k_squared_sum.sqrt();
                         }
                     }
                 }
@@ -577,7 +888,7 @@ impl<'a> MarginByRiskClass<'a> {
     }
 
     /// IR Vega Margin
-    pub fn ir_vega_margin(&self) -> HashMap<String, HashMap<String, f64>> {
+    pub fn ir_vega_margin(&self) -> MarginDict {
         let mut updates = init_margin_dict();
 
         if !self.list_risk_types.contains(&"Risk_IRVol".to_string())
@@ -603,7 +914,7 @@ impl<'a> MarginByRiskClass<'a> {
 
             let sensitivities_cr = simm_utils::sum_sensitivities(&crif_currency);
             let vt = self.wnc.t("Rates", "Vega", Some(currency), None).unwrap_or(1.0);
-            let vcr = 1.0_f64.max((sensitivities_cr.abs() / vt).sqrt());
+            let vcr = self.concentration(sensitivities_cr, vt);
             dict_vcr.insert(currency.clone(), vcr);
 
             let risk_types_in_currency = unique_values(&crif_currency, "RiskType");
@@ -622,8 +933,8 @@ impl<'a> MarginByRiskClass<'a> {
                     t_cond.insert("Label1".to_string(), vec![tenor.clone()]);
                     let crif_tenor = filter_rows(&crif_risk_class, &t_cond);
 
-                    let sensitivities = simm_utils::sum_sensitivities(&crif_tenor);
-                    vr.push(IR_VRW * sensitivities * vcr);
+                    let sensitivities = self.consume(&crif_tenor);
+                    vr.push(self.wnc.scalar_params().ir_vrw * sensitivities * vcr);
 
                     if risk_class == "Risk_IRVol" {
                         index.push(tenor);
@@ -637,7 +948,7 @@ impl<'a> MarginByRiskClass<'a> {
             let k = k_vega(self.wnc, "Rates", &vr, None, None, Some(&index_refs));
             list_k.push(k);
 
-            let s = vr.iter().sum::<f64>().min(k).max(-k);
+            let s = simm_utils::capped_bucket_sum(vr.iter().sum(), k);
             dict_s.insert(currency.clone(), s);
         }
 
@@ -655,21 +966,21 @@ impl<'a> MarginByRiskClass<'a> {
                 let vcr_b = *dict_vcr.get(currency_b).unwrap_or(&1.0);
                 let vcr_c = *dict_vcr.get(currency_c).unwrap_or(&1.0);
                 let g = vcr_b.min(vcr_c) / vcr_b.max(vcr_c);
-                let gamma = IR_GAMMA_DIFF_CCY;
+                let gamma = self.wnc.scalar_params().ir_gamma_diff_ccy;
 
                 k_squared_sum += gamma * dict_s[currency_b] * dict_s[currency_c] * g;
             }
         }
 
         if let Some(rates) = updates.get_mut("Rates") {
-            *rates.get_mut("Vega").unwrap() += k_squared_sum.sqrt();
+            *rates.get_mut(&RiskType::Vega).unwrap() += k_squared_sum.sqrt();
         }
 
         updates
     }
 
     /// Vega Margin for non-Rates risk classes
-    pub fn vega_margin(&self) -> HashMap<String, HashMap<String, f64>> {
+    pub fn vega_margin(&self) -> MarginDict {
         let mut updates = init_margin_dict();
 
         let allowed = ["Risk_CreditVol", "Risk_CreditVolNonQ", "Risk_EquityVol", "Risk_CommodityVol", "Risk_FXVol"];
@@ -682,10 +993,16 @@ impl<'a> MarginByRiskClass<'a> {
 
         for risk_class in &list_risk_classes {
             if risk_class == "Risk_FXVol" {
+                // Pushed together so k_vega always sees equal lengths; qualifiers
+                // that are not 6-character pairs yield no entries (and K = 0)
                 let mut list_vr = Vec::new();
                 let mut list_vcr = Vec::new();
 
-                for currency_pair in simm_utils::currency_pair_list(&self.crif) {
+                let mut rt_cond = HashMap::new();
+                rt_cond.insert("RiskType".to_string(), vec![risk_class.clone()]);
+                let crif_fx_vol = filter_rows(&self.crif, &rt_cond);
+
+                for currency_pair in simm_utils::currency_pair_list(&crif_fx_vol) {
                     let reversed = format!("{}{}", &currency_pair[3..6], &currency_pair[0..3]);
                     let mut cond = HashMap::new();
                     cond.insert("RiskType".to_string(), vec![risk_class.clone()]);
@@ -702,19 +1019,19 @@ impl<'a> MarginByRiskClass<'a> {
 
                     let normal = Normal::new(0.0, 1.0).unwrap();
                     let sigma = rw * (365.0_f64 / 14.0_f64).sqrt() / normal.inverse_cdf(0.99);
-                    let sensitivities = simm_utils::sum_sensitivities(&crif_fx);
-                    let vr_ik = FX_HVR * sigma * sensitivities;
+                    let sensitivities = self.consume(&crif_fx);
+                    let vr_ik = self.wnc.scalar_params().fx_hvr * sigma * sensitivities;
                     let vt = self.wnc.t(risk_class, "Vega", Some(&currency_pair), None).unwrap_or(1.0);
-                    let vcr = 1.0_f64.max((vr_ik.abs() / vt).sqrt());
+                    let vcr = self.concentration(vr_ik, vt);
                     list_vcr.push(vcr);
 
-                    let vr_k = FX_VRW * vr_ik * vcr;
+                    let vr_k = self.wnc.scalar_params().fx_vrw * vr_ik * vcr;
                     list_vr.push(vr_k);
                 }
 
                 let k = k_vega(self.wnc, risk_class, &list_vr, Some(&list_vcr), None, None);
                 if let Some(fx) = updates.get_mut("FX") {
-                    *fx.get_mut("Vega").unwrap() += k;
+                    *fx.get_mut(&RiskType::Vega).unwrap() += k;
                 }
             } else {
                 // Equity, Commodity, Credit
@@ -725,18 +1042,13 @@ impl<'a> MarginByRiskClass<'a> {
                 let mut cond = HashMap::new();
                 cond.insert("RiskType".to_string(), vec![risk_class.clone()]);
                 let crif_risk_type = filter_rows(&self.crif, &cond);
-                let bucket_list = simm_utils::bucket_list(&crif_risk_type);
+                let bucket_list = self.selected_buckets(risk_class, &crif_risk_type);
 
                 for bucket in &bucket_list {
-                    let crif_bucket = if *bucket == 0 {
-                        let mut b_cond = HashMap::new();
-                        b_cond.insert("Bucket".to_string(), vec!["Residual".to_string()]);
-                        filter_rows(&crif_risk_type, &b_cond)
-                    } else {
-                        let mut b_cond = HashMap::new();
-                        b_cond.insert("Bucket".to_string(), vec![bucket.to_string()]);
-                        filter_rows(&crif_risk_type, &b_cond)
-                    };
+                    let bucket_label = bucket.to_string();
+                    let mut b_cond = HashMap::new();
+                    b_cond.insert("Bucket".to_string(), vec![bucket_label.clone()]);
+                    let crif_bucket = filter_rows(&crif_risk_type, &b_cond);
 
                     let mut vr = Vec::new();
                     let mut list_vcr_local = Vec::new();
@@ -749,33 +1061,42 @@ impl<'a> MarginByRiskClass<'a> {
                         q_cond.insert("Qualifier".to_string(), vec![qualifier.clone()]);
                         let crif_qualifier = filter_rows(&crif_bucket, &q_cond);
 
-                        let rw = self.wnc.rw(risk_class, &bucket.to_string()).unwrap_or(1.0);
+                        let rw = self.wnc.rw(risk_class, &bucket_label).unwrap_or(1.0);
                         let normal = Normal::new(0.0, 1.0).unwrap();
                         let sigma = rw * (365.0_f64 / 14.0_f64).sqrt() / normal.inverse_cdf(0.99);
 
                         if ["Risk_EquityVol", "Risk_CommodityVol"].contains(&risk_class.as_str()) {
-                            let hvr = if risk_class == "Risk_EquityVol" { EQUITY_HVR } else { COMMODITY_HVR };
+                            let params = self.wnc.scalar_params();
+                            let hvr = if risk_class == "Risk_EquityVol" {
+                                params.equity_hvr
+                            } else {
+                                params.commodity_hvr
+                            };
                             let vrw = if risk_class == "Risk_EquityVol" {
-                                if *bucket == 12 { EQUITY_VRW_BUCKET_12 } else { EQUITY_VRW }
+                                if *bucket == Bucket::Numbered(12) {
+                                    params.equity_vrw_bucket_12
+                                } else {
+                                    params.equity_vrw
+                                }
                             } else {
-                                COMMODITY_VRW
+                                params.commodity_vrw
                             };
 
                             let mut vr_ik = Vec::new();
-                            let sensitivities = simm_utils::sum_sensitivities(&crif_qualifier);
+                            let sensitivities = self.consume(&crif_qualifier);
                             vr_ik.push(hvr * sigma * sensitivities);
 
                             let vr_i: f64 = vr_ik.iter().sum();
-                            let vt = self.wnc.t(risk_class, "Vega", None, Some(&bucket.to_string())).unwrap_or(1.0);
-                            let vcr = 1.0_f64.max((vr_i.abs() / vt).sqrt());
+                            let vt = self.wnc.t(risk_class, "Vega", None, Some(&bucket_label)).unwrap_or(1.0);
+                            let vcr = self.concentration(vr_i, vt);
 
                             list_vcr_local.push(vcr);
                             vr.push(vr_i * vrw * vcr);
                         } else {
                             // Credit
-                            let vt = self.wnc.t(risk_class, "Vega", None, Some(&bucket.to_string())).unwrap_or(1.0);
+                            let vt = self.wnc.t(risk_class, "Vega", None, Some(&bucket_label)).unwrap_or(1.0);
                             let sensitivities_vt = simm_utils::sum_sensitivities(&crif_qualifier);
-                            let vcr = 1.0_f64.max((sensitivities_vt.abs() / vt).sqrt());
+                            let vcr = self.concentration(sensitivities_vt, vt);
 
                             let label2_list = unique_values(&crif_qualifier, "Label2");
                             for label2 in &label2_list {
@@ -785,18 +1106,18 @@ impl<'a> MarginByRiskClass<'a> {
                                     t_cond.insert("Label2".to_string(), vec![label2.clone()]);
                                     let crif_tenor = filter_rows(&crif_qualifier, &t_cond);
 
-                                    let sensitivities = simm_utils::sum_sensitivities(&crif_tenor);
+                                    let sensitivities = self.consume(&crif_tenor);
 
                                     let vrw = if risk_class == "Risk_CreditVol" {
-                                        CREDIT_Q_V_RW
+                                        self.wnc.scalar_params().credit_q_vrw
                                     } else {
-                                        CREDIT_NON_Q_VRW
+                                        self.wnc.scalar_params().credit_non_q_vrw
                                     };
 
                                     vr.push(vrw * sensitivities * vcr);
                                     list_vcr_local.push(vcr);
 
-                                    if *bucket == 0 {
+                                    if bucket.is_residual() {
                                         index.push("Res".to_string());
                                     } else if risk_class == "Risk_CreditVol" {
                                         index.push(qualifier.clone());
@@ -814,21 +1135,21 @@ impl<'a> MarginByRiskClass<'a> {
                         risk_class,
                         &vr,
                         Some(&list_vcr_local),
-                        Some(&bucket.to_string()),
+                        Some(&bucket_label),
                         if index.is_empty() { None } else { Some(&index_refs) },
                     );
 
-                    if *bucket == 0 {
+                    if bucket.is_residual() {
                         k_res += k;
                     } else {
                         list_k.push(k);
                     }
 
-                    let s = vr.iter().sum::<f64>().min(k).max(-k);
+                    let s = simm_utils::capped_bucket_sum(vr.iter().sum(), k);
                     list_s.push(s);
                 }
 
-                let bucket_list_non_res: Vec<usize> = bucket_list.iter().filter(|&&b| b != 0).copied().collect();
+                let bucket_list_non_res: Vec<Bucket> = bucket_list.iter().filter(|b| !b.is_residual()).copied().collect();
 
                 let mut k_squared_sum: f64 = list_k.iter().map(|x| x.powi(2)).sum();
 
@@ -855,19 +1176,19 @@ impl<'a> MarginByRiskClass<'a> {
 
                 if LIST_CREDIT_Q.contains(&risk_class.as_str()) {
                     if let Some(cq) = updates.get_mut("CreditQ") {
-                        *cq.get_mut("Vega").unwrap() += total;
+                        *cq.get_mut(&RiskType::Vega).unwrap() += total;
                     }
                 } else if LIST_CREDIT_NON_Q.contains(&risk_class.as_str()) {
                     if let Some(cnq) = updates.get_mut("CreditNonQ") {
-                        *cnq.get_mut("Vega").unwrap() += total;
+                        *cnq.get_mut(&RiskType::Vega).unwrap() += total;
                     }
                 } else if LIST_EQUITY.contains(&risk_class.as_str()) {
                     if let Some(eq) = updates.get_mut("Equity") {
-                        *eq.get_mut("Vega").unwrap() += total;
+                        *eq.get_mut(&RiskType::Vega).unwrap() += total;
                     }
                 } else if LIST_COMMODITY.contains(&risk_class.as_str()) {
                     if let Some(com) = updates.get_mut("Commodity") {
-                        *com.get_mut("Vega").unwrap() += total;
+                        *com.get_mut(&RiskType::Vega).unwrap() += total;
                     }
                 }
             }
@@ -877,7 +1198,7 @@ impl<'a> MarginByRiskClass<'a> {
     }
 
     /// IR Curvature Margin
-    pub fn ir_curvature_margin(&self) -> HashMap<String, HashMap<String, f64>> {
+    pub fn ir_curvature_margin(&self) -> MarginDict {
         let mut updates = init_margin_dict();
 
         if !self.list_risk_types.contains(&"Risk_IRVol".to_string())
@@ -925,8 +1246,12 @@ impl<'a> MarginByRiskClass<'a> {
                     t_cond.insert("Label1".to_string(), vec![tenor.clone()]);
                     let crif_tenor = filter_rows(&crif_risk_class, &t_cond);
 
-                    let sensitivities = simm_utils::sum_sensitivities(&crif_tenor);
-                    let cvr = simm_utils::scaling_func(&tenor) * sensitivities;
+                    // Tenors that cannot be scaled are rejected by CRIF validation
+                    let Ok(scale) = simm_utils::scaling_func(&tenor) else {
+                        continue;
+                    };
+                    let sensitivities = self.consume(&crif_tenor);
+                    let cvr = scale * sensitivities;
                     cvr_ik.push(cvr);
                     cvr_sum += cvr;
                     cvr_abs_sum += cvr.abs();
@@ -943,18 +1268,11 @@ impl<'a> MarginByRiskClass<'a> {
             let k = k_curvature(self.wnc, "Rates", &cvr_ik, None, Some(&index_refs));
             list_k.push(k);
 
-            let s = cvr_ik.iter().sum::<f64>().min(k).max(-k);
+            let s = simm_utils::capped_bucket_sum(cvr_ik.iter().sum(), k);
             list_s.push(s);
         }
 
-        let theta = if cvr_abs_sum != 0.0 {
-            (cvr_sum / cvr_abs_sum).min(0.0)
-        } else {
-            0.0
-        };
-
-        let normal = Normal::new(0.0, 1.0).unwrap();
-        let lambda = (normal.inverse_cdf(0.995).powi(2) - 1.0) * (1.0 + theta) - theta;
+        let (_, lambda) = curvature_lambda(cvr_sum, cvr_abs_sum, CURVATURE_CONFIDENCE);
 
         let mut k: f64 = list_k.iter().map(|x| x.powi(2)).sum();
 
@@ -964,20 +1282,22 @@ impl<'a> MarginByRiskClass<'a> {
                     continue;
                 }
 
-                let gamma = IR_GAMMA_DIFF_CCY;
+                let gamma = self.wnc.scalar_params().ir_gamma_diff_ccy;
                 k += list_s[i] * list_s[j] * gamma.powi(2);
             }
         }
 
+        // The HVR of the calibration in use, which differs between versions
+        let ir_hvr = self.wnc.scalar_params().ir_hvr;
         if let Some(rates) = updates.get_mut("Rates") {
-            *rates.get_mut("Curvature").unwrap() += (cvr_sum + lambda * k.sqrt()).max(0.0) / IR_HVR.powi(2);
+            *rates.get_mut(&RiskType::Curvature).unwrap() += (cvr_sum + lambda * k.sqrt()).max(0.0) / ir_hvr.powi(2);
         }
 
         updates
     }
 
     /// Curvature Margin for non-Rates risk classes
-    pub fn curvature_margin(&self) -> HashMap<String, HashMap<String, f64>> {
+    pub fn curvature_margin(&self) -> MarginDict {
         let mut updates = init_margin_dict();
 
         let allowed = ["Risk_CreditVol", "Risk_CreditVolNonQ", "Risk_EquityVol", "Risk_CommodityVol", "Risk_FXVol"];
@@ -994,7 +1314,11 @@ impl<'a> MarginByRiskClass<'a> {
                 let mut cvr_sum = 0.0;
                 let mut cvr_abs_sum = 0.0;
 
-                for currency_pair in simm_utils::currency_pair_list(&self.crif) {
+                let mut rt_cond = HashMap::new();
+                rt_cond.insert("RiskType".to_string(), vec![risk_class.clone()]);
+                let crif_fx_vol = filter_rows(&self.crif, &rt_cond);
+
+                for currency_pair in simm_utils::currency_pair_list(&crif_fx_vol) {
                     let reversed = format!("{}{}", &currency_pair[3..6], &currency_pair[0..3]);
                     let mut cond = HashMap::new();
                     cond.insert("RiskType".to_string(), vec![risk_class.clone()]);
@@ -1011,15 +1335,17 @@ impl<'a> MarginByRiskClass<'a> {
 
                     let normal = Normal::new(0.0, 1.0).unwrap();
                     let sigma = rw * (365.0_f64 / 14.0_f64).sqrt() / normal.inverse_cdf(0.99);
+                    self.mark_used(&df);
                     let vega_list = to_list(&df, "AmountUSD");
                     let tenor_list = to_list(&df, "Label1");
 
                     let mut cvr = 0.0;
                     for (k, vega) in tenor_list.iter().zip(vega_list.iter()) {
-                        if !vega.is_empty() {
-                            if let Ok(v) = vega.parse::<f64>() {
-                                cvr += simm_utils::scaling_func(k) * sigma * v;
-                            }
+                        if !vega.is_empty()
+                            && let Ok(v) = simm_utils::parse_amount(vega)
+                            && let Ok(scale) = simm_utils::scaling_func(k)
+                        {
+                            cvr += scale * sigma * v;
                         }
                     }
 
@@ -1030,17 +1356,10 @@ impl<'a> MarginByRiskClass<'a> {
 
                 let k = k_curvature(self.wnc, risk_class, &list_cvr, None, None);
 
-                let theta = if cvr_abs_sum != 0.0 {
-                    (cvr_sum / cvr_abs_sum).min(0.0)
-                } else {
-                    0.0
-                };
-
-                let normal = Normal::new(0.0, 1.0).unwrap();
-                let lambda = (normal.inverse_cdf(0.995).powi(2) - 1.0) * (1.0 + theta) - theta;
+                let (_, lambda) = curvature_lambda(cvr_sum, cvr_abs_sum, CURVATURE_CONFIDENCE);
 
                 if let Some(fx) = updates.get_mut("FX") {
-                    *fx.get_mut("Curvature").unwrap() += (cvr_sum + lambda * k).max(0.0);
+                    *fx.get_mut(&RiskType::Curvature).unwrap() += (cvr_sum + lambda * k).max(0.0);
                 }
             } else {
                 // Equity, Commodity, Credit
@@ -1056,18 +1375,13 @@ impl<'a> MarginByRiskClass<'a> {
                 let mut cond = HashMap::new();
                 cond.insert("RiskType".to_string(), vec![risk_class.clone()]);
                 let crif_filtered = filter_rows(&self.crif, &cond);
-                let bucket_list = simm_utils::bucket_list(&crif_filtered);
+                let bucket_list = self.selected_buckets(risk_class, &crif_filtered);
 
                 for bucket in &bucket_list {
-                    let crif_bucket = if *bucket == 0 {
-                        let mut b_cond = HashMap::new();
-                        b_cond.insert("Bucket".to_string(), vec!["Residual".to_string()]);
-                        filter_rows(&crif_filtered, &b_cond)
-                    } else {
-                        let mut b_cond = HashMap::new();
-                        b_cond.insert("Bucket".to_string(), vec![bucket.to_string()]);
-                        filter_rows(&crif_filtered, &b_cond)
-                    };
+                    let bucket_label = bucket.to_string();
+                    let mut b_cond = HashMap::new();
+                    b_cond.insert("Bucket".to_string(), vec![bucket_label.clone()]);
+                    let crif_bucket = filter_rows(&crif_filtered, &b_cond);
 
                     let mut cvr_i = Vec::new();
                     let mut index = Vec::new();
@@ -1078,26 +1392,23 @@ impl<'a> MarginByRiskClass<'a> {
                         let crif_qualifier = filter_rows(&crif_bucket, &q_cond);
 
                         let tenor_list = to_list(&crif_qualifier, "Label1");
+                        self.mark_used(&crif_qualifier);
                         let vega_list = to_list(&crif_qualifier, "AmountUSD");
 
-                        let rw = self.wnc.rw(risk_class, &bucket.to_string()).unwrap_or(1.0);
+                        let rw = self.wnc.rw(risk_class, &bucket_label).unwrap_or(1.0);
                         let normal = Normal::new(0.0, 1.0).unwrap();
                         let sigma = rw * (365.0_f64 / 14.0_f64).sqrt() / normal.inverse_cdf(0.99);
 
                         if ["Risk_EquityVol", "Risk_CommodityVol"].contains(&risk_class.as_str()) {
-                            // No curvature for equity bucket 12
-                            let sigma = if risk_class == "Risk_EquityVol" && *bucket == 12 {
-                                0.0
-                            } else {
-                                sigma
-                            };
+                            let sigma = if has_curvature(risk_class, *bucket) { sigma } else { 0.0 };
 
                             let mut cvr_ik = Vec::new();
                             for (k, vega) in tenor_list.iter().zip(vega_list.iter()) {
-                                if !vega.is_empty() {
-                                    if let Ok(v) = vega.parse::<f64>() {
-                                        cvr_ik.push(simm_utils::scaling_func(k) * sigma * v);
-                                    }
+                                if !vega.is_empty()
+                                    && let Ok(v) = simm_utils::parse_amount(vega)
+                                    && let Ok(scale) = simm_utils::scaling_func(k)
+                                {
+                                    cvr_ik.push(scale * sigma * v);
                                 }
                             }
                             cvr_i.push(cvr_ik.iter().sum());
@@ -1111,9 +1422,12 @@ impl<'a> MarginByRiskClass<'a> {
                                     t_cond.insert("Label2".to_string(), vec![label2.clone()]);
                                     let crif_tenor = filter_rows(&crif_qualifier, &t_cond);
 
-                                    let sensitivities = simm_utils::sum_sensitivities(&crif_tenor);
+                                    let Ok(scale) = simm_utils::scaling_func(&tenor) else {
+                                        continue;
+                                    };
+                                    let sensitivities = self.consume(&crif_tenor);
 
-                                    if *bucket == 0 {
+                                    if bucket.is_residual() {
                                         index.push("Res".to_string());
                                     } else if risk_class == "Risk_CreditVol" {
                                         index.push(qualifier.clone());
@@ -1121,7 +1435,7 @@ impl<'a> MarginByRiskClass<'a> {
                                         index.push(label2.clone());
                                     }
 
-                                    cvr_i.push(simm_utils::scaling_func(&tenor) * sensitivities);
+                                    cvr_i.push(scale * sensitivities);
                                 }
                             }
                         }
@@ -1132,18 +1446,18 @@ impl<'a> MarginByRiskClass<'a> {
                         self.wnc,
                         risk_class,
                         &cvr_i,
-                        Some(&bucket.to_string()),
+                        Some(&bucket_label),
                         if index.is_empty() { None } else { Some(&index_refs) },
                     );
 
-                    if *bucket == 0 {
+                    if bucket.is_residual() {
                         k_res += k;
                         cvr_sum_res += cvr_i.iter().sum::<f64>();
                         cvr_abs_sum_res += cvr_i.iter().map(|x| x.abs()).sum::<f64>();
                     } else {
-                        if !(risk_class == "Risk_EquityVol" && *bucket == 12) {
+                        if has_curvature(risk_class, *bucket) {
                             list_k.push(k);
-                            let s = cvr_i.iter().sum::<f64>().min(k).max(-k);
+                            let s = simm_utils::capped_bucket_sum(cvr_i.iter().sum(), k);
                             list_s.push(s);
                             buckets_in_list_s.push(*bucket);  // Track this bucket
                         }
@@ -1152,30 +1466,8 @@ impl<'a> MarginByRiskClass<'a> {
                     }
                 }
 
-                let bucket_list_unique: Vec<usize> = bucket_list.iter().copied().collect();
-                let has_residual = bucket_list_unique.contains(&0);
-                let has_non_residual = bucket_list_unique.iter().any(|&b| b != 0);
-
-                let (_theta, lambda, _theta_res, lambda_res) = {
-                    let normal = Normal::new(0.0, 1.0).unwrap();
-                    let ppf = normal.inverse_cdf(0.995).powi(2);
-
-                    if has_residual && has_non_residual {
-                        let t = if cvr_abs_sum != 0.0 { (cvr_sum / cvr_abs_sum).min(0.0) } else { 0.0 };
-                        let l = (ppf - 1.0) * (1.0 + t) - t;
-                        let t_res = if cvr_abs_sum_res != 0.0 { (cvr_sum_res / cvr_abs_sum_res).min(0.0) } else { 0.0 };
-                        let l_res = (ppf - 1.0) * (1.0 + t_res) - t_res;
-                        (t, l, t_res, l_res)
-                    } else if !has_residual {
-                        let t = if cvr_abs_sum != 0.0 { (cvr_sum / cvr_abs_sum).min(0.0) } else { 0.0 };
-                        let l = (ppf - 1.0) * (1.0 + t) - t;
-                        (t, l, 0.0, 0.0)
-                    } else {
-                        let t_res = if cvr_abs_sum_res != 0.0 { (cvr_sum_res / cvr_abs_sum_res).min(0.0) } else { 0.0 };
-                        let l_res = (ppf - 1.0) * (1.0 + t_res) - t_res;
-                        (0.0, 0.0, t_res, l_res)
-                    }
-                };
+                let (lambda, lambda_res) =
+                    split_curvature_lambdas(&bucket_list, (cvr_sum, cvr_abs_sum), (cvr_sum_res, cvr_abs_sum_res));
 
                 let mut k_squared: f64 = list_k.iter().map(|x| x.powi(2)).sum();
 
@@ -1199,30 +1491,31 @@ impl<'a> MarginByRiskClass<'a> {
                     }
                 }
 
-                let curvature_margin_non_res = if k_squared >= 0.0 {
-                    (cvr_sum + lambda * k_squared.sqrt()).max(0.0)
-                } else {
-                    0.0
-                };
+                // Curvature squares gamma, so negative inter-bucket
+                // correlations (commodity has some) add like positive ones and
+                // k_squared is a PSD form, never below zero in exact
+                // arithmetic. Clamp rounding noise to 0 rather than dropping
+                // the margin as a whole.
+                let curvature_margin_non_res = (cvr_sum + lambda * k_squared.max(0.0).sqrt()).max(0.0);
                 let curvature_margin_res = (cvr_sum_res + lambda_res * k_res).max(0.0);
 
                 let total = curvature_margin_non_res + curvature_margin_res;
 
                 if LIST_EQUITY.contains(&risk_class.as_str()) {
                     if let Some(eq) = updates.get_mut("Equity") {
-                        *eq.get_mut("Curvature").unwrap() += total;
+                        *eq.get_mut(&RiskType::Curvature).unwrap() += total;
                     }
                 } else if LIST_COMMODITY.contains(&risk_class.as_str()) {
                     if let Some(com) = updates.get_mut("Commodity") {
-                        *com.get_mut("Curvature").unwrap() += total;
+                        *com.get_mut(&RiskType::Curvature).unwrap() += total;
                     }
                 } else if risk_class == "Risk_CreditVol" {
                     if let Some(cq) = updates.get_mut("CreditQ") {
-                        *cq.get_mut("Curvature").unwrap() += total;
+                        *cq.get_mut(&RiskType::Curvature).unwrap() += total;
                     }
                 } else if risk_class == "Risk_CreditVolNonQ" {
                     if let Some(cnq) = updates.get_mut("CreditNonQ") {
-                        *cnq.get_mut("Curvature").unwrap() += total;
+                        *cnq.get_mut(&RiskType::Curvature).unwrap() += total;
                     }
                 }
             }
@@ -1232,7 +1525,7 @@ impl<'a> MarginByRiskClass<'a> {
     }
 
     /// Base Correlation Margin
-    pub fn base_corr_margin(&self) -> HashMap<String, HashMap<String, f64>> {
+    pub fn base_corr_margin(&self) -> MarginDict {
         let mut updates = init_margin_dict();
 
         let mut cond = HashMap::new();
@@ -1247,8 +1540,8 @@ impl<'a> MarginByRiskClass<'a> {
             q_cond.insert("Qualifier".to_string(), vec![qualifier.clone()]);
             let crif_qualifier = filter_rows(&crif_base_corr, &q_cond);
 
-            let rw = CREDIT_Q_BASE_CORR_WEIGHT;
-            let sensitivities = simm_utils::sum_sensitivities(&crif_qualifier);
+            let rw = self.wnc.scalar_params().credit_q_base_corr_weight;
+            let sensitivities = self.consume(&crif_qualifier);
             let ws = rw * sensitivities;
             list_ws.push(ws);
         }
@@ -1267,7 +1560,7 @@ impl<'a> MarginByRiskClass<'a> {
         }
 
         if let Some(cq) = updates.get_mut("CreditQ") {
-            *cq.get_mut("BaseCorr").unwrap() += base_corr.sqrt();
+            *cq.get_mut(&RiskType::BaseCorr).unwrap() += base_corr.sqrt();
         }
 
         updates
@@ -1277,6 +1570,7 @@ impl<'a> MarginByRiskClass<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::simm_utils::crif;
 
     #[test]
     fn test_init_margin_dict() {
@@ -1301,4 +1595,618 @@ mod tests {
 
         assert_eq!(result.len(), 3); // Header + 2 rows
     }
+
+    #[test]
+    fn test_all_zero_ir_vol_yields_zero_margin() {
+        let crif = crif(&[
+            ["Rates", "Risk_IRVol", "USD", "", "1y", "", "0"],
+            ["Rates", "Risk_IRVol", "USD", "", "5y", "", "0"],
+            ["Rates", "Risk_IRVol", "EUR", "", "10y", "", "0"],
+        ]);
+
+        let wnc = crate::v2_5::V2_5;
+        let margin = MarginByRiskClass::new(crif, "USD".to_string(), &wnc);
+        assert!(!margin.list_risk_types.contains(&"Risk_IRVol".to_string()));
+
+        assert_eq!(margin.ir_vega_margin()["Rates"][&RiskType::Vega], 0.0);
+        assert_eq!(margin.ir_curvature_margin()["Rates"][&RiskType::Curvature], 0.0);
+    }
+
+    #[test]
+    fn test_ir_delta_rw_lookup() {
+        assert_eq!(ir_delta_rw("USD", "5y").unwrap(), reg_vol_rw_lookup("5y").unwrap());
+        assert_eq!(ir_delta_rw("JPY", "5y").unwrap(), low_vol_rw_lookup("5y").unwrap());
+        assert!(ir_delta_rw("USD", "7y").is_err());
+    }
+
+    #[test]
+    fn test_ir_delta_unlisted_currency_uses_high_vol() {
+        let crif = vec![
+            vec!["ProductClass".to_string(), "RiskType".to_string(), "Qualifier".to_string(),
+                 "Bucket".to_string(), "Label1".to_string(), "Label2".to_string(), "AmountUSD".to_string()],
+            vec!["Rates".to_string(), "Risk_IRCurve".to_string(), "MXN".to_string(),
+                 "3".to_string(), "5y".to_string(), "OIS".to_string(), "1000".to_string()],
+        ];
+
+        let wnc = crate::v2_5::V2_5;
+        let margin = MarginByRiskClass::new(crif, "USD".to_string(), &wnc);
+        let delta = margin.ir_delta_margin()["Rates"][&RiskType::Delta];
+
+        // Well below the concentration threshold, so K = |RW * s|
+        assert!((delta - high_vol_rw_lookup("5y").unwrap() * 1000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_residual_equity_delta_uses_residual_corr() {
+        let crif = crif(&[
+            ["Equity", "Risk_Equity", "ISSUER_A", "Residual", "", "", "1000"],
+            ["Equity", "Risk_Equity", "ISSUER_B", "Residual", "", "", "2000"],
+        ]);
+
+        let wnc = crate::v2_5::V2_5;
+        let margin = MarginByRiskClass::new(crif, "USD".to_string(), &wnc);
+        let delta = margin.delta_margin()["Equity"][&RiskType::Delta];
+
+        // Residual intra-bucket correlation is zero, so K_res is the root sum of squares
+        let rw = wnc.rw("Risk_Equity", "Residual").unwrap();
+        let expected = ((rw * 1000.0).powi(2) + (rw * 2000.0).powi(2)).sqrt();
+        assert_eq!(wnc.rho("Risk_Equity", "", "", Some("Residual")), Some(0.0));
+        assert!((delta - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_negative_delta_concentration_matches_positive() {
+        let wnc = crate::v2_5::V2_5;
+        let amount = 1e11;
+
+        let single_row = |product: &str, risk_type: &str, qualifier: &str, bucket: &str, label1: &str, value: f64| -> Crif {
+            // Credit sensitivities carry their currency in Label2
+            let label2 = if risk_type == "Risk_CreditQ" { "USD" } else { "" };
+            crif(&[[product, risk_type, qualifier, bucket, label1, label2, &value.to_string()]])
+        };
+
+        for (product, risk_type, risk_class, qualifier) in [
+            ("Credit", "Risk_CreditQ", "CreditQ", "ISSUER"),
+            ("Equity", "Risk_Equity", "Equity", "ISSUER"),
+            ("Commodity", "Risk_Commodity", "Commodity", "WTI"),
+        ] {
+            let label1 = if risk_type == "Risk_CreditQ" { "5y" } else { "" };
+            let long = MarginByRiskClass::new(single_row(product, risk_type, qualifier, "1", label1, amount), "USD".to_string(), &wnc);
+            let short = MarginByRiskClass::new(single_row(product, risk_type, qualifier, "1", label1, -amount), "USD".to_string(), &wnc);
+
+            let rw = wnc.rw(risk_type, "1").unwrap();
+            let t = wnc.t(risk_type, "Delta", None, Some("1")).unwrap();
+            let cr = simm_utils::concentration_threshold(-amount, t);
+            assert!(cr > 1.0);
+
+            let short_delta = short.delta_margin()[risk_class][&RiskType::Delta];
+            assert!((short_delta - rw * amount * cr).abs() / short_delta < 1e-12);
+            assert_eq!(short_delta, long.delta_margin()[risk_class][&RiskType::Delta]);
+        }
+
+        let long = MarginByRiskClass::new(single_row("RatesFX", "Risk_IRCurve", "USD", "1", "5y", amount), "USD".to_string(), &wnc);
+        let short = MarginByRiskClass::new(single_row("RatesFX", "Risk_IRCurve", "USD", "1", "5y", -amount), "USD".to_string(), &wnc);
+        assert_eq!(long.ir_delta_margin()["Rates"][&RiskType::Delta], short.ir_delta_margin()["Rates"][&RiskType::Delta]);
+
+        let long = MarginByRiskClass::new(single_row("RatesFX", "Risk_FX", "EUR", "", "", amount), "USD".to_string(), &wnc);
+        let short = MarginByRiskClass::new(single_row("RatesFX", "Risk_FX", "EUR", "", "", -amount), "USD".to_string(), &wnc);
+        assert_eq!(long.delta_margin()["FX"][&RiskType::Delta], short.delta_margin()["FX"][&RiskType::Delta]);
+    }
+
+    #[test]
+    fn test_equity_bucket_12_has_no_curvature() {
+        assert!(!has_curvature("Risk_EquityVol", Bucket::Numbered(12)));
+        assert!(has_curvature("Risk_EquityVol", Bucket::Numbered(11)));
+        assert!(has_curvature("Risk_CommodityVol", Bucket::Numbered(12)));
+
+        let equity_vol = |bucket: &str| crif(&[["Equity", "Risk_EquityVol", "INDEX", bucket, "1y", "", "1000000"]]);
+
+        let wnc = crate::v2_5::V2_5;
+        let bucket_12 = MarginByRiskClass::new(equity_vol("12"), "USD".to_string(), &wnc);
+        let bucket_11 = MarginByRiskClass::new(equity_vol("11"), "USD".to_string(), &wnc);
+        assert_eq!(bucket_12.curvature_margin()["Equity"][&RiskType::Curvature], 0.0);
+        assert!(bucket_11.curvature_margin()["Equity"][&RiskType::Curvature] > 0.0);
+    }
+
+    #[test]
+    fn test_from_records_matches_new() {
+        let crif = crate::file_utils::read_csv_to_list("tests_2_5/C1_crif.csv").unwrap();
+        let records = simm_utils::records_from_crif(&crif).unwrap();
+
+        let wnc = crate::v2_5::V2_5;
+        let from_crif = MarginByRiskClass::new(crif, "USD".to_string(), &wnc).calculate_all();
+        let from_records = MarginByRiskClass::from_records(&records, "USD".to_string(), &wnc).calculate_all();
+
+        for (risk_class, measures) in &from_crif {
+            for (measure, value) in measures {
+                assert!((value - from_records[risk_class][measure]).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_curvature_lambda_theta_zero() {
+        let (theta, lambda) = curvature_lambda(100.0, 100.0, 0.995);
+        assert_eq!(theta, 0.0);
+
+        let z = Normal::new(0.0, 1.0).unwrap().inverse_cdf(0.995);
+        assert!((lambda - (z * z - 1.0)).abs() < 1e-12);
+
+        // No exposure at all also gives theta = 0
+        assert_eq!(curvature_lambda(0.0, 0.0, 0.995), (theta, lambda));
+    }
+
+    #[test]
+    fn test_curvature_lambda_negative_theta() {
+        // Net short: sum CVR = -50, sum |CVR| = 150
+        let (theta, lambda) = curvature_lambda(-50.0, 150.0, 0.995);
+        assert!((theta + 1.0 / 3.0).abs() < 1e-12);
+
+        let z = Normal::new(0.0, 1.0).unwrap().inverse_cdf(0.995);
+        let expected = (z * z - 1.0) * (1.0 + theta) - theta;
+        assert!((lambda - expected).abs() < 1e-12);
+        assert!(lambda < curvature_lambda(50.0, 150.0, 0.995).1);
+    }
+
+    #[test]
+    fn test_fx_vol_invalid_qualifier_gives_zero_margin() {
+        let crif = crif(&[["RatesFX", "Risk_FXVol", "EURU", "", "1y", "", "1000000"]]);
+
+        let wnc = crate::v2_5::V2_5;
+        let margin = MarginByRiskClass::new(crif, "USD".to_string(), &wnc);
+        assert_eq!(margin.vega_margin()["FX"][&RiskType::Vega], 0.0);
+        assert_eq!(margin.curvature_margin()["FX"][&RiskType::Curvature], 0.0);
+    }
+
+    #[test]
+    fn test_fx_delta_in_calc_currency_is_eliminated() {
+        assert!(simm_utils::is_self_fx("EUR", "EUR"));
+        assert!(simm_utils::is_self_fx(" eur", "EUR"));
+        assert!(!simm_utils::is_self_fx("USD", "EUR"));
+
+        let fx_crif = |rows: &[(&str, &str)]| {
+            let rows: Vec<_> = rows.iter().map(|&(qualifier, amount)| ["RatesFX", "Risk_FX", qualifier, "", "", "", amount]).collect();
+            crif(&rows)
+        };
+
+        let wnc = crate::v2_6::V2_6;
+        let margin = MarginByRiskClass::new(fx_crif(&[("USD", "5e9"), ("EUR", "-7e9")]), "EUR".to_string(), &wnc);
+        let only_usd = MarginByRiskClass::new(fx_crif(&[("USD", "5e9")]), "EUR".to_string(), &wnc);
+        assert_eq!(margin.delta_margin()["FX"][&RiskType::Delta], only_usd.delta_margin()["FX"][&RiskType::Delta]);
+
+        // The EUR position leaves no weighted sensitivity at all
+        let ws = margin.weighted_sensitivities();
+        assert_eq!(ws.len(), 1);
+        assert_eq!(ws[0].qualifier, "USD");
+    }
+
+    #[test]
+    fn test_missing_delta_threshold_skips_concentration() {
+        // V2_5 has no equity bucket 13; a threshold of 1 would give a CR of ~2236
+        let crif = crif(&[["Equity", "Risk_Equity", "ABC", "13", "", "", "5000000"]]);
+        let wnc = crate::v2_5::V2_5;
+        let margin = MarginByRiskClass::new(crif, "USD".to_string(), &wnc);
+        margin.delta_margin();
+
+        assert_eq!(margin.weighted_sensitivities()[0].concentration, 1.0);
+        assert_eq!(
+            margin.warnings(),
+            ["No delta concentration threshold for Risk_Equity bucket 13; concentration not applied"]
+        );
+    }
+
+    #[test]
+    fn test_xccy_basis_has_no_concentration() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "5000000000"],
+            ["RatesFX", "Risk_XCcyBasis", "USD", "", "", "", "9000000000"],
+        ]);
+        let wnc = crate::v2_5::V2_5;
+        let margin = MarginByRiskClass::new(crif, "USD".to_string(), &wnc);
+        margin.ir_delta_margin();
+
+        let ws_rows = margin.weighted_sensitivities();
+        let curve = ws_rows.iter().find(|r| r.risk_type == "Risk_IRCurve").unwrap();
+        let basis = ws_rows.iter().find(|r| r.risk_type == "Risk_XCcyBasis").unwrap();
+
+        // The CR comes from the curve alone, not the larger basis amount
+        let t = wnc.t("Rates", "Delta", Some("USD"), None).unwrap();
+        assert!((curve.concentration - (5e9 / t).sqrt()).abs() < 1e-12);
+        assert!(curve.concentration > 1.0);
+        assert_eq!(basis.concentration, 1.0);
+        assert_eq!(basis.ws, CCY_BASIS_SWAP_SPREAD_RW * 9e9);
+    }
+
+    #[test]
+    fn test_inflation_indices_form_one_risk_factor() {
+        let two_indices = crif(&[
+            ["RatesFX", "Risk_IRCurve", "GBP", "1", "5y", "OIS", "400000"],
+            ["RatesFX", "Risk_Inflation", "GBP", "", "", "RPI", "300000"],
+            ["RatesFX", "Risk_Inflation", "GBP", "", "", "CPI", "-100000"],
+        ]);
+        let summed = crif(&[
+            ["RatesFX", "Risk_IRCurve", "GBP", "1", "5y", "OIS", "400000"],
+            ["RatesFX", "Risk_Inflation", "GBP", "", "", "", "200000"],
+        ]);
+
+        let wnc = crate::v2_5::V2_5;
+        let margin = MarginByRiskClass::new(two_indices, "USD".to_string(), &wnc);
+        let split = margin.ir_delta_margin()["Rates"][&RiskType::Delta];
+        let inflation: Vec<WsRow> = margin
+            .weighted_sensitivities()
+            .into_iter()
+            .filter(|r| r.risk_type == "Risk_Inflation")
+            .collect();
+        assert_eq!(inflation.len(), 1);
+        assert_eq!(inflation[0].sensitivity, 200000.0);
+
+        let single = MarginByRiskClass::new(summed, "USD".to_string(), &wnc).ir_delta_margin()["Rates"][&RiskType::Delta];
+        assert_eq!(split, single);
+    }
+
+    #[test]
+    fn test_residual_bucket_end_to_end() {
+        let crif = crif(&[
+            ["Equity", "Risk_Equity", "ABC", "3", "", "", "100000"],
+            ["Equity", "Risk_Equity", "XYZ", "Residual", "", "", "100000"],
+        ]);
+        let wnc = crate::v2_5::V2_5;
+        let margin = MarginByRiskClass::new(crif, "USD".to_string(), &wnc);
+        let delta = margin.delta_margin()["Equity"][&RiskType::Delta];
+
+        let ws_rows = margin.weighted_sensitivities();
+        let buckets: Vec<&str> = ws_rows.iter().map(|r| r.bucket.as_str()).collect();
+        assert_eq!(buckets, ["3", "Residual"]);
+        let rw_res = wnc.rw("Risk_Equity", "Residual").unwrap();
+        assert_eq!(ws_rows[1].risk_weight, rw_res);
+
+        // The residual K is added outside the cross-bucket aggregation
+        let rw_3 = wnc.rw("Risk_Equity", "3").unwrap();
+        assert!((delta - (rw_3 + rw_res) * 1e5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_curvature_squares_negative_commodity_gamma() {
+        let commodity_vol = |rows: &[[&str; 7]]| -> f64 {
+            let wnc = crate::v2_5::V2_5;
+            MarginByRiskClass::new(crif(rows), "USD".to_string(), &wnc).curvature_margin()["Commodity"][&RiskType::Curvature]
+        };
+        let row_7 = ["Commodity", "Risk_CommodityVol", "GAS", "7", "1y", "", "5000000"];
+        let row_12 = ["Commodity", "Risk_CommodityVol", "POWER", "12", "1y", "", "3000000"];
+
+        let gamma = crate::v2_5::V2_5.gamma("Risk_CommodityVol", "7", "12").unwrap();
+        assert!(gamma < 0.0);
+
+        // A single positive bucket has theta = 0, so its margin is (1 + lambda) * CVR
+        let lambda = curvature_lambda(1.0, 1.0, CURVATURE_CONFIDENCE).1;
+        let cvr_7 = commodity_vol(&[row_7]) / (1.0 + lambda);
+        let cvr_12 = commodity_vol(&[row_12]) / (1.0 + lambda);
+
+        let k_squared = cvr_7.powi(2) + cvr_12.powi(2) + 2.0 * gamma.powi(2) * cvr_7 * cvr_12;
+        let expected = cvr_7 + cvr_12 + lambda * k_squared.sqrt();
+        let both = commodity_vol(&[row_7, row_12]);
+        assert!((both - expected).abs() < 1e-6 * expected, "{} vs {}", both, expected);
+    }
+
+    /// All margins of a one-row CRIF under the v2.5 calibration
+    fn single_row_margins(row: [&str; 7]) -> MarginDict {
+        let wnc = crate::v2_5::V2_5;
+        MarginByRiskClass::new(crif(&[row]), "USD".to_string(), &wnc).calculate_all()
+    }
+
+    /// Assert that the margin of one risk class and measure matches `expected`
+    /// and every other margin is zero
+    fn assert_only_margin(margins: &MarginDict, risk_class: &str, measure: RiskType, expected: f64) {
+        for (rc, measures) in margins {
+            for (m, value) in measures {
+                if rc == risk_class && *m == measure {
+                    assert!((value - expected).abs() < 1e-9 * expected, "{} {:?}: {} vs {}", rc, m, value, expected);
+                } else {
+                    assert_eq!(*value, 0.0, "{} {:?}", rc, m);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_single_row_delta_margins() {
+        // One sensitivity of 1000 well below the thresholds: K = RW * 1000
+        let cases = [
+            (["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000"], "Rates", 52.0),
+            (["RatesFX", "Risk_FX", "EUR", "", "", "", "1000"], "FX", 7.4),
+            (["Credit", "Risk_CreditQ", "ISSUER", "1", "5y", "USD", "1000"], "CreditQ", 75.0),
+            (["Credit", "Risk_CreditNonQ", "ISSUER", "1", "5y", "CMBX", "1000"], "CreditNonQ", 280.0),
+            (["Equity", "Risk_Equity", "ABC", "1", "", "", "1000"], "Equity", 26.0),
+            (["Commodity", "Risk_Commodity", "GAS", "1", "", "", "1000"], "Commodity", 27.0),
+        ];
+        for (row, risk_class, rw) in cases {
+            assert_only_margin(&single_row_margins(row), risk_class, RiskType::Delta, rw * 1000.0);
+        }
+    }
+
+    #[test]
+    fn test_single_row_vega_and_curvature_margins() {
+        // sigma = RW * sqrt(365 / 14) / N^-1(0.99) converts a delta risk weight to a volatility
+        let sigma = |rw: f64| rw * (365.0_f64 / 14.0).sqrt() / 2.326_347_874_040_841;
+        // Curvature scaling of a 5y vega, 0.5 * 14 / (5 * 365) days
+        let sf_5y = 0.5 * 14.0 / 1825.0;
+        // A single positive CVR has theta = 0, so lambda = N^-1(0.995)^2 - 1
+        let one_plus_lambda = 2.575_829_303_548_900_4_f64.powi(2);
+
+        let cases = [
+            // Rates: VR = VRW * s; curvature is divided by HVR^2
+            (["RatesFX", "Risk_IRVol", "USD", "", "5y", "", "1000"], "Rates", 0.18 * 1000.0, 1000.0 / 0.44_f64.powi(2)),
+            // FX: VR = HVR * sigma * s * VRW with the regular FX RW of 7.4
+            (["RatesFX", "Risk_FXVol", "EURUSD", "", "5y", "", "1000"], "FX", 0.52 * sigma(7.4) * 1000.0 * 0.47, sigma(7.4) * 1000.0),
+            // Credit: VR = VRW * s
+            (["Credit", "Risk_CreditVol", "ISSUER", "1", "5y", "USD", "1000"], "CreditQ", 0.74 * 1000.0, 1000.0),
+            (["Credit", "Risk_CreditVolNonQ", "ISSUER", "1", "5y", "CMBX", "1000"], "CreditNonQ", 0.74 * 1000.0, 1000.0),
+            // Equity and commodity: VR = HVR * sigma * s * VRW, curvature uses sigma * s
+            (["Equity", "Risk_EquityVol", "ABC", "1", "5y", "", "1000"], "Equity", 0.58 * sigma(26.0) * 1000.0 * 0.45, sigma(26.0) * 1000.0),
+            (["Commodity", "Risk_CommodityVol", "GAS", "1", "5y", "", "1000"], "Commodity", 0.69 * sigma(27.0) * 1000.0 * 0.60, sigma(27.0) * 1000.0),
+        ];
+        for (row, risk_class, vega, curvature_exposure) in cases {
+            let margins = single_row_margins(row);
+            let curvature = one_plus_lambda * sf_5y * curvature_exposure;
+
+            let mut vega_only = margins.clone();
+            vega_only.get_mut(risk_class).unwrap().insert(RiskType::Curvature, 0.0);
+            assert_only_margin(&vega_only, risk_class, RiskType::Vega, vega);
+            let got = margins[risk_class][&RiskType::Curvature];
+            assert!((got - curvature).abs() < 1e-9 * curvature, "{} curvature: {} vs {}", risk_class, got, curvature);
+        }
+    }
+
+    #[test]
+    fn test_threshold_rate_shifts_concentration_breakpoint() {
+        let wnc = crate::v2_5::V2_5;
+        let t = wnc.t("Risk_Equity", "Delta", None, Some("1")).unwrap();
+        let amount = 0.75 * t;
+        let crif = crif(&[["Equity", "Risk_Equity", "ABC", "1", "", "", &amount.to_string()]]);
+        let rw = wnc.rw("Risk_Equity", "1").unwrap();
+
+        // Below the USD threshold there is no concentration
+        let usd = MarginByRiskClass::new(crif.clone(), "USD".to_string(), &wnc);
+        assert_eq!(usd.effective_threshold(t), t);
+        assert!((usd.delta_margin()["Equity"][&RiskType::Delta] - rw * amount).abs() < 1e-6);
+
+        // At 0.5 the threshold halves, so the same amount is 1.5 thresholds
+        let eur = MarginByRiskClass::new(crif, "EUR".to_string(), &wnc).with_threshold_rate(0.5);
+        assert_eq!(eur.effective_threshold(t), 0.5 * t);
+        let expected = rw * amount * 1.5_f64.sqrt();
+        assert!((eur.delta_margin()["Equity"][&RiskType::Delta] - expected).abs() < 1e-6 * expected);
+    }
+
+    #[test]
+    fn test_split_curvature_lambdas() {
+        // Phi^-1(0.995)^2 - 1, the lambda of a theta of 0
+        let base = 2.575_829_303_548_900_4_f64.powi(2) - 1.0;
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        let numbered = [Bucket::Numbered(1), Bucket::Numbered(4)];
+
+        // Non-residual only: theta = min(-50 / 150, 0) = -1/3
+        let (lambda, lambda_res) = split_curvature_lambdas(&numbered, (-50.0, 150.0), (0.0, 0.0));
+        assert!(close(lambda, base * (2.0 / 3.0) + 1.0 / 3.0));
+        assert_eq!(lambda_res, 0.0);
+
+        // Residual only: a positive net CVR has theta = 0
+        let (lambda, lambda_res) = split_curvature_lambdas(&[Bucket::Residual], (0.0, 0.0), (80.0, 100.0));
+        assert_eq!(lambda, 0.0);
+        assert!(close(lambda_res, base));
+
+        // Mixed: each group uses its own theta, -1/3 and -1 (all negative)
+        let mixed = [Bucket::Numbered(1), Bucket::Residual];
+        let (lambda, lambda_res) = split_curvature_lambdas(&mixed, (-50.0, 150.0), (-30.0, 30.0));
+        assert!(close(lambda, base * (2.0 / 3.0) + 1.0 / 3.0));
+        assert!(close(lambda_res, 1.0));
+    }
+
+    #[test]
+    fn test_credit_q_pair_correlations() {
+        // Two bucket 1 sensitivities of 1000 and 2000 (RW 75, below the
+        // threshold): K^2 = WS1^2 + WS2^2 + 2 rho WS1 WS2
+        let cases = [
+            // Same issuer, different tenor
+            (("ISSUER1", "5y", "USD"), ("ISSUER1", "10y", "USD"), 0.93_f64),
+            // Same issuer and tenor, different Label2 sub-curve
+            (("ISSUER1", "5y", "USD"), ("ISSUER1", "5y", "EUR"), 0.93),
+            // Different issuers
+            (("ISSUER1", "5y", "USD"), ("ISSUER2", "5y", "USD"), 0.42),
+            (("ISSUER1", "5y", "USD"), ("ISSUER2", "10y", "EUR"), 0.42),
+        ];
+        let wnc = crate::v2_5::V2_5;
+        for ((q1, t1, l1), (q2, t2, l2), rho) in cases {
+            let crif = crif(&[
+                ["Credit", "Risk_CreditQ", q1, "1", t1, l1, "1000"],
+                ["Credit", "Risk_CreditQ", q2, "1", t2, l2, "2000"],
+            ]);
+
+            let (ws1, ws2) = (75.0 * 1000.0, 75.0 * 2000.0);
+            let expected = (ws1 * ws1 + ws2 * ws2 + 2.0 * rho * ws1 * ws2).sqrt();
+            let margins = MarginByRiskClass::new(crif, "USD".to_string(), &wnc).calculate_all();
+            assert_only_margin(&margins, "CreditQ", RiskType::Delta, expected);
+        }
+    }
+
+    #[test]
+    fn test_ir_curvature_uses_version_hvr() {
+        for row in [
+            ["RatesFX", "Risk_IRVol", "USD", "", "5y", "", "1000000"],
+            ["RatesFX", "Risk_InflationVol", "USD", "", "1y", "", "400000"],
+        ] {
+            let crif = crif(&[row]);
+            let curvature = |wnc: &dyn WeightsAndCorr| {
+                MarginByRiskClass::new(crif.clone(), "USD".to_string(), wnc).ir_curvature_margin()["Rates"]
+                    [&RiskType::Curvature]
+            };
+            let v2_5 = curvature(&crate::v2_5::V2_5);
+            let v2_7 = curvature(&crate::v2_7::V2_7);
+
+            // A single vertex's curvature only depends on the version through HVR^2
+            let expected = v2_5 * (crate::v2_5::IR_HVR / crate::v2_7::IR_HVR).powi(2);
+            assert!(v2_5 > 0.0);
+            assert!((v2_7 - expected).abs() < 1e-9 * expected, "{}: {} vs {}", row[1], v2_7, expected);
+        }
+    }
+
+    /// The calibrations whose scalars the engine must pick up
+    const VERSIONS: [&dyn WeightsAndCorr; 3] = [&crate::v2_5::V2_5, &crate::v2_6::V2_6, &crate::v2_7::V2_7];
+
+    /// One margin of a CRIF under the given calibration
+    fn margin_of(rows: &[[&str; 7]], wnc: &dyn WeightsAndCorr, risk_class: &str, measure: RiskType) -> f64 {
+        MarginByRiskClass::new(crif(rows), "USD".to_string(), wnc).calculate_all()[risk_class][&measure]
+    }
+
+    fn assert_close(got: f64, expected: f64) {
+        assert!(expected > 0.0);
+        assert!((got - expected).abs() < 1e-9 * expected, "{} vs {}", got, expected);
+    }
+
+    /// Two sensitivities combined with correlation `rho`
+    fn combined(a: f64, b: f64, rho: f64) -> f64 {
+        (a * a + b * b + 2.0 * rho * a * b).sqrt()
+    }
+
+    /// Vega margin over the curvature exposure (sigma * s) of one vol row,
+    /// which leaves HVR * VRW whatever the delta risk weight
+    fn vega_hvr_times_vrw(row: [&str; 7], wnc: &dyn WeightsAndCorr, risk_class: &str) -> f64 {
+        let sf_5y = 0.5 * 14.0 / 1825.0;
+        let one_plus_lambda = 2.575_829_303_548_900_4_f64.powi(2);
+        let curvature = margin_of(&[row], wnc, risk_class, RiskType::Curvature);
+        margin_of(&[row], wnc, risk_class, RiskType::Vega) * one_plus_lambda * sf_5y / curvature
+    }
+
+    #[test]
+    fn test_ir_vega_uses_version_vrw() {
+        for wnc in VERSIONS {
+            let row = ["RatesFX", "Risk_IRVol", "USD", "", "5y", "", "1000"];
+            let got = margin_of(&[row], wnc, "Rates", RiskType::Vega);
+            assert_close(got, wnc.scalar_params().ir_vrw * 1000.0);
+        }
+    }
+
+    #[test]
+    fn test_inflation_delta_uses_version_rw() {
+        for wnc in VERSIONS {
+            let row = ["RatesFX", "Risk_Inflation", "USD", "", "", "", "1000"];
+            let got = margin_of(&[row], wnc, "Rates", RiskType::Delta);
+            assert_close(got, wnc.scalar_params().inflation_rw * 1000.0);
+        }
+    }
+
+    #[test]
+    fn test_ir_sub_curves_use_version_corr() {
+        let ois = ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000"];
+        let libor = ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "Libor3m", "1000"];
+        for wnc in VERSIONS {
+            let a = margin_of(&[ois], wnc, "Rates", RiskType::Delta);
+            let got = margin_of(&[ois, libor], wnc, "Rates", RiskType::Delta);
+            assert_close(got, combined(a, a, wnc.scalar_params().sub_curves_corr));
+        }
+    }
+
+    #[test]
+    fn test_inflation_uses_version_corr() {
+        let curve = ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000"];
+        let inflation = ["RatesFX", "Risk_Inflation", "USD", "", "", "", "1000"];
+        for wnc in VERSIONS {
+            let params = wnc.scalar_params();
+            let a = margin_of(&[curve], wnc, "Rates", RiskType::Delta);
+            let got = margin_of(&[curve, inflation], wnc, "Rates", RiskType::Delta);
+            assert_close(got, combined(a, params.inflation_rw * 1000.0, params.inflation_corr));
+        }
+    }
+
+    #[test]
+    fn test_xccy_basis_uses_version_corr() {
+        let curve = ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000"];
+        let basis = ["RatesFX", "Risk_XCcyBasis", "USD", "", "", "", "1000"];
+        for wnc in VERSIONS {
+            let params = wnc.scalar_params();
+            let a = margin_of(&[curve], wnc, "Rates", RiskType::Delta);
+            let got = margin_of(&[curve, basis], wnc, "Rates", RiskType::Delta);
+            assert_close(got, combined(a, params.ccy_basis_swap_spread_rw * 1000.0, params.ccy_basis_spread_corr));
+        }
+    }
+
+    #[test]
+    fn test_ir_currencies_use_version_gamma() {
+        let cases = [
+            (RiskType::Delta, ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000"], "Risk_IRCurve", "1"),
+            (RiskType::Vega, ["RatesFX", "Risk_IRVol", "USD", "", "5y", "", "1000"], "Risk_IRVol", ""),
+        ];
+        for wnc in VERSIONS {
+            for (measure, usd, risk_type, bucket) in cases {
+                let eur = ["RatesFX", risk_type, "EUR", bucket, "5y", usd[5], "2000"];
+                let (a, b) = (margin_of(&[usd], wnc, "Rates", measure), margin_of(&[eur], wnc, "Rates", measure));
+                let got = margin_of(&[usd, eur], wnc, "Rates", measure);
+                assert_close(got, combined(a, b, wnc.scalar_params().ir_gamma_diff_ccy));
+            }
+        }
+    }
+
+    #[test]
+    fn test_credit_q_vega_uses_version_vrw() {
+        let row = ["Credit", "Risk_CreditVol", "ISSUER", "1", "5y", "USD", "1000"];
+        for wnc in VERSIONS {
+            assert_close(margin_of(&[row], wnc, "CreditQ", RiskType::Vega), wnc.scalar_params().credit_q_vrw * 1000.0);
+        }
+    }
+
+    #[test]
+    fn test_credit_non_q_vega_uses_version_vrw() {
+        let row = ["Credit", "Risk_CreditVolNonQ", "ISSUER", "1", "5y", "CMBX", "1000"];
+        for wnc in VERSIONS {
+            let got = margin_of(&[row], wnc, "CreditNonQ", RiskType::Vega);
+            assert_close(got, wnc.scalar_params().credit_non_q_vrw * 1000.0);
+        }
+    }
+
+    #[test]
+    fn test_base_corr_uses_version_weight() {
+        let row = ["Credit", "Risk_BaseCorr", "CDX.IG", "", "", "", "1000"];
+        for wnc in VERSIONS {
+            let got = margin_of(&[row], wnc, "CreditQ", RiskType::BaseCorr);
+            assert_close(got, wnc.scalar_params().credit_q_base_corr_weight * 1000.0);
+        }
+    }
+
+    #[test]
+    fn test_equity_vega_uses_version_hvr_and_vrw() {
+        let row = ["Equity", "Risk_EquityVol", "ABC", "1", "5y", "", "1000"];
+        for wnc in VERSIONS {
+            let params = wnc.scalar_params();
+            assert_close(vega_hvr_times_vrw(row, wnc, "Equity"), params.equity_hvr * params.equity_vrw);
+        }
+    }
+
+    #[test]
+    fn test_equity_bucket_12_vega_uses_version_vrw() {
+        let row = ["Equity", "Risk_EquityVol", "VIX", "12", "5y", "", "1000"];
+        for wnc in VERSIONS {
+            let params = wnc.scalar_params();
+            let vega = margin_of(&[row], wnc, "Equity", RiskType::Vega);
+            // Bucket 12 has no curvature to divide by, so sigma comes from its risk weight
+            let sigma = wnc.rw("Risk_EquityVol", "12").unwrap() * (365.0_f64 / 14.0).sqrt() / 2.326_347_874_040_841;
+            assert_close(vega, params.equity_hvr * sigma * 1000.0 * params.equity_vrw_bucket_12);
+        }
+    }
+
+    #[test]
+    fn test_commodity_vega_uses_version_hvr_and_vrw() {
+        let row = ["Commodity", "Risk_CommodityVol", "GAS", "1", "5y", "", "1000"];
+        for wnc in VERSIONS {
+            let params = wnc.scalar_params();
+            assert_close(vega_hvr_times_vrw(row, wnc, "Commodity"), params.commodity_hvr * params.commodity_vrw);
+        }
+    }
+
+    #[test]
+    fn test_fx_vega_uses_version_hvr_and_vrw() {
+        let row = ["RatesFX", "Risk_FXVol", "EURUSD", "", "5y", "", "1000"];
+        for wnc in VERSIONS {
+            let params = wnc.scalar_params();
+            assert_close(vega_hvr_times_vrw(row, wnc, "FX"), params.fx_hvr * params.fx_vrw);
+        }
+    }
 }
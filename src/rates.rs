@@ -0,0 +1,88 @@
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+
+/// Source of currency conversion rates
+pub trait RateProvider {
+    /// Units of `to` per one unit of `from`, if known
+    fn rate(&self, from: &str, to: &str) -> Option<f64>;
+}
+
+/// Parse a rate written in either decimal convention
+///
+/// A lone comma is a decimal separator (`"1,2345"`); with both separators
+/// the later one is the decimal point and the other groups thousands
+/// (`"1,234.5"` and `"1.234,5"`). Scientific notation is accepted. Several
+/// commas and no point is ambiguous and rejected.
+pub fn parse_rate(value: &str) -> Result<f64> {
+    let trimmed = value.trim();
+    let normalized = match (trimmed.rfind(','), trimmed.rfind('.')) {
+        (Some(comma), Some(point)) if point > comma => trimmed.replace(',', ""),
+        (Some(_), Some(_)) => trimmed.replace('.', "").replace(',', "."),
+        (Some(_), None) if trimmed.matches(',').count() == 1 => trimmed.replace(',', "."),
+        (Some(_), None) => bail!("Ambiguous rate '{}': several commas and no decimal point", value),
+        (None, _) => trimmed.to_string(),
+    };
+
+    normalized.parse().map_err(|_| anyhow::anyhow!("Invalid rate '{}'", value))
+}
+
+/// Fixed set of rates held in memory
+#[derive(Debug, Clone, Default)]
+pub struct StaticRates {
+    rates: HashMap<(String, String), f64>,
+}
+
+impl StaticRates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the rate converting one unit of `from` into `to`
+    pub fn with_rate(mut self, from: &str, to: &str, rate: f64) -> Self {
+        self.rates.insert((from.to_string(), to.to_string()), rate);
+        self
+    }
+}
+
+impl RateProvider for StaticRates {
+    fn rate(&self, from: &str, to: &str) -> Option<f64> {
+        if from == to {
+            return Some(1.0);
+        }
+
+        if let Some(rate) = self.rates.get(&(from.to_string(), to.to_string())) {
+            return Some(*rate);
+        }
+
+        // Fall back to the inverse of the opposite quote
+        self.rates
+            .get(&(to.to_string(), from.to_string()))
+            .filter(|r| **r != 0.0)
+            .map(|r| 1.0 / r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_rates() {
+        let rates = StaticRates::new().with_rate("USD", "EUR", 0.8);
+        assert_eq!(rates.rate("USD", "EUR"), Some(0.8));
+        assert_eq!(rates.rate("EUR", "USD"), Some(1.25));
+        assert_eq!(rates.rate("GBP", "GBP"), Some(1.0));
+        assert_eq!(rates.rate("USD", "JPY"), None);
+    }
+
+    #[test]
+    fn test_parse_rate_decimal_conventions() {
+        assert_eq!(parse_rate("1,2345").unwrap(), 1.2345);
+        assert_eq!(parse_rate(" 0.92 ").unwrap(), 0.92);
+        assert_eq!(parse_rate("1,350.25").unwrap(), 1350.25);
+        assert_eq!(parse_rate("1.350,25").unwrap(), 1350.25);
+        assert_eq!(parse_rate("6,7e-3").unwrap(), 0.0067);
+        assert!(parse_rate("1,350,000").unwrap_err().to_string().contains("Ambiguous"));
+        assert!(parse_rate("abc").is_err());
+    }
+}
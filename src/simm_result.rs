@@ -0,0 +1,304 @@
+//! Structured SIMM results
+
+use serde::Serialize;
+use std::fmt::Write;
+
+/// Margin for one product class / risk class / risk measure cell
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimmResultRow {
+    pub product_class: String,
+    pub risk_class: String,
+    pub risk_measure: String,
+    pub amount: f64,
+}
+
+/// Components of the add-on margin
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AddOnBreakdown {
+    /// Sum of the `Param_AddOnFixedAmount` rows
+    pub fixed: f64,
+    /// `Param_AddOnNotionalFactor` (in percent) times `Notional`, per qualifier
+    pub notional_based: Vec<(String, f64)>,
+    /// SIMM scaled by `Param_ProductClassMultiplier - 1`, over product classes
+    pub product_multiplier: f64,
+}
+
+impl AddOnBreakdown {
+    /// Add-on margin before rounding
+    pub fn total(&self) -> f64 {
+        self.fixed + self.notional_based.iter().map(|(_, a)| a).sum::<f64>() + self.product_multiplier
+    }
+}
+
+/// A calibration lookup that found no value, so the engine used a default
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DefaultedLookup {
+    /// `WeightsAndCorr` method: `rw`, `rho`, `intra_bucket_rho`, `gamma`, `t` or `psi`
+    pub function: &'static str,
+    /// Arguments of the lookup, comma separated
+    pub arguments: String,
+    /// Value used in place of the missing parameter
+    pub default: f64,
+}
+
+/// Structured SIMM results, in the calculation currency
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimmResult {
+    /// Total SIMM including add-ons
+    pub total: f64,
+    /// Add-on margin (fixed, notional-based and product class multiplier)
+    pub addon: f64,
+    pub calculation_currency: String,
+    /// SIMM per product class, in product class order
+    pub product_classes: Vec<(String, f64)>,
+    /// One row per product class / risk class / risk measure
+    pub rows: Vec<SimmResultRow>,
+    /// Lookups that fell back to a default, when `EngineConfig::diagnostics`
+    /// is set
+    pub diagnostics: Vec<DefaultedLookup>,
+}
+
+/// Columns of the `SIMM::simm_break_down` table
+///
+/// `AddOn` is only present when the portfolio has an add-on, so look
+/// columns up by name with `index` rather than by position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakdownColumn {
+    SimmTotal,
+    AddOn,
+    ProductClass,
+    SimmProductClass,
+    RiskClass,
+    SimmRiskClass,
+    RiskMeasure,
+    SimmRiskMeasure,
+}
+
+impl BreakdownColumn {
+    /// Every column, in table order
+    pub const ALL: [BreakdownColumn; 8] = [
+        BreakdownColumn::SimmTotal,
+        BreakdownColumn::AddOn,
+        BreakdownColumn::ProductClass,
+        BreakdownColumn::SimmProductClass,
+        BreakdownColumn::RiskClass,
+        BreakdownColumn::SimmRiskClass,
+        BreakdownColumn::RiskMeasure,
+        BreakdownColumn::SimmRiskMeasure,
+    ];
+
+    /// Header name of the column
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            BreakdownColumn::SimmTotal => "SIMM Total",
+            BreakdownColumn::AddOn => "Add-On",
+            BreakdownColumn::ProductClass => "Product Class",
+            BreakdownColumn::SimmProductClass => "SIMM_ProductClass",
+            BreakdownColumn::RiskClass => "Risk Class",
+            BreakdownColumn::SimmRiskClass => "SIMM_RiskClass",
+            BreakdownColumn::RiskMeasure => "Risk Measure",
+            BreakdownColumn::SimmRiskMeasure => "SIMM_RiskMeasure",
+        }
+    }
+
+    /// Position of the column in a breakdown header, if present
+    pub fn index(self, header: &[String]) -> Option<usize> {
+        header.iter().position(|h| h == self.as_str())
+    }
+
+    /// Breakdown header, with the add-on column or without it
+    pub(crate) fn header(with_addon: bool) -> Vec<String> {
+        Self::ALL
+            .iter()
+            .filter(|c| with_addon || **c != BreakdownColumn::AddOn)
+            .map(|c| c.as_str().to_string())
+            .collect()
+    }
+}
+
+/// Column names of the ISDA results layout
+pub const ISDA_RESULTS_HEADER: [&str; 6] = [
+    "IMModel",
+    "ProductClass",
+    "RiskClass",
+    "RiskMeasure",
+    "Amount",
+    "AmountCurrency",
+];
+
+impl SimmResult {
+    /// Margin for a single cell, if present
+    pub fn amount(&self, product_class: &str, risk_class: &str, risk_measure: &str) -> Option<f64> {
+        self.rows
+            .iter()
+            .find(|r| r.product_class == product_class && r.risk_class == risk_class && r.risk_measure == risk_measure)
+            .map(|r| r.amount)
+    }
+
+    /// Results in the ISDA results CSV layout
+    ///
+    /// One row per product class / risk class / risk measure under the
+    /// `ISDA_RESULTS_HEADER` columns, with amounts to two decimals.
+    pub fn to_isda_csv(&self) -> String {
+        let mut out = ISDA_RESULTS_HEADER.join(",");
+        out.push('\n');
+
+        for row in &self.rows {
+            let _ = writeln!(
+                out,
+                "SIMM,{},{},{},{:.2},{}",
+                row.product_class, row.risk_class, row.risk_measure, row.amount, self.calculation_currency
+            );
+        }
+
+        out
+    }
+}
+
+/// One product class / risk class / risk measure cell compared between two
+/// results
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReconCell {
+    pub product_class: String,
+    pub risk_class: String,
+    pub risk_measure: String,
+    /// Our margin, `None` when we have no such cell
+    pub ours: Option<f64>,
+    /// The counterparty's margin, `None` when they have no such cell
+    pub theirs: Option<f64>,
+    /// `ours - theirs`, a missing cell counting as zero
+    pub difference: f64,
+    pub within_tolerance: bool,
+}
+
+/// Cell by cell comparison of our results with a counterparty's, for margin
+/// disputes
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReconReport {
+    /// Our cells in our order, then the cells only the counterparty has
+    pub cells: Vec<ReconCell>,
+    /// `ours.total - theirs.total`
+    pub total_difference: f64,
+    /// Absolute tolerance the cells were checked against
+    pub tolerance: f64,
+}
+
+impl ReconReport {
+    /// Cells whose difference exceeds the tolerance
+    pub fn mismatches(&self) -> impl Iterator<Item = &ReconCell> {
+        self.cells.iter().filter(|c| !c.within_tolerance)
+    }
+
+    /// True when every cell and the total agree within the tolerance
+    pub fn is_reconciled(&self) -> bool {
+        self.mismatches().next().is_none() && self.total_difference.abs() <= self.tolerance
+    }
+
+    /// The cells as CSV, with empty fields for missing cells
+    pub fn to_csv(&self) -> String {
+        let mut out = "ProductClass,RiskClass,RiskMeasure,Ours,Theirs,Difference,WithinTolerance\n".to_string();
+        let amount = |a: Option<f64>| a.map(|a| format!("{:.2}", a)).unwrap_or_default();
+        for cell in &self.cells {
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{:.2},{}",
+                cell.product_class,
+                cell.risk_class,
+                cell.risk_measure,
+                amount(cell.ours),
+                amount(cell.theirs),
+                cell.difference,
+                cell.within_tolerance
+            );
+        }
+        out
+    }
+}
+
+/// Compare our results with a counterparty's, cell by cell
+///
+/// Cells are matched on product class, risk class and risk measure; a cell
+/// only one side has is compared against zero. `tol` is an absolute amount
+/// in the calculation currency.
+pub fn reconcile_against(ours: &SimmResult, theirs: &SimmResult, tol: f64) -> ReconReport {
+    let cell = |row: &SimmResultRow, ours: Option<f64>, theirs: Option<f64>| {
+        let difference = ours.unwrap_or(0.0) - theirs.unwrap_or(0.0);
+        ReconCell {
+            product_class: row.product_class.clone(),
+            risk_class: row.risk_class.clone(),
+            risk_measure: row.risk_measure.clone(),
+            ours,
+            theirs,
+            difference,
+            within_tolerance: difference.abs() <= tol,
+        }
+    };
+
+    let mut cells: Vec<ReconCell> = ours
+        .rows
+        .iter()
+        .map(|row| cell(row, Some(row.amount), theirs.amount(&row.product_class, &row.risk_class, &row.risk_measure)))
+        .collect();
+    cells.extend(
+        theirs
+            .rows
+            .iter()
+            .filter(|row| ours.amount(&row.product_class, &row.risk_class, &row.risk_measure).is_none())
+            .map(|row| cell(row, None, Some(row.amount))),
+    );
+
+    ReconReport {
+        cells,
+        total_difference: ours.total - theirs.total,
+        tolerance: tol,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(rows: &[(&str, &str, &str, f64)]) -> SimmResult {
+        SimmResult {
+            total: rows.iter().map(|r| r.3).sum(),
+            calculation_currency: "USD".to_string(),
+            rows: rows
+                .iter()
+                .map(|&(product_class, risk_class, risk_measure, amount)| SimmResultRow {
+                    product_class: product_class.to_string(),
+                    risk_class: risk_class.to_string(),
+                    risk_measure: risk_measure.to_string(),
+                    amount,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_reconcile_against_flags_one_cell() {
+        let ours = result(&[
+            ("RatesFX", "Rates", "Delta", 1000.0),
+            ("RatesFX", "FX", "Delta", 500.0),
+            ("Equity", "Equity", "Vega", 300.0),
+        ]);
+        let theirs = result(&[
+            ("RatesFX", "Rates", "Delta", 1000.4),
+            ("RatesFX", "FX", "Delta", 450.0),
+            ("Equity", "Equity", "Vega", 300.0),
+        ]);
+
+        let report = reconcile_against(&ours, &theirs, 1.0);
+        assert_eq!(report.cells.len(), 3);
+        let mismatches: Vec<&ReconCell> = report.mismatches().collect();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].risk_class, "FX");
+        assert_eq!(mismatches[0].difference, 50.0);
+        assert!(report.cells[0].within_tolerance);
+        assert!(!report.is_reconciled());
+        assert!(report.to_csv().contains("RatesFX,FX,Delta,500.00,450.00,50.00,false"));
+
+        assert!(reconcile_against(&ours, &ours, 0.0).is_reconciled());
+    }
+}
+
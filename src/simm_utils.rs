@@ -1,9 +1,135 @@
-use crate::constants::SIMM_TENOR_LIST;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+
+use crate::constants::{CREDIT_TENOR_LIST, SIMM_TENOR_LIST};
+use crate::rates::RateProvider;
 
 /// Type alias for CRIF data (Common Risk Interchange Format)
 /// First row is the header, subsequent rows are data
 pub type Crif = Vec<Vec<String>>;
 
+/// Standard CRIF column order used when building a `Crif` from typed records
+pub const CRIF_HEADER: [&str; 9] = [
+    "ProductClass",
+    "RiskType",
+    "Qualifier",
+    "Bucket",
+    "Label1",
+    "Label2",
+    "Amount",
+    "AmountCurrency",
+    "AmountUSD",
+];
+
+/// A single typed CRIF row
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CrifRecord {
+    pub product_class: String,
+    pub risk_type: String,
+    pub qualifier: String,
+    pub bucket: String,
+    pub label1: String,
+    pub label2: String,
+    pub amount: f64,
+    pub amount_currency: String,
+    pub amount_usd: f64,
+}
+
+/// The fields that identify a risk factor: a CRIF row without its amounts
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RiskKey {
+    pub product_class: String,
+    pub risk_type: String,
+    pub qualifier: String,
+    pub bucket: String,
+    pub label1: String,
+    pub label2: String,
+}
+
+impl RiskKey {
+    /// Risk key of a CRIF record
+    pub fn of(record: &CrifRecord) -> Self {
+        Self {
+            product_class: record.product_class.clone(),
+            risk_type: record.risk_type.clone(),
+            qualifier: record.qualifier.clone(),
+            bucket: record.bucket.clone(),
+            label1: record.label1.clone(),
+            label2: record.label2.clone(),
+        }
+    }
+}
+
+/// Sum the USD amounts of records sharing a risk key
+pub fn group_records(records: &[CrifRecord]) -> HashMap<RiskKey, f64> {
+    let mut groups = HashMap::new();
+    for record in records {
+        *groups.entry(RiskKey::of(record)).or_insert(0.0) += record.amount_usd;
+    }
+    groups
+}
+
+/// Convert typed records into the stringly-typed `Crif` used by the engine
+pub fn crif_from_records(records: &[CrifRecord]) -> Crif {
+    let mut crif: Crif = vec![CRIF_HEADER.iter().map(|s| s.to_string()).collect()];
+
+    for r in records {
+        crif.push(vec![
+            r.product_class.clone(),
+            r.risk_type.clone(),
+            r.qualifier.clone(),
+            r.bucket.clone(),
+            r.label1.clone(),
+            r.label2.clone(),
+            r.amount.to_string(),
+            r.amount_currency.clone(),
+            r.amount_usd.to_string(),
+        ]);
+    }
+
+    crif
+}
+
+/// Convert a `Crif` into typed records
+///
+/// Missing columns and empty amounts map to defaults; non-numeric amounts are an error.
+pub fn records_from_crif(crif: &Crif) -> Result<Vec<CrifRecord>> {
+    let idx: Vec<Option<usize>> = CRIF_HEADER.iter().map(|c| get_column_index(crif, c)).collect();
+
+    let text = |row: &[String], col: usize| -> String {
+        idx[col]
+            .and_then(|i| row.get(i))
+            .cloned()
+            .unwrap_or_default()
+    };
+    let number = |row: &[String], col: usize, line: usize| -> Result<f64> {
+        let v = text(row, col);
+        if v.is_empty() {
+            return Ok(0.0);
+        }
+        parse_amount(&v)
+            .with_context(|| format!("Invalid {} '{}' on row {}", CRIF_HEADER[col], v, line))
+    };
+
+    crif.iter()
+        .enumerate()
+        .skip(1)
+        .map(|(line, row)| {
+            Ok(CrifRecord {
+                product_class: text(row, 0),
+                risk_type: text(row, 1),
+                qualifier: text(row, 2),
+                bucket: text(row, 3),
+                label1: text(row, 4),
+                label2: text(row, 5),
+                amount: number(row, 6, line)?,
+                amount_currency: text(row, 7),
+                amount_usd: number(row, 8, line)?,
+            })
+        })
+        .collect()
+}
+
 /// Get the index of a column by name from the header row
 pub fn get_column_index(crif: &Crif, column_name: &str) -> Option<usize> {
     if crif.is_empty() {
@@ -12,6 +138,288 @@ pub fn get_column_index(crif: &Crif, column_name: &str) -> Option<usize> {
     crif[0].iter().position(|x| x == column_name)
 }
 
+/// Resolve a canonical column name to its index, consulting `aliases`
+/// (canonical name -> actual header name) when the canonical name is absent
+pub fn resolve_column(crif: &Crif, column_name: &str, aliases: &HashMap<String, String>) -> Option<usize> {
+    get_column_index(crif, column_name).or_else(|| {
+        let alias = aliases.get(column_name)?;
+        get_column_index(crif, alias)
+    })
+}
+
+/// Pad short rows with empty strings to the header width
+///
+/// Also strips a UTF-8 byte order mark from the first header name, as left
+/// by Excel's "CSV UTF-8" export when a CRIF is read without the `csv`
+/// crate (which drops it itself). Fields beyond the header are kept; see
+/// `normalize_rows`.
+pub fn pad_rows(crif: &mut Crif) {
+    if let Some(first) = crif.first_mut().and_then(|header| header.first_mut())
+        && let Some(name) = first.strip_prefix('\u{feff}')
+    {
+        *first = name.to_string();
+    }
+
+    let Some(width) = crif.first().map(|h| h.len()) else {
+        return;
+    };
+
+    for row in crif.iter_mut().skip(1) {
+        if row.len() < width {
+            row.resize(width, String::new());
+        }
+    }
+}
+
+/// Pad short rows with empty strings and drop fields beyond the header,
+/// so every row has exactly one value per header column
+///
+/// # Returns
+/// One warning per row that lost a non-empty field
+pub fn normalize_rows(crif: &mut Crif) -> Vec<String> {
+    pad_rows(crif);
+    let Some(width) = crif.first().map(|h| h.len()) else {
+        return Vec::new();
+    };
+
+    let mut warnings = Vec::new();
+    for (i, row) in crif.iter_mut().enumerate().skip(1) {
+        let dropped = row.split_off(width);
+        if dropped.iter().any(|field| !field.is_empty()) {
+            warnings.push(format!("Row {}: {} field(s) beyond the header dropped", i, dropped.len()));
+        }
+    }
+
+    warnings
+}
+
+/// Rename aliased header columns to their canonical names in place
+pub fn apply_column_aliases(crif: &mut Crif, aliases: &HashMap<String, String>) {
+    for canonical in aliases.keys() {
+        if let Some(idx) = resolve_column(crif, canonical, aliases) {
+            crif[0][idx] = canonical.clone();
+        }
+    }
+}
+
+/// Use the `Amount` column as the sensitivity in place of `AmountUSD`
+///
+/// Every row takes its `Amount`, so a USD figure is never summed as if it
+/// were in the calculation currency. Rows with an empty `Amount` are left
+/// without an amount and reported; unparseable values are copied as is for
+/// `validate_amounts` to flag.
+///
+/// # Returns
+/// One warning per row with an empty `Amount`
+pub fn use_amount_as_sensitivity(crif: &mut Crif) -> Result<Vec<String>> {
+    let Some(amount_idx) = get_column_index(crif, "Amount") else {
+        anyhow::bail!("Amount column not found");
+    };
+
+    let usd_idx = match get_column_index(crif, "AmountUSD") {
+        Some(idx) => idx,
+        None => {
+            crif[0].push("AmountUSD".to_string());
+            crif[0].len() - 1
+        }
+    };
+
+    let mut warnings = Vec::new();
+    for (i, row) in crif.iter_mut().enumerate().skip(1) {
+        if row.len() <= usd_idx {
+            row.resize(usd_idx + 1, String::new());
+        }
+        let amount = row.get(amount_idx).cloned().unwrap_or_default();
+        if amount.is_empty() {
+            warnings.push(format!("Row {}: empty Amount", i));
+        }
+        row[usd_idx] = amount;
+    }
+
+    Ok(warnings)
+}
+
+/// Fill `AmountUSD` from `Amount` and `AmountCurrency`, converting each row
+/// at the rate of its own currency
+///
+/// Each currency's USD rate comes from `rates` when given and quoting it,
+/// otherwise from `fx_rates`, which maps a currency to the USD value of one
+/// unit; USD itself needs no entry. Only rows with a non-empty `Amount` and
+/// an empty or missing `AmountUSD` are converted. Rows whose currency has no
+/// rate, or whose `Amount` does not parse, are left without an amount and
+/// reported. A rate that is not a finite positive number is an error.
+///
+/// # Returns
+/// One warning per row left unconverted
+pub fn derive_amount_usd(
+    crif: &mut Crif,
+    fx_rates: &HashMap<String, f64>,
+    rates: Option<&dyn RateProvider>,
+) -> Result<Vec<String>> {
+    let Some(amount_idx) = get_column_index(crif, "Amount") else {
+        anyhow::bail!("Amount column not found");
+    };
+    let Some(ccy_idx) = get_column_index(crif, "AmountCurrency") else {
+        anyhow::bail!("AmountCurrency column not found");
+    };
+
+    let usd_idx = match get_column_index(crif, "AmountUSD") {
+        Some(idx) => idx,
+        None => {
+            crif[0].push("AmountUSD".to_string());
+            crif[0].len() - 1
+        }
+    };
+
+    let mut warnings = Vec::new();
+    for (i, row) in crif.iter_mut().enumerate().skip(1) {
+        if row.len() <= usd_idx {
+            row.resize(usd_idx + 1, String::new());
+        }
+        let amount = row.get(amount_idx).map(String::as_str).unwrap_or("");
+        if amount.is_empty() || !row[usd_idx].is_empty() {
+            continue;
+        }
+
+        let ccy = row.get(ccy_idx).map(String::as_str).unwrap_or("");
+        let rate = match rates.and_then(|r| r.rate(ccy, "USD")).or_else(|| fx_rates.get(ccy).copied()) {
+            Some(rate) if !rate.is_finite() || rate <= 0.0 => {
+                anyhow::bail!("Row {}: rate {} for AmountCurrency '{}' is not a positive number", i, rate, ccy)
+            }
+            Some(rate) => rate,
+            None if ccy == "USD" => 1.0,
+            None => {
+                warnings.push(format!("Row {}: no FX rate for AmountCurrency '{}'", i, ccy));
+                continue;
+            }
+        };
+        match parse_amount(amount) {
+            Ok(value) => row[usd_idx] = (value * rate).to_string(),
+            Err(e) => warnings.push(format!("Row {}: {}", i, e)),
+        }
+    }
+
+    Ok(warnings)
+}
+
+/// Serialize a CRIF to CSV text, header first
+///
+/// Fields holding commas, quotes or line breaks are quoted, so
+/// `parse_csv_from_string` reads the text back into the same `Crif` (short
+/// rows come back padded to the header width). For dumping the normalized,
+/// merged or filtered CRIF a calculation ran on.
+pub fn crif_to_csv(crif: &Crif) -> String {
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(Vec::new());
+    for row in crif {
+        writer.write_record(row).expect("writing CSV to memory");
+    }
+    String::from_utf8(writer.into_inner().expect("writing CSV to memory")).expect("CRIF fields are UTF-8")
+}
+
+/// Multiply the `AmountUSD` of each risk type in `signs` by its sign
+///
+/// Aligns a CRIF with another producer's sign convention. Empty and
+/// unparseable amounts are left for validation to report.
+///
+/// # Returns
+/// Number of rows flipped
+pub fn apply_sign_overrides(crif: &mut Crif, signs: &HashMap<String, f64>) -> usize {
+    let (Some(risk_type_idx), Some(amount_idx)) = (get_column_index(crif, "RiskType"), get_column_index(crif, "AmountUSD"))
+    else {
+        return 0;
+    };
+
+    let mut flipped = 0;
+    for row in crif.iter_mut().skip(1) {
+        let Some(&sign) = row.get(risk_type_idx).and_then(|rt| signs.get(rt)) else {
+            continue;
+        };
+        if sign == 1.0 {
+            continue;
+        }
+        if let Some(amount) = row.get_mut(amount_idx)
+            && let Ok(value) = parse_amount(amount)
+        {
+            *amount = (value * sign).to_string();
+            flipped += 1;
+        }
+    }
+
+    flipped
+}
+
+/// Concatenate the rows of several CRIFs under the header of the first
+///
+/// The CRIFs may list their columns in different orders, but must all have
+/// the same set of columns; rows are reordered to the first header.
+pub fn merge_crifs(crifs: &[Crif]) -> Result<Crif> {
+    let Some(header) = crifs.first().and_then(|crif| crif.first()) else {
+        anyhow::bail!("no CRIFs to merge");
+    };
+
+    let mut merged = vec![header.clone()];
+    for (n, crif) in crifs.iter().enumerate() {
+        let other = crif.first().map(Vec::as_slice).unwrap_or_default();
+        let positions: Option<Vec<usize>> = header.iter().map(|c| other.iter().position(|o| o == c)).collect();
+        let positions = match positions {
+            Some(positions) if other.len() == header.len() => positions,
+            _ => anyhow::bail!("CRIF {} header {:?} does not match {:?}", n, other, header),
+        };
+
+        merged.extend(
+            crif.iter()
+                .skip(1)
+                .map(|row| positions.iter().map(|&i| row.get(i).cloned().unwrap_or_default()).collect()),
+        );
+    }
+
+    Ok(merged)
+}
+
+/// CRIF columns listing the regulatory regimes a row applies to
+pub const REGULATION_COLUMNS: [&str; 2] = ["CollectRegulations", "PostRegulations"];
+
+/// Keep the rows of `crif` that apply under `regulation`
+///
+/// A row applies when either regulation column lists the regime (a comma
+/// separated list, optionally in brackets, compared case-insensitively), or
+/// when all its regulation cells are empty. A CRIF without the columns is
+/// returned unchanged.
+pub fn filter_by_regulation(crif: &Crif, regulation: &str) -> Crif {
+    let columns: Vec<usize> = REGULATION_COLUMNS.iter().filter_map(|c| get_column_index(crif, c)).collect();
+    let lists_regulation = |cell: &str| {
+        cell.trim_matches(|c: char| c == '[' || c == ']' || c.is_whitespace())
+            .split(',')
+            .any(|r| r.trim().trim_matches('"').eq_ignore_ascii_case(regulation))
+    };
+
+    crif.iter()
+        .enumerate()
+        .filter(|(i, row)| {
+            let cells: Vec<&str> = columns.iter().map(|&c| row.get(c).map(String::as_str).unwrap_or("")).collect();
+            *i == 0 || cells.iter().all(|c| c.trim().is_empty()) || cells.iter().any(|c| lists_regulation(c))
+        })
+        .map(|(_, row)| row.clone())
+        .collect()
+}
+
+/// Drop the rows of `crif` whose `Qualifier` is in `excluded`
+///
+/// Qualifiers are matched exactly, so `excluded` lists them as they appear
+/// in the CRIF (issuer, currency, ticker, ...). A CRIF without a `Qualifier`
+/// column is returned unchanged.
+pub fn exclude_qualifiers(crif: &Crif, excluded: &HashSet<String>) -> Crif {
+    let Some(qualifier_idx) = get_column_index(crif, "Qualifier") else {
+        return crif.clone();
+    };
+
+    crif.iter()
+        .enumerate()
+        .filter(|(i, row)| *i == 0 || !row.get(qualifier_idx).is_some_and(|q| excluded.contains(q)))
+        .map(|(_, row)| row.clone())
+        .collect()
+}
+
 /// Get all values from a column
 pub fn get_column_values(crif: &Crif, column_name: &str) -> Vec<Option<String>> {
     let idx = match get_column_index(crif, column_name) {
@@ -31,27 +439,111 @@ pub fn get_column_values(crif: &Crif, column_name: &str) -> Vec<Option<String>>
         .collect()
 }
 
+/// Column holding each row's position in the input CRIF, appended by the
+/// engine so aggregation can record which rows it consumed
+pub(crate) const ROW_INDEX_COLUMN: &str = "__RowIndex";
+
+/// Tag every data row with its 0-based position in `ROW_INDEX_COLUMN`
+pub(crate) fn add_row_index(crif: &mut Crif) {
+    let Some((header, rows)) = crif.split_first_mut() else {
+        return;
+    };
+    if header.iter().any(|c| c == ROW_INDEX_COLUMN) {
+        return;
+    }
+    let width = header.len();
+    header.push(ROW_INDEX_COLUMN.to_string());
+    for (i, row) in rows.iter_mut().enumerate() {
+        row.resize(width, String::new());
+        row.push(i.to_string());
+    }
+}
+
+/// Row positions recorded in `ROW_INDEX_COLUMN`, empty if the column is absent
+pub(crate) fn row_indices(crif: &Crif) -> Vec<usize> {
+    get_column_values(crif, ROW_INDEX_COLUMN)
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.parse().ok())
+        .collect()
+}
+
 /// Calculate Concentration Threshold
 pub fn concentration_threshold(sum_s: f64, t: f64) -> f64 {
     1.0_f64.max((sum_s.abs() / t).sqrt())
 }
 
-/// Sum Sensitivities (AmountUSD) from CRIF
-pub fn sum_sensitivities(crif: &Crif) -> f64 {
-    let amount_values = get_column_values(crif, "AmountUSD");
+/// Compensated (Kahan-Babuska/Neumaier) summation
+///
+/// Keeps the low-order digits that a plain running sum loses when large
+/// sensitivities of opposite sign cancel.
+pub fn kahan_sum<I: IntoIterator<Item = f64>>(values: I) -> f64 {
     let mut total = 0.0;
+    let mut compensation = 0.0;
 
-    for val in amount_values {
-        if let Some(v) = val {
-            if !v.is_empty() {
-                if let Ok(num) = v.parse::<f64>() {
-                    total += num;
-                }
-            }
+    for x in values {
+        let t = total + x;
+        if f64::abs(total) >= x.abs() {
+            compensation += (total - t) + x;
+        } else {
+            compensation += (x - t) + total;
         }
+        total = t;
     }
 
-    total
+    total + compensation
+}
+
+/// Bucket sum S_b capped to [-K_b, K_b] for the inter-bucket aggregation
+pub fn capped_bucket_sum(ws_sum: f64, k: f64) -> f64 {
+    ws_sum.min(k).max(-k)
+}
+
+/// Parse a CRIF amount, accepting scientific notation and comma thousands
+/// separators (e.g. `1,000,000` or `1.5e9`)
+///
+/// Commas are only accepted in groups of three digits, so a decimal comma
+/// such as `1,5` is an error rather than being read as `15`. `NaN`, `inf`
+/// and values overflowing `f64` are errors too.
+pub fn parse_amount(value: &str) -> Result<f64> {
+    let trimmed = value.trim();
+    let invalid = || anyhow::anyhow!("Invalid amount '{}'", value);
+
+    let cleaned = if trimmed.contains(',') {
+        let end = trimmed.find(['.', 'e', 'E']).unwrap_or(trimmed.len());
+        let (integer, rest) = trimmed.split_at(end);
+        let digits = integer.trim_start_matches(['+', '-']);
+
+        let mut groups = digits.split(',');
+        let first = groups.next().unwrap_or("");
+        let grouped = (1..=3).contains(&first.len())
+            && groups.all(|g| g.len() == 3)
+            && digits.chars().all(|c| c.is_ascii_digit() || c == ',');
+        if !grouped || rest.contains(',') {
+            return Err(invalid());
+        }
+        trimmed.replace(',', "")
+    } else {
+        trimmed.to_string()
+    };
+
+    match cleaned.parse::<f64>() {
+        Ok(amount) if amount.is_finite() => Ok(amount),
+        _ => Err(invalid()),
+    }
+}
+
+/// Sum Sensitivities (AmountUSD) from CRIF
+pub fn sum_sensitivities(crif: &Crif) -> f64 {
+    let amount_values = get_column_values(crif, "AmountUSD");
+
+    kahan_sum(
+        amount_values
+            .into_iter()
+            .flatten()
+            .filter(|v| !v.is_empty())
+            .filter_map(|v| parse_amount(&v).ok()),
+    )
 }
 
 /// Extract tenors as a list from CRIF
@@ -103,20 +595,36 @@ pub fn unique_column_values(crif: &Crif, column_name: &str) -> Vec<String> {
     result
 }
 
+/// Whether a `Risk_FX` qualifier is the calculation currency itself
+///
+/// There is no FX risk against oneself, so such sensitivities are dropped
+/// from the FX delta rather than just given a zero risk weight.
+pub fn is_self_fx(qualifier: &str, calc_ccy: &str) -> bool {
+    qualifier.trim().eq_ignore_ascii_case(calc_ccy.trim())
+}
+
+/// Canonical orientation of a currency pair, with the two currencies in
+/// alphabetical order (`USDKRW` and `KRWUSD` both give `KRWUSD`)
+pub fn canonical_currency_pair(pair: &str) -> String {
+    let (ccy1, ccy2) = pair.split_at(3);
+    if ccy1 <= ccy2 {
+        format!("{}{}", ccy1, ccy2)
+    } else {
+        format!("{}{}", ccy2, ccy1)
+    }
+}
+
 /// Extract currency pairs from CRIF as a list
+///
+/// Each pair appears once in its canonical orientation (see
+/// `canonical_currency_pair`), whatever order the orientations appear in.
 pub fn currency_pair_list(crif: &Crif) -> Vec<String> {
     let qualifier_values = get_column_values(crif, "Qualifier");
     let mut currency_pairs = Vec::new();
 
-    for x in qualifier_values {
-        if let Some(val) = x {
-            if !val.is_empty() && val != "nan" && val.len() == 6 {
-                // Prevent duplicates (e.g., KRWUSD is identical to USDKRW)
-                let reversed = format!("{}{}", &val[3..6], &val[0..3]);
-                if !currency_pairs.contains(&reversed) {
-                    currency_pairs.push(val);
-                }
-            }
+    for val in qualifier_values.into_iter().flatten() {
+        if val.len() == 6 && val.is_ascii() {
+            currency_pairs.push(canonical_currency_pair(&val));
         }
     }
 
@@ -147,27 +655,70 @@ pub fn product_list(crif: &Crif) -> Vec<String> {
     products
 }
 
-/// Extract buckets from CRIF
-pub fn bucket_list(crif: &Crif) -> Vec<usize> {
-    let bucket_values = get_column_values(crif, "Bucket");
+/// Bucket of a bucketed risk type (credit, equity, commodity)
+///
+/// Written as the bucket number or `Residual` in a CRIF and in the
+/// `WeightsAndCorr` lookups. `0` is accepted as the residual bucket too, and
+/// is its position in the calibrations' per-bucket tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Bucket {
+    Numbered(usize),
+    Residual,
+}
+
+impl Bucket {
+    /// Position in the per-bucket parameter tables, with the residual bucket at 0
+    pub fn index(self) -> usize {
+        match self {
+            Bucket::Numbered(n) => n,
+            Bucket::Residual => 0,
+        }
+    }
+
+    pub fn is_residual(self) -> bool {
+        self == Bucket::Residual
+    }
+}
+
+impl std::fmt::Display for Bucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bucket::Numbered(n) => write!(f, "{}", n),
+            Bucket::Residual => f.write_str("Residual"),
+        }
+    }
+}
+
+impl std::str::FromStr for Bucket {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Residual" | "0" => Ok(Bucket::Residual),
+            _ => s
+                .parse::<usize>()
+                .map(Bucket::Numbered)
+                .map_err(|_| anyhow::anyhow!("Invalid bucket '{}'", s)),
+        }
+    }
+}
+
+/// Extract buckets from CRIF, in order of appearance with the residual
+/// bucket last
+pub fn bucket_list(crif: &Crif) -> Vec<Bucket> {
     let mut buckets = Vec::new();
     let mut has_residual = false;
 
-    for x in bucket_values {
-        if let Some(val) = x {
-            if val == "Residual" {
-                has_residual = true;
-            } else if !val.is_empty() && val != "nan" {
-                if let Ok(num) = val.parse::<usize>() {
-                    buckets.push(num);
-                }
-            }
+    for val in get_column_values(crif, "Bucket").into_iter().flatten() {
+        match val.parse::<Bucket>() {
+            Ok(Bucket::Residual) => has_residual = true,
+            Ok(bucket) => buckets.push(bucket),
+            Err(_) => {}
         }
     }
 
-    // Residual goes to 0
     if has_residual {
-        buckets.push(0);
+        buckets.push(Bucket::Residual);
     }
 
     // Remove duplicates
@@ -177,33 +728,187 @@ pub fn bucket_list(crif: &Crif) -> Vec<usize> {
     buckets
 }
 
+/// Table index of a bucket label, see `Bucket::index`
+pub fn bucket_index(bucket: &str) -> Option<usize> {
+    bucket.parse::<Bucket>().ok().map(Bucket::index)
+}
+
+/// Length of a tenor label in days, e.g. "2w" or "5y"
+fn tenor_days(t: &str) -> Result<f64> {
+    let t_lower = t.trim().to_lowercase();
+
+    let days_per_unit = match t_lower.chars().last() {
+        Some('d') => 1.0,
+        Some('w') => 7.0,
+        Some('m') => 365.0 / 12.0,
+        Some('y') => 365.0,
+        _ => anyhow::bail!("Unparseable tenor: {}", t),
+    };
+
+    let count = t_lower[..t_lower.len() - 1]
+        .parse::<f64>()
+        .ok()
+        .filter(|n| *n > 0.0)
+        .with_context(|| format!("Unparseable tenor: {}", t))?;
+
+    Ok(days_per_unit * count)
+}
+
 /// Scaling Function of time t (for Curvature Margin)
-pub fn scaling_func(t: &str) -> f64 {
-    let t_lower = t.to_lowercase();
+///
+/// `0.5 * min(1, 14 / t_days)` for a tenor label in days, weeks, months or
+/// years (e.g. "3d", "1w", "2w", "6m", "5y").
+pub fn scaling_func(t: &str) -> Result<f64> {
+    let t_days = tenor_days(t).map_err(|_| anyhow::anyhow!("Unparseable tenor for curvature scaling: {}", t))?;
+    Ok(0.5 * (1.0_f64).min(14.0 / t_days))
+}
 
-    if t_lower == "2w" {
-        return 0.5;
-    } else if t_lower.contains('m') {
-        // Month tenor
-        if let Ok(months) = t_lower.replace('m', "").parse::<f64>() {
-            let t_days = (365.0 / 12.0) * months;
-            return 0.5 * (1.0_f64).min(14.0 / t_days);
-        }
-    } else if t_lower.contains('y') {
-        // Year tenor
-        if let Ok(years) = t_lower.replace('y', "").parse::<f64>() {
-            let t_days = 365.0 * years;
-            return 0.5 * (1.0_f64).min(14.0 / t_days);
+/// Days since 1970-01-01 of an ISO `YYYY-MM-DD` date
+pub(crate) fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+
+    // Days from civil, counting years from March so the leap day comes last
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146097 + doe - 719468)
+}
+
+/// Replace `Label1` maturity dates (`YYYY-MM-DD`) with the nearest SIMM tenor
+///
+/// The time to maturity from `valuation_date` is matched to the closest
+/// vertex in days, a year counting 365 days. A date exactly midway between
+/// two vertices goes to the shorter one (4 years less a day, 1460 days, maps
+/// to `3y` rather than `5y`). Credit rows (`Risk_Credit*`) use the credit
+/// vertices of `CREDIT_TENOR_LIST` so they net with the other credit
+/// sensitivities; all other rows use `SIMM_TENOR_LIST`. Dates on or before
+/// the valuation date map to the shortest vertex. Labels that are not dates
+/// are left alone.
+///
+/// # Returns
+/// Number of labels converted
+pub fn map_dates_to_tenors(crif: &mut Crif, valuation_date: &str) -> Result<usize> {
+    let valuation = parse_date(valuation_date)
+        .with_context(|| format!("Invalid valuation date '{}' (expected YYYY-MM-DD)", valuation_date))?;
+    let Some(label1_idx) = get_column_index(crif, "Label1") else {
+        return Ok(0);
+    };
+
+    let risk_type_idx = get_column_index(crif, "RiskType");
+    let vertices = |tenors: &[&'static str]| -> Result<Vec<(&'static str, f64)>> {
+        tenors.iter().map(|t| Ok((*t, tenor_days(t)?))).collect()
+    };
+    let (simm_vertices, credit_vertices) = (vertices(SIMM_TENOR_LIST)?, vertices(CREDIT_TENOR_LIST)?);
+
+    let mut converted = 0;
+    for row in crif.iter_mut().skip(1) {
+        let Some(maturity) = row.get(label1_idx).and_then(|label| parse_date(label)) else {
+            continue;
+        };
+        let is_credit = risk_type_idx
+            .and_then(|idx| row.get(idx))
+            .is_some_and(|risk_type| risk_type.starts_with("Risk_Credit"));
+        let vertices = if is_credit { &credit_vertices } else { &simm_vertices };
+
+        let days = (maturity - valuation).max(0) as f64;
+        let mut nearest = vertices[0];
+        for vertex in &vertices[1..] {
+            if (vertex.1 - days).abs() < (nearest.1 - days).abs() {
+                nearest = *vertex;
+            }
         }
+
+        row[label1_idx] = nearest.0.to_string();
+        converted += 1;
     }
 
-    0.5 // Default fallback
+    Ok(converted)
+}
+
+/// CRIF with the standard seven columns, `AmountUSD` last, for tests
+#[cfg(test)]
+pub(crate) fn crif(rows: &[[&str; 7]]) -> Crif {
+    const HEADER: [&str; 7] = ["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2", "AmountUSD"];
+    std::iter::once(&HEADER)
+        .chain(rows)
+        .map(|row| row.iter().map(|s| s.to_string()).collect())
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_sum_sensitivities_large_opposing_amounts() {
+        let mut crif = vec![vec!["AmountUSD".to_string()]];
+        for _ in 0..1000 {
+            crif.push(vec!["1e16".to_string()]);
+            crif.push(vec!["1".to_string()]);
+            crif.push(vec!["-1e16".to_string()]);
+        }
+
+        // A plain running sum drops every 1 against the 1e16 magnitude and returns 0
+        let naive: f64 = crif.iter().skip(1).map(|r| r[0].parse::<f64>().unwrap()).sum();
+        assert_eq!(naive, 0.0);
+        assert_eq!(sum_sensitivities(&crif), 1000.0);
+    }
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("1,000,000").unwrap(), 1_000_000.0);
+        assert_eq!(parse_amount("-1,234.5").unwrap(), -1234.5);
+        assert_eq!(parse_amount("1.5e9").unwrap(), 1.5e9);
+        assert_eq!(parse_amount(" 42 ").unwrap(), 42.0);
+        assert!(parse_amount("abc").is_err());
+        assert!(parse_amount("1,5").is_err());
+        assert!(parse_amount("1,00,000").is_err());
+        for non_finite in ["NaN", "inf", "-Infinity", "1e400"] {
+            assert!(parse_amount(non_finite).is_err(), "{}", non_finite);
+        }
+    }
+
+    #[test]
+    fn test_bucket_round_trip() {
+        assert_eq!(Bucket::Residual.to_string(), "Residual");
+        assert_eq!(Bucket::Numbered(7).to_string(), "7");
+        assert_eq!("0".parse::<Bucket>().unwrap(), Bucket::Residual);
+        assert!("Res".parse::<Bucket>().is_err());
+
+        let crif = vec![
+            vec!["Bucket".to_string()],
+            vec!["Residual".to_string()],
+            vec!["3".to_string()],
+            vec!["".to_string()],
+        ];
+        let buckets = bucket_list(&crif);
+        assert_eq!(buckets, vec![Bucket::Numbered(3), Bucket::Residual]);
+
+        for bucket in buckets {
+            assert_eq!(bucket.to_string().parse::<Bucket>().unwrap(), bucket);
+            assert_eq!(bucket_index(&bucket.to_string()), Some(bucket.index()));
+        }
+        assert_eq!(bucket_index("0"), Some(0));
+        assert_eq!(bucket_index("Res"), None);
+    }
+
     #[test]
     fn test_concentration_threshold() {
         let result = concentration_threshold(100.0, 25.0);
@@ -213,17 +918,64 @@ mod tests {
         assert_eq!(result2, 1.0); // max(1, sqrt(0.1)) = 1
     }
 
+    #[test]
+    fn test_currency_pair_list_either_order() {
+        let forward = vec![
+            vec!["Qualifier".to_string()],
+            vec!["USDKRW".to_string()],
+            vec!["EURUSD".to_string()],
+            vec!["KRWUSD".to_string()],
+        ];
+        let reverse = vec![
+            vec!["Qualifier".to_string()],
+            vec!["KRWUSD".to_string()],
+            vec!["USDEUR".to_string()],
+            vec!["USDKRW".to_string()],
+        ];
+
+        let expected = vec!["KRWUSD".to_string(), "EURUSD".to_string()];
+        assert_eq!(currency_pair_list(&forward), expected);
+        assert_eq!(currency_pair_list(&reverse), expected);
+    }
+
+    #[test]
+    fn test_capped_bucket_sum() {
+        assert_eq!(capped_bucket_sum(150.0, 100.0), 100.0);
+        assert_eq!(capped_bucket_sum(-150.0, 100.0), -100.0);
+        assert_eq!(capped_bucket_sum(40.0, 100.0), 40.0);
+        assert_eq!(capped_bucket_sum(-40.0, 100.0), -40.0);
+        assert_eq!(capped_bucket_sum(5.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_concentration_threshold_negative_sensitivity() {
+        assert_eq!(concentration_threshold(-100.0, 25.0), 2.0);
+        assert_eq!(concentration_threshold(-10.0, 100.0), 1.0);
+    }
+
     #[test]
     fn test_scaling_func() {
-        assert_eq!(scaling_func("2w"), 0.5);
+        assert_eq!(scaling_func("2w").unwrap(), 0.5);
 
-        let result_1y = scaling_func("1y");
+        let result_1y = scaling_func("1y").unwrap();
         assert!(result_1y > 0.0 && result_1y <= 0.5);
 
-        let result_3m = scaling_func("3m");
+        let result_3m = scaling_func("3m").unwrap();
         assert!(result_3m > 0.0 && result_3m <= 0.5);
     }
 
+    #[test]
+    fn test_scaling_func_days_and_weeks() {
+        // Under 14 days the scaling caps at 0.5
+        assert_eq!(scaling_func("1w").unwrap(), 0.5);
+        assert_eq!(scaling_func("3d").unwrap(), 0.5);
+        assert!((scaling_func("4w").unwrap() - 0.25).abs() < 1e-12);
+
+        assert!(scaling_func("abc").is_err());
+        assert!(scaling_func("").is_err());
+        assert!(scaling_func("0y").is_err());
+    }
+
     #[test]
     fn test_get_column_index() {
         let crif = vec![
@@ -234,4 +986,171 @@ mod tests {
         assert_eq!(get_column_index(&crif, "RiskType"), Some(1));
         assert_eq!(get_column_index(&crif, "NonExistent"), None);
     }
+
+    #[test]
+    fn test_resolve_column_alias() {
+        let mut crif = vec![
+            vec!["ProductClass".to_string(), "RiskType".to_string(), "AmountUsd".to_string()],
+            vec!["Rates".to_string(), "Risk_IRCurve".to_string(), "1000".to_string()],
+        ];
+        let mut aliases = HashMap::new();
+        aliases.insert("AmountUSD".to_string(), "AmountUsd".to_string());
+
+        assert_eq!(get_column_index(&crif, "AmountUSD"), None);
+        assert_eq!(resolve_column(&crif, "AmountUSD", &aliases), Some(2));
+        assert_eq!(resolve_column(&crif, "RiskType", &aliases), Some(1));
+
+        apply_column_aliases(&mut crif, &aliases);
+        assert_eq!(sum_sensitivities(&crif), 1000.0);
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let records = vec![CrifRecord {
+            product_class: "Rates".to_string(),
+            risk_type: "Risk_IRCurve".to_string(),
+            qualifier: "USD".to_string(),
+            bucket: "1".to_string(),
+            label1: "5y".to_string(),
+            label2: "Libor3m".to_string(),
+            amount: 1234.5,
+            amount_currency: "USD".to_string(),
+            amount_usd: 1234.5,
+        }];
+
+        let crif = crif_from_records(&records);
+        assert_eq!(crif[0], CRIF_HEADER.to_vec());
+        assert_eq!(records_from_crif(&crif).unwrap(), records);
+    }
+
+    #[test]
+    fn test_map_dates_to_tenors() {
+        let mut crif = vec![vec!["RiskType".to_string(), "Label1".to_string()]];
+        for label in ["2031-01-15", "2032-01-15", "2033-01-15", "2024-12-01", "5y", ""] {
+            crif.push(vec!["Risk_IRCurve".to_string(), label.to_string()]);
+        }
+
+        assert_eq!(map_dates_to_tenors(&mut crif, "2026-01-15").unwrap(), 4);
+        let labels: Vec<&str> = crif[1..].iter().map(|r| r[1].as_str()).collect();
+        // 5, 6 and 7 years out go to 5y (7 is nearer 5 than 10); past dates to 2w
+        assert_eq!(labels, vec!["5y", "5y", "5y", "2w", "5y", ""]);
+
+        let mut later = vec![crif[0].clone(), vec!["Risk_IRCurve".to_string(), "2034-03-01".to_string()]];
+        map_dates_to_tenors(&mut later, "2026-01-15").unwrap();
+        assert_eq!(later[1][1], "10y");
+
+        // 2030-01-14 is 1460 days out, midway between 3y (1095) and 5y (1825)
+        let mut tie = vec![crif[0].clone()];
+        for label in ["2030-01-14", "2030-01-15"] {
+            tie.push(vec!["Risk_IRCurve".to_string(), label.to_string()]);
+        }
+        map_dates_to_tenors(&mut tie, "2026-01-15").unwrap();
+        assert_eq!([tie[1][1].as_str(), tie[2][1].as_str()], ["3y", "5y"]);
+
+        // Credit rows only map to credit vertices: 3 months to 1y, 20 years to 10y
+        let mut credit = vec![crif[0].clone()];
+        for (risk_type, label) in [
+            ("Risk_CreditQ", "2026-04-15"),
+            ("Risk_CreditNonQ", "2046-01-15"),
+            ("Risk_IRCurve", "2046-01-15"),
+        ] {
+            credit.push(vec![risk_type.to_string(), label.to_string()]);
+        }
+        map_dates_to_tenors(&mut credit, "2026-01-15").unwrap();
+        let labels: Vec<&str> = credit[1..].iter().map(|r| r[1].as_str()).collect();
+        assert_eq!(labels, ["1y", "10y", "20y"]);
+
+        assert!(map_dates_to_tenors(&mut crif, "2026-02-30").is_err());
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2000-03-01"), Some(11017));
+    }
+
+    #[test]
+    fn test_derive_amount_usd_per_row_currency() {
+        let header = ["ProductClass", "RiskType", "Qualifier", "Bucket", "Label1", "Label2", "Amount", "AmountCurrency"];
+        let rows = [
+            ["Equity", "Risk_Equity", "A", "1", "", "", "1000", "EUR"],
+            ["Equity", "Risk_Equity", "B", "1", "", "", "2000", "GBP"],
+            ["Equity", "Risk_Equity", "C", "1", "", "", "3000", "USD"],
+            ["Equity", "Risk_Equity", "D", "1", "", "", "4000", "JPY"],
+        ];
+        let mut crif: Crif = std::iter::once(header.iter().map(|s| s.to_string()).collect())
+            .chain(rows.iter().map(|r| r.iter().map(|s| s.to_string()).collect()))
+            .collect();
+        let fx_rates = HashMap::from([("EUR".to_string(), 1.5), ("GBP".to_string(), 1.25)]);
+
+        let original = crif.clone();
+
+        let warnings = derive_amount_usd(&mut crif, &fx_rates, None).unwrap();
+        let usd: Vec<Option<String>> = get_column_values(&crif, "AmountUSD");
+        assert_eq!(
+            usd,
+            [Some("1500".to_string()), Some("2500".to_string()), Some("3000".to_string()), Some(String::new())]
+        );
+        assert_eq!(warnings, ["Row 4: no FX rate for AmountCurrency 'JPY'"]);
+
+        // A rate provider wins over fx_rates, which fill in the currencies it lacks
+        let rates = crate::rates::StaticRates::new().with_rate("EUR", "USD", 1.25).with_rate("USD", "JPY", 160.0);
+        let mut crif = original;
+        assert!(derive_amount_usd(&mut crif, &fx_rates, Some(&rates)).unwrap().is_empty());
+        let usd: Vec<Option<String>> = get_column_values(&crif, "AmountUSD");
+        assert_eq!(
+            usd,
+            [Some("1250".to_string()), Some("2500".to_string()), Some("3000".to_string()), Some("25".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_merge_crifs() {
+        let to_crif = |rows: &[&[&str]]| -> Crif { rows.iter().map(|r| r.iter().map(|s| s.to_string()).collect()).collect() };
+        let a = to_crif(&[&["RiskType", "Qualifier", "AmountUSD"], &["Risk_FX", "EUR", "100"]]);
+        let b = to_crif(&[&["AmountUSD", "RiskType", "Qualifier"], &["-40", "Risk_FX", "GBP"]]);
+
+        let merged = merge_crifs(&[a.clone(), b]).unwrap();
+        assert_eq!(merged, to_crif(&[
+            &["RiskType", "Qualifier", "AmountUSD"],
+            &["Risk_FX", "EUR", "100"],
+            &["Risk_FX", "GBP", "-40"],
+        ]));
+
+        let extra = to_crif(&[&["RiskType", "Qualifier", "AmountUSD", "Bucket"]]);
+        assert!(merge_crifs(&[a.clone(), extra]).is_err());
+        let missing = to_crif(&[&["RiskType", "AmountUSD"]]);
+        assert!(merge_crifs(&[a, missing]).is_err());
+        assert!(merge_crifs(&[]).is_err());
+    }
+
+    #[test]
+    fn test_filter_by_regulation() {
+        let to_crif = |rows: &[&[&str]]| -> Crif { rows.iter().map(|r| r.iter().map(|s| s.to_string()).collect()).collect() };
+        let crif = to_crif(&[
+            &["Qualifier", "CollectRegulations", "PostRegulations", "AmountUSD"],
+            &["A", "[SEC,CFTC]", "", "1"],
+            &["B", "EMIR", "EMIR", "2"],
+            &["C", "", "cftc", "3"],
+            &["D", "", "", "4"],
+        ]);
+
+        let cftc = filter_by_regulation(&crif, "CFTC");
+        let qualifiers: Vec<&str> = cftc.iter().skip(1).map(|r| r[0].as_str()).collect();
+        assert_eq!(qualifiers, ["A", "C", "D"]);
+        assert_eq!(filter_by_regulation(&crif, "EMIR").len(), 3);
+
+        let plain = to_crif(&[&["Qualifier", "AmountUSD"], &["A", "1"]]);
+        assert_eq!(filter_by_regulation(&plain, "CFTC"), plain);
+    }
+
+    #[test]
+    fn test_crif_to_csv_round_trips() {
+        let crif = crif(&[
+            ["RatesFX", "Risk_IRCurve", "USD", "1", "5y", "OIS", "1000000"],
+            ["Credit", "Risk_CreditQ", "ACME, Inc. \"Senior\"", "2", "5y", "USD", "-2.5e5"],
+            ["Equity", "Risk_Equity", "Line\nbreak", "1", "", "", ""],
+        ]);
+
+        let csv = crif_to_csv(&crif);
+        assert!(csv.starts_with("ProductClass,RiskType,"));
+        assert!(csv.contains("\"ACME, Inc. \"\"Senior\"\"\""));
+        assert_eq!(crate::file_utils::parse_csv_from_string(&csv).unwrap(), crif);
+    }
 }
@@ -0,0 +1,148 @@
+//! Sensitivity of the margin to the calibration parameters
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::agg_margins::SIMM;
+use crate::constants::{LIST_COMMODITY, LIST_CREDIT_NON_Q, LIST_CREDIT_Q, LIST_EQUITY};
+use crate::engine_config::{EngineConfig, SimmVersion};
+use crate::simm_utils::{CrifRecord, crif_from_records};
+use crate::wnc::ScaledWnc;
+
+/// Risk classes whose risk weights come from `WeightsAndCorr::rw`, with
+/// their risk types
+///
+/// Rates and FX risk weights are fixed tables in this crate rather than
+/// trait lookups, so they cannot be bumped this way.
+const RW_RISK_CLASSES: [(&str, &[&str]); 4] = [
+    ("CreditQ", LIST_CREDIT_Q),
+    ("CreditNonQ", LIST_CREDIT_NON_Q),
+    ("Equity", LIST_EQUITY),
+    ("Commodity", LIST_COMMODITY),
+];
+
+/// SIMM with every calibration correlation shifted toward 1
+///
+/// Adds `corr_bump` to each `rho`, `gamma` and `psi` of `version`'s
+/// calibration, capped at 1, and reruns the calculation. Correlations that
+/// are fixed in this crate rather than looked up through `WeightsAndCorr`
+/// (IR sub-curve and FX delta correlations) and a configured `psi_override`
+/// are not stressed. Less diversification raises the IM of a portfolio whose
+/// exposures add up, but can lower it where positions hedge each other.
+///
+/// # Arguments
+/// * `records` - Portfolio sensitivities
+/// * `cfg` - Engine configuration
+/// * `version` - Calibration to stress
+/// * `corr_bump` - Absolute correlation shift, e.g. `0.1`
+pub fn stressed_im(records: &[CrifRecord], cfg: &EngineConfig, version: SimmVersion, corr_bump: f64) -> Result<f64> {
+    let stressed = ScaledWnc::new(version.wnc(), 1.0).with_corr_shift(corr_bump);
+    Ok(SIMM::from_crif(crif_from_records(records), cfg, &stressed)?.simm)
+}
+
+/// Change in SIMM when the risk weights of each risk class are bumped
+///
+/// Each risk class in turn has its risk weights multiplied by `1 + bump`
+/// (so `bump = 0.01` is a 1% increase) with everything else unchanged.
+/// Covers the risk classes whose weights come from `WeightsAndCorr::rw`:
+/// CreditQ, CreditNonQ, Equity and Commodity. Rates and FX are excluded and
+/// have no entry in the result, since their weights are fixed tables.
+///
+/// # Arguments
+/// * `base` - Portfolio sensitivities
+/// * `cfg` - Engine configuration
+/// * `version` - Calibration to bump
+/// * `bump` - Relative risk weight bump
+///
+/// # Returns
+/// Bumped minus base SIMM, keyed by risk class
+pub fn im_sensitivity_to_rw(
+    base: &[CrifRecord],
+    cfg: &EngineConfig,
+    version: SimmVersion,
+    bump: f64,
+) -> Result<HashMap<String, f64>> {
+    let wnc = version.wnc();
+    let crif = crif_from_records(base);
+    let base_im = SIMM::from_crif(crif.clone(), cfg, wnc)?.simm;
+
+    RW_RISK_CLASSES
+        .iter()
+        .map(|(risk_class, risk_types)| {
+            let bumped = ScaledWnc::new(wnc, 1.0 + bump).with_risk_types(risk_types);
+            let im = SIMM::from_crif(crif.clone(), cfg, &bumped)?.simm;
+            Ok((risk_class.to_string(), im - base_im))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v2_5::V2_5;
+
+    fn record(product_class: &str, risk_type: &str, qualifier: &str, bucket: &str, label1: &str, amount: f64) -> CrifRecord {
+        CrifRecord {
+            product_class: product_class.to_string(),
+            risk_type: risk_type.to_string(),
+            qualifier: qualifier.to_string(),
+            bucket: bucket.to_string(),
+            label1: label1.to_string(),
+            label2: if risk_type == "Risk_CreditQ" { "USD".to_string() } else { String::new() },
+            amount,
+            amount_currency: "USD".to_string(),
+            amount_usd: amount,
+        }
+    }
+
+    fn portfolio() -> Vec<CrifRecord> {
+        vec![
+            record("RatesFX", "Risk_IRCurve", "USD", "1", "5y", 100000.0),
+            record("Credit", "Risk_CreditQ", "ISSUER1", "2", "5y", 50000.0),
+            record("Equity", "Risk_Equity", "ABC", "1", "", 30000.0),
+            record("Equity", "Risk_Equity", "XYZ", "5", "", -10000.0),
+        ]
+    }
+
+    #[test]
+    fn test_zero_bump_has_no_effect() {
+        let changes = im_sensitivity_to_rw(&portfolio(), &EngineConfig::default(), SimmVersion::V2_5, 0.0).unwrap();
+        assert_eq!(changes.len(), 4);
+        assert!(!changes.contains_key("Rates") && !changes.contains_key("FX"));
+        assert!(changes.values().all(|&v| v == 0.0));
+    }
+
+    #[test]
+    fn test_uniform_bump_increases_im_monotonically() {
+        let cfg = EngineConfig::default();
+        let small = im_sensitivity_to_rw(&portfolio(), &cfg, SimmVersion::V2_5, 0.01).unwrap();
+        let large = im_sensitivity_to_rw(&portfolio(), &cfg, SimmVersion::V2_5, 0.05).unwrap();
+
+        for risk_class in ["CreditQ", "Equity"] {
+            assert!(small[risk_class] > 0.0, "{}", risk_class);
+            assert!(large[risk_class] > small[risk_class], "{}", risk_class);
+        }
+        // No commodity exposure, so its bump changes nothing
+        assert_eq!(large["Commodity"], 0.0);
+    }
+
+    #[test]
+    fn test_stressed_im() {
+        let long_only = vec![
+            record("RatesFX", "Risk_IRCurve", "USD", "1", "5y", 100000.0),
+            record("RatesFX", "Risk_IRCurve", "USD", "1", "10y", 80000.0),
+            record("Credit", "Risk_CreditQ", "ISSUER1", "2", "5y", 50000.0),
+            record("Credit", "Risk_CreditQ", "ISSUER2", "3", "5y", 40000.0),
+            record("Equity", "Risk_Equity", "ABC", "1", "", 30000.0),
+            record("Equity", "Risk_Equity", "DEF", "1", "", 20000.0),
+            record("Equity", "Risk_Equity", "XYZ", "5", "", 10000.0),
+        ];
+        let cfg = EngineConfig::default();
+        let base = SIMM::from_crif(crif_from_records(&long_only), &cfg, &V2_5).unwrap().simm;
+
+        assert_eq!(stressed_im(&long_only, &cfg, SimmVersion::V2_5, 0.0).unwrap(), base);
+        let stressed = stressed_im(&long_only, &cfg, SimmVersion::V2_5, 0.1).unwrap();
+        assert!(stressed > base);
+        assert!(stressed_im(&long_only, &cfg, SimmVersion::V2_5, 2.0).unwrap() > stressed);
+    }
+}
@@ -1,7 +1,10 @@
 #![allow(dead_code)]
 
+use anyhow::{Result, anyhow};
+
 use crate::constants::*;
-use crate::wnc::WeightsAndCorr;
+use crate::simm_utils::bucket_index;
+use crate::wnc::{ScalarParams, WeightsAndCorr};
 
 /// SIMM version 2.5 parameter set
 pub struct V2_5;
@@ -94,11 +97,11 @@ pub static REG_VOL_RW: &[(&str, f64)] = &[
     ("30y", 66.0),
 ];
 
-pub fn reg_vol_rw_lookup(tenor: &str) -> f64 {
+pub fn reg_vol_rw_lookup(tenor: &str) -> Result<f64> {
     REG_VOL_RW.iter()
         .find(|(t, _)| *t == tenor)
-        .unwrap_or_else(|| panic!("Unknown tenor: {}", tenor))
-        .1
+        .map(|(_, rw)| *rw)
+        .ok_or_else(|| anyhow!("Unknown tenor: {}", tenor))
 }
 
 pub static LOW_VOL_RW: &[(&str, f64)] = &[
@@ -117,12 +120,12 @@ pub static LOW_VOL_RW: &[(&str, f64)] = &[
 ];
 
 /// Lookup function for low volatility RW
-pub fn low_vol_rw_lookup(tenor: &str) -> f64 {
+pub fn low_vol_rw_lookup(tenor: &str) -> Result<f64> {
     LOW_VOL_RW
         .iter()
         .find(|(t, _)| *t == tenor)
-        .unwrap_or_else(|| panic!("Unknown tenor: {}", tenor))
-        .1
+        .map(|(_, rw)| *rw)
+        .ok_or_else(|| anyhow!("Unknown tenor: {}", tenor))
 }
 
 pub static HIGH_VOL_RW: &[(&str, f64)] = &[
@@ -141,12 +144,12 @@ pub static HIGH_VOL_RW: &[(&str, f64)] = &[
 ];
 
 /// Lookup function for high volatility RW
-pub fn high_vol_rw_lookup(tenor: &str) -> f64 {
+pub fn high_vol_rw_lookup(tenor: &str) -> Result<f64> {
     HIGH_VOL_RW
         .iter()
         .find(|(t, _)| *t == tenor)
-        .unwrap_or_else(|| panic!("Unknown tenor: {}", tenor))
-        .1
+        .map(|(_, rw)| *rw)
+        .ok_or_else(|| anyhow!("Unknown tenor: {}", tenor))
 }
 
 pub const INFLATION_RW : f64 = 63.0;
@@ -336,12 +339,6 @@ pub const COMMODITY_CORR_NON_RES: [[f64; 17]; 17] = [
 
 pub const HIGH_VOL_CURRENCY_GROUP : &[&str] = &["BRL", "RUB", "TRY", "ZAR"];
 
-#[derive(Debug, Clone, Copy)]
-pub enum RiskLevel {
-    Regular,
-    High,
-}
-
 pub const fn fx_rw(outer: RiskLevel, inner: RiskLevel) -> f64 {
     match (outer, inner) {
         (RiskLevel::Regular, RiskLevel::Regular) => 7.4,
@@ -354,21 +351,6 @@ pub const fn fx_rw(outer: RiskLevel, inner: RiskLevel) -> f64 {
 pub const FX_HVR : f64 = 0.52;
 pub const FX_VRW : f64 = 0.47;
 
-#[derive(Debug, Clone, Copy)]
-pub enum VolatilityLevel {
-    Regular,
-    High,
-}
-
-impl VolatilityLevel {
-    const fn as_index(self) -> usize {
-        match self {
-            VolatilityLevel::Regular => 0,
-            VolatilityLevel::High => 1,
-        }
-    }
-}
-
 // Regular Volatility FX Correlations
 pub const FX_REG_VOL_CORR: [[f64; 2]; 2] = [
     [0.50, 0.27],  // Regular -> [Regular, High]
@@ -391,9 +373,6 @@ pub const fn fx_high_vol_corr(outer: VolatilityLevel, inner: VolatilityLevel) ->
 }
 
 pub const FX_VEGA_CORR:f64 = 0.5;
-static SIMM_TENORS: &[&str] = &[
-    "2w", "1m", "3m", "6m", "1y", "2y", "3y", "5y", "10y", "15y", "20y", "30y"
-];
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Currency {
@@ -680,8 +659,6 @@ pub const CORR_PARAMS: [[f64; 6]; 6] = [
     [0.32, 0.38, 0.12, 0.35, 0.41, 1.00],
 ];
 
-static RISK_CLASSES: &[&str] = &["Rates", "CreditQ", "CreditNonQ", "Equity", "Commodity", "FX"];
-
 // Bucket labels for gamma lookups
 static BUCKET_LIST_12: &[&str] = &["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12"];
 static BUCKET_LIST_17: &[&str] = &["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14", "15", "16", "17"];
@@ -704,12 +681,7 @@ fn map_lookup(table: &[(&str, f64)], key: &str) -> f64 {
 
 impl WeightsAndCorr for V2_5 {
     fn rw(&self, risk_class: &str, bucket: &str) -> Option<f64> {
-        // Parse bucket string to usize
-        let bucket_idx = if bucket == "Residual" {
-            0
-        } else {
-            bucket.parse::<usize>().ok()?
-        };
+        let bucket_idx = bucket_index(bucket)?;
 
         if LIST_CREDIT_Q.contains(&risk_class) {
             CREDIT_Q_RW.get(bucket_idx).copied()
@@ -727,7 +699,9 @@ impl WeightsAndCorr for V2_5 {
     fn rho(&self, risk_class: &str, index1: &str, index2: &str, bucket: Option<&str>) -> Option<f64> {
         if LIST_RATES.contains(&risk_class) {
             // For rates: use tenor correlation matrix
-            Some(Self::matrix_lookup_12(&IR_CORR, SIMM_TENORS, SIMM_TENORS, index1, index2))
+            let i = tenor_index(index1).unwrap_or_else(|| panic!("Row key not found: {}", index1));
+            let j = tenor_index(index2).unwrap_or_else(|| panic!("Col key not found: {}", index2));
+            Some(IR_CORR[i][j])
         } else if LIST_CREDIT_Q.contains(&risk_class) {
             // For CreditQ
             if risk_class == "Risk_BaseCorr" {
@@ -751,20 +725,12 @@ impl WeightsAndCorr for V2_5 {
         } else if LIST_EQUITY.contains(&risk_class) {
             // For Equity: correlation by bucket
             let bucket_str = bucket?;
-            let bucket_idx = if bucket_str == "Residual" {
-                0
-            } else {
-                bucket_str.parse::<usize>().ok()?
-            };
+            let bucket_idx = bucket_index(bucket_str)?;
             EQUITY_CORR.get(bucket_idx).copied()
         } else if LIST_COMMODITY.contains(&risk_class) {
             // For Commodity: correlation by bucket
             let bucket_str = bucket?;
-            let bucket_idx = if bucket_str == "Residual" {
-                0
-            } else {
-                bucket_str.parse::<usize>().ok()?
-            };
+            let bucket_idx = bucket_index(bucket_str)?;
             COMMODITY_CORR.get(bucket_idx).copied()
         } else {
             None
@@ -799,41 +765,25 @@ impl WeightsAndCorr for V2_5 {
             } else if LIST_CREDIT_Q.contains(&risk_class) {
                 // Credit Qualifying Delta CT
                 let bucket_str = bucket?;
-                let bucket_idx = if bucket_str == "Residual" {
-                    0
-                } else {
-                    bucket_str.parse::<usize>().ok()?
-                };
+                let bucket_idx = bucket_index(bucket_str)?;
                 let ct = CREDIT_DELTA_CT_QUALIFYING.get(bucket_idx)?;
                 Some(ct * 1_000_000.0)
             } else if LIST_CREDIT_NON_Q.contains(&risk_class) {
                 // Credit Non-Qualifying Delta CT
                 let bucket_str = bucket?;
-                let bucket_idx = if bucket_str == "Residual" {
-                    0
-                } else {
-                    bucket_str.parse::<usize>().ok()?
-                };
+                let bucket_idx = bucket_index(bucket_str)?;
                 let ct = CREDIT_DELTA_CT_NON_QUALIFYING.get(bucket_idx)?;
                 Some(ct * 1_000_000.0)
             } else if LIST_EQUITY.contains(&risk_class) {
                 // Equity Delta CT
                 let bucket_str = bucket?;
-                let bucket_idx = if bucket_str == "Residual" {
-                    0
-                } else {
-                    bucket_str.parse::<usize>().ok()?
-                };
+                let bucket_idx = bucket_index(bucket_str)?;
                 let ct = EQUITY_DELTA_CT.get(bucket_idx)?;
                 Some(ct * 1_000_000.0)
             } else if LIST_COMMODITY.contains(&risk_class) {
                 // Commodity Delta CT
                 let bucket_str = bucket?;
-                let bucket_idx = if bucket_str == "Residual" {
-                    0
-                } else {
-                    bucket_str.parse::<usize>().ok()?
-                };
+                let bucket_idx = bucket_index(bucket_str)?;
                 let ct = COMMODITY_DELTA_CT.get(bucket_idx)?;
                 Some(ct * 1_000_000.0)
             } else if LIST_FX.contains(&risk_class) {
@@ -862,21 +812,13 @@ impl WeightsAndCorr for V2_5 {
             } else if LIST_EQUITY.contains(&risk_class) {
                 // Equity Vega CT
                 let bucket_str = bucket?;
-                let bucket_idx = if bucket_str == "Residual" {
-                    0
-                } else {
-                    bucket_str.parse::<usize>().ok()?
-                };
+                let bucket_idx = bucket_index(bucket_str)?;
                 let ct = EQUITY_VEGA_CT.get(bucket_idx)?;
                 Some(*ct as f64 * 1_000_000.0)
             } else if LIST_COMMODITY.contains(&risk_class) {
                 // Commodity Vega CT
                 let bucket_str = bucket?;
-                let bucket_idx = if bucket_str == "Residual" {
-                    0
-                } else {
-                    bucket_str.parse::<usize>().ok()?
-                };
+                let bucket_idx = bucket_index(bucket_str)?;
                 let ct = COMMODITY_VEGA_CT.get(bucket_idx)?;
                 Some(*ct as f64 * 1_000_000.0)
             } else if LIST_FX.contains(&risk_class) {
@@ -900,6 +842,31 @@ impl WeightsAndCorr for V2_5 {
     }
 
     fn psi(&self, rc1: &str, rc2: &str) -> Option<f64> {
-        Some(Self::matrix_lookup_6(&CORR_PARAMS, RISK_CLASSES, RISK_CLASSES, rc1, rc2))
+        Some(Self::matrix_lookup_6(&CORR_PARAMS, &PSI_TABLE_ORDER, &PSI_TABLE_ORDER, rc1, rc2))
+    }
+
+    fn scalar_params(&self) -> ScalarParams {
+        ScalarParams {
+            ir_hvr: IR_HVR,
+            ir_vrw: IR_VRW,
+            inflation_rw: INFLATION_RW,
+            ccy_basis_swap_spread_rw: CCY_BASIS_SWAP_SPREAD_RW,
+            sub_curves_corr: SUB_CURVES_CORR,
+            inflation_corr: INFLATION_CORR,
+            ccy_basis_spread_corr: CCY_BASIS_SPREAD_CORR,
+            ir_gamma_diff_ccy: IR_GAMMA_DIFF_CCY,
+            credit_q_vrw: CREDIT_Q_V_RW,
+            credit_q_base_corr_weight: CREDIT_Q_BASE_CORR_WEIGHT,
+            credit_non_q_vrw: CREDIT_NON_Q_VRW,
+            credit_non_q_gamma_diff_ccy: CR_GAMMA_DIFF_CCY,
+            equity_hvr: EQUITY_HVR,
+            equity_vrw: EQUITY_VRW,
+            equity_vrw_bucket_12: EQUITY_VRW_BUCKET_12,
+            commodity_hvr: COMMODITY_HVR,
+            commodity_vrw: COMMODITY_VRW,
+            fx_hvr: FX_HVR,
+            fx_vrw: FX_VRW,
+            fx_vega_corr: FX_VEGA_CORR,
+        }
     }
 }
@@ -0,0 +1,492 @@
+//! CRIF validation checks run before the calculation
+
+use crate::constants::{
+    LIST_COMMODITY, LIST_CREDIT_NON_Q, LIST_CREDIT_Q, LIST_EQUITY, LIST_FX, LIST_PRODUCT_CLASSES, LIST_VEGA,
+};
+use crate::file_utils::CrifSchema;
+use crate::simm_utils::{Crif, get_column_index, parse_amount, product_list, scaling_func};
+
+/// Problems found while validating a CRIF
+///
+/// Errors make the CRIF unusable for the calculation; warnings are
+/// reported but do not stop it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationReport {
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl ValidationReport {
+    /// True when no errors were found
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Append the findings of another report
+    pub fn merge(&mut self, other: ValidationReport) {
+        self.warnings.extend(other.warnings);
+        self.errors.extend(other.errors);
+    }
+}
+
+/// Flag ProductClass values outside the documented set, and CRIFs mixing
+/// `RatesFX` with `Rates`
+///
+/// Each product class is margined separately and the results are summed,
+/// so a typo such as `Ratess` would otherwise add a phantom product class.
+pub fn validate_product_classes(crif: &Crif) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    let product_classes = product_list(crif);
+    for product_class in &product_classes {
+        if !LIST_PRODUCT_CLASSES.contains(&product_class.as_str()) {
+            report.errors.push(format!(
+                "Unknown ProductClass '{}' (expected one of: {})",
+                product_class,
+                LIST_PRODUCT_CLASSES.join(", ")
+            ));
+        }
+    }
+
+    // RatesFX is the ISDA product class holding both interest rate and FX
+    // risk; a separate Rates class alongside it is margined on its own and
+    // summed, losing the diversification between the two
+    if product_classes.iter().any(|p| p == "RatesFX") && product_classes.iter().any(|p| p == "Rates") {
+        report.warnings.push(
+            "CRIF mixes ProductClass 'RatesFX' and 'Rates'; they are margined as separate product classes".to_string(),
+        );
+    }
+
+    report
+}
+
+/// Flag vega rows whose Label1 tenor cannot be scaled for curvature
+pub fn validate_vol_tenors(crif: &Crif) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    let (Some(risk_type_idx), Some(label1_idx)) = (get_column_index(crif, "RiskType"), get_column_index(crif, "Label1")) else {
+        return report;
+    };
+
+    for (i, row) in crif.iter().enumerate().skip(1) {
+        let risk_type = row.get(risk_type_idx).map(String::as_str).unwrap_or("");
+        if !LIST_VEGA.contains(&risk_type) {
+            continue;
+        }
+
+        let tenor = row.get(label1_idx).map(String::as_str).unwrap_or("");
+        if let Err(e) = scaling_func(tenor) {
+            report.errors.push(format!("Row {}: {}", i, e));
+        }
+    }
+
+    report
+}
+
+/// Flag AmountUSD values that cannot be parsed as numbers
+///
+/// These are skipped by the calculation, so they are warnings unless the
+/// engine runs in strict mode.
+pub fn validate_amounts(crif: &Crif) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    let Some(amount_idx) = get_column_index(crif, "AmountUSD") else {
+        return report;
+    };
+
+    for (i, row) in crif.iter().enumerate().skip(1) {
+        let amount = row.get(amount_idx).map(String::as_str).unwrap_or("");
+        if !amount.is_empty()
+            && let Err(e) = parse_amount(amount)
+        {
+            report.warnings.push(format!("Row {}: {}", i, e));
+        }
+    }
+
+    report
+}
+
+/// Flag sensitivities whose size suggests a unit error
+///
+/// A row is flagged when its absolute `AmountUSD` exceeds `max_abs`, or is
+/// more than `max_to_median` times the median absolute amount of the
+/// sensitivity rows. Add-on parameter and notional rows are not checked.
+/// Both bounds are optional since plausible sizes depend on the portfolio.
+pub fn validate_amount_magnitudes(crif: &Crif, max_abs: Option<f64>, max_to_median: Option<f64>) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    let (Some(risk_type_idx), Some(amount_idx)) = (get_column_index(crif, "RiskType"), get_column_index(crif, "AmountUSD")) else {
+        return report;
+    };
+
+    let amounts: Vec<(usize, f64)> = crif
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, row)| {
+            let risk_type = row.get(risk_type_idx).map(String::as_str).unwrap_or("");
+            !risk_type.starts_with("Param_") && risk_type != "Notional"
+        })
+        .filter_map(|(i, row)| Some((i, parse_amount(row.get(amount_idx)?).ok()?)))
+        .filter(|(_, amount)| *amount != 0.0)
+        .collect();
+
+    let mut sizes: Vec<f64> = amounts.iter().map(|(_, a)| a.abs()).collect();
+    sizes.sort_by(f64::total_cmp);
+    let median = match sizes.len() {
+        0 => 0.0,
+        n if n % 2 == 1 => sizes[n / 2],
+        n => (sizes[n / 2 - 1] + sizes[n / 2]) / 2.0,
+    };
+
+    for (i, amount) in amounts {
+        if let Some(bound) = max_abs
+            && amount.abs() > bound
+        {
+            report.warnings.push(format!("Row {}: AmountUSD {} exceeds the bound {}", i, amount, bound));
+        } else if let Some(ratio) = max_to_median
+            && median > 0.0
+            && amount.abs() > ratio * median
+        {
+            report.warnings.push(format!(
+                "Row {}: AmountUSD {} is more than {} times the median absolute amount {}",
+                i, amount, ratio, median
+            ));
+        }
+    }
+
+    report
+}
+
+/// Flag FX rows whose `AmountCurrency` contradicts the qualifier or the
+/// reporting currency
+///
+/// A `Risk_FX` sensitivity is the exposure to the qualifier currency, so it
+/// cannot be denominated in that same currency; and when `reporting_currency`
+/// is given every FX amount is expected in it. Not part of `validate_crif`:
+/// merge the report in where the CRIF source is known to set `AmountCurrency`.
+pub fn validate_fx_amount_currency(crif: &Crif, reporting_currency: Option<&str>) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    let (Some(risk_type_idx), Some(qualifier_idx), Some(ccy_idx)) = (
+        get_column_index(crif, "RiskType"),
+        get_column_index(crif, "Qualifier"),
+        get_column_index(crif, "AmountCurrency"),
+    ) else {
+        return report;
+    };
+
+    for (i, row) in crif.iter().enumerate().skip(1) {
+        let field = |idx: usize| row.get(idx).map(String::as_str).unwrap_or("");
+        let risk_type = field(risk_type_idx);
+        let amount_ccy = field(ccy_idx);
+        if !LIST_FX.contains(&risk_type) || amount_ccy.is_empty() {
+            continue;
+        }
+
+        let qualifier = field(qualifier_idx);
+        if risk_type == "Risk_FX" && amount_ccy.eq_ignore_ascii_case(qualifier) {
+            report.warnings.push(format!(
+                "Row {}: Risk_FX amount is in its own qualifier currency {}",
+                i, qualifier
+            ));
+        } else if let Some(expected) = reporting_currency
+            && !amount_ccy.eq_ignore_ascii_case(expected)
+        {
+            report.warnings.push(format!(
+                "Row {}: {} AmountCurrency {} differs from the reporting currency {}",
+                i, risk_type, amount_ccy, expected
+            ));
+        }
+    }
+
+    report
+}
+
+/// Check the scale of the IR delta sensitivities against a known DV01
+///
+/// Sums the `AmountUSD` of the `Risk_IRCurve` rows, which for sensitivities
+/// per 1bp is the portfolio DV01, and warns when it is more than 10 times
+/// away from `expected_dv01` either way. A sum near 100 times too large or
+/// too small suggests sensitivities per 1% instead of per 1bp, or the
+/// reverse. Not part of `validate_crif`: it needs a DV01 from the source
+/// system.
+///
+/// # Returns
+/// Summed IR curve sensitivity and the findings
+pub fn validate_ir_delta_scale(crif: &Crif, expected_dv01: f64) -> (f64, ValidationReport) {
+    let mut report = ValidationReport::default();
+
+    let (Some(risk_type_idx), Some(amount_idx)) = (get_column_index(crif, "RiskType"), get_column_index(crif, "AmountUSD")) else {
+        return (0.0, report);
+    };
+
+    let total: f64 = crif
+        .iter()
+        .skip(1)
+        .filter(|row| row.get(risk_type_idx).is_some_and(|rt| rt == "Risk_IRCurve"))
+        .filter_map(|row| parse_amount(row.get(amount_idx)?).ok())
+        .sum();
+
+    if expected_dv01 == 0.0 {
+        report.errors.push("Expected DV01 must be non-zero".to_string());
+        return (total, report);
+    }
+
+    let ratio = (total / expected_dv01).abs();
+    if !(0.1..=10.0).contains(&ratio) {
+        let hint = if (50.0..=200.0).contains(&ratio) {
+            "; sensitivities may be per 1% rather than per 1bp"
+        } else if (0.005..=0.02).contains(&ratio) {
+            "; sensitivities may be per 0.01bp, or the DV01 per 1%"
+        } else {
+            ""
+        };
+        report.warnings.push(format!(
+            "IR delta sensitivities sum to {}, {:.3} times the expected DV01 {}{}",
+            total, ratio, expected_dv01, hint
+        ));
+    }
+
+    (total, report)
+}
+
+/// Risk types margined per bucket, which need the `Bucket` column: those
+/// of the credit, equity and commodity risk classes bar `Risk_BaseCorr`,
+/// which is margined per qualifier
+fn is_bucketed(risk_type: &str) -> bool {
+    risk_type != "Risk_BaseCorr"
+        && [LIST_CREDIT_Q, LIST_CREDIT_NON_Q, LIST_EQUITY, LIST_COMMODITY].iter().any(|list| list.contains(&risk_type))
+}
+
+/// Flag bucketed risk types in a CRIF without a `Bucket` column
+///
+/// Rates, FX and base correlation do not use buckets, so a CRIF holding only
+/// those may omit the column; bucketed rows would otherwise margin to zero.
+pub fn validate_bucket_column(crif: &Crif) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    let Some(risk_type_idx) = get_column_index(crif, "RiskType") else {
+        return report;
+    };
+    if get_column_index(crif, "Bucket").is_some() {
+        return report;
+    }
+
+    for (i, row) in crif.iter().enumerate().skip(1) {
+        let risk_type = row.get(risk_type_idx).map(String::as_str).unwrap_or("");
+        if is_bucketed(risk_type) {
+            report.errors.push(format!("Row {}: {} requires a Bucket column", i, risk_type));
+        }
+    }
+
+    report
+}
+
+/// Check the CRIF header against the columns of an expected schema
+///
+/// Missing schema columns are errors and columns outside the schema are
+/// warnings, so an upstream export that renames or adds columns is caught.
+/// Not part of `validate_crif`: the expected schema is agreed with the
+/// CRIF producer.
+pub fn validate_crif_schema(crif: &Crif, schema: CrifSchema) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let header = crif.first().map(Vec::as_slice).unwrap_or_default();
+    let columns = schema.columns();
+
+    for column in &columns {
+        if !header.iter().any(|h| h == column) {
+            report.errors.push(format!("Missing {:?} CRIF column {}", schema, column));
+        }
+    }
+    for column in header {
+        if !columns.contains(&column.as_str()) {
+            report.warnings.push(format!("Column {} is not in the {:?} CRIF schema", column, schema));
+        }
+    }
+
+    report
+}
+
+/// Run all CRIF validation checks
+pub fn validate_crif(crif: &Crif) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    report.merge(validate_product_classes(crif));
+    report.merge(validate_vol_tenors(crif));
+    report.merge(validate_amounts(crif));
+    report.merge(validate_bucket_column(crif));
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crif_with_products(products: &[&str]) -> Crif {
+        let mut crif = vec![vec!["ProductClass".to_string(), "RiskType".to_string()]];
+        for product in products {
+            crif.push(vec![product.to_string(), "Risk_IRCurve".to_string()]);
+        }
+        crif
+    }
+
+    #[test]
+    fn test_known_product_classes_are_valid() {
+        let crif = crif_with_products(&["RatesFX", "Credit", "Equity", "Commodity", "Rates", ""]);
+        assert!(validate_crif(&crif).is_valid());
+    }
+
+    #[test]
+    fn test_unscalable_vol_tenor_is_flagged() {
+        let crif = vec![
+            vec!["ProductClass".to_string(), "RiskType".to_string(), "Label1".to_string()],
+            vec!["RatesFX".to_string(), "Risk_IRVol".to_string(), "1w".to_string()],
+            vec!["RatesFX".to_string(), "Risk_IRVol".to_string(), "abc".to_string()],
+            vec!["RatesFX".to_string(), "Risk_IRCurve".to_string(), "abc".to_string()],
+        ];
+
+        let report = validate_crif(&crif);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].starts_with("Row 2"));
+    }
+
+    #[test]
+    fn test_unknown_product_class_is_flagged() {
+        let crif = crif_with_products(&["RatesFX", "Ratess"]);
+        let report = validate_crif(&crif);
+        assert!(!report.is_valid());
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].contains("Ratess"));
+    }
+
+    #[test]
+    fn test_non_finite_amounts_are_flagged() {
+        let crif = vec![
+            vec!["RiskType".to_string(), "AmountUSD".to_string()],
+            vec!["Risk_IRCurve".to_string(), "NaN".to_string()],
+            vec!["Risk_IRCurve".to_string(), "inf".to_string()],
+            vec!["Risk_IRCurve".to_string(), "1000".to_string()],
+        ];
+
+        let report = validate_amounts(&crif);
+        assert_eq!(report.warnings.len(), 2);
+        assert!(report.warnings[0].starts_with("Row 1"));
+        assert!(report.warnings[1].starts_with("Row 2"));
+    }
+
+    #[test]
+    fn test_fx_amount_currency_mismatch_is_flagged() {
+        let header = ["RiskType", "Qualifier", "AmountCurrency"];
+        let rows = [
+            ["Risk_FX", "EUR", "USD"],
+            ["Risk_FX", "EUR", "EUR"],
+            ["Risk_FXVol", "EURUSD", "GBP"],
+            ["Risk_IRCurve", "EUR", "EUR"],
+        ];
+        let mut crif: Crif = vec![header.iter().map(|s| s.to_string()).collect()];
+        crif.extend(rows.iter().map(|r| r.iter().map(|s| s.to_string()).collect()));
+
+        let report = validate_fx_amount_currency(&crif, None);
+        assert!(report.is_valid());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].starts_with("Row 2"));
+
+        let report = validate_fx_amount_currency(&crif, Some("USD"));
+        assert_eq!(report.warnings.len(), 2);
+        assert!(report.warnings[1].starts_with("Row 3"));
+        assert!(report.warnings[1].contains("GBP"));
+    }
+
+    #[test]
+    fn test_mixed_rates_product_classes_warn() {
+        let report = validate_crif(&crif_with_products(&["RatesFX", "Rates", "Credit"]));
+        assert!(report.is_valid());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("RatesFX"));
+
+        assert!(validate_crif(&crif_with_products(&["RatesFX", "Credit"])).warnings.is_empty());
+    }
+
+    #[test]
+    fn test_bucket_column_only_required_for_bucketed_risk_types() {
+        let header = vec!["ProductClass".to_string(), "RiskType".to_string()];
+        let row = |risk_type: &str| vec!["RatesFX".to_string(), risk_type.to_string()];
+
+        let rates_fx = vec![
+            header.clone(),
+            row("Risk_IRCurve"),
+            row("Risk_FX"),
+            row("Risk_FXVol"),
+            row("Risk_BaseCorr"),
+        ];
+        assert!(validate_crif(&rates_fx).is_valid());
+        for risk_type in [LIST_CREDIT_Q, LIST_CREDIT_NON_Q, LIST_EQUITY, LIST_COMMODITY].concat() {
+            assert_eq!(is_bucketed(risk_type), risk_type != "Risk_BaseCorr", "{}", risk_type);
+        }
+
+        let with_equity = vec![header, row("Risk_IRCurve"), row("Risk_Equity")];
+        let report = validate_bucket_column(&with_equity);
+        assert_eq!(report.errors, vec!["Row 2: Risk_Equity requires a Bucket column".to_string()]);
+    }
+
+    #[test]
+    fn test_amount_magnitudes_flag_outlier() {
+        let mut crif = vec![vec!["RiskType".to_string(), "AmountUSD".to_string()]];
+        for amount in ["1000000", "-2000000", "1500000", "3e12", "-800000"] {
+            crif.push(vec!["Risk_IRCurve".to_string(), amount.to_string()]);
+        }
+        crif.push(vec!["Notional".to_string(), "5e12".to_string()]);
+
+        assert!(validate_amount_magnitudes(&crif, None, None).warnings.is_empty());
+
+        let by_bound = validate_amount_magnitudes(&crif, Some(1e10), None);
+        assert_eq!(by_bound.warnings, vec!["Row 4: AmountUSD 3000000000000 exceeds the bound 10000000000".to_string()]);
+
+        // Median absolute amount is 1.5m
+        let by_median = validate_amount_magnitudes(&crif, None, Some(1000.0));
+        assert_eq!(by_median.warnings.len(), 1);
+        assert!(by_median.warnings[0].starts_with("Row 4:"));
+        assert!(by_median.is_valid());
+    }
+
+    #[test]
+    fn test_ir_delta_scale_flags_percent_sensitivities() {
+        let crif: Crif = [
+            ["RiskType", "Qualifier", "Label1", "AmountUSD"],
+            ["Risk_IRCurve", "USD", "5y", "30000"],
+            ["Risk_IRCurve", "USD", "10y", "20000"],
+            ["Risk_IRVol", "USD", "5y", "900000"],
+        ]
+        .iter()
+        .map(|r| r.iter().map(|s| s.to_string()).collect())
+        .collect();
+
+        let (total, report) = validate_ir_delta_scale(&crif, 50000.0);
+        assert_eq!(total, 50000.0);
+        assert!(report.warnings.is_empty());
+
+        // The same portfolio with a DV01 of 500 has sensitivities 100x too large
+        let (_, report) = validate_ir_delta_scale(&crif, 500.0);
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("per 1%"), "{}", report.warnings[0]);
+
+        assert!(!validate_ir_delta_scale(&crif, 0.0).1.is_valid());
+    }
+
+    #[test]
+    fn test_crif_schema_columns() {
+        let minimal: Crif = vec![CrifSchema::Minimal.columns().iter().map(|c| c.to_string()).collect()];
+        assert_eq!(validate_crif_schema(&minimal, CrifSchema::Minimal), ValidationReport::default());
+
+        let report = validate_crif_schema(&minimal, CrifSchema::Standard);
+        assert_eq!(report.errors, vec!["Missing Standard CRIF column Amount", "Missing Standard CRIF column AmountCurrency"]);
+
+        // A richer export validated against the minimal schema only warns
+        let mut rich = minimal.clone();
+        rich[0].extend(["Amount", "TradeID"].map(String::from));
+        let report = validate_crif_schema(&rich, CrifSchema::Minimal);
+        assert!(report.is_valid());
+        assert_eq!(report.warnings.len(), 2);
+        assert!(report.warnings[1].starts_with("Column TradeID"));
+    }
+}
@@ -1,7 +1,485 @@
+use std::cell::RefCell;
+
+use crate::agg_margins::PSI_RISK_CLASSES;
+use crate::constants::SIMM_TENOR_LIST;
+use crate::simm_result::DefaultedLookup;
+
+/// Scalar constants of a calibration, for display or audit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScalarParams {
+    /// IR historical volatility ratio
+    pub ir_hvr: f64,
+    /// IR vega risk weight
+    pub ir_vrw: f64,
+    /// Inflation delta risk weight
+    pub inflation_rw: f64,
+    /// Cross currency basis swap spread risk weight
+    pub ccy_basis_swap_spread_rw: f64,
+    /// Correlation between IR sub-curves of one currency
+    pub sub_curves_corr: f64,
+    /// Correlation of inflation with the IR curve
+    pub inflation_corr: f64,
+    /// Correlation of cross currency basis with the IR curve
+    pub ccy_basis_spread_corr: f64,
+    /// IR correlation across currencies
+    pub ir_gamma_diff_ccy: f64,
+    /// Credit qualifying vega risk weight
+    pub credit_q_vrw: f64,
+    /// Base correlation risk weight
+    pub credit_q_base_corr_weight: f64,
+    /// Credit non-qualifying vega risk weight
+    pub credit_non_q_vrw: f64,
+    /// Credit non-qualifying correlation across buckets
+    pub credit_non_q_gamma_diff_ccy: f64,
+    /// Equity historical volatility ratio
+    pub equity_hvr: f64,
+    /// Equity vega risk weight
+    pub equity_vrw: f64,
+    /// Equity vega risk weight of bucket 12
+    pub equity_vrw_bucket_12: f64,
+    /// Commodity historical volatility ratio
+    pub commodity_hvr: f64,
+    /// Commodity vega risk weight
+    pub commodity_vrw: f64,
+    /// FX historical volatility ratio
+    pub fx_hvr: f64,
+    /// FX vega risk weight
+    pub fx_vrw: f64,
+    /// Correlation between FX vol currency pairs
+    pub fx_vega_corr: f64,
+}
+
+/// Risk weights, correlations and concentration thresholds of a calibration
+///
+/// Bucket arguments are `Bucket` labels as displayed: the bucket number, or
+/// `Residual` for the residual bucket (`0` is accepted for it too).
 pub trait WeightsAndCorr {
     fn rw(&self, risk_class: &str, bucket: &str) -> Option<f64>;
     fn rho(&self, risk_class: &str, index1: &str, index2: &str, bucket: Option<&str>) -> Option<f64>;
     fn gamma(&self, risk_class: &str, bucket1: &str, bucket2: &str) -> Option<f64>;
     fn t(&self, risk_class: &str, risk_type: &str, currency: Option<&str>, bucket: Option<&str>) -> Option<f64>;
     fn psi(&self, risk_class1: &str, risk_class2: &str) -> Option<f64>;
+
+    /// Snapshot of the calibration's scalar constants
+    fn scalar_params(&self) -> ScalarParams;
+
+    /// Correlation between different qualifiers in the same equity or
+    /// commodity bucket
+    ///
+    /// ISDA uses the delta parameter for vega and curvature as well (squared
+    /// in curvature), so all three K functions read it from here.
+    fn intra_bucket_rho(&self, risk_class: &str, bucket: &str) -> Option<f64> {
+        self.rho(risk_class, "", "", Some(bucket))
+    }
+}
+
+/// Weights and correlations from another provider with the risk weights,
+/// and optionally the correlations, scaled
+///
+/// For "what if the calibration moves" analyses. `rw` is multiplied by
+/// `rw_scale`, for every risk type or only those in `risk_types`; `rho` and
+/// `gamma` by `corr_scale`, then shifted by `corr_shift` along with psi, all
+/// clamped to [-1, 1]. Concentration thresholds pass through unchanged.
+pub struct ScaledWnc<'a> {
+    pub inner: &'a dyn WeightsAndCorr,
+    pub rw_scale: f64,
+    pub risk_types: Option<&'a [&'a str]>,
+    pub corr_scale: f64,
+    pub corr_shift: f64,
+}
+
+impl<'a> ScaledWnc<'a> {
+    /// Scale only the risk weights, leaving correlations as they are
+    pub fn new(inner: &'a dyn WeightsAndCorr, rw_scale: f64) -> Self {
+        Self { inner, rw_scale, risk_types: None, corr_scale: 1.0, corr_shift: 0.0 }
+    }
+
+    /// Scale the risk weights of these risk types only (e.g. `LIST_EQUITY`)
+    pub fn with_risk_types(mut self, risk_types: &'a [&'a str]) -> Self {
+        self.risk_types = Some(risk_types);
+        self
+    }
+
+    /// Also scale the intra- and inter-bucket correlations
+    pub fn with_corr_scale(mut self, corr_scale: f64) -> Self {
+        self.corr_scale = corr_scale;
+        self
+    }
+
+    /// Also add `corr_shift` to every correlation, psi included (e.g. `0.1`
+    /// to move them all toward 1)
+    pub fn with_corr_shift(mut self, corr_shift: f64) -> Self {
+        self.corr_shift = corr_shift;
+        self
+    }
+
+    fn scale_corr(&self, corr: f64) -> f64 {
+        (corr * self.corr_scale + self.corr_shift).clamp(-1.0, 1.0)
+    }
+}
+
+impl WeightsAndCorr for ScaledWnc<'_> {
+    fn rw(&self, risk_class: &str, bucket: &str) -> Option<f64> {
+        let rw = self.inner.rw(risk_class, bucket)?;
+        if self.risk_types.is_none_or(|risk_types| risk_types.contains(&risk_class)) {
+            Some(rw * self.rw_scale)
+        } else {
+            Some(rw)
+        }
+    }
+
+    fn rho(&self, risk_class: &str, index1: &str, index2: &str, bucket: Option<&str>) -> Option<f64> {
+        self.inner.rho(risk_class, index1, index2, bucket).map(|c| self.scale_corr(c))
+    }
+
+    fn gamma(&self, risk_class: &str, bucket1: &str, bucket2: &str) -> Option<f64> {
+        self.inner.gamma(risk_class, bucket1, bucket2).map(|c| self.scale_corr(c))
+    }
+
+    fn t(&self, risk_class: &str, risk_type: &str, currency: Option<&str>, bucket: Option<&str>) -> Option<f64> {
+        self.inner.t(risk_class, risk_type, currency, bucket)
+    }
+
+    fn psi(&self, risk_class1: &str, risk_class2: &str) -> Option<f64> {
+        self.inner.psi(risk_class1, risk_class2).map(|c| (c + self.corr_shift).clamp(-1.0, 1.0))
+    }
+
+    fn scalar_params(&self) -> ScalarParams {
+        self.inner.scalar_params()
+    }
+}
+
+/// Weights and correlations from another provider, recording every lookup
+/// that finds no value along with the default the engine substitutes
+///
+/// The engine falls back to 1 for risk weights, correlations and
+/// thresholds, and to 0 for gamma and psi; a bucket's missing delta
+/// threshold means no concentration, recorded as an infinite threshold.
+pub(crate) struct RecordingWnc<'a> {
+    inner: &'a dyn WeightsAndCorr,
+    defaulted: RefCell<Vec<DefaultedLookup>>,
+}
+
+impl<'a> RecordingWnc<'a> {
+    pub(crate) fn new(inner: &'a dyn WeightsAndCorr) -> Self {
+        Self { inner, defaulted: RefCell::new(Vec::new()) }
+    }
+
+    /// Distinct defaulted lookups, in the order first seen
+    pub(crate) fn defaulted(&self) -> Vec<DefaultedLookup> {
+        self.defaulted.borrow().clone()
+    }
+
+    fn record(&self, value: Option<f64>, function: &'static str, arguments: String, default: f64) -> Option<f64> {
+        if value.is_none() {
+            let lookup = DefaultedLookup { function, arguments, default };
+            let mut defaulted = self.defaulted.borrow_mut();
+            if !defaulted.contains(&lookup) {
+                defaulted.push(lookup);
+            }
+        }
+        value
+    }
+}
+
+impl WeightsAndCorr for RecordingWnc<'_> {
+    fn rw(&self, risk_class: &str, bucket: &str) -> Option<f64> {
+        let value = self.inner.rw(risk_class, bucket);
+        self.record(value, "rw", format!("{}, {}", risk_class, bucket), 1.0)
+    }
+
+    fn rho(&self, risk_class: &str, index1: &str, index2: &str, bucket: Option<&str>) -> Option<f64> {
+        let value = self.inner.rho(risk_class, index1, index2, bucket);
+        let arguments = format!("{}, {}, {}, {:?}", risk_class, index1, index2, bucket);
+        self.record(value, "rho", arguments, 1.0)
+    }
+
+    fn gamma(&self, risk_class: &str, bucket1: &str, bucket2: &str) -> Option<f64> {
+        let value = self.inner.gamma(risk_class, bucket1, bucket2);
+        self.record(value, "gamma", format!("{}, {}, {}", risk_class, bucket1, bucket2), 0.0)
+    }
+
+    fn t(&self, risk_class: &str, risk_type: &str, currency: Option<&str>, bucket: Option<&str>) -> Option<f64> {
+        let value = self.inner.t(risk_class, risk_type, currency, bucket);
+        let default = if risk_type == "Delta" && bucket.is_some() { f64::INFINITY } else { 1.0 };
+        let arguments = format!("{}, {}, {:?}, {:?}", risk_class, risk_type, currency, bucket);
+        self.record(value, "t", arguments, default)
+    }
+
+    fn psi(&self, risk_class1: &str, risk_class2: &str) -> Option<f64> {
+        let value = self.inner.psi(risk_class1, risk_class2);
+        self.record(value, "psi", format!("{}, {}", risk_class1, risk_class2), 0.0)
+    }
+
+    fn scalar_params(&self) -> ScalarParams {
+        self.inner.scalar_params()
+    }
+
+    fn intra_bucket_rho(&self, risk_class: &str, bucket: &str) -> Option<f64> {
+        let value = self.inner.intra_bucket_rho(risk_class, bucket);
+        self.record(value, "intra_bucket_rho", format!("{}, {}", risk_class, bucket), 1.0)
+    }
+}
+
+/// One lookup whose value differs between two calibrations
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamDiff {
+    /// Trait method of the lookup: `rw`, `rho`, `gamma`, `t` or `psi`
+    pub parameter: &'static str,
+    /// Arguments of the lookup joined with `/`, e.g. `Risk_CreditQ/3`
+    pub key: String,
+    /// Value in the first calibration
+    pub a: Option<f64>,
+    /// Value in the second calibration
+    pub b: Option<f64>,
+}
+
+/// Parameters that differ between two calibrations, from `diff_calibrations`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CalibrationDiff {
+    pub differences: Vec<ParamDiff>,
+}
+
+impl CalibrationDiff {
+    /// Whether the two calibrations agree on every compared lookup
+    pub fn is_empty(&self) -> bool {
+        self.differences.is_empty()
+    }
+
+    /// Differences of one trait method
+    pub fn for_parameter<'a>(&'a self, parameter: &'a str) -> impl Iterator<Item = &'a ParamDiff> {
+        self.differences.iter().filter(move |d| d.parameter == parameter)
+    }
+}
+
+/// Currencies covering every concentration threshold category of the
+/// calibrations
+const DIFF_CURRENCIES: &[&str] = &["USD", "EUR", "JPY", "AUD", "CAD", "CHF", "CNY", "BRL", "TRY", "ZAR"];
+
+/// Compare two calibrations lookup by lookup
+///
+/// Covers the risk weights of every bucket, the correlations between every
+/// pair of IR tenors and of buckets, the concentration thresholds of every
+/// bucket and of a set of currencies, and psi. Lookups that are `None` in
+/// both are skipped; values are compared exactly.
+///
+/// # Arguments
+/// * `a` - First calibration, e.g. the current version
+/// * `b` - Second calibration, e.g. the new version
+pub fn diff_calibrations(a: &dyn WeightsAndCorr, b: &dyn WeightsAndCorr) -> CalibrationDiff {
+    let mut diff = CalibrationDiff::default();
+    let mut compare = |parameter: &'static str, key: String, lookup: &dyn Fn(&dyn WeightsAndCorr) -> Option<f64>| {
+        let (value_a, value_b) = (lookup(a), lookup(b));
+        if value_a != value_b {
+            diff.differences.push(ParamDiff { parameter, key, a: value_a, b: value_b });
+        }
+    };
+
+    // Bucketed risk types with the number of non-residual buckets
+    let bucketed = [
+        ("Risk_CreditQ", 12),
+        ("Risk_CreditNonQ", 2),
+        ("Risk_Equity", 12),
+        ("Risk_Commodity", 17),
+    ];
+    let labels = |count: usize| std::iter::once("Residual".to_string()).chain((1..=count).map(|b| b.to_string()));
+
+    for (risk_type, count) in bucketed {
+        for bucket in labels(count) {
+            compare("rw", format!("{}/{}", risk_type, bucket), &|w| w.rw(risk_type, &bucket));
+        }
+    }
+
+    for t1 in SIMM_TENOR_LIST {
+        for t2 in SIMM_TENOR_LIST {
+            compare("rho", format!("Risk_IRCurve/{}/{}", t1, t2), &|w| w.rho("Risk_IRCurve", t1, t2, None));
+        }
+    }
+    for risk_type in ["Risk_CreditQ", "Risk_BaseCorr", "Risk_CreditNonQ"] {
+        for (q1, q2) in [("A", "A"), ("A", "B"), ("A", "Res")] {
+            compare("rho", format!("{}/{}/{}", risk_type, q1, q2), &|w| w.rho(risk_type, q1, q2, None));
+        }
+    }
+    for (risk_type, count) in [("Risk_Equity", 12), ("Risk_Commodity", 17)] {
+        for bucket in labels(count) {
+            compare("rho", format!("{}/{}", risk_type, bucket), &|w| w.rho(risk_type, "", "", Some(&bucket)));
+        }
+    }
+
+    for (risk_type, count) in bucketed {
+        for b1 in 1..=count {
+            for b2 in 1..=count {
+                let (b1, b2) = (b1.to_string(), b2.to_string());
+                compare("gamma", format!("{}/{}/{}", risk_type, b1, b2), &|w| w.gamma(risk_type, &b1, &b2));
+            }
+        }
+    }
+
+    for measure in ["Delta", "Vega"] {
+        for ccy in DIFF_CURRENCIES {
+            compare("t", format!("Rates/{}/{}", measure, ccy), &|w| w.t("Rates", measure, Some(ccy), None));
+        }
+        for (risk_type, count) in bucketed {
+            for bucket in labels(count) {
+                compare("t", format!("{}/{}/{}", risk_type, measure, bucket), &|w| {
+                    w.t(risk_type, measure, None, Some(&bucket))
+                });
+            }
+        }
+    }
+    for ccy in DIFF_CURRENCIES {
+        compare("t", format!("Risk_FX/Delta/{}", ccy), &|w| w.t("Risk_FX", "Delta", Some(ccy), None));
+    }
+    for ccy1 in DIFF_CURRENCIES {
+        for ccy2 in DIFF_CURRENCIES {
+            let pair = format!("{}{}", ccy1, ccy2);
+            compare("t", format!("Risk_FXVol/Vega/{}", pair), &|w| w.t("Risk_FXVol", "Vega", Some(&pair), None));
+        }
+    }
+
+    for rc1 in PSI_RISK_CLASSES {
+        for rc2 in PSI_RISK_CLASSES {
+            compare("psi", format!("{}/{}", rc1, rc2), &|w| w.psi(rc1, rc2));
+        }
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v2_5::V2_5;
+
+    #[test]
+    fn test_scaled_wnc_delegates_and_scales() {
+        let base = V2_5;
+        let scaled = ScaledWnc::new(&base, 1.1).with_corr_scale(2.0);
+
+        assert_eq!(scaled.rw("Risk_Equity", "1"), base.rw("Risk_Equity", "1").map(|rw| rw * 1.1));
+        assert_eq!(scaled.rw("Risk_Equity", "99"), None);
+
+        // Correlations are scaled but stay within [-1, 1]
+        let rho = base.rho("Risk_Commodity", "", "", Some("1")).unwrap();
+        assert_eq!(scaled.rho("Risk_Commodity", "", "", Some("1")), Some((rho * 2.0).min(1.0)));
+        let gamma = base.gamma("Risk_Equity", "1", "2").unwrap();
+        assert_eq!(scaled.gamma("Risk_Equity", "1", "2"), Some(gamma * 2.0));
+
+        assert_eq!(scaled.t("Rates", "Delta", Some("USD"), None), base.t("Rates", "Delta", Some("USD"), None));
+        assert_eq!(scaled.psi("Rates", "FX"), base.psi("Rates", "FX"));
+
+        let equity_only = ScaledWnc::new(&base, 1.1).with_risk_types(crate::constants::LIST_EQUITY);
+        assert_eq!(equity_only.rw("Risk_Equity", "1"), scaled.rw("Risk_Equity", "1"));
+        assert_eq!(equity_only.rw("Risk_CreditQ", "1"), base.rw("Risk_CreditQ", "1"));
+
+        // A shift moves psi too, which scaling leaves alone
+        let shifted = ScaledWnc::new(&base, 1.0).with_corr_shift(0.1);
+        assert_eq!(shifted.gamma("Risk_Equity", "1", "2"), Some(gamma + 0.1));
+        assert_eq!(shifted.psi("Rates", "FX"), base.psi("Rates", "FX").map(|psi| psi + 0.1));
+        assert_eq!(ScaledWnc::new(&base, 1.0).with_corr_shift(2.0).psi("Rates", "FX"), Some(1.0));
+    }
+
+    #[test]
+    fn test_residual_bucket_lookups() {
+        let versions: [&dyn WeightsAndCorr; 3] = [&V2_5, &crate::v2_6::V2_6, &crate::v2_7::V2_7];
+        for wnc in versions {
+            for risk_class in ["Risk_CreditQ", "Risk_CreditNonQ", "Risk_Equity", "Risk_Commodity"] {
+                let rw = wnc.rw(risk_class, "Residual");
+                assert!(rw.is_some(), "{}", risk_class);
+                assert_eq!(wnc.rw(risk_class, "0"), rw);
+
+                let t = wnc.t(risk_class, "Delta", None, Some("Residual"));
+                assert!(t.is_some(), "{}", risk_class);
+                assert_eq!(wnc.t(risk_class, "Delta", None, Some("0")), t);
+            }
+
+            for risk_class in ["Risk_EquityVol", "Risk_CommodityVol"] {
+                let vt = wnc.t(risk_class, "Vega", None, Some("Residual"));
+                assert!(vt.is_some(), "{}", risk_class);
+                assert_eq!(wnc.t(risk_class, "Vega", None, Some("0")), vt);
+                assert_eq!(wnc.intra_bucket_rho(risk_class, "0"), wnc.intra_bucket_rho(risk_class, "Residual"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_scalar_params_match_module_constants() {
+        let v2_5 = V2_5.scalar_params();
+        assert_eq!(v2_5.ir_hvr, crate::v2_5::IR_HVR);
+        assert_eq!(v2_5.inflation_rw, crate::v2_5::INFLATION_RW);
+        assert_eq!(v2_5.equity_vrw_bucket_12, crate::v2_5::EQUITY_VRW_BUCKET_12);
+
+        let v2_6 = crate::v2_6::V2_6.scalar_params();
+        assert_eq!(v2_6.ir_vrw, crate::v2_6::IR_VRW);
+        assert_eq!(v2_6.credit_q_vrw, crate::v2_6::CREDIT_Q_V_RW);
+        assert_eq!(v2_6.commodity_hvr, crate::v2_6::COMMODITY_HVR);
+
+        let v2_7 = crate::v2_7::V2_7.scalar_params();
+        assert_eq!(v2_7.fx_hvr, crate::v2_7::FX_HVR);
+        assert_eq!(v2_7.fx_vrw, crate::v2_7::FX_VRW);
+        assert_eq!(v2_7.ccy_basis_spread_corr, crate::v2_7::CCY_BASIS_SPREAD_CORR);
+
+        assert_ne!(v2_5, v2_7);
+        assert_eq!(ScaledWnc::new(&V2_5, 2.0).scalar_params(), v2_5);
+    }
+
+    #[test]
+    fn test_fx_helpers_share_level_types() {
+        use crate::constants::{RiskLevel, VolatilityLevel};
+
+        let (regular, high) = (RiskLevel::Regular, RiskLevel::High);
+        assert_eq!(crate::v2_5::fx_rw(regular, high), 13.6);
+        assert_eq!(crate::v2_6::fx_rw(high, high), 21.4);
+        assert_eq!(crate::v2_7::fx_rw(regular, regular), 7.3);
+
+        let (regular, high) = (VolatilityLevel::Regular, VolatilityLevel::High);
+        assert_eq!(crate::v2_5::fx_reg_vol_corr(regular, high), crate::v2_5::FX_REG_VOL_CORR[0][1]);
+        assert_eq!(crate::v2_7::fx_high_vol_corr(high, high), crate::v2_7::FX_HIGH_VOL_CORR[1][1]);
+    }
+
+    #[test]
+    fn test_diff_calibrations_reports_credit_rw_changes() {
+        let diff = diff_calibrations(&crate::v2_6::V2_6, &crate::v2_7::V2_7);
+        let rw: Vec<&ParamDiff> = diff.for_parameter("rw").collect();
+
+        let credit_q_1 = rw.iter().find(|d| d.key == "Risk_CreditQ/1").unwrap();
+        assert_eq!((credit_q_1.a, credit_q_1.b), (Some(75.0), Some(69.0)));
+        let credit_non_q_res = rw.iter().find(|d| d.key == "Risk_CreditNonQ/Residual").unwrap();
+        assert_eq!((credit_non_q_res.a, credit_non_q_res.b), (Some(1300.0), Some(2900.0)));
+        // Unchanged between the versions
+        assert!(!rw.iter().any(|d| d.key == "Risk_CreditNonQ/1"));
+
+        assert!(diff_calibrations(&crate::v2_7::V2_7, &crate::v2_7::V2_7).is_empty());
+    }
+
+    #[test]
+    fn psi_lookup_follows_table_order() {
+        use crate::constants::PSI_TABLE_ORDER;
+
+        let tables: [(&dyn WeightsAndCorr, [[f64; 6]; 6], f64); 3] = [
+            (&V2_5, crate::v2_5::CORR_PARAMS, 0.32),
+            (&crate::v2_6::V2_6, crate::v2_6::CORR_PARAMS, 0.14),
+            (&crate::v2_7::V2_7, crate::v2_7::CORR_PARAMS, 0.09),
+        ];
+        for (wnc, table, rates_fx) in tables {
+            assert_eq!(wnc.psi("Rates", "FX"), Some(rates_fx));
+            for rc1 in PSI_RISK_CLASSES {
+                for rc2 in PSI_RISK_CLASSES {
+                    let i = PSI_TABLE_ORDER.iter().position(|rc| *rc == rc1).unwrap();
+                    let j = PSI_TABLE_ORDER.iter().position(|rc| *rc == rc2).unwrap();
+                    assert_eq!(wnc.psi(rc1, rc2), Some(table[i][j]), "{} {}", rc1, rc2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fx_delta_threshold_follows_version() {
+        // USD is an FX Category1 currency; each version has its own threshold
+        let versions: [(&dyn WeightsAndCorr, f64); 3] =
+            [(&V2_5, 5100.0), (&crate::v2_6::V2_6, 3300.0), (&crate::v2_7::V2_7, 2000.0)];
+        for (wnc, ct) in versions {
+            assert_eq!(wnc.t("Risk_FX", "Delta", Some("USD"), None), Some(ct * 1_000_000.0));
+        }
+    }
 }